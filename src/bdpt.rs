@@ -0,0 +1,465 @@
+use crate::scene::Scene;
+use crate::math::Vec3;
+use crate::primitive::Ray;
+use crate::primitive::Intersection;
+use crate::primitive::SpawnPoint;
+use crate::renderer::Renderer;
+use crate::control::RenderControl;
+use crate::renderer::sky;
+use crate::renderer::unoccluded;
+use crate::film::Film;
+use crate::sampler::Sampler;
+use crate::sampler::RandomSampler;
+
+//Bidirectional path tracer: for every camera sample a camera subpath and a
+//light subpath are traced and all pairs of their vertices are connected,
+//weighted with the balance heuristic. Connections to the camera (light
+//tracing) are splatted to the pixel they land in; they need a camera that
+//implements sample_importance, otherwise those strategies are left out.
+//Interface surfaces are crossed, participating media are ignored.
+pub struct BdptRenderer {
+    samples: u32,
+    //maximum number of bounces of a full path
+    max_depth: usize
+}
+
+#[derive(Copy,Clone,PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface
+}
+
+#[derive(Copy,Clone)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    p: Vec3,
+    //zero for the camera, which is a point
+    n: Vec3,
+    it: Option<Intersection<'a>>,
+    //index into scene.lights, for light vertices
+    light: usize,
+    //throughput of the subpath up to and including this vertex
+    beta: Vec3,
+    delta: bool,
+    //area densities of sampling this vertex from the previous vertex of its
+    //own subpath, and from the next one (the reverse direction)
+    pdf_fwd: f32,
+    pdf_rev: f32
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(p: Vec3, beta: Vec3) -> Vertex<'a> {
+        Vertex{kind: VertexKind::Camera, p, n: Vec3::new(), it: None, light: 0,
+            beta, delta: false, pdf_fwd: 0.0, pdf_rev: 0.0}
+    }
+
+    fn light(p: Vec3, n: Vec3, light: usize, beta: Vec3, pdf_fwd: f32) -> Vertex<'a> {
+        Vertex{kind: VertexKind::Light, p, n, it: None, light,
+            beta, delta: false, pdf_fwd, pdf_rev: 0.0}
+    }
+
+    fn surface(it: Intersection<'a>, beta: Vec3, pdf_fwd: f32) -> Vertex<'a> {
+        Vertex{kind: VertexKind::Surface, p: it.p, n: it.n, it: Some(it), light: 0,
+            beta, delta: false, pdf_fwd, pdf_rev: 0.0}
+    }
+
+    fn connectible(&self) -> bool {
+        !self.delta
+    }
+
+    //for connections; the camera and light vertices are off any surface
+    //that could be hit again
+    fn spawn_point(&self) -> SpawnPoint {
+        match &self.it {
+            Some(it) => it.spawn_point(),
+            None => SpawnPoint::at(self.p)
+        }
+    }
+
+    //scattering towards the point q, including the cosine at this vertex
+    fn f(&self, q: &Vec3) -> Vec3 {
+        match &self.it {
+            Some(it) => it.mat.eval(it, &(q - &self.p).normalized()),
+            None => Vec3::new()
+        }
+    }
+
+    //converts a solid angle density at this vertex into an area density at next
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        let w = next.p - self.p;
+        let d2 = w.lensqr();
+        if d2 == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf/d2;
+        if next.kind != VertexKind::Camera {
+            pdf *= next.n.dot(&w).abs()/d2.sqrt();
+        }
+        pdf
+    }
+
+    //area density at next of continuing the path prev -> self -> next
+    fn pdf(&self, scene: &Scene, time: f32, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let d = (next.p - self.p).normalized();
+        let pdf = match self.kind {
+            VertexKind::Light => scene.lights[self.light].pdf_emission(&self.p, &d).1,
+            VertexKind::Camera => scene.camera.pdf_direction(&d, time),
+            VertexKind::Surface => {
+                let (it, prev) = match (&self.it, prev) {
+                    (Some(it), Some(prev)) => (it, prev),
+                    _ => {return 0.0;}
+                };
+                let mut x = *it;
+                x.wo = (prev.p - self.p).normalized();
+                it.mat.pdf(&x, &d)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+}
+
+//light whose surface contains p
+fn find_light(scene: &Scene, p: &Vec3, d: &Vec3) -> Option<usize> {
+    (0..scene.lights.len()).find(|l| scene.lights[*l].pdf_emission(p, d).0 > 0.0)
+}
+
+fn remap0(f: f32) -> f32 {
+    if f != 0.0 {f} else {1.0}
+}
+
+impl BdptRenderer {
+    pub fn new(samples: u32, max_depth: usize) -> BdptRenderer {
+        BdptRenderer{samples, max_depth}
+    }
+
+    //Extends path from its last vertex along ray, whose direction was
+    //sampled with solid angle density pdf. Returns the throughput and the
+    //ray if it leaves the scene.
+    #[allow(clippy::too_many_arguments, reason = "the ray, the state of the walk and the path it extends")]
+    fn random_walk<'a>(&self, scene: &'a Scene, ray: Ray, beta: Vec3, pdf: f32, max_depth: usize,
+        path: &mut Vec<Vertex<'a>>, sampler: &mut dyn Sampler) -> Option<(Vec3, Ray)> {
+        if max_depth == 0 {
+            return None;
+        }
+        let primitive = &*scene.primitive;
+        let mut ray = ray;
+        let mut beta = beta;
+        let mut pdf_fwd = pdf;
+        let mut bounces = 0;
+        for _ in 0..256 {
+            let x = match primitive.intersect(&mut ray) {
+                None => {return Some((beta, ray));}
+                Some(x) => x
+            };
+            if x.mat.is_interface() {
+                ray = x.spawn_ray(ray.d, ray.time);
+                continue;
+            }
+            let prev = path.len() - 1;
+            let mut v = Vertex::surface(x, beta, 0.0);
+            v.pdf_fwd = path[prev].convert_density(pdf_fwd, &v);
+            path.push(v);
+            bounces += 1;
+            if bounces >= max_depth {
+                break;
+            }
+
+            let (wi, pdf) = x.mat.sample(&x, sampler.next(), sampler.next());
+            if pdf <= 0.0 {
+                break;
+            }
+            beta = &(&beta*&x.mat.eval(&x, &wi))/pdf;
+            if beta.is_zero() {
+                break;
+            }
+            let mut reverse = x;
+            reverse.wo = wi;
+            let mut pdf_rev = x.mat.pdf(&reverse, &x.wo);
+            pdf_fwd = pdf;
+            if x.mat.is_delta() {
+                path[prev + 1].delta = true;
+                pdf_fwd = 0.0;
+                pdf_rev = 0.0;
+            }
+            let current = path[prev + 1];
+            path[prev].pdf_rev = current.convert_density(pdf_rev, &path[prev]);
+            ray = x.spawn_ray(wi, ray.time);
+        }
+        None
+    }
+
+    fn light_subpath<'a>(&self, scene: &'a Scene, time: f32, path: &mut Vec<Vertex<'a>>, sampler: &mut dyn Sampler) {
+        if scene.lights.is_empty() {
+            return;
+        }
+        let n = scene.lights.len();
+        let l = ((sampler.next()*n as f32) as usize).min(n-1);
+        let es = match scene.lights[l].sample_emission(None, sampler.next(), sampler.next(),
+            sampler.next(), sampler.next()) {
+            Some(x) => x,
+            None => {return;}
+        };
+        let pdf_pos = es.pdf_pos/n as f32;
+        if pdf_pos <= 0.0 || es.pdf_dir <= 0.0 || es.radiance.is_zero() {
+            return;
+        }
+        path.push(Vertex::light(es.ray.o, es.n, l, &es.radiance/pdf_pos, pdf_pos));
+        let beta = es.radiance*(es.n.dot(&es.ray.d).abs()/(pdf_pos*es.pdf_dir));
+        let ray = Ray::with_time(es.ray.o, es.ray.d, time);
+        self.random_walk(scene, ray, beta, es.pdf_dir, self.max_depth, path, sampler);
+    }
+
+    //Balance heuristic weight of the strategy with s light and t camera
+    //vertices. sampled replaces the endpoint created by the connection.
+    #[allow(clippy::too_many_arguments, reason = "both subpaths and the connection between them")]
+    fn mis_weight(&self, scene: &Scene, time: f32, camera: &[Vertex], light: &[Vertex],
+        sampled: Option<Vertex>, s: usize, t: usize) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let mut cam: Vec<Vertex> = camera[..t].to_vec();
+        let mut lig: Vec<Vertex> = light[..s].to_vec();
+        if let Some(v) = sampled {
+            if s == 1 {
+                lig[0] = v;
+            } else if t == 1 {
+                cam[0] = v;
+            }
+        }
+        cam[t-1].delta = false;
+        if s > 0 {
+            lig[s-1].delta = false;
+        }
+
+        //densities of the reverse direction for the vertices next to the connection
+        let pt = cam[t-1];
+        if s > 0 {
+            let qs = lig[s-1];
+            let qs_minus = if s > 1 {Some(lig[s-2])} else {None};
+            let pt_minus = if t > 1 {Some(cam[t-2])} else {None};
+            cam[t-1].pdf_rev = qs.pdf(scene, time, qs_minus.as_ref(), &pt);
+            if let Some(v) = pt_minus {
+                cam[t-2].pdf_rev = pt.pdf(scene, time, Some(&qs), &v);
+            }
+            lig[s-1].pdf_rev = pt.pdf(scene, time, pt_minus.as_ref(), &qs);
+            if let Some(v) = qs_minus {
+                lig[s-2].pdf_rev = qs.pdf(scene, time, Some(&pt), &v);
+            }
+        } else {
+            //the camera path ends on an emitter
+            let d = if t > 1 {(cam[t-2].p - pt.p).normalized()} else {Vec3::new()};
+            let l = match find_light(scene, &pt.p, &d) {
+                Some(l) => l,
+                None => {return 1.0;}
+            };
+            let n = scene.lights.len() as f32;
+            let (pdf_pos, pdf_dir) = scene.lights[l].pdf_emission(&pt.p, &d);
+            cam[t-1].pdf_rev = pdf_pos/n;
+            if t > 1 {
+                let v = cam[t-2];
+                cam[t-2].pdf_rev = pt.convert_density(pdf_dir, &v);
+            }
+        }
+
+        let light_tracing = camera.len() > 1 && camera[1].pdf_fwd > 0.0;
+        let mut sum = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(cam[i].pdf_rev)/remap0(cam[i].pdf_fwd);
+            if !cam[i].delta && !cam[i-1].delta && (i > 1 || light_tracing) {
+                sum += ri;
+            }
+        }
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(lig[i].pdf_rev)/remap0(lig[i].pdf_fwd);
+            let delta_before = i > 0 && lig[i-1].delta;
+            if !lig[i].delta && !delta_before {
+                sum += ri;
+            }
+        }
+        1.0/(1.0 + sum)
+    }
+
+    //Contribution of the strategy with s light and t camera vertices, and
+    //for t = 1 the image coordinate it has to be splatted to
+    #[allow(clippy::too_many_arguments, reason = "both subpaths, the strategy joining them and its sampler")]
+    fn connect(&self, scene: &Scene, time: f32, camera: &[Vertex], light: &[Vertex],
+        s: usize, t: usize, sampler: &mut dyn Sampler) -> (Vec3, Option<Vec3>) {
+        let mut sampled = None;
+        let mut u = None;
+        let mut l = Vec3::new();
+        if s == 0 {
+            let pt = &camera[t-1];
+            if let Some(it) = &pt.it {
+                l = &pt.beta*&it.mat.emit(it);
+            }
+        } else if t == 1 {
+            let qs = &light[s-1];
+            if qs.connectible() {
+                if let Some(is) = scene.camera.sample_importance(&qs.p, time) {
+                    if is.pdf > 0.0 && is.importance > 0.0 {
+                        let v = Vertex::camera(qs.p + (is.wi*is.dist), Vec3::from(is.importance/is.pdf));
+                        l = &(&qs.beta*&qs.f(&v.p))*&v.beta;
+                        if !l.is_zero() && !unoccluded(scene, &qs.spawn_point(), &v.spawn_point(), time) {
+                            l = Vec3::new();
+                        }
+                        sampled = Some(v);
+                        u = Some(is.u);
+                    }
+                }
+            }
+        } else if s == 1 {
+            let pt = &camera[t-1];
+            if pt.connectible() && !scene.lights.is_empty() {
+                let n = scene.lights.len();
+                let li = ((sampler.next()*n as f32) as usize).min(n-1);
+                if let Some(ls) = scene.lights[li].sample(&pt.p, None, sampler.next(), sampler.next()) {
+                    if ls.pdf > 0.0 && !ls.radiance.is_zero() {
+                        let p = pt.p + (ls.wi*ls.dist);
+                        let pdf_pos = scene.lights[li].pdf_emission(&p, &(ls.wi*(-1.0))).0;
+                        let v = Vertex::light(p, ls.n, li, &ls.radiance/(ls.pdf/n as f32), pdf_pos/n as f32);
+                        l = &(&pt.beta*&pt.f(&p))*&v.beta;
+                        if !l.is_zero() && !unoccluded(scene, &pt.spawn_point(), &SpawnPoint::at(p), time) {
+                            l = Vec3::new();
+                        }
+                        sampled = Some(v);
+                    }
+                }
+            }
+        } else {
+            let qs = &light[s-1];
+            let pt = &camera[t-1];
+            if qs.connectible() && pt.connectible() {
+                let d2 = (pt.p - qs.p).lensqr();
+                l = &(&(&qs.beta*&qs.f(&pt.p))*&(&pt.f(&qs.p)*&pt.beta))/d2;
+                if !l.is_zero() && !unoccluded(scene, &qs.spawn_point(), &pt.spawn_point(), time) {
+                    l = Vec3::new();
+                }
+            }
+        }
+        if l.is_zero() {
+            return (l, u);
+        }
+        let w = self.mis_weight(scene, time, camera, light, sampled, s, t);
+        (l*w, u)
+    }
+
+    //Radiance of one camera sample; light tracing contributions go to splats
+    fn sample(&self, scene: &Scene, ray: Ray, weight: f32, splats: &mut Film, sampler: &mut dyn Sampler) -> Vec3 {
+        let time = ray.time;
+        let mut camera = vec![Vertex::camera(ray.o, Vec3::from(weight))];
+        let pdf_dir = scene.camera.pdf_direction(&ray.d.normalized(), time);
+        let mut l = Vec3::new();
+        if let Some((beta, escaped)) = self.random_walk(scene, ray, Vec3::from(weight),
+            pdf_dir, self.max_depth + 1, &mut camera, sampler) {
+            //the sky is not a light, so only camera paths find it
+            l = &beta*&sky(&escaped);
+        }
+        let mut light = Vec::new();
+        self.light_subpath(scene, time, &mut light, sampler);
+
+        for t in 1..camera.len() + 1 {
+            for s in 0..light.len() + 1 {
+                let depth = s + t;
+                if (s == 1 && t == 1) || depth < 2 || depth - 2 > self.max_depth {
+                    continue;
+                }
+                let (c, u) = self.connect(scene, time, &camera, &light, s, t, sampler);
+                if c.is_zero() {
+                    continue;
+                }
+                match u {
+                    None => {l = l + c;}
+                    Some(u) => {
+                        if let Some((x, y)) = splats.raster(&u) {
+                            splats.add(x, y, &c);
+                        }
+                    }
+                }
+            }
+        }
+        l
+    }
+}
+
+impl Renderer for BdptRenderer {
+    fn render(&self, scene: &mut Scene, fname: &str, control: &RenderControl) -> Result<(), String> {
+        let (x, y) = scene.camera.image().dimensions();
+        let mut film = Film::new(x, y);
+        let mut splats = Film::new(x, y);
+        //one stratum per pass over the image, so that a stopped render has
+        //covered every pixel and is exposed by the passes it did
+        let passes = self.samples*self.samples;
+        let mut done = 0;
+        {
+            let scene: &Scene = scene;
+            for pass in 0..passes {
+                if pass > 0 && control.should_stop() {
+                    control.report(&format!("Stopping after pass {}", pass));
+                    break;
+                }
+                control.report(&format!("Pass {} of {}", pass + 1, passes));
+                let (k, l) = (pass/self.samples, pass%self.samples);
+                for i in 0..x {
+                    for j in 0..y {
+                        let u = ((i as f32 + (k as f32 + 0.5)/self.samples as f32)/x as f32)*2.0 - 1.0;
+                        let v = -(((j as f32 + (l as f32 + 0.5)/self.samples as f32)/y as f32)*2.0 - 1.0);
+                        let mut sampler = RandomSampler::with_stream((j*x + i) as u64, pass as u64);
+                        let (ray, weight) = scene.camera.sample_ray(Vec3::xyz(u, v, 0.0),
+                            sampler.next(), sampler.next(), sampler.next());
+                        if weight > 0.0 {
+                            let c = self.sample(scene, ray, weight, &mut splats, &mut sampler);
+                            film.add(i, j, &c);
+                        }
+                    }
+                }
+                done += 1;
+            }
+        }
+        film.add_film(&splats);
+        let exposure = scene.camera.exposure();
+        film.develop(&mut *scene.camera, exposure/done.max(1) as f32);
+        scene.camera.save_image(fname);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::linear2srgb;
+    use crate::scenes::furnace;
+
+    #[test]
+    fn stopped_render_covers_the_image_at_full_exposure() {
+        let (mut scene, _) = furnace(16, 0.5).build().unwrap();
+        let fname = std::env::temp_dir().join("rusty_pete_bdpt_stop_test.png");
+        let mut control = RenderControl::new();
+        control.set_time_budget(Some(0.0));
+        BdptRenderer::new(4, 8).render(&mut scene, &fname.to_string_lossy(), &control).unwrap();
+        let image = scene.camera_mut().image();
+        assert!(image.pixels().all(|p| p[0] > 0), "black pixels left");
+        let ball = (6..10).flat_map(|i| (6..10).map(move |j| (i, j)))
+            .map(|(i, j)| image.get_pixel(i, j)[0] as f32).sum::<f32>()/16.0;
+        let background = image.get_pixel(0, 0)[0] as f32;
+        assert!((ball - linear2srgb(0.5)*255.0).abs() <= 8.0, "ball {}", ball);
+        assert!(background >= 250.0, "background {}", background);
+        std::fs::remove_file(fname).ok();
+    }
+
+    #[test]
+    fn furnace_ball_renders_as_its_albedo() {
+        let (mut scene, _) = furnace(16, 0.5).build().unwrap();
+        let fname = std::env::temp_dir().join("rusty_pete_bdpt_furnace_test.png");
+        BdptRenderer::new(4, 8).render(&mut scene, &fname.to_string_lossy(), &RenderControl::new()).unwrap();
+        let image = scene.camera_mut().image();
+        let ball = (6..10).flat_map(|i| (6..10).map(move |j| (i, j)))
+            .map(|(i, j)| image.get_pixel(i, j)[0] as f32).sum::<f32>()/16.0;
+        let background = image.get_pixel(0, 0)[0] as f32;
+        assert!((ball - linear2srgb(0.5)*255.0).abs() <= 2.0, "ball {}", ball);
+        assert!(background >= 254.0, "background {}", background);
+        std::fs::remove_file(fname).ok();
+    }
+}
@@ -0,0 +1,505 @@
+use crate::math::Vec3;
+use crate::scene::Scene;
+use crate::camera::Camera;
+use crate::material::Material;
+use crate::material::DiffuseMaterial;
+use crate::medium::Medium;
+use crate::light::SphereLight;
+use crate::light::AreaLight;
+use crate::primitive::Primitive;
+use crate::primitive::Intersection;
+use crate::primitive::Ray;
+use crate::primitive::Sphere;
+use crate::primitive::MovingSphere;
+use crate::primitive::Surface;
+use crate::primitive::Plane;
+use crate::primitive::SdfPrimitive;
+use crate::sdf::SdfExpr;
+use crate::primitive::Bvh;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+//makes a shape with the material given to it, or says why it can't
+type MakeSurface = Box<dyn Fn(Arc<dyn Material>) -> Result<Box<dyn Surface>, String>>;
+
+enum Shape {
+    Sphere{o: Vec3, r: f32},
+    MovingSphere{o0: Vec3, t0: f32, o1: Vec3, t1: f32, r: f32},
+    Plane{o: Vec3, n: Vec3},
+    Sdf(SdfExpr),
+    //made once the material is known
+    Surface(MakeSurface),
+    //ready made, with its own materials
+    Primitive(Box<dyn Primitive>),
+    //couldn't be loaded, and why
+    Failed(String)
+}
+
+struct Entry {
+    shape: Shape,
+    material: Option<String>
+}
+
+//Fluent construction of a scene from named materials and shapes:
+//
+//    SceneBuilder::new()
+//        .material("red", DiffuseMaterial::new(Vec3::xyz(0.8, 0.05, 0.05)))
+//        .sphere(Vec3::xyz(0.0, 0.0, 0.0), 1.0).with_material("red")
+//        .camera(camera)
+//        .build()   //the scene and the warnings of validate
+//
+//with_material applies to the shape added last. Spheres and surfaces with
+//an emitting material are also added as lights. The primitives go into a BVH.
+pub struct SceneBuilder {
+    materials: HashMap<String, Arc<dyn Material>>,
+    entries: Vec<Entry>,
+    camera: Option<Box<dyn Camera>>,
+    medium: Option<Arc<dyn Medium>>,
+    //problems found while building up, reported by validate
+    misuse: Vec<String>
+}
+
+//surface at the origin facing +z, to ask materials whether they emit
+fn emits(mat: &dyn Material) -> bool {
+    let z = Vec3::xyz(0.0, 0.0, 1.0);
+    let it = Intersection{p: Vec3::new(), p_error: Vec3::new(), wo: z, n: z, uv: Vec3::new(), mat, object: 0, dpdt: Vec3::new(), interior: None};
+    !mat.emit(&it).is_zero()
+}
+
+fn finite(v: &Vec3) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+//whether the camera at c is inside a closed primitive
+fn inside(p: &dyn Primitive, c: &Vec3) -> bool {
+    let spans = p.spans(&Ray::new(*c, Vec3::xyz(0.0, 0.0, 1.0)));
+    spans.is_some_and(|spans| spans.iter().any(|s| s.t0 < 0.0 && s.t1 > 0.0))
+}
+
+//whether sphere tracing can be limited to somewhere around the surface
+fn sdf_bounded(expr: &SdfExpr) -> bool {
+    let b = expr.bounds();
+    finite(&b.min) && finite(&b.max) && b.min.x <= b.max.x && b.min.y <= b.max.y && b.min.z <= b.max.z
+}
+
+impl Default for SceneBuilder {
+    fn default() -> SceneBuilder {
+        SceneBuilder::new()
+    }
+}
+
+impl SceneBuilder {
+    pub fn new() -> SceneBuilder {
+        SceneBuilder{materials: HashMap::new(), entries: Vec::new(), camera: None, medium: None, misuse: Vec::new()}
+    }
+
+    //defines (or redefines) a material for later with_material calls
+    pub fn material<M: Material + 'static>(self, name: &str, material: M) -> SceneBuilder {
+        self.shared_material(name, Arc::new(material))
+    }
+
+    pub fn shared_material(mut self, name: &str, material: Arc<dyn Material>) -> SceneBuilder {
+        self.materials.insert(String::from(name), material);
+        self
+    }
+
+    pub fn lookup_material(&self, name: &str) -> Option<Arc<dyn Material>> {
+        self.materials.get(name).cloned()
+    }
+
+    pub fn sphere(mut self, o: Vec3, r: f32) -> SceneBuilder {
+        self.entries.push(Entry{shape: Shape::Sphere{o, r}, material: None});
+        self
+    }
+
+    pub fn moving_sphere(mut self, o0: Vec3, t0: f32, o1: Vec3, t1: f32, r: f32) -> SceneBuilder {
+        self.entries.push(Entry{shape: Shape::MovingSphere{o0, t0, o1, t1, r}, material: None});
+        self
+    }
+
+    //infinite, so never added as a light
+    pub fn plane(mut self, o: Vec3, n: Vec3) -> SceneBuilder {
+        self.entries.push(Entry{shape: Shape::Plane{o, n}, material: None});
+        self
+    }
+
+    //implicit surface, e.g. from SdfExpr::parse; never added as a light
+    pub fn sdf(mut self, expr: SdfExpr) -> SceneBuilder {
+        self.entries.push(Entry{shape: Shape::Sdf(expr), material: None});
+        self
+    }
+
+    //implicit surface from a file, see SdfExpr::load; one that can't be
+    //read or parsed makes build fail
+    pub fn sdf_file(mut self, filename: &str) -> SceneBuilder {
+        let shape = match SdfExpr::load(filename) {
+            Ok(expr) => Shape::Sdf(expr),
+            Err(e) => Shape::Failed(e)
+        };
+        self.entries.push(Entry{shape, material: None});
+        self
+    }
+
+    //Any shape that takes its material last, e.g.
+    //    .surface(|m| Rect::axis_aligned(min, max, m)).with_material("white")
+    pub fn surface<S: Surface + 'static, F: Fn(Arc<dyn Material>) -> Result<S, String> + 'static>(mut self, make: F) -> SceneBuilder {
+        let make = move |m| make(m).map(|s| Box::new(s) as Box<dyn Surface>);
+        self.entries.push(Entry{shape: Shape::Surface(Box::new(make)), material: None});
+        self
+    }
+
+    //adds a primitive as it is, e.g. an Instance or a MediumBoundary
+    pub fn primitive(mut self, primitive: Box<dyn Primitive>) -> SceneBuilder {
+        self.entries.push(Entry{shape: Shape::Primitive(primitive), material: None});
+        self
+    }
+
+    pub fn with_material(mut self, name: &str) -> SceneBuilder {
+        match self.entries.last_mut() {
+            Some(Entry{shape: Shape::Primitive(_), ..}) =>
+                self.misuse.push(format!("material {} given to a primitive that has its own", name)),
+            Some(e) => e.material = Some(String::from(name)),
+            None => self.misuse.push(format!("material {} given before any shape", name))
+        }
+        self
+    }
+
+    pub fn camera<C: Camera + 'static>(mut self, camera: C) -> SceneBuilder {
+        self.camera = Some(Box::new(camera));
+        self
+    }
+
+    //atmosphere around everything, see Scene::set_medium
+    pub fn medium<M: Medium + 'static>(mut self, medium: M) -> SceneBuilder {
+        self.medium = Some(Arc::new(medium));
+        self
+    }
+
+    fn camera_origin(&self) -> Option<Vec3> {
+        self.camera.as_ref().map(|c| c.get_ray(Vec3::new()).o)
+    }
+
+    //A dome around everything that lights the scene, or the boundary of a
+    //medium, is meant to contain the camera; any other closed shape that
+    //does hides the scene from it.
+    fn encloses_camera<F: Fn(&Vec3) -> bool>(&self, contains: F, material: Option<&Arc<dyn Material>>) -> bool {
+        self.camera_origin().is_some_and(|c| contains(&c)) && !material.is_some_and(|m| emits(&**m) || m.is_interface())
+    }
+
+    //Problems that don't stop the scene from being built, but likely make
+    //it render something other than intended
+    pub fn validate(&self) -> Vec<String> {
+        let mut warnings = self.misuse.clone();
+        if self.camera.is_none() {
+            warnings.push(String::from("scene has no camera"));
+        }
+        for (i, e) in self.entries.iter().enumerate() {
+            //centre and radius of spheres
+            let sphere = match &e.shape {
+                Shape::Sphere{o, r} => Some((*o, *r)),
+                Shape::MovingSphere{o0, t0, o1, t1, r} => {
+                    if t1 < t0 {
+                        warnings.push(format!("shape {}: motion ends before it starts", i));
+                    }
+                    if !finite(o1) {
+                        warnings.push(format!("shape {}: centre is not finite", i));
+                    }
+                    Some((*o0, *r))
+                }
+                Shape::Plane{o, n} => {
+                    if !finite(o) || !finite(n) || n.is_zero() {
+                        warnings.push(format!("shape {}: degenerate plane", i));
+                    }
+                    None
+                }
+                Shape::Sdf(expr) => {
+                    if !sdf_bounded(expr) {
+                        warnings.push(format!("shape {}: implicit surface has no finite bounds", i));
+                    }
+                    None
+                }
+                Shape::Surface(_) => None,
+                Shape::Primitive(_) => {continue;}
+                Shape::Failed(error) => {
+                    warnings.push(format!("shape {}: {}", i, error));
+                    continue;
+                }
+            };
+            //made with a stand-in material, only to check it
+            let surface = match &e.shape {
+                Shape::Surface(make) => match make(Arc::new(DiffuseMaterial::new(Vec3::new()))) {
+                    Ok(s) => Some(s),
+                    Err(error) => {
+                        warnings.push(format!("shape {}: {}", i, error));
+                        None
+                    }
+                }
+                _ => None
+            };
+            if let Some((o, r)) = sphere {
+                if !finite(&o) {
+                    warnings.push(format!("shape {}: centre is not finite", i));
+                }
+                if !(r > 0.0 && r.is_finite()) {
+                    warnings.push(format!("shape {}: degenerate radius {}", i, r));
+                }
+            }
+            let material = match &e.material {
+                None => {
+                    warnings.push(format!("shape {}: no material", i));
+                    None
+                }
+                Some(name) => {
+                    let m = self.materials.get(name);
+                    if m.is_none() {
+                        warnings.push(format!("shape {}: unknown material {}", i, name));
+                    }
+                    m
+                }
+            };
+            let enclosed = match (sphere, &surface) {
+                (Some((o, r)), _) => self.encloses_camera(|c| (c - &o).len() < r, material),
+                (None, Some(s)) => self.encloses_camera(|c| inside(&**s, c), material),
+                (None, None) => false
+            };
+            if enclosed {
+                warnings.push(format!("shape {}: camera is inside it", i));
+            }
+        }
+        warnings
+    }
+
+    //Builds the scene and returns it with the warnings of validate.
+    //Degenerate shapes and unbounded implicit surfaces are left out and
+    //shapes without a known material get a magenta one; only a missing
+    //camera or a shape that couldn't be loaded is an error.
+    pub fn build(self) -> Result<(Scene, Vec<String>), String> {
+        let warnings = self.validate();
+        for (i, e) in self.entries.iter().enumerate() {
+            if let Shape::Failed(error) = &e.shape {
+                return Err(format!("shape {}: {}", i, error));
+            }
+        }
+        let camera = self.camera.ok_or_else(|| String::from("scene has no camera"))?;
+        let mut scene = Scene::with_group(Box::new(Bvh::new()), camera);
+        scene.set_medium(self.medium);
+
+        let materials = self.materials;
+        let missing: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::xyz(0.8, 0.0, 0.8)));
+        for e in self.entries {
+            let mat = e.material.and_then(|name| materials.get(&name).cloned()).unwrap_or_else(|| missing.clone());
+            match e.shape {
+                Shape::Sphere{o, r} => {
+                    if !(r > 0.0 && r.is_finite() && finite(&o)) {
+                        continue;
+                    }
+                    let sphere = Sphere::new(o, r, mat.clone());
+                    if emits(&*mat) {
+                        scene.add_light(Arc::new(SphereLight::new(&sphere)));
+                    }
+                    scene.add(Box::new(sphere));
+                }
+                Shape::MovingSphere{o0, t0, o1, t1, r} => {
+                    if !(r > 0.0 && r.is_finite() && finite(&o0) && finite(&o1)) {
+                        continue;
+                    }
+                    scene.add(Box::new(MovingSphere::new(o0, t0, o1, t1, r, mat)));
+                }
+                Shape::Plane{o, n} => {
+                    if !finite(&o) || !finite(&n) || n.is_zero() {
+                        continue;
+                    }
+                    if let Ok(plane) = Plane::new(o, n, mat) {
+                        scene.add(Box::new(plane));
+                    }
+                }
+                Shape::Sdf(expr) => {
+                    if !sdf_bounded(&expr) {
+                        continue;
+                    }
+                    scene.add(Box::new(SdfPrimitive::new(expr, mat)));
+                }
+                Shape::Surface(make) => {
+                    let surface: Arc<dyn Surface> = match make(mat.clone()) {
+                        Ok(s) => Arc::from(s),
+                        Err(_) => continue
+                    };
+                    if emits(&*mat) {
+                        scene.add_light(Arc::new(AreaLight::new(surface.clone())));
+                    }
+                    scene.add(Box::new(surface));
+                }
+                Shape::Primitive(p) => scene.add(p),
+                Shape::Failed(_) => {}
+            }
+        }
+        scene.build_index();
+        Ok((scene, warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::CameraPerspective;
+    use crate::material::EmissionMaterial;
+    use crate::primitive::Rect;
+    use crate::primitive::Disk;
+    use crate::primitive::Cuboid;
+    use crate::primitive::Cylinder;
+    use crate::primitive::Torus;
+
+    fn camera() -> CameraPerspective {
+        CameraPerspective::new(16, 16, 1.0, Vec3::xyz(0.0, -4.0, 0.0), Vec3::xyz(0.0, 1.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0))
+    }
+
+    #[test]
+    fn valid_scene_has_no_warnings() {
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .sphere(Vec3::new(), 1.0).with_material("white")
+            .camera(camera());
+        assert!(b.validate().is_empty());
+    }
+
+    #[test]
+    fn warns_about_missing_and_misplaced_materials() {
+        let b = SceneBuilder::new()
+            .with_material("white")
+            .sphere(Vec3::new(), 1.0)
+            .sphere(Vec3::xyz(2.0, 0.0, 0.0), 1.0).with_material("black")
+            .camera(camera());
+        let w = b.validate();
+        assert_eq!(w.len(), 3);
+        assert!(w[0].contains("before any shape"));
+        assert!(w[1].contains("no material"));
+        assert!(w[2].contains("unknown material black"));
+        //built anyway, with a stand-in material
+        assert!(b.build().is_ok());
+    }
+
+    #[test]
+    fn warns_about_degenerate_spheres() {
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .sphere(Vec3::new(), 0.0).with_material("white")
+            .sphere(Vec3::xyz(f32::NAN, 0.0, 0.0), 1.0).with_material("white")
+            .moving_sphere(Vec3::new(), 1.0, Vec3::new(), 0.0, 1.0).with_material("white")
+            .camera(camera());
+        let w = b.validate();
+        assert_eq!(w.len(), 3);
+        assert!(w[0].contains("degenerate radius"));
+        assert!(w[1].contains("not finite"));
+        assert!(w[2].contains("motion ends"));
+    }
+
+    #[test]
+    fn warns_about_camera_inside_geometry() {
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .material("sky", EmissionMaterial::new(Vec3::from(1.0), 1.0))
+            .sphere(Vec3::new(), 5.0).with_material("white")
+            .sphere(Vec3::new(), 50.0).with_material("sky")
+            .camera(camera());
+        let w = b.validate();
+        assert_eq!(w, vec![String::from("shape 0: camera is inside it")]);
+    }
+
+    #[test]
+    fn warns_about_degenerate_surfaces_and_leaves_them_out() {
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .surface(|m| Disk::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0), 0.0, m)).with_material("white")
+            .surface(|m| Cylinder::new(Vec3::new(), Vec3::new(), 1.0, true, m)).with_material("white")
+            .surface(|m| Cuboid::new(Vec3::new(), Vec3::xyz(1.0, 1.0, 0.0), m)).with_material("white")
+            .surface(|m| Torus::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0), 1.0, 1.0, m)).with_material("white")
+            .camera(camera());
+        let w = b.validate();
+        assert_eq!(w.len(), 4);
+        assert!(w[0].starts_with("shape 0: the radius"));
+        assert!(w[1].starts_with("shape 1: the axis"));
+        assert!(w[2].starts_with("shape 2: the size"));
+        assert!(w[3].starts_with("shape 3: the minor radius"));
+        let (scene, warnings) = b.build().unwrap();
+        assert_eq!(warnings, w);
+        let mut r = Ray::new(Vec3::xyz(0.5, -4.0, 0.5), Vec3::xyz(0.0, 1.0, 0.0));
+        assert!(scene.primitive().intersect(&mut r).is_none());
+    }
+
+    #[test]
+    fn warns_about_camera_inside_closed_surfaces() {
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .surface(|m| Cuboid::new(Vec3::from(-5.0), Vec3::from(5.0), m)).with_material("white")
+            .surface(|m| Cylinder::new(Vec3::xyz(0.0, -4.0, -1.0), Vec3::xyz(0.0, 0.0, 2.0), 1.0, false, m)).with_material("white")
+            .surface(|m| Torus::new(Vec3::xyz(0.0, -4.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0), 2.0, 0.5, m)).with_material("white")
+            .camera(camera());
+        assert_eq!(b.validate(), vec![String::from("shape 0: camera is inside it")]);
+    }
+
+    #[test]
+    fn emitting_surfaces_are_lights() {
+        let scene = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .material("light", EmissionMaterial::new(Vec3::from(1.0), 1.0))
+            .plane(Vec3::xyz(0.0, 0.0, -1.0), Vec3::xyz(0.0, 0.0, 1.0)).with_material("white")
+            .surface(|m| Rect::axis_aligned(Vec3::xyz(-1.0, -1.0, 1.0), Vec3::xyz(1.0, 1.0, 1.0), m)).with_material("light")
+            .surface(|m| Disk::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0), 0.5, m)).with_material("white")
+            .camera(camera())
+            .build().unwrap().0;
+        assert_eq!(scene.lights.len(), 1);
+    }
+
+    #[test]
+    fn warns_about_degenerate_planes() {
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .plane(Vec3::new(), Vec3::new()).with_material("white")
+            .camera(camera());
+        assert_eq!(b.validate(), vec![String::from("shape 0: degenerate plane")]);
+    }
+
+    #[test]
+    fn warns_about_unbounded_implicit_surfaces() {
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .sdf(SdfExpr::parse("(sphere 1)").unwrap()).with_material("white")
+            .sdf(SdfExpr::Sphere{r: f32::INFINITY}).with_material("white")
+            .camera(camera());
+        assert_eq!(b.validate(), vec![String::from("shape 1: implicit surface has no finite bounds")]);
+        assert!(b.build().is_ok());
+    }
+
+    #[test]
+    fn implicit_surfaces_load_from_files() {
+        let fname = std::env::temp_dir().join("rusty_pete_builder_test.sdf");
+        std::fs::write(&fname, "# a ball\n(translate 0 1 0\n  (sphere 0.5))\n").unwrap();
+        let (scene, warnings) = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .sdf_file(&fname.to_string_lossy()).with_material("white")
+            .camera(camera())
+            .build().unwrap();
+        assert!(warnings.is_empty());
+        let mut r = Ray::new(Vec3::xyz(0.0, -4.0, 0.0), Vec3::xyz(0.0, 1.0, 0.0));
+        let x = scene.primitive().intersect(&mut r).unwrap();
+        assert!((x.p.y - 0.5).abs() < 1e-3, "hit at {}", x.p.y);
+
+        std::fs::write(&fname, "(translate 0 1 0 (sphere))").unwrap();
+        let b = SceneBuilder::new()
+            .material("white", DiffuseMaterial::new(Vec3::from(0.8)))
+            .sdf_file(&fname.to_string_lossy()).with_material("white")
+            .sdf_file("no/such/file.sdf")
+            .camera(camera());
+        let w = b.validate();
+        assert_eq!(w.len(), 2);
+        assert!(w[0].starts_with("shape 0: "));
+        assert!(w[1].starts_with("shape 1: couldn't read"));
+        assert_eq!(b.build().err(), Some(w[0].clone()));
+        std::fs::remove_file(fname).ok();
+    }
+
+    #[test]
+    fn missing_camera_is_an_error() {
+        assert!(SceneBuilder::new().build().is_err());
+    }
+}
@@ -2,19 +2,66 @@ use crate::math::Vec3;
 use crate::primitive::Ray;
 use crate::math::float2u8;
 use crate::math::Mat3;
+use crate::math::sample_disk_concentric;
+use crate::math::lerp;
+use crate::lens::LensSystem;
 
 use image::{RgbImage, Rgb};
 
-pub trait Camera{    
+use std::f32::consts::PI;
+
+//Camera seen from a scene point, for light tracing
+pub struct ImportanceSample {
+    //unit direction from the point towards the camera
+    pub wi: Vec3,
+    pub dist: f32,
+    //image coordinate in [-1,1]^2 the point projects to
+    pub u: Vec3,
+    //emitted importance towards the point
+    pub importance: f32,
+    //solid angle density of wi at the point
+    pub pdf: f32
+}
+
+pub trait Camera: Send + Sync {    
     fn get_ray(&self, u: Vec3) -> Ray;
 
+    //like get_ray, but uses two random numbers to sample the lens and a third
+    //one to pick a time within the shutter interval.
+    //Returns the ray and its weight (zero if the ray was blocked by the lens)
+    fn sample_ray(&self, u: Vec3, _r1: f32, _r2: f32, _r3: f32) -> (Ray, f32) {
+        (self.get_ray(u), 1.0)
+    }
+
+    //scale applied to the radiance before it is written to the image
+    fn exposure(&self) -> f32 {
+        1.0
+    }
+
+    //times at which the shutter opens and closes
+    fn shutter(&self) -> (f32, f32) {
+        (0.0, 0.0)
+    }
+
+    //Connects the point p to the camera, for bidirectional methods. Cameras
+    //that don't support it return None and can only be reached by paths
+    //starting at the camera.
+    fn sample_importance(&self, _p: &Vec3, _time: f32) -> Option<ImportanceSample> {
+        None
+    }
+
+    //solid angle density of generating a camera ray along the unit direction d
+    fn pdf_direction(&self, _d: &Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
     //todo: make private
     fn image(&mut self) -> &mut RgbImage;
 
     
     //make not mut?
     
-    fn save_image(&mut self, filename: &String){
+    fn save_image(&mut self, filename: &str){
         
         match self.image().save_with_format(filename, image::ImageFormat::Png){
             Ok(_) => {}
@@ -22,7 +69,7 @@ pub trait Camera{
         }
     }
     fn set_pixel(&mut self, i:u32,j:u32,rgb: [f32;3]){
-        let a = Rgb{0:[float2u8(rgb[0]),float2u8(rgb[1]),float2u8(rgb[2])]};
+        let a = Rgb([float2u8(rgb[0]),float2u8(rgb[1]),float2u8(rgb[2])]);
         self.image().put_pixel(i,j,a);
     }
 }
@@ -34,9 +81,9 @@ pub struct Camera2D{
 
 
 impl Camera2D {
-    pub fn new(resX: u32, resY: u32, scale: &Vec3, origin: &Vec3) -> Camera2D{
+    pub fn new(res_x: u32, res_y: u32, _scale: &Vec3, _origin: &Vec3) -> Camera2D{
         Camera2D{
-            image: RgbImage::new(resX, resY)
+            image: RgbImage::new(res_x, res_y)
         }
     }
 }
@@ -49,6 +96,7 @@ impl Camera for Camera2D {
     
 
     //todo: make private
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
     fn image(&mut self) -> &mut RgbImage{
         return &mut self.image;
     }
@@ -57,54 +105,634 @@ impl Camera for Camera2D {
 }
 
 
+//orthonormal camera frame looking down -z
+fn camera_frame(forward: Vec3, up: Vec3) -> Mat3 {
+    let z = (forward*(-1.0)).normalized();
+    let x = (up.cross(&z)).normalized();
+    let y = (z.cross(&x)).normalized();
+    Mat3::from_axis(&x,&y,&z)
+}
+
 pub struct CameraPerspective {
     aspect_ratio: f32,
     tan_fovy: f32,
+    //horizontal shift of the image plane, used for off-axis stereo
+    shift_x: f32,
     coords: Mat3,
     origin: Vec3,
+    shutter_open: f32,
+    shutter_close: f32,
+    //placement at shutter close, if the camera moves
+    end: Option<(Vec3, Vec3, Vec3)>,
     image: RgbImage
 }
 
 impl CameraPerspective {
     
 
+    #[allow(clippy::redundant_field_names, reason = "baseline code, kept as written")]
     pub fn new(res_x: u32, res_y: u32, 
         tan_fovy: f32, origin: Vec3, 
         forward: Vec3, up: Vec3) 
                 -> CameraPerspective{
-        let z = (forward*(-1.0)).normalized();
-        let x = (up.cross(&z)).normalized();
-        let y = (z.cross(&x)).normalized();
         CameraPerspective{
                             aspect_ratio: res_x as f32 / res_y as f32,
                             tan_fovy: tan_fovy,
-                            coords: Mat3::from_axis(&x,&y,&z),
+                            shift_x: 0.0,
+                            coords: camera_frame(forward, up),
                             origin: origin,
+                            shutter_open: 0.0,
+                            shutter_close: 0.0,
+                            end: None,
                             image: RgbImage::new(res_x, res_y)
                             }
     }
-}
 
-impl Camera for CameraPerspective {
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.shutter_open = open;
+        self.shutter_close = close;
+    }
 
-    fn get_ray(&self, u: Vec3) -> Ray{
-        let mut dir = Vec3{ x:u.x*self.aspect_ratio*self.tan_fovy, 
+    //moves the camera from its initial placement to this one over the shutter interval
+    pub fn set_motion(&mut self, end_origin: Vec3, end_forward: Vec3, end_up: Vec3) {
+        self.end = Some((end_origin, end_forward, end_up));
+    }
+
+    //origin and frame at the fraction a of the shutter interval
+    fn frame(&self, a: f32) -> (Vec3, Mat3) {
+        match self.end {
+            None => (self.origin, self.coords),
+            Some((end_origin, end_forward, end_up)) => {
+                let start_forward = Vec3::xyz(-*self.coords.at_ro(0,2), -*self.coords.at_ro(1,2), -*self.coords.at_ro(2,2));
+                let start_up = Vec3::xyz(*self.coords.at_ro(0,1), *self.coords.at_ro(1,1), *self.coords.at_ro(2,1));
+                (lerp(&self.origin, &end_origin, a),
+                 camera_frame(lerp(&start_forward, &end_forward.normalized(), a), lerp(&start_up, &end_up, a)))
+            }
+        }
+    }
+
+    fn shutter_fraction(&self, time: f32) -> f32 {
+        if self.shutter_close > self.shutter_open {
+            ((time - self.shutter_open)/(self.shutter_close - self.shutter_open)).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+
+    //image coordinate of the unit direction d and the cosine to the view
+    //axis, if d is inside the frustum
+    fn project(&self, coords: &Mat3, d: &Vec3) -> Option<(Vec3, f32)> {
+        let local = &coords.transpose()*d;
+        let cos = -local.z;
+        if cos <= 0.0 {
+            return None;
+        }
+        let u = Vec3::xyz((local.x/cos - self.shift_x)/(self.aspect_ratio*self.tan_fovy),
+                          (local.y/cos)/self.tan_fovy, 0.0);
+        if u.x.abs() > 1.0 || u.y.abs() > 1.0 {
+            return None;
+        }
+        Some((u, cos))
+    }
+
+    //area of the image plane at distance 1
+    fn image_area(&self) -> f32 {
+        4.0*self.aspect_ratio*self.tan_fovy*self.tan_fovy
+    }
+
+    fn ray_from(&self, origin: Vec3, coords: &Mat3, u: Vec3) -> Ray {
+        let mut dir = Vec3{ x:u.x*self.aspect_ratio*self.tan_fovy + self.shift_x, 
                             y:u.y*self.tan_fovy, 
                             z:-1.0
 
                             };
-        dir = &self.coords*&dir;
+        dir = coords*&dir;
         dir = dir.normalized();
 
 
-        Ray::new(self.origin, dir)
+        Ray::new(origin, dir)
+    }
+
+    //a camera that only generates rays; used as an eye of a stereo rig,
+    //which owns the image
+    fn eye(aspect_ratio: f32, tan_fovy: f32, shift_x: f32,
+        origin: Vec3, forward: Vec3, up: Vec3) -> CameraPerspective {
+        let mut c = CameraPerspective::new(0, 0, tan_fovy, origin, forward, up);
+        c.aspect_ratio = aspect_ratio;
+        c.shift_x = shift_x;
+        c
+    }
+}
+
+impl Camera for CameraPerspective {
+
+    fn get_ray(&self, u: Vec3) -> Ray{
+        self.ray_from(self.origin, &self.coords, u)
+    }
+
+    fn sample_ray(&self, u: Vec3, _r1: f32, _r2: f32, r3: f32) -> (Ray, f32) {
+        let time = self.shutter_open + r3*(self.shutter_close - self.shutter_open);
+        let (origin, coords) = self.frame(self.shutter_fraction(time));
+        let mut ray = self.ray_from(origin, &coords, u);
+        ray.time = time;
+        (ray, 1.0)
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+
+    //Pinhole importance, normalized so that camera rays have weight 1:
+    //We = 1/(A cos^4) with A the image area at distance 1
+    fn sample_importance(&self, p: &Vec3, time: f32) -> Option<ImportanceSample> {
+        let (origin, coords) = self.frame(self.shutter_fraction(time));
+        let to_camera = &origin - p;
+        let dist = to_camera.len();
+        if dist <= 0.0 {
+            return None;
+        }
+        let wi = &to_camera/dist;
+        let (u, cos) = self.project(&coords, &(wi*(-1.0)))?;
+        Some(ImportanceSample{
+            wi,
+            dist,
+            u,
+            importance: 1.0/(self.image_area()*cos*cos*cos*cos),
+            //the pinhole is a point facing the view axis: its unit position
+            //density converted to solid angle at p
+            pdf: dist*dist/cos
+        })
+    }
+
+    fn pdf_direction(&self, d: &Vec3, time: f32) -> f32 {
+        let (_, coords) = self.frame(self.shutter_fraction(time));
+        match self.project(&coords, d) {
+            Some((_, cos)) => 1.0/(self.image_area()*cos*cos*cos),
+            None => 0.0
+        }
     }
 
 
     //todo: make private
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
     fn image(&mut self) -> &mut RgbImage{
         return &mut self.image;
     }
     
 
-}
\ No newline at end of file
+}
+
+
+#[derive(Copy,Clone)]
+pub enum StereoLayout {
+    //left eye on the left half, right eye on the right half
+    SideBySide,
+    //left eye on the top half, right eye on the bottom half
+    TopBottom
+}
+
+#[derive(Copy,Clone)]
+pub enum StereoConvergence {
+    //both eyes rotate to look at the convergence point
+    ToeIn,
+    //parallel eyes with sheared frustums meeting at the convergence distance
+    OffAxis
+}
+
+//maps a full-image coordinate to (eye index, coordinate inside that eye's view)
+fn split_eye(layout: StereoLayout, u: Vec3) -> (usize, Vec3) {
+    match layout {
+        StereoLayout::SideBySide => {
+            if u.x < 0.0 {
+                (0, Vec3::xyz(u.x*2.0+1.0, u.y, u.z))
+            } else {
+                (1, Vec3::xyz(u.x*2.0-1.0, u.y, u.z))
+            }
+        }
+        StereoLayout::TopBottom => {
+            if u.y > 0.0 {
+                (0, Vec3::xyz(u.x, u.y*2.0-1.0, u.z))
+            } else {
+                (1, Vec3::xyz(u.x, u.y*2.0+1.0, u.z))
+            }
+        }
+    }
+}
+
+
+//stereo rig of two perspective eyes rendered into a single image
+pub struct CameraStereo {
+    eyes: [CameraPerspective;2],
+    layout: StereoLayout,
+    image: RgbImage
+}
+
+impl CameraStereo {
+    #[allow(clippy::too_many_arguments, reason = "one per setting of the rig")]
+    pub fn new(res_x: u32, res_y: u32,
+        tan_fovy: f32, origin: Vec3,
+        forward: Vec3, up: Vec3,
+        interocular: f32, convergence: f32,
+        mode: StereoConvergence, layout: StereoLayout)
+                -> Result<CameraStereo, String>{
+        if !(convergence.is_finite() && convergence > 0.0) {
+            return Err(format!("stereo camera: convergence distance {} must be positive and finite", convergence));
+        }
+        if !(interocular >= 0.0 && interocular.is_finite()) {
+            return Err(format!("stereo camera: interocular distance {} must not be negative", interocular));
+        }
+
+        let aspect_ratio = match layout {
+            StereoLayout::SideBySide => (res_x as f32*0.5) / res_y as f32,
+            StereoLayout::TopBottom => res_x as f32 / (res_y as f32*0.5)
+        };
+
+        let f = forward.normalized();
+        let right = f.cross(&up).normalized();
+        let half = interocular*0.5;
+        let target = origin + (f*convergence);
+
+        let make_eye = |side: f32| {
+            let eye_origin = origin + (right*(side*half));
+            match mode {
+                StereoConvergence::ToeIn => {
+                    CameraPerspective::eye(aspect_ratio, tan_fovy, 0.0,
+                        eye_origin, target - eye_origin, up)
+                }
+                StereoConvergence::OffAxis => {
+                    //shift so that both frustums coincide at the convergence plane
+                    CameraPerspective::eye(aspect_ratio, tan_fovy, -side*half/convergence,
+                        eye_origin, f, up)
+                }
+            }
+        };
+
+        Ok(CameraStereo{
+            eyes: [make_eye(-1.0), make_eye(1.0)],
+            layout,
+            image: RgbImage::new(res_x, res_y)
+        })
+    }
+}
+
+impl Camera for CameraStereo {
+
+    fn get_ray(&self, u: Vec3) -> Ray{
+        let (eye, v) = split_eye(self.layout, u);
+        self.eyes[eye].get_ray(v)
+    }
+
+    //todo: make private
+    fn image(&mut self) -> &mut RgbImage{
+        &mut self.image
+    }
+}
+
+
+//full 360x180 latitude-longitude camera. With a non-zero interocular distance
+//it renders omni-directional stereo (ODS): each column gets its own pair of
+//eye positions on a circle around the origin
+pub struct CameraEquirectangular {
+    coords: Mat3,
+    origin: Vec3,
+    interocular: f32,
+    layout: StereoLayout,
+    image: RgbImage
+}
+
+impl CameraEquirectangular {
+    pub fn new(res_x: u32, res_y: u32, origin: Vec3,
+        forward: Vec3, up: Vec3) -> CameraEquirectangular{
+        CameraEquirectangular{
+            coords: camera_frame(forward, up),
+            origin,
+            interocular: 0.0,
+            layout: StereoLayout::TopBottom,
+            image: RgbImage::new(res_x, res_y)
+        }
+    }
+
+    //omni-directional stereo; 360 viewers usually expect a top-bottom layout
+    pub fn stereo(res_x: u32, res_y: u32, origin: Vec3,
+        forward: Vec3, up: Vec3,
+        interocular: f32, layout: StereoLayout) -> CameraEquirectangular{
+        let mut c = CameraEquirectangular::new(res_x, res_y, origin, forward, up);
+        c.interocular = interocular;
+        c.layout = layout;
+        c
+    }
+}
+
+impl Camera for CameraEquirectangular {
+
+    fn get_ray(&self, u: Vec3) -> Ray{
+        let (eye, v) = if self.interocular > 0.0 {
+            split_eye(self.layout, u)
+        } else {
+            (0, u)
+        };
+
+        let theta = v.x*PI;
+        let phi = v.y*PI*0.5;
+        let dir = Vec3::xyz(theta.sin()*phi.cos(), phi.sin(), -theta.cos()*phi.cos());
+
+        //eyes sit on a circle, offset perpendicular to the horizontal view direction
+        let side = if eye == 0 { -1.0 } else { 1.0 };
+        let offset = Vec3::xyz(theta.cos(), 0.0, theta.sin())*(side*self.interocular*0.5);
+
+        Ray::new(self.origin + (&self.coords*&offset), (&self.coords*&dir).normalized())
+    }
+
+    //todo: make private
+    fn image(&mut self) -> &mut RgbImage{
+        &mut self.image
+    }
+}
+
+
+//Camera parameterised like a real one. Sensor size and focal length are in
+//millimeters, shutter in seconds; the scene is assumed to be in meters.
+//The focal length and sensor give the field of view, the f-stop the depth of
+//field, and shutter, f-stop and ISO the exposure.
+pub struct CameraPhysical {
+    sensor_width: f32,
+    sensor_height: f32,
+    focal_length: f32,
+    f_stop: f32,
+    shutter: f32,
+    iso: f32,
+    focus_distance: f32,
+    coords: Mat3,
+    origin: Vec3,
+    lens: Option<LensSystem>,
+    //rear element area over exit pupil area, so that the exposure is set by
+    //the f-stop alone and not again by the stop blocking rays
+    lens_weight: f32,
+    image: RgbImage
+}
+
+impl CameraPhysical {
+    #[allow(clippy::too_many_arguments, reason = "one per control of a real camera")]
+    pub fn new(res_x: u32, res_y: u32,
+        sensor_width: f32, focal_length: f32,
+        f_stop: f32, shutter: f32, iso: f32, focus_distance: f32,
+        origin: Vec3, forward: Vec3, up: Vec3) -> Result<CameraPhysical, String>{
+        if !(sensor_width > 0.0 && focal_length > 0.0 && f_stop > 0.0 && shutter > 0.0 && iso > 0.0) {
+            return Err(String::from("camera: sensor width, focal length, f-stop, shutter and ISO must be positive"));
+        }
+        //the thin lens focuses closer than this behind the sensor
+        if !(focus_distance.is_finite() && focus_distance > focal_length*0.001) {
+            return Err(format!("camera: focus distance {} must be finite and beyond the focal length", focus_distance));
+        }
+        Ok(CameraPhysical{
+            sensor_width,
+            sensor_height: sensor_width * res_y as f32 / res_x as f32,
+            focal_length,
+            f_stop,
+            shutter,
+            iso,
+            focus_distance,
+            coords: camera_frame(forward, up),
+            origin,
+            lens: None,
+            lens_weight: 1.0,
+            image: RgbImage::new(res_x, res_y)
+        })
+    }
+
+    //Replaces the thin lens with a traced multi-element lens. The lens is
+    //focused at the camera's focus distance and stopped down to its f-stop.
+    pub fn set_lens(&mut self, mut lens: LensSystem) -> Result<(), String> {
+        lens.stop_down(self.focal_length*0.001, self.f_stop);
+        lens.focus(self.focus_distance)?;
+        let pupil = lens.exit_pupil_area();
+        if pupil <= 0.0 {
+            return Err(String::from("lens: no light reaches the center of the film"));
+        }
+        self.lens_weight = PI*lens.rear_radius()*lens.rear_radius()/pupil;
+        self.lens = Some(lens);
+        Ok(())
+    }
+
+    //distance from the lens to the sensor for the thin lens model, in meters
+    fn image_distance(&self) -> f32 {
+        let f = self.focal_length*0.001;
+        f*self.focus_distance/(self.focus_distance - f)
+    }
+
+    fn tan_fovy(&self) -> f32 {
+        self.sensor_height*0.001*0.5/self.image_distance()
+    }
+
+    fn to_world(&self, o: &Vec3, d: &Vec3) -> Ray {
+        Ray::new(self.origin + (&self.coords*o), (&self.coords*d).normalized())
+    }
+
+    fn sample_thin_lens(&self, u: Vec3, r1: f32, r2: f32) -> Ray {
+        let tan_fovy = self.tan_fovy();
+        let aspect_ratio = self.sensor_width/self.sensor_height;
+        let dir = Vec3::xyz(u.x*aspect_ratio*tan_fovy, u.y*tan_fovy, -1.0);
+        let focus = dir*self.focus_distance;
+
+        let aperture = self.focal_length*0.001/(2.0*self.f_stop);
+        let (lx, ly) = sample_disk_concentric(r1, r2);
+        let lens = Vec3::xyz(lx*aperture, ly*aperture, 0.0);
+
+        self.to_world(&lens, &(focus - lens))
+    }
+
+    fn sample_lens_system(&self, lens: &LensSystem, u: Vec3, r1: f32, r2: f32) -> (Ray, f32) {
+        //the lens flips the image, so sample the mirrored film point
+        let film = Vec3::xyz(-u.x*self.sensor_width*0.0005, -u.y*self.sensor_height*0.0005, 0.0);
+        let (lx, ly) = sample_disk_concentric(r1, r2);
+        let rear = Vec3::xyz(lx*lens.rear_radius(), ly*lens.rear_radius(), lens.rear_z());
+        let d = (rear - film).normalized();
+
+        match lens.trace_from_film(&Ray::new(film, d)) {
+            None => (self.to_world(&film, &d), 0.0),
+            Some(r) => {
+                //cos^4 falloff of the irradiance on the film
+                let cos2 = d.z*d.z;
+                (self.to_world(&r.o, &r.d), cos2*cos2*self.lens_weight)
+            }
+        }
+    }
+}
+
+impl Camera for CameraPhysical {
+
+    fn get_ray(&self, u: Vec3) -> Ray{
+        self.sample_ray(u, 0.5, 0.5, 0.0).0
+    }
+
+    //the shutter opens at time zero and stays open for `shutter` seconds
+    fn sample_ray(&self, u: Vec3, r1: f32, r2: f32, r3: f32) -> (Ray, f32) {
+        let (mut ray, weight) = match &self.lens {
+            None => (self.sample_thin_lens(u, r1, r2), 1.0),
+            Some(lens) => self.sample_lens_system(lens, u, r1, r2)
+        };
+        ray.time = r3*self.shutter;
+        (ray, weight)
+    }
+
+    //Radiance is taken to be in cd/m^2; this maps the saturation-based
+    //maximum luminance (1.2*2^EV100) to white.
+    fn exposure(&self) -> f32 {
+        self.shutter*self.iso/(120.0*self.f_stop*self.f_stop)
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (0.0, self.shutter)
+    }
+
+    //todo: make private
+    fn image(&mut self) -> &mut RgbImage{
+        &mut self.image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Vec3, b: &Vec3) -> bool {
+        (a - b).len() < 1e-4
+    }
+
+    //point where the ray is at distance t along the view direction f
+    fn at_depth(r: &Ray, f: &Vec3, t: f32) -> Vec3 {
+        r.o + (r.d*(t/r.d.dot(f)))
+    }
+
+    fn rig(mode: StereoConvergence) -> CameraStereo {
+        CameraStereo::new(64, 32, 0.5, Vec3::xyz(1.0, 2.0, 3.0), Vec3::xyz(0.0, 1.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0),
+            0.06, 2.0, mode, StereoLayout::SideBySide).unwrap()
+    }
+
+    #[test]
+    fn split_eye_maps_each_half_to_a_full_view() {
+        let cases = [
+            (StereoLayout::SideBySide, (-1.0, 0.3), (0, -1.0, 0.3)),
+            (StereoLayout::SideBySide, (-0.5, 0.3), (0, 0.0, 0.3)),
+            (StereoLayout::SideBySide, (0.5, -0.2), (1, 0.0, -0.2)),
+            (StereoLayout::SideBySide, (1.0, -0.2), (1, 1.0, -0.2)),
+            (StereoLayout::TopBottom, (0.4, 1.0), (0, 0.4, 1.0)),
+            (StereoLayout::TopBottom, (0.4, 0.5), (0, 0.4, 0.0)),
+            (StereoLayout::TopBottom, (-0.7, -0.5), (1, -0.7, 0.0)),
+            (StereoLayout::TopBottom, (-0.7, -1.0), (1, -0.7, -1.0))
+        ];
+        for (layout, (x, y), (eye, vx, vy)) in cases.iter() {
+            let (e, v) = split_eye(*layout, Vec3::xyz(*x, *y, 0.0));
+            assert_eq!(e, *eye);
+            assert!(close(&v, &Vec3::xyz(*vx, *vy, 0.0)), "{} {} -> {} {}", x, y, v.x, v.y);
+        }
+    }
+
+    #[test]
+    fn eyes_sit_half_the_interocular_distance_to_either_side() {
+        for mode in [StereoConvergence::ToeIn, StereoConvergence::OffAxis].iter() {
+            let c = rig(*mode);
+            assert!(close(&c.eyes[0].origin, &Vec3::xyz(0.97, 2.0, 3.0)));
+            assert!(close(&c.eyes[1].origin, &Vec3::xyz(1.03, 2.0, 3.0)));
+            //the centre of either half of the image is the centre of its eye
+            assert!(close(&c.get_ray(Vec3::xyz(-0.5, 0.0, 0.0)).o, &c.eyes[0].origin));
+            assert!(close(&c.get_ray(Vec3::xyz(0.5, 0.0, 0.0)).o, &c.eyes[1].origin));
+        }
+    }
+
+    #[test]
+    fn toe_in_eyes_look_at_the_convergence_point() {
+        let c = rig(StereoConvergence::ToeIn);
+        let target = Vec3::xyz(1.0, 4.0, 3.0);
+        for eye in c.eyes.iter() {
+            let forward = &eye.coords*&Vec3::xyz(0.0, 0.0, -1.0);
+            assert!(close(&forward, &(target - eye.origin).normalized()));
+            assert_eq!(eye.shift_x, 0.0);
+        }
+    }
+
+    #[test]
+    fn off_axis_frustums_meet_at_the_convergence_distance() {
+        let c = rig(StereoConvergence::OffAxis);
+        let f = Vec3::xyz(0.0, 1.0, 0.0);
+        for eye in c.eyes.iter() {
+            assert!(close(&(&eye.coords*&Vec3::xyz(0.0, 0.0, -1.0)), &f));
+        }
+        //the same image point of both eyes looks at the same point of the
+        //convergence plane, and only there
+        for v in [(0.0, 0.0), (1.0, 0.0), (-1.0, -1.0), (0.5, 0.7)].iter() {
+            let v = Vec3::xyz(v.0, v.1, 0.0);
+            let (l, r) = (c.eyes[0].get_ray(v), c.eyes[1].get_ray(v));
+            assert!(close(&at_depth(&l, &f, 2.0), &at_depth(&r, &f, 2.0)));
+            assert!(!close(&at_depth(&l, &f, 4.0), &at_depth(&r, &f, 4.0)));
+        }
+    }
+
+    #[test]
+    fn invalid_stereo_rigs_are_rejected() {
+        let make = |interocular: f32, convergence: f32| CameraStereo::new(64, 32, 0.5, Vec3::new(),
+            Vec3::xyz(0.0, 1.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0), interocular, convergence,
+            StereoConvergence::OffAxis, StereoLayout::SideBySide);
+        assert!(make(0.06, 0.0).is_err());
+        assert!(make(0.06, -1.0).is_err());
+        assert!(make(0.06, f32::INFINITY).is_err());
+        assert!(make(0.06, f32::NAN).is_err());
+        assert!(make(-0.06, 2.0).is_err());
+        assert!(make(0.0, 2.0).is_ok());
+    }
+
+    #[test]
+    fn ods_eyes_are_offset_across_the_view_direction() {
+        let up = Vec3::xyz(0.0, 0.0, 1.0);
+        let c = CameraEquirectangular::stereo(64, 64, Vec3::xyz(0.0, 0.0, 1.0), Vec3::xyz(0.0, 1.0, 0.0), up,
+            0.064, StereoLayout::TopBottom);
+        //looking forward, right, backwards and left, at eye level
+        let views = [(0.0, Vec3::xyz(0.0, 1.0, 0.0)), (0.5, Vec3::xyz(1.0, 0.0, 0.0)),
+            (1.0, Vec3::xyz(0.0, -1.0, 0.0)), (-0.5, Vec3::xyz(-1.0, 0.0, 0.0))];
+        for (x, d) in views.iter() {
+            let l = c.get_ray(Vec3::xyz(*x, 0.5, 0.0));
+            let r = c.get_ray(Vec3::xyz(*x, -0.5, 0.0));
+            assert!(close(&l.d, d) && close(&r.d, d), "direction at {}", x);
+            let right = d.cross(&up);
+            assert!(close(&(l.o - c.origin), &(right*(-0.032))), "left eye at {}", x);
+            assert!(close(&(r.o - c.origin), &(right*0.032)), "right eye at {}", x);
+        }
+        //up and down rays are shared by all columns
+        let top = c.get_ray(Vec3::xyz(0.3, 1.0, 0.0));
+        assert!(close(&top.d, &up));
+    }
+
+    fn physical(f_stop: f32, shutter: f32, iso: f32) -> CameraPhysical {
+        CameraPhysical::new(64, 48, 36.0, 50.0, f_stop, shutter, iso, 3.0,
+            Vec3::new(), Vec3::xyz(0.0, 1.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0)).unwrap()
+    }
+
+    #[test]
+    fn exposure_scales_with_shutter_and_iso_over_the_f_stop_squared() {
+        let base = physical(4.0, 0.01, 100.0).exposure();
+        let settings = [(4.0, 0.02, 100.0, 2.0), (4.0, 0.01, 400.0, 4.0), (8.0, 0.01, 100.0, 0.25),
+            (2.8, 1.0/60.0, 800.0, (4.0*4.0)/(2.8*2.8)*(100.0/60.0)*8.0)];
+        for (f_stop, shutter, iso, ratio) in settings.iter() {
+            let e = physical(*f_stop, *shutter, *iso).exposure();
+            assert!((e/base - ratio).abs() < 1e-4*ratio, "{} for {}", e/base, ratio);
+        }
+    }
+
+    #[test]
+    fn thin_lens_rays_meet_at_the_focus_distance() {
+        let c = physical(2.0, 0.01, 100.0);
+        let f = Vec3::xyz(0.0, 1.0, 0.0);
+        for u in [(0.0, 0.0), (0.8, -0.5)].iter() {
+            let u = Vec3::xyz(u.0, u.1, 0.0);
+            //through the centre of the lens, and through its edges
+            let centre = c.sample_ray(u, 0.5, 0.5, 0.0).0;
+            assert!(close(&centre.o, &Vec3::new()));
+            for (r1, r2) in [(0.0, 0.5), (1.0, 0.5), (0.5, 0.0), (0.9, 0.2)].iter() {
+                let r = c.sample_ray(u, *r1, *r2, 0.0).0;
+                assert!(!close(&r.o, &centre.o));
+                assert!(close(&at_depth(&r, &f, 3.0), &at_depth(&centre, &f, 3.0)));
+                assert!(!close(&at_depth(&r, &f, 1.5), &at_depth(&centre, &f, 1.5)));
+            }
+        }
+    }
+}
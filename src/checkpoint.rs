@@ -0,0 +1,211 @@
+use crate::math::Vec3;
+use crate::film::PixelStats;
+use crate::film::AovFilm;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"RPETECK1";
+
+//State of a progressive render after a number of passes: the running
+//statistics of every pixel and the accumulated AOVs, stored bit for bit so
+//that a resumed render continues exactly where it stopped. Samplers are
+//seeded by pixel and pass, so the pass count is all the sampler state.
+pub struct Checkpoint {
+    pub width: u32,
+    pub height: u32,
+    pub passes: u32,
+    pub stats: Vec<PixelStats>,
+    pub aovs: Option<AovFilm>
+}
+
+fn put_u32(data: &mut Vec<u8>, v: u32) {
+    data.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_vec3(data: &mut Vec<u8>, v: &Vec3) {
+    for c in [v.x, v.y, v.z].iter() {
+        data.extend_from_slice(&c.to_le_bytes());
+    }
+}
+
+//reads little endian values from a byte slice
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.data.len() {
+            return Err(String::from("checkpoint is truncated"));
+        }
+        let b = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn vec3(&mut self) -> Result<Vec3, String> {
+        Ok(Vec3::xyz(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+impl Checkpoint {
+    //Writes to a temporary file first, so that a render killed while saving
+    //leaves the previous checkpoint intact
+    pub fn save(&self, fname: &str) -> Result<(), String> {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        put_u32(&mut data, self.width);
+        put_u32(&mut data, self.height);
+        put_u32(&mut data, self.passes);
+        for p in &self.stats {
+            put_u32(&mut data, p.n);
+            put_vec3(&mut data, &p.mean);
+            put_vec3(&mut data, &p.m2);
+        }
+        put_u32(&mut data, if self.aovs.is_some() {1} else {0});
+        if let Some(aovs) = &self.aovs {
+            for film in aovs.films() {
+                for c in film.pixels() {
+                    put_vec3(&mut data, c);
+                }
+            }
+        }
+
+        let tmp = format!("{}.tmp", fname);
+        let mut file = std::fs::File::create(&tmp).map_err(|e| format!("{}: {}", tmp, e))?;
+        file.write_all(&data).map_err(|e| format!("{}: {}", tmp, e))?;
+        std::fs::rename(&tmp, fname).map_err(|e| format!("{}: {}", fname, e))
+    }
+
+    pub fn load(fname: &str) -> Result<Checkpoint, String> {
+        let data = std::fs::read(fname).map_err(|e| format!("{}: {}", fname, e))?;
+        let mut r = Reader{data: &data, pos: 0};
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err(format!("{} is not a checkpoint", fname));
+        }
+        let width = r.u32()?;
+        let height = r.u32()?;
+        let passes = r.u32()?;
+        let mut stats = Vec::with_capacity((width*height) as usize);
+        for _ in 0..width*height {
+            stats.push(PixelStats{n: r.u32()?, mean: r.vec3()?, m2: r.vec3()?});
+        }
+        let aovs = if r.u32()? != 0 {
+            let mut aovs = AovFilm::new(width, height);
+            for film in aovs.films_mut() {
+                for c in film.pixels_mut() {
+                    *c = r.vec3()?;
+                }
+            }
+            Some(aovs)
+        } else {
+            None
+        };
+        Ok(Checkpoint{width, height, passes, stats, aovs})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::Aov;
+    use crate::renderer::PtRenderer;
+    use crate::renderer::Renderer;
+    use crate::control::RenderControl;
+    use crate::scenes::furnace;
+
+    fn bits(v: &Vec3) -> [u32; 3] {
+        [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]
+    }
+
+    fn temp(name: &str) -> String {
+        std::env::temp_dir().join(name).to_string_lossy().into_owned()
+    }
+
+    fn example() -> Checkpoint {
+        let stats = (0..6).map(|p| {
+            let mut s = PixelStats::new();
+            for k in 0..p {
+                s.add(&Vec3::xyz(0.1*k as f32, 1.0/(p + 1) as f32, -0.3));
+            }
+            s
+        }).collect();
+        let mut aovs = AovFilm::new(3, 2);
+        aovs.add(Aov::Normal, 2, 1, &Vec3::xyz(0.0, -1.0, 1e-7));
+        aovs.add(Aov::Depth, 0, 0, &Vec3::from(f32::MAX));
+        Checkpoint{width: 3, height: 2, passes: 5, stats, aovs: Some(aovs)}
+    }
+
+    #[test]
+    fn round_trip_is_exact() {
+        let fname = temp("rusty_pete_checkpoint_round_trip.ckpt");
+        let saved = example();
+        saved.save(&fname).unwrap();
+        let loaded = Checkpoint::load(&fname).unwrap();
+        std::fs::remove_file(&fname).ok();
+        assert_eq!((loaded.width, loaded.height, loaded.passes), (3, 2, 5));
+        for (a, b) in saved.stats.iter().zip(loaded.stats.iter()) {
+            assert_eq!((a.n, bits(&a.mean), bits(&a.m2)), (b.n, bits(&b.mean), bits(&b.m2)));
+        }
+        let (a, b) = (saved.aovs.unwrap(), loaded.aovs.unwrap());
+        for (fa, fb) in a.films().iter().zip(b.films().iter()) {
+            for (ca, cb) in fa.pixels().iter().zip(fb.pixels().iter()) {
+                assert_eq!(bits(ca), bits(cb));
+            }
+        }
+    }
+
+    #[test]
+    fn bad_magic_and_truncated_files_are_rejected() {
+        let fname = temp("rusty_pete_checkpoint_bad.ckpt");
+        example().save(&fname).unwrap();
+        let data = std::fs::read(&fname).unwrap();
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        std::fs::write(&fname, &bad).unwrap();
+        assert!(Checkpoint::load(&fname).is_err());
+
+        std::fs::write(&fname, &data[..data.len() - 1]).unwrap();
+        let err = Checkpoint::load(&fname).err().unwrap();
+        assert!(err.contains("truncated"), "{}", err);
+        std::fs::remove_file(&fname).ok();
+    }
+
+    //renders the furnace to the given number of passes, continuing from the
+    //checkpoint if it exists
+    fn render_to(passes: u32, checkpoint: &str) {
+        let (mut scene, _) = furnace(8, 0.5).build().unwrap();
+        let mut renderer = PtRenderer::new(4);
+        renderer.set_checkpoint(Some(String::from(checkpoint)));
+        let mut control = RenderControl::new();
+        control.set_target_samples(Some(passes));
+        renderer.render(&mut scene, &temp("rusty_pete_checkpoint_resume.png"), &control).unwrap();
+    }
+
+    #[test]
+    fn resumed_render_matches_an_uninterrupted_one() {
+        let (resumed, whole) = (temp("rusty_pete_checkpoint_resumed.ckpt"), temp("rusty_pete_checkpoint_whole.ckpt"));
+        std::fs::remove_file(&resumed).ok();
+        std::fs::remove_file(&whole).ok();
+        render_to(3, &resumed);
+        assert_eq!(Checkpoint::load(&resumed).unwrap().passes, 3);
+        render_to(7, &resumed);
+        render_to(7, &whole);
+        let (a, b) = (std::fs::read(&resumed).unwrap(), std::fs::read(&whole).unwrap());
+        assert_eq!(Checkpoint::load(&resumed).unwrap().passes, 7);
+        assert!(a == b, "resumed render differs");
+        for f in [resumed, whole, temp("rusty_pete_checkpoint_resume.png")].iter() {
+            std::fs::remove_file(f).ok();
+        }
+    }
+}
@@ -1 +0,0 @@
-pub const EPS : f32 = 2e-4;
\ No newline at end of file
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+type Progress = Box<dyn Fn(&str)>;
+
+//Limits on a render, checked by the renderers between passes (or lines,
+//iterations, chains). When one is reached the renderer stops and writes
+//what it has so far. The wall clock budget counts from the creation of the
+//handle. Target samples and noise apply to the path tracer, which then
+//renders in progressive passes; the other renderers only stop early.
+//Renderers also report their progress through it.
+pub struct RenderControl {
+    start: Instant,
+    //seconds
+    time_budget: Option<f32>,
+    //samples per pixel
+    target_samples: Option<u32>,
+    //relative standard error every pixel has to reach
+    target_noise: Option<f32>,
+    cancelled: Arc<AtomicBool>,
+    //receives progress messages; without it renders are silent
+    progress: Option<Progress>
+}
+
+impl Default for RenderControl {
+    fn default() -> RenderControl {
+        RenderControl::new()
+    }
+}
+
+impl RenderControl {
+    //no limits
+    pub fn new() -> RenderControl {
+        RenderControl{
+            start: Instant::now(),
+            time_budget: None,
+            target_samples: None,
+            target_noise: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            progress: None
+        }
+    }
+
+    pub fn set_time_budget(&mut self, seconds: Option<f32>) {
+        self.time_budget = seconds;
+    }
+
+    pub fn set_target_samples(&mut self, samples: Option<u32>) {
+        self.target_samples = samples;
+    }
+
+    pub fn set_target_noise(&mut self, error: Option<f32>) {
+        self.target_noise = error;
+    }
+
+    pub fn target_samples(&self) -> Option<u32> {
+        self.target_samples
+    }
+
+    pub fn target_noise(&self) -> Option<f32> {
+        self.target_noise
+    }
+
+    //whether anything but cancellation can end the render early
+    pub fn is_limited(&self) -> bool {
+        self.time_budget.is_some() || self.target_samples.is_some() || self.target_noise.is_some()
+    }
+
+    pub fn set_progress<F: Fn(&str) + 'static>(&mut self, progress: F) {
+        self.progress = Some(Box::new(progress));
+    }
+
+    pub fn report(&self, message: &str) {
+        if let Some(progress) = &self.progress {
+            progress(message);
+        }
+    }
+
+    //flag that cancels the render when set, e.g. from a signal handler
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn out_of_time(&self) -> bool {
+        match self.time_budget {
+            Some(t) => self.start.elapsed().as_secs_f32() >= t,
+            None => false
+        }
+    }
+
+    //cancelled or out of time
+    pub fn should_stop(&self) -> bool {
+        self.is_cancelled() || self.out_of_time()
+    }
+}
@@ -0,0 +1,189 @@
+use crate::scene::Scene;
+use crate::math::Vec3;
+use crate::math::linear2srgb;
+use crate::math::orthogonal;
+use crate::math::sample_hemisphere_cos;
+use crate::primitive::Ray;
+use crate::primitive::Intersection;
+use crate::renderer::Renderer;
+use crate::control::RenderControl;
+use crate::renderer::PtRenderer;
+use crate::renderer::first_surface;
+use crate::film::false_colour;
+use crate::material::material_id;
+use crate::film::heatmap;
+use crate::sampler::Sampler;
+use crate::sampler::RandomSampler;
+
+//what the debug renderer shows at the first visible surface
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum DebugMode {
+    //outward normal of the primitive
+    Normals,
+    //normal flipped towards the viewer, as used for shading
+    ShadingNormals,
+    //distance to the camera, scaled by the largest in the image
+    Depth,
+    Uv,
+    //false colour per primitive and per material
+    PrimitiveId,
+    MaterialId,
+    Albedo,
+    //fraction of unoccluded directions within the given distance
+    AmbientOcclusion(f32),
+    //heatmap of the path length of the path tracer
+    Bounces,
+    //heatmap of the bounding box and primitive tests of the primary ray
+    BvhCost
+}
+
+//Renders auxiliary quantities instead of light, to inspect scenes and
+//acceleration structures. Uses samples x samples camera rays per pixel.
+pub struct DebugRenderer {
+    mode: DebugMode,
+    samples: u32
+}
+
+impl DebugRenderer {
+    pub fn new(mode: DebugMode, samples: u32) -> DebugRenderer {
+        DebugRenderer{mode, samples}
+    }
+
+    fn ambient_occlusion(scene: &Scene, x: &Intersection, dist: f32, time: f32, sampler: &mut dyn Sampler) -> f32 {
+        let n = if x.n.dot(&x.wo) < 0.0 {x.n*(-1.0)} else {x.n};
+        let (nz, nx, ny) = orthogonal(n);
+        let local = sample_hemisphere_cos(sampler.next(), sampler.next());
+        let d = ((nx*local.x) + (ny*local.y)) + (nz*local.z);
+        let mut r = x.spawn_ray(d, time);
+        r.max_t = dist;
+        if scene.primitive.intersect_any(&mut r) {0.0} else {1.0}
+    }
+
+    //number of scattering events of a path traced like PtRenderer does
+    fn bounces(scene: &Scene, r: Ray, sampler: &mut dyn Sampler) -> f32 {
+        let mut r = r;
+        let mut count = 0;
+        let mut cost = 0;
+        while count < 256 {
+            let x = match first_surface(scene, &mut r, &mut cost) {
+                Some(x) => x,
+                None => {break;}
+            };
+            let (wi, pdf) = x.mat.sample(&x, sampler.next(), sampler.next());
+            if pdf <= 0.0 {
+                break;
+            }
+            count += 1;
+            if count > 2 && sampler.next() > PtRenderer::russian_roulette(&x.mat.eval(&x, &wi)) {
+                break;
+            }
+            r = x.spawn_ray(wi, r.time);
+        }
+        count as f32
+    }
+
+    //value of one camera sample; scalar modes return it in x
+    fn sample(&self, scene: &Scene, u: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let (mut r, weight) = scene.camera.sample_ray(u, 0.5, 0.5, 0.5);
+        if weight <= 0.0 {
+            return Vec3::new();
+        }
+        if self.mode == DebugMode::Bounces {
+            return Vec3::from(DebugRenderer::bounces(scene, r, sampler));
+        }
+        let o = r.o;
+        let mut cost = 0;
+        let hit = first_surface(scene, &mut r, &mut cost);
+        if self.mode == DebugMode::BvhCost {
+            return Vec3::from(cost as f32);
+        }
+        let x = match hit {
+            Some(x) => x,
+            None => {return Vec3::new();}
+        };
+        match self.mode {
+            DebugMode::Normals => (x.n*0.5) + Vec3::from(0.5),
+            DebugMode::ShadingNormals => {
+                let n = if x.n.dot(&x.wo) < 0.0 {x.n*(-1.0)} else {x.n};
+                (n*0.5) + Vec3::from(0.5)
+            }
+            DebugMode::Depth => Vec3::from((x.p - o).len()),
+            DebugMode::Uv => Vec3::xyz(x.uv.x, x.uv.y, 0.0),
+            DebugMode::PrimitiveId => false_colour(x.object),
+            DebugMode::MaterialId => false_colour(material_id(x.mat)),
+            DebugMode::Albedo => x.mat.albedo(&x),
+            DebugMode::AmbientOcclusion(dist) =>
+                Vec3::from(DebugRenderer::ambient_occlusion(scene, &x, dist, r.time, sampler)),
+            DebugMode::Bounces | DebugMode::BvhCost => Vec3::new()
+        }
+    }
+}
+
+impl Renderer for DebugRenderer {
+    fn render(&self, scene: &mut Scene, fname: &str, control: &RenderControl) -> Result<(), String> {
+        let (w, h) = scene.camera.image().dimensions();
+        let s = self.samples.max(1);
+        let mut values = vec![Vec3::new(); (w*h) as usize];
+        let mut samplers: Vec<RandomSampler> = (0..w*h).map(|p| RandomSampler::new(p as u64)).collect();
+        //one stratum per pass over the image, so that a stopped render has
+        //covered every pixel
+        let mut done = 0;
+        for pass in 0..s*s {
+            if pass > 0 && control.should_stop() {
+                break;
+            }
+            let (k, l) = (pass/s, pass%s);
+            for j in 0..h {
+                for i in 0..w {
+                    let p = (j*w + i) as usize;
+                    let u = ((i as f32 + (k as f32 + 0.5)/s as f32)/w as f32)*2.0 - 1.0;
+                    let v = -(((j as f32 + (l as f32 + 0.5)/s as f32)/h as f32)*2.0 - 1.0);
+                    values[p] = values[p] + self.sample(scene, Vec3::xyz(u, v, 0.0), &mut samplers[p]);
+                }
+            }
+            done += 1;
+        }
+        for c in values.iter_mut() {
+            *c = &*c/done as f32;
+        }
+
+        let max = values.iter().fold(0.0f32, |a, c| a.max(c.x));
+        let scale = if max > 0.0 {1.0/max} else {0.0};
+        for j in 0..h {
+            for i in 0..w {
+                let c = values[(j*w + i) as usize];
+                let c = match self.mode {
+                    DebugMode::Depth => Vec3::from(c.x*scale),
+                    DebugMode::Bounces | DebugMode::BvhCost => heatmap(c.x*scale),
+                    DebugMode::Albedo | DebugMode::AmbientOcclusion(_) =>
+                        Vec3::xyz(linear2srgb(c.x), linear2srgb(c.y), linear2srgb(c.z)),
+                    _ => c
+                };
+                scene.camera.set_pixel(i, j, [c.x, c.y, c.z]);
+            }
+        }
+        scene.camera.save_image(fname);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenes::furnace;
+
+    #[test]
+    fn stopped_render_covers_the_image_at_full_exposure() {
+        let (mut scene, _) = furnace(16, 0.5).build().unwrap();
+        let fname = std::env::temp_dir().join("rusty_pete_debug_stop_test.png");
+        let mut control = RenderControl::new();
+        control.set_time_budget(Some(0.0));
+        DebugRenderer::new(DebugMode::ShadingNormals, 4).render(&mut scene, &fname.to_string_lossy(), &control).unwrap();
+        let image = scene.camera_mut().image();
+        assert!(image.pixels().all(|p| p[0] as u32 + p[1] as u32 + p[2] as u32 > 0), "black pixels left");
+        //the ball faces the camera, along -y
+        let ball = image.get_pixel(8, 8);
+        assert!((ball[0] as f32 - 127.5).abs() <= 4.0 && ball[1] <= 4, "ball {:?}", ball);
+        std::fs::remove_file(fname).ok();
+    }
+}
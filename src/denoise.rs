@@ -0,0 +1,260 @@
+use crate::math::Vec3;
+use crate::math::luminance;
+use crate::film::Film;
+
+//B3 spline, the à-trous kernel
+const KERNEL: [f32; 5] = [1.0/16.0, 1.0/4.0, 3.0/8.0, 1.0/4.0, 1.0/16.0];
+
+//Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010) with the
+//variance guided colour weight of SVGF (Schied et al. 2017). The image is
+//divided by the first hit albedo so that texture is kept, filtered with a
+//5x5 kernel whose taps spread out by a factor of two every iteration, and
+//multiplied back. Neighbours with a different normal or albedo, or whose
+//brightness differs by more than the noise explains, get little weight.
+pub struct Denoiser {
+    iterations: u32,
+    //colour differences are measured in standard deviations of the noise
+    sigma_color: f32,
+    //exponent of the cosine between normals
+    sigma_normal: f32,
+    sigma_albedo: f32
+}
+
+struct Pixel {
+    //colour divided by albedo, and the variance of its luminance
+    color: Vec3,
+    variance: f32,
+    albedo: Vec3,
+    normal: Vec3
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser::new()
+    }
+}
+
+impl Denoiser {
+    pub fn new() -> Denoiser {
+        Denoiser{iterations: 5, sigma_color: 4.0, sigma_normal: 64.0, sigma_albedo: 0.1}
+    }
+
+    //albedo where it can be divided by, white elsewhere (emitters, sky)
+    fn demodulation(albedo: &Vec3) -> Vec3 {
+        Vec3::xyz(if albedo.x > 1e-3 {albedo.x} else {1.0},
+                  if albedo.y > 1e-3 {albedo.y} else {1.0},
+                  if albedo.z > 1e-3 {albedo.z} else {1.0})
+    }
+
+    //box filtered variance, since a few samples per pixel (e.g. all of them
+    //black) can badly underestimate it
+    fn blur_variance(pixels: &[Pixel], width: u32, height: u32, radius: i64) -> Vec<f32> {
+        let mut out = Vec::with_capacity(pixels.len());
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let mut sum = 0.0;
+                let mut n = 0;
+                for qy in (y - radius).max(0)..(y + radius + 1).min(height as i64) {
+                    for qx in (x - radius).max(0)..(x + radius + 1).min(width as i64) {
+                        sum += pixels[(qy*width as i64 + qx) as usize].variance;
+                        n += 1;
+                    }
+                }
+                out.push(sum/n as f32);
+            }
+        }
+        out
+    }
+
+    //Variance of pixels with a single sample, which have none of their own:
+    //half the squared difference of two neighbours has the pixel variance
+    //as its mean, and the median over a window ignores the few pairs that
+    //straddle an edge. For gaussian noise the median is 0.455 of the mean.
+    fn spatial_variance(pixels: &[Pixel], width: u32, height: u32, radius: i64) -> Vec<f32> {
+        let l: Vec<f32> = pixels.iter().map(|p| luminance(&p.color)).collect();
+        let mut out = Vec::with_capacity(pixels.len());
+        let mut pairs = Vec::new();
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                pairs.clear();
+                for qy in (y - radius).max(0)..(y + radius + 1).min(height as i64) {
+                    for qx in (x - radius).max(0)..(x + radius + 1).min(width as i64) {
+                        let q = (qy*width as i64 + qx) as usize;
+                        if qx + 1 < width as i64 {
+                            pairs.push(0.5*(l[q] - l[q + 1])*(l[q] - l[q + 1]));
+                        }
+                        if qy + 1 < height as i64 {
+                            pairs.push(0.5*(l[q] - l[q + width as usize])*(l[q] - l[q + width as usize]));
+                        }
+                    }
+                }
+                if pairs.is_empty() {
+                    out.push(0.0);
+                    continue;
+                }
+                let mid = pairs.len()/2;
+                let (_, median, _) = pairs.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+                out.push(*median/0.455);
+            }
+        }
+        out
+    }
+
+    //One filter pass with taps `step` pixels apart. The variance is filtered
+    //with the squared weights, so it tracks the remaining noise.
+    fn pass(&self, pixels: &[Pixel], width: u32, height: u32, step: i64) -> Vec<(Vec3, f32)> {
+        let local_variance = Denoiser::blur_variance(pixels, width, height, 1);
+        let mut out = Vec::with_capacity(pixels.len());
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let p = &pixels[(y*width as i64 + x) as usize];
+                let l = luminance(&p.color);
+                let scale = self.sigma_color*local_variance[(y*width as i64 + x) as usize].max(0.0).sqrt() + 1e-6;
+                let mut sum = Vec3::new();
+                let mut variance = 0.0;
+                let mut total = 0.0;
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x + (i as i64 - 2)*step;
+                        let qy = y + (j as i64 - 2)*step;
+                        if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                            continue;
+                        }
+                        let q = &pixels[(qy*width as i64 + qx) as usize];
+                        let w_color = -(l - luminance(&q.color)).abs()/scale;
+                        let w_albedo = -(p.albedo - q.albedo).lensqr()/(self.sigma_albedo*self.sigma_albedo);
+                        //pixels without a surface only mix among themselves
+                        let w_normal = if p.normal.is_zero() || q.normal.is_zero() {
+                            if p.normal.is_zero() && q.normal.is_zero() {1.0} else {0.0}
+                        } else {
+                            p.normal.dot(&q.normal).max(0.0).powf(self.sigma_normal)
+                        };
+                        let w = kx*ky*w_normal*(w_color + w_albedo).exp();
+                        sum = sum + (q.color*w);
+                        variance += w*w*q.variance;
+                        total += w;
+                    }
+                }
+                //the centre tap always has weight
+                out.push((&sum/total, variance/(total*total)));
+            }
+        }
+        out
+    }
+
+    //Filters color given the variance of its per pixel mean and the feature
+    //buffers, all linear and of the same size. Without a variance (a single
+    //sample per pixel) it is estimated from the image.
+    pub fn denoise(&self, color: &Film, variance: Option<&Film>, albedo: &Film, normal: &Film) -> Film {
+        let (width, height) = (color.width, color.height);
+        let mut pixels = Vec::with_capacity((width*height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let a = albedo.get(x, y);
+                let d = Denoiser::demodulation(&a);
+                let l = luminance(&d);
+                let c = color.get(x, y);
+                let n = normal.get(x, y);
+                pixels.push(Pixel{
+                    color: Vec3::xyz(c.x/d.x, c.y/d.y, c.z/d.z),
+                    variance: variance.map_or(0.0, |v| luminance(&v.get(x, y))/(l*l)),
+                    albedo: a,
+                    normal: if n.is_zero() {n} else {n.normalized()}
+                });
+            }
+        }
+
+        if variance.is_none() {
+            let spatial = Denoiser::spatial_variance(&pixels, width, height, 3);
+            for (p, v) in pixels.iter_mut().zip(spatial) {
+                p.variance = v;
+            }
+        }
+        let initial = Denoiser::blur_variance(&pixels, width, height, 3);
+        for (p, v) in pixels.iter_mut().zip(initial) {
+            p.variance = v;
+        }
+
+        for i in 0..self.iterations {
+            let filtered = self.pass(&pixels, width, height, 1 << i);
+            for (p, (c, v)) in pixels.iter_mut().zip(filtered) {
+                p.color = c;
+                p.variance = v;
+            }
+        }
+
+        let mut out = Film::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let p = &pixels[(y*width + x) as usize];
+                out.add(x, y, &(&p.color*&Denoiser::demodulation(&p.albedo)));
+            }
+        }
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    //a dark and a bright half, as if rendered with one sample per pixel
+    fn noisy_step(width: u32, height: u32) -> (Film, Film, Film, Film) {
+        let mut rng = StdRng::seed_from_u64(40);
+        let (mut truth, mut noisy, mut albedo, mut normal) =
+            (Film::new(width, height), Film::new(width, height), Film::new(width, height), Film::new(width, height));
+        for y in 0..height {
+            for x in 0..width {
+                let c = if x < width/2 {0.2} else {0.8};
+                truth.add(x, y, &Vec3::from(c));
+                noisy.add(x, y, &Vec3::from(c + rng.gen_range(-0.1..0.1)));
+                albedo.add(x, y, &Vec3::from(1.0));
+                normal.add(x, y, &Vec3::xyz(0.0, 0.0, 1.0));
+            }
+        }
+        (truth, noisy, albedo, normal)
+    }
+
+    //mean squared error over columns x0..x1
+    fn error(a: &Film, b: &Film, x0: u32, x1: u32) -> f32 {
+        let mut sum = 0.0;
+        for y in 0..a.height {
+            for x in x0..x1 {
+                sum += (luminance(&a.get(x, y)) - luminance(&b.get(x, y))).powi(2);
+            }
+        }
+        sum/((x1 - x0)*a.height) as f32
+    }
+
+    #[test]
+    fn single_samples_are_smoothed_and_edges_kept() {
+        let (width, height) = (48, 32);
+        let (truth, noisy, albedo, normal) = noisy_step(width, height);
+        let denoised = Denoiser::new().denoise(&noisy, None, &albedo, &normal);
+        for &(x0, x1) in [(0, width/2 - 4), (width/2 + 4, width)].iter() {
+            assert!(error(&denoised, &truth, x0, x1) < 0.1*error(&noisy, &truth, x0, x1));
+        }
+        //the columns on either side of the step stay where they were
+        let edge = (width/2 - 1, width/2 + 1);
+        assert!(error(&denoised, &truth, edge.0, edge.1) < error(&noisy, &truth, edge.0, edge.1));
+    }
+
+    #[test]
+    fn single_sample_variance_matches_the_noise() {
+        let (width, height) = (48, 32);
+        let (_, noisy, albedo, normal) = noisy_step(width, height);
+        let pixels: Vec<Pixel> = (0..width*height).map(|i| Pixel{
+            color: noisy.get(i%width, i/width), variance: 0.0, albedo: albedo.get(0, 0), normal: normal.get(0, 0)
+        }).collect();
+        //uniform noise in [-0.1, 0.1) has variance 0.01/3
+        let v = Denoiser::spatial_variance(&pixels, width, height, 3);
+        for x in [0, width/2 - 1, width/2, width - 1].iter() {
+            let estimate = v[(16*width + x) as usize];
+            assert!(estimate > 0.3*0.01/3.0 && estimate < 3.0*0.01/3.0, "column {}: {}", x, estimate);
+        }
+    }
+}
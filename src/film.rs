@@ -0,0 +1,239 @@
+use crate::math::Vec3;
+use crate::math::linear2srgb;
+use crate::math::luminance;
+use crate::camera::Camera;
+use std::io::Write;
+
+//Floating point image that accumulates linear radiance, for renderers that
+//add contributions to arbitrary pixels (light tracing splats)
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<Vec3>
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film{width, height, pixels: vec![Vec3::new(); (width*height) as usize]}
+    }
+
+    pub fn add(&mut self, x: u32, y: u32, c: &Vec3) {
+        let i = (y*self.width + x) as usize;
+        self.pixels[i] = &self.pixels[i] + c;
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Vec3 {
+        self.pixels[(y*self.width + x) as usize]
+    }
+
+    //all pixels, row by row
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Vec3] {
+        &mut self.pixels
+    }
+
+    pub fn scale_pixel(&mut self, x: u32, y: u32, s: f32) {
+        let i = (y*self.width + x) as usize;
+        self.pixels[i] = self.pixels[i]*s;
+    }
+
+    pub fn add_film(&mut self, other: &Film) {
+        for (a, b) in self.pixels.iter_mut().zip(other.pixels.iter()) {
+            *a = &*a + b;
+        }
+    }
+
+    //pixel containing the image coordinate u in [-1,1]^2 (y up), the inverse
+    //of the mapping used by the renderers to generate camera rays
+    pub fn raster(&self, u: &Vec3) -> Option<(u32, u32)> {
+        let x = (u.x + 1.0)*0.5*self.width as f32;
+        let y = (1.0 - u.y)*0.5*self.height as f32;
+        if !(x >= 0.0 && y >= 0.0) || x >= self.width as f32 || y >= self.height as f32 {
+            return None;
+        }
+        Some((x as u32, y as u32))
+    }
+
+    //writes the film scaled by `scale` to the camera image, as sRGB
+    pub fn develop(&self, camera: &mut dyn Camera, scale: f32) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let c = self.get(x, y)*scale;
+                camera.set_pixel(x, y, [linear2srgb(c.x), linear2srgb(c.y), linear2srgb(c.z)]);
+            }
+        }
+    }
+
+    //Writes the film scaled by `scale` as a little endian portable float map
+    //(linear, unclamped), for compositing
+    pub fn save_pfm(&self, fname: &str, scale: f32) -> Result<(), String> {
+        let mut data = Vec::with_capacity((self.width*self.height*12) as usize + 32);
+        data.extend_from_slice(format!("PF\n{} {}\n-1.0\n", self.width, self.height).as_bytes());
+        //rows are stored bottom to top
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let c = self.get(x, y)*scale;
+                for v in [c.x, c.y, c.z].iter() {
+                    data.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+        let mut file = std::fs::File::create(fname).map_err(|e| format!("{}: {}", fname, e))?;
+        file.write_all(&data).map_err(|e| format!("{}: {}", fname, e))
+    }
+}
+
+
+//well spread colour for an arbitrary id
+pub fn false_colour(id: usize) -> Vec3 {
+    let mut h = (id as u64).wrapping_add(0x9e3779b97f4a7c15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^= h >> 31;
+    let c = |shift: u64| ((h >> shift) & 0xff) as f32/255.0;
+    Vec3::xyz(c(0), c(8), c(16))
+}
+
+//blue, cyan, green, yellow, red for t from 0 to 1
+pub fn heatmap(t: f32) -> Vec3 {
+    let t = t.clamp(0.0, 1.0)*4.0;
+    let f = t - t.floor();
+    match t as u32 {
+        0 => Vec3::xyz(0.0, f, 1.0),
+        1 => Vec3::xyz(0.0, 1.0, 1.0 - f),
+        2 => Vec3::xyz(f, 1.0, 0.0),
+        3 => Vec3::xyz(1.0, 1.0 - f, 0.0),
+        _ => Vec3::xyz(1.0, 0.0, 0.0)
+    }
+}
+
+
+//running mean and variance of the samples of a pixel (Welford)
+#[derive(Copy,Clone)]
+pub struct PixelStats {
+    pub n: u32,
+    pub mean: Vec3,
+    pub m2: Vec3
+}
+
+impl Default for PixelStats {
+    fn default() -> PixelStats {
+        PixelStats::new()
+    }
+}
+
+impl PixelStats {
+    pub fn new() -> PixelStats {
+        PixelStats{n: 0, mean: Vec3::new(), m2: Vec3::new()}
+    }
+
+    pub fn add(&mut self, c: &Vec3) {
+        self.n += 1;
+        let delta = c - &self.mean;
+        self.mean = self.mean + (&delta/self.n as f32);
+        self.m2 = self.m2 + (&delta*&(c - &self.mean));
+    }
+
+    //variance of the mean, per channel
+    pub fn variance(&self) -> Vec3 {
+        if self.n < 2 {
+            return Vec3::new();
+        }
+        &self.m2/((self.n - 1) as f32*self.n as f32)
+    }
+
+    //standard error of the mean relative to its luminance, which is
+    //clamped so that black pixels don't need endless samples
+    pub fn relative_error(&self) -> f32 {
+        if self.n < 2 {
+            return f32::INFINITY;
+        }
+        luminance(&self.variance()).max(0.0).sqrt()/luminance(&self.mean).max(1e-2)
+    }
+}
+
+
+//Arbitrary output variables: first hit quantities and the split of the
+//radiance by the lobe it was scattered by first
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    //motion of the first hit over the shutter interval, in pixels
+    Motion,
+    ObjectId,
+    MaterialId,
+    DirectDiffuse,
+    IndirectDiffuse,
+    Specular,
+    //emitters and sky seen directly
+    Emission
+}
+
+impl Aov {
+    pub const ALL: [Aov; 11] = [Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::Motion,
+        Aov::ObjectId, Aov::MaterialId, Aov::DirectDiffuse, Aov::IndirectDiffuse, Aov::Specular, Aov::Emission];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Motion => "motion",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::Specular => "specular",
+            Aov::Emission => "emission"
+        }
+    }
+}
+
+//one film per AOV
+pub struct AovFilm {
+    films: Vec<Film>
+}
+
+impl AovFilm {
+    pub fn new(width: u32, height: u32) -> AovFilm {
+        AovFilm{films: Aov::ALL.iter().map(|_| Film::new(width, height)).collect()}
+    }
+
+    pub fn add(&mut self, aov: Aov, x: u32, y: u32, c: &Vec3) {
+        self.films[aov as usize].add(x, y, c);
+    }
+
+    pub fn get(&self, aov: Aov) -> &Film {
+        &self.films[aov as usize]
+    }
+
+    //the films in the order of Aov::ALL
+    pub fn films(&self) -> &[Film] {
+        &self.films
+    }
+
+    pub fn films_mut(&mut self) -> &mut [Film] {
+        &mut self.films
+    }
+
+    pub fn scale_pixel(&mut self, x: u32, y: u32, s: f32) {
+        for f in self.films.iter_mut() {
+            f.scale_pixel(x, y, s);
+        }
+    }
+
+    //writes every AOV to <stem>_<name>.pfm
+    pub fn save(&self, stem: &str, scale: f32) -> Result<(), String> {
+        for aov in Aov::ALL.iter() {
+            self.get(*aov).save_pfm(&format!("{}_{}.pfm", stem, aov.name()), scale)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use crate::math::Vec3;
+use crate::math::solve_quadratic;
+use crate::math::refract;
+use crate::primitive::Ray;
+
+use std::fs;
+
+//one spherical interface of a lens prescription, in scene units (meters)
+struct LensElement {
+    //signed radius of curvature; 0 marks the aperture stop
+    curvature: f32,
+    //distance along the axis to the next interface (or to the film for the last one)
+    thickness: f32,
+    //index of refraction of the medium behind this interface; 0 means air
+    ior: f32,
+    aperture_radius: f32
+}
+
+//A multi-element lens following the PBRT lens file convention:
+//interfaces are listed from the scene side to the film side, the film
+//sits at z=0 and the scene is towards -z.
+pub struct LensSystem {
+    elements: Vec<LensElement>
+}
+
+impl LensSystem {
+    //Parses a prescription table with one interface per line:
+    //  radius(mm) thickness(mm) ior aperture_diameter(mm)
+    //Empty lines and lines starting with '#' are ignored.
+    pub fn parse(table: &str) -> Result<LensSystem, String> {
+        let mut elements = Vec::new();
+        for (n, line) in table.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Result<Vec<f32>, _> = line.split_whitespace().map(|v| v.parse::<f32>()).collect();
+            let values = match values {
+                Ok(v) => v,
+                Err(_) => {return Err(format!("line {}: could not parse number", n+1));}
+            };
+            if values.len() != 4 {
+                return Err(format!("line {}: expected 4 values, got {}", n+1, values.len()));
+            }
+            elements.push(LensElement{
+                curvature: values[0]*0.001,
+                thickness: values[1]*0.001,
+                ior: values[2],
+                aperture_radius: values[3]*0.001*0.5
+            });
+        }
+        if elements.is_empty() {
+            return Err(String::from("lens prescription has no elements"));
+        }
+        Ok(LensSystem{elements})
+    }
+
+    pub fn load(filename: &str) -> Result<LensSystem, String> {
+        match fs::read_to_string(filename) {
+            Ok(s) => LensSystem::parse(&s),
+            Err(e) => Err(format!("couldn't read {}: {}", filename, e))
+        }
+    }
+
+    //restricts the aperture stop so that the lens is not faster than the given f-number
+    pub fn stop_down(&mut self, focal_length: f32, f_stop: f32) {
+        let max_radius = focal_length/(2.0*f_stop);
+        for e in &mut self.elements {
+            if e.curvature == 0.0 && e.aperture_radius > max_radius {
+                e.aperture_radius = max_radius;
+            }
+        }
+    }
+
+    pub fn rear_z(&self) -> f32 {
+        -self.elements.last().unwrap().thickness
+    }
+
+    pub fn rear_radius(&self) -> f32 {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    fn front_z(&self) -> f32 {
+        -self.elements.iter().map(|e| e.thickness).sum::<f32>()
+    }
+
+    //Area of the exit pupil seen from the center of the film: the part of the
+    //rear element through which light reaches the scene, found on a grid.
+    pub fn exit_pupil_area(&self) -> f32 {
+        const N: usize = 64;
+        let r = self.rear_radius();
+        let mut passed = 0;
+        for i in 0..N {
+            for j in 0..N {
+                let x = ((i as f32 + 0.5)/N as f32*2.0 - 1.0)*r;
+                let y = ((j as f32 + 0.5)/N as f32*2.0 - 1.0)*r;
+                let d = Vec3::xyz(x, y, self.rear_z()).normalized();
+                if self.trace_from_film(&Ray::new(Vec3::new(), d)).is_some() {
+                    passed += 1;
+                }
+            }
+        }
+        4.0*r*r*passed as f32/(N*N) as f32
+    }
+
+    //traces a ray from the film out into the scene; None if it is blocked
+    pub fn trace_from_film(&self, r: &Ray) -> Option<Ray> {
+        let mut o = r.o;
+        let mut d = r.d;
+        let mut z = 0.0;
+        for i in (0..self.elements.len()).rev() {
+            let e = &self.elements[i];
+            z -= e.thickness;
+            let (t, n) = LensSystem::intersect_element(e, z, &o, &d)?;
+            o = o + (d*t);
+            if o.x*o.x + o.y*o.y > e.aperture_radius*e.aperture_radius {
+                return None;
+            }
+            if e.curvature != 0.0 {
+                let eta_i = if e.ior == 0.0 {1.0} else {e.ior};
+                let eta_t = if i > 0 && self.elements[i-1].ior != 0.0 {self.elements[i-1].ior} else {1.0};
+                d = refract(&(d*(-1.0)), &n, eta_i/eta_t)?;
+            }
+        }
+        Some(Ray::new(o, d))
+    }
+
+    //traces a ray from the scene side towards the film; None if it is blocked
+    fn trace_from_scene(&self, r: &Ray) -> Option<Ray> {
+        let mut o = r.o;
+        let mut d = r.d;
+        let mut z = self.front_z();
+        for i in 0..self.elements.len() {
+            let e = &self.elements[i];
+            let (t, n) = LensSystem::intersect_element(e, z, &o, &d)?;
+            o = o + (d*t);
+            if o.x*o.x + o.y*o.y > e.aperture_radius*e.aperture_radius {
+                return None;
+            }
+            if e.curvature != 0.0 {
+                let eta_i = if i == 0 || self.elements[i-1].ior == 0.0 {1.0} else {self.elements[i-1].ior};
+                let eta_t = if e.ior == 0.0 {1.0} else {e.ior};
+                d = refract(&(d*(-1.0)), &n, eta_i/eta_t)?;
+            }
+            z += e.thickness;
+        }
+        Some(Ray::new(o, d))
+    }
+
+    //returns the ray parameter and the normal facing against the ray
+    fn intersect_element(e: &LensElement, z: f32, o: &Vec3, d: &Vec3) -> Option<(f32, Vec3)> {
+        if e.curvature == 0.0 {
+            let t = (z - o.z)/d.z;
+            if t < 0.0 {
+                return None;
+            }
+            return Some((t, Vec3::xyz(0.0, 0.0, 1.0)));
+        }
+        let center = Vec3::xyz(0.0, 0.0, z + e.curvature);
+        let oc = o - &center;
+        let roots = solve_quadratic(d.lensqr(), 2.0*oc.dot(d), oc.lensqr() - e.curvature*e.curvature)?;
+        let closer = (d.z > 0.0) ^ (e.curvature < 0.0);
+        let t = if closer {roots[0].min(roots[1])} else {roots[0].max(roots[1])};
+        if t < 0.0 {
+            return None;
+        }
+        let mut n = (oc + (d*t)).normalized();
+        if n.dot(d) > 0.0 {
+            n = n*(-1.0);
+        }
+        Some((t, n))
+    }
+
+    //Moves the film so that objects at the given distance are in focus,
+    //using the thick lens approximation (as in PBRT).
+    pub fn focus(&mut self, focus_distance: f32) -> Result<(), String> {
+        let x = 0.1*self.rear_radius();
+
+        let r_scene = Ray::new(Vec3::xyz(x, 0.0, self.front_z() - 1.0), Vec3::xyz(0.0, 0.0, 1.0));
+        let (pz0, fz0) = match self.trace_from_scene(&r_scene) {
+            Some(r_film) => cardinal_points(&r_scene, &r_film),
+            None => {return Err(String::from("focus: ray from scene side was blocked"));}
+        };
+        let r_film = Ray::new(Vec3::xyz(x, 0.0, self.rear_z() + 1.0), Vec3::xyz(0.0, 0.0, -1.0));
+        let (pz1, _) = match self.trace_from_film(&r_film) {
+            Some(r_scene) => cardinal_points(&r_film, &r_scene),
+            None => {return Err(String::from("focus: ray from film side was blocked"));}
+        };
+
+        let f = fz0 - pz0;
+        let z = -focus_distance;
+        let c = (pz1 - z - pz0)*(pz1 - z - 4.0*f - pz0);
+        if c <= 0.0 {
+            return Err(format!("focus distance {} is closer than the lens can focus", focus_distance));
+        }
+        let delta = 0.5*(pz1 - z + pz0 - c.sqrt());
+        self.elements.last_mut().unwrap().thickness += delta;
+        Ok(())
+    }
+}
+
+//principal plane and focal point z from an axis-parallel ray and its refracted counterpart
+fn cardinal_points(r_in: &Ray, r_out: &Ray) -> (f32, f32) {
+    let tf = -r_out.o.x/r_out.d.x;
+    let fz = r_out.o.z + r_out.d.z*tf;
+    let tp = (r_in.o.x - r_out.o.x)/r_out.d.x;
+    let pz = r_out.o.z + r_out.d.z*tp;
+    (pz, fz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //biconvex singlet of about 50mm focal length, behind a stop
+    const SINGLET: &str = "
+        # radius thickness ior aperture
+        0 2 0 8
+        50 4 1.5 20
+        -50 48 0 20
+    ";
+
+    //z where the ray crosses the optical axis
+    fn axis_crossing(r: &Ray) -> f32 {
+        r.o.z + r.d.z*(-r.o.x/r.d.x)
+    }
+
+    #[test]
+    fn parse_reads_millimeters_and_rejects_bad_lines() {
+        let lens = LensSystem::parse(SINGLET).unwrap();
+        assert_eq!(lens.elements.len(), 3);
+        assert!((lens.rear_z() + 0.048).abs() < 1e-6);
+        assert!((lens.rear_radius() - 0.01).abs() < 1e-6);
+        assert!(LensSystem::parse("50 4 1.5").is_err());
+        assert!(LensSystem::parse("50 4 glass 20").is_err());
+        assert!(LensSystem::parse("# nothing").is_err());
+    }
+
+    #[test]
+    fn focused_lens_images_the_focus_distance_on_the_film() {
+        for distance in [0.5, 2.0, 10.0].iter() {
+            let mut lens = LensSystem::parse(SINGLET).unwrap();
+            lens.focus(*distance).unwrap();
+            //along the axis through the centre of the lens, undeviated
+            let axial = lens.trace_from_film(&Ray::new(Vec3::new(), Vec3::xyz(0.0, 0.0, -1.0))).unwrap();
+            assert!(axial.d.x.abs() < 1e-6 && axial.d.y.abs() < 1e-6);
+            //rays from the point on the axis at the focus distance through the
+            //middle of the lens, where the thick lens model holds, meet on
+            //the film (the object side is too sensitive to rounding to check)
+            for x in [0.0002, 0.0005, -0.001].iter() {
+                let o = Vec3::xyz(0.0, 0.0, -distance);
+                let d = Vec3::xyz(*x, 0.0, lens.front_z() + distance).normalized();
+                let r = lens.trace_from_scene(&Ray::new(o, d)).unwrap();
+                let z = axis_crossing(&r);
+                assert!(z.abs() < 5e-5, "focused {}m off the film for {}m", z, distance);
+            }
+        }
+    }
+
+    #[test]
+    fn stop_down_limits_the_aperture() {
+        let mut lens = LensSystem::parse(SINGLET).unwrap();
+        let open = lens.exit_pupil_area();
+        lens.stop_down(0.05, 8.0);
+        let stopped = lens.exit_pupil_area();
+        assert!(stopped > 0.0 && stopped < open);
+        assert!(lens.trace_from_film(&Ray::new(Vec3::new(), Vec3::xyz(0.009, 0.0, lens.rear_z()).normalized())).is_none());
+    }
+}
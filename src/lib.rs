@@ -0,0 +1,29 @@
+//! Rusty Pete, a simple extendable pathtracer.
+//!
+//! A scene is a primitive (usually a group or BVH of shapes carrying
+//! materials), a camera, the lights used for next event estimation and an
+//! optional atmosphere. Renderers take a scene and write its image.
+
+pub mod math;
+pub mod scene;
+pub mod builder;
+pub mod scenes;
+pub mod material;
+pub mod primitive;
+pub mod light;
+pub mod renderer;
+pub mod camera;
+pub mod lens;
+pub mod medium;
+pub mod voxel;
+pub mod sdf;
+pub mod spectrum;
+pub mod film;
+pub mod bdpt;
+pub mod sppm;
+pub mod sampler;
+pub mod mlt;
+pub mod debug;
+pub mod denoise;
+pub mod checkpoint;
+pub mod control;
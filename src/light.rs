@@ -1,3 +1,282 @@
-pub trait Light {
-    
-}
\ No newline at end of file
+use crate::math::Vec3;
+use crate::math::orthogonal;
+use crate::material::Material;
+use std::sync::Arc;
+use crate::primitive::Intersection;
+use crate::primitive::Sphere;
+use crate::primitive::Ray;
+use crate::primitive::offset_ray_origin;
+use crate::primitive::reproject_on_sphere;
+use crate::primitive::Surface;
+use crate::math::sample_hemisphere_cos;
+
+use std::f32::consts::PI;
+
+pub struct LightSample {
+    //direction from the shaded point towards the light
+    pub wi: Vec3,
+    pub dist: f32,
+    //surface normal at the sampled point on the light
+    pub n: Vec3,
+    //radiance leaving the light towards the shaded point
+    pub radiance: Vec3,
+    //solid angle density
+    pub pdf: f32
+}
+
+//Ray leaving a light, for light tracing
+pub struct EmissionSample {
+    //starts on the light, unit direction
+    pub ray: Ray,
+    pub n: Vec3,
+    pub radiance: Vec3,
+    //area density of the origin and solid angle density of the direction
+    pub pdf_pos: f32,
+    pub pdf_dir: f32
+}
+
+//Lights are sampled for next event estimation. The emitting geometry is
+//still part of the scene, so rays can also hit it.
+pub trait Light: Send + Sync {
+    //lambdas selects spectral radiance, see Material::emit_at
+    fn sample(&self, p: &Vec3, lambdas: Option<&Vec3>, r1: f32, r2: f32) -> Option<LightSample>;
+    //solid angle density of sampling the direction wi from p, given that the
+    //ray along wi hit the point `hit`; zero if `hit` is not on this light
+    fn pdf(&self, p: &Vec3, wi: &Vec3, hit: &Vec3) -> f32;
+    //samples an emitted ray: r1, r2 pick the origin and r3, r4 the direction
+    fn sample_emission(&self, lambdas: Option<&Vec3>, r1: f32, r2: f32, r3: f32, r4: f32) -> Option<EmissionSample>;
+    //densities of sample_emission for a ray leaving p along d;
+    //zero if p is not on this light
+    fn pdf_emission(&self, p: &Vec3, d: &Vec3) -> (f32, f32);
+}
+
+
+//Emitting sphere, sampled uniformly inside the cone it subtends, or by area
+//from points inside it. It emits from both sides, as emitting materials do
+//when hit.
+pub struct SphereLight {
+    pub(crate) o: Vec3,
+    pub(crate) r: f32,
+    pub(crate) mat: Arc<dyn Material>
+}
+
+impl SphereLight {
+    pub fn new(sphere: &Sphere) -> SphereLight {
+        SphereLight{o: sphere.o, r: sphere.r, mat: sphere.mat.clone()}
+    }
+
+    fn cos_max(&self, p: &Vec3) -> Option<f32> {
+        let d2 = (&self.o - p).lensqr();
+        if d2 <= self.r*self.r {
+            return None;
+        }
+        Some((1.0 - self.r*self.r/d2).max(0.0).sqrt())
+    }
+
+    //uniform point on the sphere and its outward normal
+    fn point(&self, r1: f32, r2: f32) -> (Vec3, Vec3) {
+        let z = 1.0 - 2.0*r1;
+        let s = (1.0 - z*z).max(0.0).sqrt();
+        let phi = 2.0*PI*r2;
+        let n = Vec3::xyz(s*phi.cos(), s*phi.sin(), z);
+        (self.o + (n*self.r), n)
+    }
+
+    //solid angle density of sampling hit by area from p
+    fn pdf_area(&self, p: &Vec3, hit: &Vec3) -> f32 {
+        let to = hit - p;
+        let d2 = to.lensqr();
+        let cos = (hit - &self.o).normalized().dot(&to).abs()/d2.sqrt();
+        if d2 <= 0.0 || cos <= 0.0 {
+            return 0.0;
+        }
+        d2/(cos*4.0*PI*self.r*self.r)
+    }
+}
+
+impl Light for SphereLight {
+    fn sample(&self, p: &Vec3, lambdas: Option<&Vec3>, r1: f32, r2: f32) -> Option<LightSample> {
+        let (wi, dist, hit, pdf) = match self.cos_max(p) {
+            Some(cos_max) => {
+                let cos = 1.0 - r1 + r1*cos_max;
+                let sin = (1.0 - cos*cos).max(0.0).sqrt();
+                let phi = 2.0*PI*r2;
+                let (z, x, y) = orthogonal(&self.o - p);
+                let wi = ((x*(sin*phi.cos())) + (y*(sin*phi.sin()))) + (z*cos);
+
+                //nearest intersection with the sphere along wi
+                let oc = p - &self.o;
+                let b = oc.dot(&wi);
+                let c = oc.lensqr() - self.r*self.r;
+                let dist = -b - (b*b - c).max(0.0).sqrt();
+                (wi, dist, p + &(wi*dist), 1.0/(2.0*PI*(1.0 - cos_max)))
+            }
+            //inside, every point of the sphere is visible
+            None => {
+                let (hit, _) = self.point(r1, r2);
+                let pdf = self.pdf_area(p, &hit);
+                if pdf <= 0.0 {
+                    return None;
+                }
+                let to = &hit - p;
+                let dist = to.len();
+                (&to/dist, dist, hit, pdf)
+            }
+        };
+
+        let it = Intersection{
+            p: hit,
+            p_error: Vec3::new(),
+            wo: wi*(-1.0),
+            n: (hit - self.o).normalized(),
+            uv: Vec3::new(),
+            mat: &*self.mat,
+            object: 0,
+            dpdt: Vec3::new(),
+            interior: None
+        };
+        Some(LightSample{
+            wi,
+            dist,
+            n: it.n,
+            radiance: self.mat.emit_at(&it, lambdas),
+            pdf
+        })
+    }
+
+    fn pdf(&self, p: &Vec3, _wi: &Vec3, hit: &Vec3) -> f32 {
+        if ((hit - &self.o).len() - self.r).abs() > 1e-3*self.r.max(1.0) {
+            return 0.0;
+        }
+        match self.cos_max(p) {
+            Some(cos_max) => 1.0/(2.0*PI*(1.0 - cos_max)),
+            None => self.pdf_area(p, hit)
+        }
+    }
+
+    //uniform point on the sphere, then a side with r3 and a cosine weighted
+    //direction on it
+    fn sample_emission(&self, lambdas: Option<&Vec3>, r1: f32, r2: f32, r3: f32, r4: f32) -> Option<EmissionSample> {
+        let (p, n) = self.point(r1, r2);
+        let (p, p_error) = reproject_on_sphere(&self.o, self.r, &p);
+        let (side, r3) = if r3 < 0.5 {(1.0, r3*2.0)} else {(-1.0, r3*2.0 - 1.0)};
+        let n = n*side;
+        let (nz, nx, ny) = orthogonal(n);
+        let local = sample_hemisphere_cos(r3, r4);
+        let d = ((nx*local.x) + (ny*local.y)) + (nz*local.z);
+        let it = Intersection{
+            p,
+            p_error,
+            wo: d,
+            n,
+            uv: Vec3::new(),
+            mat: &*self.mat,
+            object: 0,
+            dpdt: Vec3::new(),
+            interior: None
+        };
+        Some(EmissionSample{
+            ray: Ray::new(offset_ray_origin(&p, &p_error, &n, &d), d),
+            n,
+            radiance: self.mat.emit_at(&it, lambdas),
+            pdf_pos: 1.0/(4.0*PI*self.r*self.r),
+            pdf_dir: local.z/(2.0*PI)
+        })
+    }
+
+    fn pdf_emission(&self, p: &Vec3, d: &Vec3) -> (f32, f32) {
+        if ((p - &self.o).len() - self.r).abs() > 1e-3*self.r.max(1.0) {
+            return (0.0, 0.0);
+        }
+        let n = (p - &self.o).normalized();
+        (1.0/(4.0*PI*self.r*self.r), n.dot(d).abs()/(2.0*PI))
+    }
+}
+
+
+//Emitting shape sampled uniformly by area, see Surface. It emits from both
+//sides, as emitting materials do when hit. The shape is shared with the
+//scene, which also needs it to be hit.
+pub struct AreaLight {
+    pub(crate) shape: Arc<dyn Surface>
+}
+
+impl AreaLight {
+    pub fn new(shape: Arc<dyn Surface>) -> AreaLight {
+        AreaLight{shape}
+    }
+
+    fn radiance(&self, p: &Vec3, n: &Vec3, wo: &Vec3, lambdas: Option<&Vec3>) -> Vec3 {
+        let it = Intersection{
+            p: *p,
+            p_error: Vec3::new(),
+            wo: *wo,
+            n: *n,
+            uv: Vec3::new(),
+            mat: self.shape.material(),
+            object: 0,
+            dpdt: Vec3::new(),
+            interior: None
+        };
+        self.shape.material().emit_at(&it, lambdas)
+    }
+}
+
+impl Light for AreaLight {
+    fn sample(&self, p: &Vec3, lambdas: Option<&Vec3>, r1: f32, r2: f32) -> Option<LightSample> {
+        let s = self.shape.sample_area(r1, r2);
+        let to = &s.p - p;
+        let dist = to.len();
+        if dist <= 0.0 {
+            return None;
+        }
+        let wi = &to/dist;
+        let cos = s.n.dot(&wi).abs();
+        if cos <= 0.0 {
+            return None;
+        }
+        Some(LightSample{
+            wi,
+            dist,
+            n: s.n,
+            radiance: self.radiance(&s.p, &s.n, &(wi*(-1.0)), lambdas),
+            pdf: dist*dist/(cos*self.shape.area())
+        })
+    }
+
+    fn pdf(&self, p: &Vec3, wi: &Vec3, hit: &Vec3) -> f32 {
+        let n = match self.shape.normal_at(hit) {
+            Some(n) => n,
+            None => return 0.0
+        };
+        let cos = n.dot(wi).abs();
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        (hit - p).lensqr()/(cos*self.shape.area())
+    }
+
+    //uniform point, then a side with r3 and a cosine weighted direction on it
+    fn sample_emission(&self, lambdas: Option<&Vec3>, r1: f32, r2: f32, r3: f32, r4: f32) -> Option<EmissionSample> {
+        let s = self.shape.sample_area(r1, r2);
+        let (side, r3) = if r3 < 0.5 {(1.0, r3*2.0)} else {(-1.0, r3*2.0 - 1.0)};
+        let n = s.n*side;
+        let (nz, nx, ny) = orthogonal(n);
+        let local = sample_hemisphere_cos(r3, r4);
+        let d = ((nx*local.x) + (ny*local.y)) + (nz*local.z);
+        Some(EmissionSample{
+            ray: Ray::new(offset_ray_origin(&s.p, &s.p_error, &s.n, &d), d),
+            n,
+            radiance: self.radiance(&s.p, &n, &d, lambdas),
+            pdf_pos: 1.0/self.shape.area(),
+            pdf_dir: local.z/(2.0*PI)
+        })
+    }
+
+    fn pdf_emission(&self, p: &Vec3, d: &Vec3) -> (f32, f32) {
+        match self.shape.normal_at(p) {
+            Some(n) => (1.0/self.shape.area(), n.dot(d).abs()/(2.0*PI)),
+            None => (0.0, 0.0)
+        }
+    }
+}
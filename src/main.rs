@@ -1,129 +1,147 @@
-mod math;
-mod scene;
-mod material;
-mod primitive;
-mod light;
-mod renderer;
-mod camera;
-mod constants;
-
-
-use scene::Scene;
-use camera::Camera2D;
-use camera::CameraPerspective;
-use primitive::SimpleGroup;
-use renderer::DummyRenderer;
-use renderer::PtRenderer;
-use renderer::Renderer;
-use math::Vec3;
-use primitive::Sphere;
-use primitive::Group;
-use material::DiffuseMaterial;
-use material::EmissionMaterial;
-use material::Material;
+use rusty_pete::renderer::PtRenderer;
+use rusty_pete::renderer::Renderer;
+use rusty_pete::bdpt::BdptRenderer;
+use rusty_pete::sppm::SppmRenderer;
+use rusty_pete::mlt::MltRenderer;
+use rusty_pete::debug::DebugRenderer;
+use rusty_pete::debug::DebugMode;
+use rusty_pete::control::RenderControl;
+use rusty_pete::scenes;
+
+const USAGE: &str = "usage: rusty-pete [--scene NAME] [--renderer NAME] [--samples N] [--output FILE] [--aovs] [--denoise] [--adaptive ERROR]
+       [--progressive SECONDS] [--checkpoint FILE] [--time-budget SECONDS] [--target-samples N] [--target-noise ERROR]
+scenes: cornell, cornell-area, furnace, preview, shapes, implicit
+renderers: pt, spectral, bdpt, sppm, mlt,
+           normals, shading-normals, depth, uv, primid, matid, albedo, ao, bounces, bvh";
+
+struct Options {
+    scene: String,
+    renderer: String,
+    samples: u32,
+    output: String,
+    //write the AOVs of the path tracer next to the output
+    aovs: bool,
+    //also write a denoised copy of the path tracer output
+    denoise: bool,
+    //relative error threshold for adaptive sampling
+    adaptive: Option<f32>,
+    //seconds between image writes of progressive rendering
+    progressive: Option<f32>,
+    checkpoint: Option<String>,
+    //limits handed to the renderer, see RenderControl
+    time_budget: Option<f32>,
+    target_samples: Option<u32>,
+    target_noise: Option<f32>
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options{scene: String::from("cornell"), renderer: String::from("pt"), samples: 50, output: String::from("lol3.png"), aovs: false, denoise: false,
+        adaptive: None, progressive: None, checkpoint: None, time_budget: None, target_samples: None, target_noise: None};
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--aovs" => options.aovs = true,
+            "--denoise" => options.denoise = true,
+            "--scene" | "--renderer" | "--samples" | "--output" | "--adaptive" | "--progressive" | "--checkpoint"
+                | "--time-budget" | "--target-samples" | "--target-noise" => {
+                let value = args.get(i+1).ok_or(format!("missing value for {}", args[i]))?;
+                match args[i].as_str() {
+                    "--scene" => options.scene = value.clone(),
+                    "--renderer" => options.renderer = value.clone(),
+                    "--samples" => options.samples = value.parse().map_err(|_| format!("invalid sample count {}", value))?,
+                    "--adaptive" => options.adaptive = Some(value.parse().map_err(|_| format!("invalid error threshold {}", value))?),
+                    "--progressive" => options.progressive = Some(value.parse().map_err(|_| format!("invalid interval {}", value))?),
+                    "--checkpoint" => options.checkpoint = Some(value.clone()),
+                    "--time-budget" => options.time_budget = Some(value.parse().map_err(|_| format!("invalid time budget {}", value))?),
+                    "--target-samples" => options.target_samples = Some(value.parse().map_err(|_| format!("invalid sample count {}", value))?),
+                    "--target-noise" => options.target_noise = Some(value.parse().map_err(|_| format!("invalid error threshold {}", value))?),
+                    _ => options.output = value.clone()
+                }
+                i += 1;
+            }
+            _ => {return Err(format!("unknown option {}", args[i]));}
+        }
+        i += 1;
+    }
+    Ok(options)
+}
+
+//samples is the per-axis count for the pixel samplers, the number of
+//iterations for SPPM and the mutations per pixel for MLT
+fn make_renderer(options: &Options) -> Result<Box<dyn Renderer>, String> {
+    let samples = options.samples;
+    let debug = |mode| -> Result<Box<dyn Renderer>, String> {Ok(Box::new(DebugRenderer::new(mode, samples)))};
+    let pt = |mut renderer: PtRenderer| -> Result<Box<dyn Renderer>, String> {
+        renderer.set_aovs(options.aovs);
+        renderer.set_denoise(options.denoise);
+        renderer.set_adaptive(options.adaptive);
+        renderer.set_progressive(options.progressive);
+        renderer.set_checkpoint(options.checkpoint.clone());
+        Ok(Box::new(renderer))
+    };
+    match options.renderer.as_str() {
+        "pt" => pt(PtRenderer::new(samples)),
+        "spectral" => pt(PtRenderer::spectral(samples)),
+        "bdpt" => Ok(Box::new(BdptRenderer::new(samples, 16))),
+        "sppm" => Ok(Box::new(SppmRenderer::new(samples, 100000, 0.05))),
+        "mlt" => Ok(Box::new(MltRenderer::new(samples))),
+        "normals" => debug(DebugMode::Normals),
+        "shading-normals" => debug(DebugMode::ShadingNormals),
+        "depth" => debug(DebugMode::Depth),
+        "uv" => debug(DebugMode::Uv),
+        "primid" => debug(DebugMode::PrimitiveId),
+        "matid" => debug(DebugMode::MaterialId),
+        "albedo" => debug(DebugMode::Albedo),
+        "ao" => debug(DebugMode::AmbientOcclusion(0.5)),
+        "bounces" => debug(DebugMode::Bounces),
+        "bvh" => debug(DebugMode::BvhCost),
+        name => Err(format!("unknown renderer {}", name))
+    }
+}
 
 fn main(){
-
-    
-
-    let floor_mat = &(DiffuseMaterial::new(Vec3::xyz(0.8,0.8,0.8))) as & dyn Material;
-    let green_mat =&(DiffuseMaterial{albedo: Vec3::xyz(0.05,0.8,0.05)}) as & dyn Material;
-    let red_mat = &(DiffuseMaterial{albedo: Vec3::xyz(0.8,0.05,0.05)}) as & dyn Material;
-    let emit = &(EmissionMaterial{light: Vec3::xyz(1.0,1.0,1.0), intensity: 6.0}) as & dyn Material;
-    
-    let sg =&mut SimpleGroup::new();
-
-
-
-    sg.add(Box::new(Sphere{
-                        o: Vec3{x:0.3,y:0.0,z:0.1},
-                        r: 0.2,
-                        mat: floor_mat
-                    })
-            );
-
-    sg.add(Box::new(Sphere{
-                o: Vec3{x:-0.2,y:-0.5,z:-0.8},
-                r: 0.2,                
-                mat: floor_mat
-            })
-    );
-
-
-    sg.add(Box::new(Sphere{
-                o: Vec3{x:1001.0,y:0.0,z:0.0},
-                r: 1000.,
-                
-                mat: green_mat
-            })
-    );
-
-    sg.add(Box::new(Sphere{
-            o: Vec3{x:-1001.,y:0.0,z:0.0},
-            r: 1000.0,
-            mat: red_mat
-        })
-    );
-    sg.add(Box::new(Sphere{
-            o: Vec3{x:0.0,y:0.0,z:1001.0},
-            r: 1000.0,
-                
-            mat: floor_mat
-        })
-    );
-    sg.add(Box::new(Sphere{
-            o: Vec3{x:0.0,y:0.0,z:-1001.0},
-            r: 1000.0,
-                
-            mat: floor_mat
-        })
-    );
-
-    sg.add(Box::new(Sphere{
-            o: Vec3{x:0.0,y:-1001.0,z:0.0},
-            r: 1000.0,
-                
-            mat: floor_mat
-        })
-    );
-
-    sg.add(Box::new(Sphere{
-        o: Vec3{x:0.6,y:0.0,z:7.05},
-        r: 6.06,
-        mat: emit
-    })
-);
-    
-
-    let mut c = CameraPerspective::new(
-        512,512,
-        1.0,
-        Vec3{x:0.0,y:1.0,z:0.0},
-        Vec3{x:0.0,y:-1.0,z:0.0},
-        Vec3{x:0.0,y:0.0,z:1.0},
-    );
-
-
-    let mut s = Scene{
-        primitive: sg,
-        camera: &mut c,
-        lights: Vec::new()
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (options, renderer) = match parse_args(&args).and_then(|o| make_renderer(&o).map(|r| (o, r))) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
     };
 
-    let renderer = PtRenderer::new(50);
-    renderer.render(&mut s, &String::from("lol3.png"));
-    /*
-
-    let mut s = Scene{
-        primitive: Box::new(sg),
-        camera: Box::new(Camera2D::new(256,256, &Vec3{x: 1.0,y: 1.0,z: 0.0}, &Vec3{x: 1.0,y: 1.,z: 0.})),
-        lights: Vec::new()
+    
 
+    let mut s = match scenes::by_name(&options.scene, 512).and_then(|b| b.build()) {
+        Ok((s, warnings)) => {
+            for w in warnings {
+                eprintln!("Warning: {}", w);
+            }
+            s
+        }
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
     };
 
-    let renderer = DummyRenderer{};
-    renderer.render(&mut s, &String::from("lol.png"));
-
-    println!("Hello world!");
-    */
-}
\ No newline at end of file
+    let mut control = RenderControl::new();
+    control.set_time_budget(options.time_budget);
+    control.set_target_samples(options.target_samples);
+    control.set_target_noise(options.target_noise);
+    control.set_progress(|message| println!("{}", message));
+    let cancelled = control.cancel_flag();
+    //the first Ctrl-C stops the render and keeps what it has, a second one
+    //kills the process
+    if let Err(e) = ctrlc::set_handler(move || {
+        if cancelled.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Stopping, press Ctrl-C again to quit");
+    }) {
+        eprintln!("Couldn't install the Ctrl-C handler: {}", e);
+    }
+    if let Err(e) = renderer.render(&mut s, &options.output, &control) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
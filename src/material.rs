@@ -1,17 +1,91 @@
 use crate::math::Vec3;
 use crate::primitive::Intersection;
+use crate::primitive::hash_floats;
 use crate::math::sample_hemisphere_cos;
 use crate::math::orthogonal;
 use crate::math::Mat3;
+use crate::math::reflect;
+use crate::math::refract;
+use crate::math::fresnel_dielectric;
+use crate::medium::Medium;
+use crate::medium::HomogeneousMedium;
+use crate::spectrum::illuminant_at;
+use crate::spectrum::blackbody;
+use crate::spectrum::blackbody_rgb;
 
 use std::f32::consts::PI;
 
-pub trait Material{
+pub trait Material: Send + Sync {
     fn eval(&self, it: &Intersection, wi: &Vec3) -> Vec3;
     fn sample(&self, it: &Intersection,r1:f32,r2:f32) -> (Vec3, f32);
     fn pdf(&self, it: &Intersection, sample: &Vec3)->f32;
 
     fn emit(&self, it: &Intersection) -> Vec3;
+
+    //emitted radiance at the wavelengths of a spectral path, or RGB without
+    fn emit_at(&self, it: &Intersection, lambdas: Option<&Vec3>) -> Vec3 {
+        illuminant_at(&self.emit(it), lambdas)
+    }
+
+    //The scattered direction depends on the wavelength (dispersion); the
+    //spectral renderer then only follows the hero wavelength and uses
+    //sample_at and eval_at instead of sample and eval.
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    fn sample_at(&self, it: &Intersection, _lambda: f32, r1: f32, r2: f32) -> (Vec3, f32) {
+        self.sample(it, r1, r2)
+    }
+
+    fn eval_at(&self, it: &Intersection, wi: &Vec3, _lambda: f32) -> Vec3 {
+        self.eval(it, wi)
+    }
+
+    //surfaces that only separate two media: rays go straight through them
+    fn is_interface(&self) -> bool {
+        false
+    }
+
+    //Perfectly specular materials: sample picks one of a few discrete
+    //directions, eval and pdf return the (equal) discrete weight and
+    //probability of the side wi is on, and lights are not sampled.
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    //medium behind the surface, for materials that define their own volume
+    fn interior(&self) -> Option<&dyn Medium> {
+        None
+    }
+
+    //Fraction of light scattered for the outgoing direction it.wo, estimated
+    //from stratified samples of eval/pdf. Used by debug renders.
+    fn albedo(&self, it: &Intersection) -> Vec3 {
+        let n = 4;
+        let mut sum = Vec3::new();
+        for i in 0..n {
+            for j in 0..n {
+                let (wi, pdf) = self.sample(it, (i as f32 + 0.5)/n as f32, (j as f32 + 0.5)/n as f32);
+                if pdf > 0.0 {
+                    sum = sum + (&self.eval(it, &wi)/pdf);
+                }
+            }
+        }
+        &sum/(n*n) as f32
+    }
+}
+
+//Id of a material for false colours and ID AOVs, the same in every run.
+//Made from its response at a reference point, so materials that behave
+//the same share it.
+pub fn material_id(mat: &dyn Material) -> usize {
+    let z = Vec3::xyz(0.0, 0.0, 1.0);
+    let it = Intersection{p: Vec3::new(), p_error: Vec3::new(), wo: z, n: z, uv: Vec3::new(), mat, object: 0, dpdt: Vec3::new(), interior: None};
+    let a = mat.albedo(&it);
+    let e = mat.emit(&it);
+    let flags = (mat.is_delta() as u32 | (mat.is_interface() as u32) << 1 | (mat.is_dispersive() as u32) << 2) as f32;
+    hash_floats(&[a.x, a.y, a.z, e.x, e.y, e.z, flags])
 }
 
 pub struct DummyMaterial{
@@ -19,16 +93,17 @@ pub struct DummyMaterial{
 }
 
 impl Material for DummyMaterial {    
-    fn eval(&self, it: &Intersection, wi: &Vec3) -> Vec3 { Vec3::new()}
-    fn sample(&self, it: &Intersection,r1:f32,r2:f32) -> (Vec3, f32) {(Vec3::new(),1.0)}
-    fn pdf(&self, it: &Intersection, sample: &Vec3)->f32 {1.0}
-    fn emit(&self, it: &Intersection) -> Vec3 {Vec3::new()}
+    fn eval(&self, _it: &Intersection, _wi: &Vec3) -> Vec3 { Vec3::new()}
+    fn sample(&self, _it: &Intersection,_r1:f32,_r2:f32) -> (Vec3, f32) {(Vec3::new(),1.0)}
+    fn pdf(&self, _it: &Intersection, _sample: &Vec3)->f32 {1.0}
+    fn emit(&self, _it: &Intersection) -> Vec3 {Vec3::new()}
 }
 
 pub struct DiffuseMaterial {
-    pub albedo: Vec3
+    pub(crate) albedo: Vec3
 }
 impl DiffuseMaterial {
+    #[allow(clippy::redundant_field_names, reason = "baseline code, kept as written")]
     pub fn new(albedo: Vec3)->DiffuseMaterial{
         DiffuseMaterial{albedo:albedo}
     }
@@ -36,6 +111,10 @@ impl DiffuseMaterial {
 
 impl Material for DiffuseMaterial {    
     fn eval(&self, it: &Intersection, wi: &Vec3) -> Vec3 {
+        //no transmission
+        if it.n.dot(wi)*it.n.dot(&it.wo) <= 0.0 {
+            return Vec3::new();
+        }
         self.albedo*(it.n.dot(wi).abs()/PI)
     }
     fn sample(&self, it: &Intersection, r1: f32, r2:f32) -> (Vec3, f32) {
@@ -46,29 +125,224 @@ impl Material for DiffuseMaterial {
         if n.dot(&it.wo) < 0.0 {
             n = it.n*(-1.0);
         }
-        let (x,y,z) = orthogonal(it.n);
+        let (x,y,z) = orthogonal(n);
         let coords = Mat3::from_axis(&y,&z,&x);
         sample = &coords*&sample;
 
         (sample, p)
     
     }
-    fn pdf(&self, it: &Intersection, sample: &Vec3)->f32 {return (sample.dot(&it.n)).abs()/PI}
-    fn emit(&self, it: &Intersection) -> Vec3 {Vec3::new()}
+    fn pdf(&self, it: &Intersection, sample: &Vec3)->f32 {
+        if it.n.dot(sample)*it.n.dot(&it.wo) <= 0.0 {
+            return 0.0;
+        }
+        (sample.dot(&it.n)).abs()/PI
+    }
+    fn emit(&self, _it: &Intersection) -> Vec3 {Vec3::new()}
+    fn albedo(&self, _it: &Intersection) -> Vec3 {self.albedo}
 
 }
 
 pub struct EmissionMaterial {
-    pub light: Vec3,
-    pub intensity: f32
+    pub(crate) light: Vec3,
+    pub(crate) intensity: f32
+}
+
+impl EmissionMaterial {
+    pub fn new(light: Vec3, intensity: f32) -> EmissionMaterial {
+        EmissionMaterial{light, intensity}
+    }
 }
 
 impl Material for EmissionMaterial {    
-    fn eval(&self, it: &Intersection, wi: &Vec3) -> Vec3 {
+    fn eval(&self, _it: &Intersection, _wi: &Vec3) -> Vec3 {
         Vec3::new()
     }
-    fn sample(&self, it: &Intersection,r1:f32,r2:f32) -> (Vec3, f32) {(Vec3::new(),-1.0)}
-    fn pdf(&self, it: &Intersection, sample: &Vec3)->f32 {-1.0}
-    fn emit(&self, it: &Intersection) -> Vec3 {self.light*self.intensity}
+    fn sample(&self, _it: &Intersection,_r1:f32,_r2:f32) -> (Vec3, f32) {(Vec3::new(),-1.0)}
+    fn pdf(&self, _it: &Intersection, _sample: &Vec3)->f32 {-1.0}
+    fn emit(&self, _it: &Intersection) -> Vec3 {self.light*self.intensity}
     
-}
\ No newline at end of file
+}
+
+//Emitter with the colour and brightness of a black body at the given
+//temperature in Kelvin (see blackbody_rgb), times scale
+pub struct BlackbodyMaterial {
+    pub(crate) temperature: f32,
+    pub(crate) scale: f32
+}
+
+impl BlackbodyMaterial {
+    pub fn new(temperature: f32, scale: f32) -> BlackbodyMaterial {
+        BlackbodyMaterial{temperature, scale}
+    }
+}
+
+impl Material for BlackbodyMaterial {
+    fn eval(&self, _it: &Intersection, _wi: &Vec3) -> Vec3 {Vec3::new()}
+    fn sample(&self, _it: &Intersection, _r1:f32, _r2:f32) -> (Vec3, f32) {(Vec3::new(),-1.0)}
+    fn pdf(&self, _it: &Intersection, _sample: &Vec3)->f32 {-1.0}
+    fn emit(&self, _it: &Intersection) -> Vec3 {blackbody_rgb(self.temperature)*self.scale}
+    fn emit_at(&self, it: &Intersection, lambdas: Option<&Vec3>) -> Vec3 {
+        match lambdas {
+            None => self.emit(it),
+            Some(l) => Vec3::xyz(blackbody(l.x, self.temperature), blackbody(l.y, self.temperature),
+                blackbody(l.z, self.temperature))*self.scale
+        }
+    }
+}
+
+//Invisible boundary of a medium, e.g. the surface of a fog volume
+pub struct InterfaceMaterial {
+}
+
+impl Material for InterfaceMaterial {
+    fn eval(&self, _it: &Intersection, _wi: &Vec3) -> Vec3 {Vec3::new()}
+    fn sample(&self, it: &Intersection, _r1:f32, _r2:f32) -> (Vec3, f32) {(it.wo*(-1.0), 1.0)}
+    fn pdf(&self, _it: &Intersection, _sample: &Vec3)->f32 {0.0}
+    fn emit(&self, _it: &Intersection) -> Vec3 {Vec3::new()}
+    fn is_interface(&self) -> bool {true}
+}
+
+
+//Wavelength dependent index of refraction, lambda in micrometers
+pub enum Dispersion {
+    //n = a + b/lambda^2
+    Cauchy(f32, f32),
+    //n^2 = 1 + sum b_i lambda^2/(lambda^2 - c_i)
+    Sellmeier([f32; 3], [f32; 3])
+}
+
+impl Dispersion {
+    //lambda in nanometers
+    pub fn ior(&self, lambda: f32) -> f32 {
+        let l2 = (lambda*1e-3)*(lambda*1e-3);
+        match self {
+            Dispersion::Cauchy(a, b) => a + b/l2,
+            Dispersion::Sellmeier(b, c) => {
+                let mut n2 = 1.0;
+                for i in 0..3 {
+                    n2 += b[i]*l2/(l2 - c[i]);
+                }
+                n2.max(1.0).sqrt()
+            }
+        }
+    }
+}
+
+//Smooth glass-like boundary: Fresnel weighted choice between mirror
+//reflection and refraction. The normal points towards the outside (ior 1).
+//With a dispersion model, ior is its value at 550nm, used in RGB mode.
+pub struct DielectricMaterial {
+    pub(crate) ior: f32,
+    pub(crate) dispersion: Option<Dispersion>
+}
+
+impl DielectricMaterial {
+    pub fn new(ior: f32) -> DielectricMaterial {
+        DielectricMaterial{ior, dispersion: None}
+    }
+
+    pub fn dispersive(dispersion: Dispersion) -> DielectricMaterial {
+        DielectricMaterial{ior: dispersion.ior(550.0), dispersion: Some(dispersion)}
+    }
+
+    //Schott N-BK7 crown glass
+    #[allow(clippy::excessive_precision, reason = "the catalogue's coefficients as published")]
+    pub fn bk7() -> DielectricMaterial {
+        DielectricMaterial::dispersive(Dispersion::Sellmeier(
+            [1.03961212, 0.231792344, 1.01046945],
+            [0.00600069867, 0.0200179144, 103.560653]))
+    }
+
+    fn ior_at(&self, lambda: f32) -> f32 {
+        match &self.dispersion {
+            Some(d) => d.ior(lambda),
+            None => self.ior
+        }
+    }
+
+    //reflectance, and the normal and relative ior on the side of wo
+    fn fresnel(&self, it: &Intersection, ior: f32) -> (f32, Vec3, f32) {
+        let cos_o = it.wo.dot(&it.n);
+        let (eta_i, eta_t, n) = if cos_o > 0.0 {
+            (1.0, ior, it.n)
+        } else {
+            (ior, 1.0, it.n*(-1.0))
+        };
+        (fresnel_dielectric(cos_o, eta_i, eta_t), n, eta_i/eta_t)
+    }
+
+    fn sample_ior(&self, it: &Intersection, ior: f32, r1: f32) -> (Vec3, f32) {
+        let (f, n, eta) = self.fresnel(it, ior);
+        if r1 < f {
+            return (reflect(&it.wo, &n), f);
+        }
+        match refract(&it.wo, &n, eta) {
+            Some(wi) => (wi, 1.0 - f),
+            None => (reflect(&it.wo, &n), 1.0)
+        }
+    }
+
+    fn pdf_ior(&self, it: &Intersection, wi: &Vec3, ior: f32) -> f32 {
+        let (f, _, _) = self.fresnel(it, ior);
+        if wi.dot(&it.n)*it.wo.dot(&it.n) > 0.0 {f} else {1.0 - f}
+    }
+}
+
+impl Material for DielectricMaterial {
+    fn eval(&self, it: &Intersection, wi: &Vec3) -> Vec3 {
+        Vec3::from(self.pdf(it, wi))
+    }
+    fn sample(&self, it: &Intersection, r1: f32, _r2: f32) -> (Vec3, f32) {
+        self.sample_ior(it, self.ior, r1)
+    }
+    fn pdf(&self, it: &Intersection, wi: &Vec3)->f32 {
+        self.pdf_ior(it, wi, self.ior)
+    }
+    fn emit(&self, _it: &Intersection) -> Vec3 {Vec3::new()}
+    fn is_delta(&self) -> bool {true}
+    fn is_dispersive(&self) -> bool {self.dispersion.is_some()}
+    fn sample_at(&self, it: &Intersection, lambda: f32, r1: f32, _r2: f32) -> (Vec3, f32) {
+        self.sample_ior(it, self.ior_at(lambda), r1)
+    }
+    fn eval_at(&self, it: &Intersection, wi: &Vec3, lambda: f32) -> Vec3 {
+        Vec3::from(self.pdf_ior(it, wi, self.ior_at(lambda)))
+    }
+}
+
+
+//Random walk subsurface scattering: a dielectric boundary around a dense
+//homogeneous medium. albedo is the resulting surface colour and mfp the mean
+//free path per channel, in scene units. With ior 1 the boundary is index
+//matched, which lets shadow rays through to the inside.
+pub struct SubsurfaceMaterial {
+    boundary: DielectricMaterial,
+    medium: HomogeneousMedium
+}
+
+impl SubsurfaceMaterial {
+    pub fn new(albedo: Vec3, mfp: Vec3, ior: f32, g: f32) -> SubsurfaceMaterial {
+        //multiple scattering albedo to single scattering albedo (van de Hulst)
+        let single = |a: f32| {
+            let a = a.clamp(0.0, 0.999);
+            let x = 4.09712 + 4.20863*a - (9.59217 + 41.6808*a + 17.7126*a*a).sqrt();
+            1.0 - x*x
+        };
+        let sigma_t = Vec3::xyz(1.0/mfp.x, 1.0/mfp.y, 1.0/mfp.z);
+        let sigma_s = &sigma_t*&Vec3::xyz(single(albedo.x), single(albedo.y), single(albedo.z));
+        SubsurfaceMaterial{
+            boundary: DielectricMaterial::new(ior),
+            medium: HomogeneousMedium::new(sigma_t - sigma_s, sigma_s, g)
+        }
+    }
+}
+
+impl Material for SubsurfaceMaterial {
+    fn eval(&self, it: &Intersection, wi: &Vec3) -> Vec3 {self.boundary.eval(it, wi)}
+    fn sample(&self, it: &Intersection, r1: f32, r2: f32) -> (Vec3, f32) {self.boundary.sample(it, r1, r2)}
+    fn pdf(&self, it: &Intersection, wi: &Vec3)->f32 {self.boundary.pdf(it, wi)}
+    fn emit(&self, _it: &Intersection) -> Vec3 {Vec3::new()}
+    fn is_interface(&self) -> bool {self.boundary.ior == 1.0}
+    fn is_delta(&self) -> bool {true}
+    fn interior(&self) -> Option<&dyn Medium> {Some(&self.medium)}
+}
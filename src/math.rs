@@ -1,9 +1,11 @@
 use std::f32::consts::PI;
 
+#[allow(clippy::needless_return, reason = "baseline code, kept as written")]
 pub fn float2u8(a: f32) -> u8 {
     return (clamp(a,0.0,0.9999)*255.0) as u8;
 }
 
+#[allow(clippy::needless_return, reason = "baseline code, kept as written")]
 pub fn clamp<T: PartialOrd>(x: T, a: T, b: T) -> T {
     if x<a {
         return a;
@@ -21,10 +23,17 @@ pub struct Vec3 {
     pub z : f32
 }
 
+impl Default for Vec3 {
+    fn default() -> Vec3 {
+        Vec3::new()
+    }
+}
+
 impl Vec3{
     pub fn new() ->Vec3 {
         Vec3{x:0.,y:0.,z:0.}
     }
+    #[allow(clippy::redundant_field_names, reason = "baseline code, kept as written")]
     pub fn xyz(x:f32,y:f32,z:f32) ->Vec3 {
         Vec3{x:x,y:y,z:z}
     }
@@ -56,6 +65,26 @@ impl Vec3{
     pub fn normalized(&self) -> Vec3 {
         self/self.len()
     }
+
+    pub fn get(&self, i: usize) -> f32 {
+        match i {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z
+        }
+    }
+
+    pub fn exp(&self) -> Vec3 {
+        Vec3{x: self.x.exp(), y: self.y.exp(), z: self.z.exp()}
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.x == 0.0 && self.y == 0.0 && self.z == 0.0
+    }
+
+    pub fn abs(&self) -> Vec3 {
+        Vec3{x: self.x.abs(), y: self.y.abs(), z: self.z.abs()}
+    }
 }
 
 impl std::ops::Add for &Vec3{
@@ -136,6 +165,7 @@ impl std::ops::Div<f32> for &Vec3 {
 }
 
 
+#[derive(Copy,Clone)]
 pub struct Mat3{
     values: [f32;9]
 }
@@ -159,15 +189,23 @@ impl Mat3{
         }
     }
 
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
     pub fn at(&mut self, a: usize, b: usize) -> &mut f32{
         return &mut self.values[a*3+b];
     }
 
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
     pub fn at_ro(&self, a: usize, b: usize) -> &f32{
         return &self.values[a*3+b];
     }
 
-    pub fn inverse(&self) -> Mat3{
+    pub fn determinant(&self) -> f32{
+        let v = &self.values;
+        v[0]*(v[4]*v[8]-v[5]*v[7]) - v[1]*(v[3]*v[8]-v[5]*v[6]) + v[2]*(v[3]*v[7]-v[4]*v[6])
+    }
+
+    //None if the matrix is singular
+    pub fn inverse(&self) -> Option<Mat3>{
         let a = self.values[0];
         let b = self.values[1];
         let c = self.values[2];
@@ -179,15 +217,21 @@ impl Mat3{
         let i = self.values[8];
 
         let det = a*e*i+d*h*c+b*f*g - c*e*g-d*b*i-a*f*h;
+        //|det| is at most the product of the column lengths, with equality
+        //for orthogonal columns, so the ratio doesn't depend on the scale
+        let columns = (a*a+d*d+g*g).sqrt()*(b*b+e*e+h*h).sqrt()*(c*c+f*f+i*i).sqrt();
+        if det.abs() <= columns*f32::EPSILON {
+            return None;
+        }
 
         
-        Mat3{
+        Some(Mat3{
             values:[
                 (e*i-f*h), -(b*i-c*h),  (b*f-c*e),
                -(d*i-f*g),  (a*i-c*g), -(a*f-c*d),
                 (d*h-e*g), -(a*h-b*g),  (a*e-b*d)
             ]
-        }/det
+        }/det)
     }
 
     pub fn transpose(&self) -> Mat3 {
@@ -321,6 +365,474 @@ impl std::ops::Div<f32> for Mat3 {
     }
 }
 
+//row-major 4x4 matrix, acting on column vectors
+#[derive(Copy,Clone)]
+pub struct Mat4{
+    values: [f32;16]
+}
+
+impl Mat4{
+    pub fn identity() -> Mat4{
+        Mat4{
+            values: [1.0,0.0,0.0,0.0,
+                     0.0,1.0,0.0,0.0,
+                     0.0,0.0,1.0,0.0,
+                     0.0,0.0,0.0,1.0]
+        }
+    }
+
+    pub fn from_rows(values: [f32;16]) -> Mat4{
+        Mat4{values}
+    }
+
+    //embeds a linear map and a translation
+    pub fn from_mat3(m: &Mat3, t: &Vec3) -> Mat4{
+        let v = &m.values;
+        Mat4::from_rows([v[0],v[1],v[2],t.x,
+                         v[3],v[4],v[5],t.y,
+                         v[6],v[7],v[8],t.z,
+                         0.0,0.0,0.0,1.0])
+    }
+
+    //upper-left 3x3 block
+    pub fn linear(&self) -> Mat3{
+        let v = &self.values;
+        Mat3{values: [v[0],v[1],v[2],
+                      v[4],v[5],v[6],
+                      v[8],v[9],v[10]]}
+    }
+
+    pub fn at(&mut self, a: usize, b: usize) -> &mut f32{
+        &mut self.values[a*4+b]
+    }
+
+    pub fn at_ro(&self, a: usize, b: usize) -> &f32{
+        &self.values[a*4+b]
+    }
+
+    pub fn transpose(&self) -> Mat4 {
+        let mut m = Mat4::identity();
+        for i in 0..4 {
+            for j in 0..4 {
+                m.values[i*4+j] = self.values[j*4+i];
+            }
+        }
+        m
+    }
+
+    //Gauss-Jordan elimination with partial pivoting; None if the matrix is singular
+    pub fn inverse(&self) -> Option<Mat4>{
+        let mut a = self.values;
+        let mut inv = Mat4::identity().values;
+        //pivots are compared to the size of their column, which scaling
+        //the matrix doesn't change relative to each other
+        let mut scale = [0.0f32; 4];
+        for (k, v) in a.iter().enumerate() {
+            scale[k%4] = scale[k%4].max(v.abs());
+        }
+        for c in 0..4 {
+            let mut pivot = c;
+            for r in c+1..4 {
+                if a[r*4+c].abs() > a[pivot*4+c].abs() {
+                    pivot = r;
+                }
+            }
+            if a[pivot*4+c].abs() <= scale[c]*f32::EPSILON {
+                return None;
+            }
+            for k in 0..4 {
+                a.swap(c*4+k, pivot*4+k);
+                inv.swap(c*4+k, pivot*4+k);
+            }
+            let d = 1.0/a[c*4+c];
+            for k in 0..4 {
+                a[c*4+k] *= d;
+                inv[c*4+k] *= d;
+            }
+            for r in 0..4 {
+                if r != c {
+                    let f = a[r*4+c];
+                    for k in 0..4 {
+                        a[r*4+k] -= f*a[c*4+k];
+                        inv[r*4+k] -= f*inv[c*4+k];
+                    }
+                }
+            }
+        }
+        Some(Mat4{values: inv})
+    }
+
+    //applies the matrix to (p, 1) and divides by w
+    pub fn mul_point(&self, p: &Vec3) -> Vec3 {
+        let v = &self.values;
+        let x = v[0]*p.x + v[1]*p.y + v[2]*p.z + v[3];
+        let y = v[4]*p.x + v[5]*p.y + v[6]*p.z + v[7];
+        let z = v[8]*p.x + v[9]*p.y + v[10]*p.z + v[11];
+        let w = v[12]*p.x + v[13]*p.y + v[14]*p.z + v[15];
+        if w == 1.0 {
+            Vec3::xyz(x,y,z)
+        } else {
+            Vec3::xyz(x/w,y/w,z/w)
+        }
+    }
+
+    //applies the matrix to (d, 0)
+    pub fn mul_vector(&self, d: &Vec3) -> Vec3 {
+        let v = &self.values;
+        Vec3::xyz(v[0]*d.x + v[1]*d.y + v[2]*d.z,
+                  v[4]*d.x + v[5]*d.y + v[6]*d.z,
+                  v[8]*d.x + v[9]*d.y + v[10]*d.z)
+    }
+}
+
+impl std::ops::Mul for &Mat4 {
+
+    type Output = Mat4;
+    fn mul(self, rhs: &Mat4) -> Mat4{
+        let mut m = Mat4{values: [0.0;16]};
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    m.values[i*4+j] += self.values[i*4+k]*rhs.values[k*4+j];
+                }
+            }
+        }
+        m
+    }
+}
+
+
+//affine transform with its inverse cached
+#[derive(Copy,Clone)]
+pub struct Transform{
+    m: Mat4,
+    m_inv: Mat4
+}
+
+impl Transform{
+    pub fn identity() -> Transform{
+        Transform{m: Mat4::identity(), m_inv: Mat4::identity()}
+    }
+
+    //None if the matrix is not invertible
+    pub fn from_matrix(m: Mat4) -> Option<Transform>{
+        let m_inv = m.inverse()?;
+        Some(Transform{m, m_inv})
+    }
+
+    pub fn translate(t: Vec3) -> Transform{
+        Transform{
+            m: Mat4::from_rows([1.0,0.0,0.0,t.x,
+                                0.0,1.0,0.0,t.y,
+                                0.0,0.0,1.0,t.z,
+                                0.0,0.0,0.0,1.0]),
+            m_inv: Mat4::from_rows([1.0,0.0,0.0,-t.x,
+                                    0.0,1.0,0.0,-t.y,
+                                    0.0,0.0,1.0,-t.z,
+                                    0.0,0.0,0.0,1.0])
+        }
+    }
+
+    pub fn scale(s: Vec3) -> Transform{
+        Transform{
+            m: Mat4::from_rows([s.x,0.0,0.0,0.0,
+                                0.0,s.y,0.0,0.0,
+                                0.0,0.0,s.z,0.0,
+                                0.0,0.0,0.0,1.0]),
+            m_inv: Mat4::from_rows([1.0/s.x,0.0,0.0,0.0,
+                                    0.0,1.0/s.y,0.0,0.0,
+                                    0.0,0.0,1.0/s.z,0.0,
+                                    0.0,0.0,0.0,1.0])
+        }
+    }
+
+    //rotation by angle (radians) around axis, counter-clockwise when looking down the axis
+    pub fn rotate(axis: Vec3, angle: f32) -> Transform{
+        let a = axis.normalized();
+        let (sin, cos) = angle.sin_cos();
+        let r = Mat3{
+            values: [
+                cos + a.x*a.x*(1.0-cos),     a.x*a.y*(1.0-cos) - a.z*sin, a.x*a.z*(1.0-cos) + a.y*sin,
+                a.y*a.x*(1.0-cos) + a.z*sin, cos + a.y*a.y*(1.0-cos),     a.y*a.z*(1.0-cos) - a.x*sin,
+                a.z*a.x*(1.0-cos) - a.y*sin, a.z*a.y*(1.0-cos) + a.x*sin, cos + a.z*a.z*(1.0-cos)
+            ]
+        };
+        Transform{
+            m: Mat4::from_mat3(&r, &Vec3::new()),
+            m_inv: Mat4::from_mat3(&r.transpose(), &Vec3::new())
+        }
+    }
+
+    //World to camera transform for a camera at eye looking at target, with the
+    //camera looking down -z (as CameraPerspective). up must not be parallel to
+    //the view direction.
+    pub fn look_at(eye: Vec3, target: Vec3, up: Vec3) -> Transform{
+        let z = (eye - target).normalized();
+        let x = up.cross(&z).normalized();
+        let y = z.cross(&x);
+        let r = Mat3::from_axis(&x,&y,&z);
+        let camera_to_world = Mat4::from_mat3(&r, &eye);
+        let rt = r.transpose();
+        let world_to_camera = Mat4::from_mat3(&rt, &((&rt*&eye)*(-1.0)));
+        Transform{m: world_to_camera, m_inv: camera_to_world}
+    }
+
+    //Projection from camera space (looking down -z) to normalized device
+    //coordinates: x and y in [-1,1] inside the field of view, z from 0 at the
+    //near plane to 1 at the far plane. fovy is in radians.
+    pub fn perspective(fovy: f32, near: f32, far: f32) -> Transform{
+        let cot = 1.0/(fovy*0.5).tan();
+        let m = Mat4::from_rows([cot,0.0,0.0,0.0,
+                                 0.0,cot,0.0,0.0,
+                                 0.0,0.0,-far/(far-near),-far*near/(far-near),
+                                 0.0,0.0,-1.0,0.0]);
+        Transform::from_matrix(m).expect("perspective: near and far planes must differ")
+    }
+
+    //linear part only, e.g. a scale or shear from a decomposition
+    pub fn from_linear(m: &Mat3) -> Option<Transform>{
+        let inv = m.inverse()?;
+        Some(Transform{m: Mat4::from_mat3(m, &Vec3::new()), m_inv: Mat4::from_mat3(&inv, &Vec3::new())})
+    }
+
+    pub fn matrix(&self) -> &Mat4{
+        &self.m
+    }
+
+    pub fn inverse_matrix(&self) -> &Mat4{
+        &self.m_inv
+    }
+
+    //Splits the transform into translation, rotation and the remaining
+    //scale/shear, so that M = T*R*S (polar decomposition, as in PBRT)
+    pub fn decompose(&self) -> (Vec3, Quaternion, Mat3){
+        let t = Vec3::xyz(self.m.values[3], self.m.values[7], self.m.values[11]);
+        let m = self.m.linear();
+
+        let mut r = m;
+        for _ in 0..100 {
+            let rit = match r.transpose().inverse() {
+                Some(x) => x,
+                None => break
+            };
+            let next = &(&r + &rit)*0.5;
+            let mut norm: f32 = 0.0;
+            for i in 0..9 {
+                norm = norm.max((r.values[i] - next.values[i]).abs());
+            }
+            r = next;
+            if norm < 1e-6 {
+                break;
+            }
+        }
+        //keep reflections in the scale part so that r is a proper rotation
+        if r.determinant() < 0.0 {
+            r = r*(-1.0);
+        }
+        let scale = match r.inverse() {
+            Some(r_inv) => &r_inv*&m,
+            None => m
+        };
+        (t, Quaternion::from_mat3(&r), scale)
+    }
+
+    pub fn inverse(&self) -> Transform{
+        Transform{m: self.m_inv, m_inv: self.m}
+    }
+
+    pub fn point(&self, p: &Vec3) -> Vec3{
+        self.m.mul_point(p)
+    }
+
+    pub fn vector(&self, v: &Vec3) -> Vec3{
+        self.m.mul_vector(v)
+    }
+
+    //normals transform with the inverse transpose; the result is not normalized
+    pub fn normal(&self, n: &Vec3) -> Vec3{
+        self.m_inv.transpose().mul_vector(n)
+    }
+
+    //transforms a point known up to p_error, returning the point and a bound
+    //on its error afterwards, from the rounding of the affine transform and
+    //the error it already had (PBRT 3.9.4)
+    pub fn point_with_error(&self, p: &Vec3, p_error: &Vec3) -> (Vec3, Vec3){
+        let v = &self.m.values;
+        let row = |i: usize| {
+            let rounding = (v[i*4]*p.x).abs() + (v[i*4+1]*p.y).abs() + (v[i*4+2]*p.z).abs() + v[i*4+3].abs();
+            let carried = v[i*4].abs()*p_error.x + v[i*4+1].abs()*p_error.y + v[i*4+2].abs()*p_error.z;
+            gamma(3)*rounding + (1.0 + gamma(3))*carried
+        };
+        (self.point(p), Vec3::xyz(row(0), row(1), row(2)))
+    }
+}
+
+impl std::ops::Mul for &Transform {
+
+    type Output = Transform;
+    //applies rhs first, then self
+    fn mul(self, rhs: &Transform) -> Transform{
+        Transform{m: &self.m*&rhs.m, m_inv: &rhs.m_inv*&self.m_inv}
+    }
+}
+
+
+#[derive(Copy,Clone)]
+pub struct Quaternion{
+    pub v: Vec3,
+    pub w: f32
+}
+
+impl Quaternion{
+    pub fn identity() -> Quaternion{
+        Quaternion{v: Vec3::new(), w: 1.0}
+    }
+
+    pub fn from_axis_angle(axis: Vec3, angle: f32) -> Quaternion{
+        let (sin, cos) = (angle*0.5).sin_cos();
+        Quaternion{v: axis.normalized()*sin, w: cos}
+    }
+
+    //m must be a rotation matrix
+    pub fn from_mat3(m: &Mat3) -> Quaternion{
+        let a = |i: usize, j: usize| m.values[i*3+j];
+        let trace = a(0,0) + a(1,1) + a(2,2);
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt()*2.0;
+            Quaternion{v: Vec3::xyz((a(2,1)-a(1,2))/s, (a(0,2)-a(2,0))/s, (a(1,0)-a(0,1))/s), w: 0.25*s}
+        } else if a(0,0) > a(1,1) && a(0,0) > a(2,2) {
+            let s = (1.0 + a(0,0) - a(1,1) - a(2,2)).sqrt()*2.0;
+            Quaternion{v: Vec3::xyz(0.25*s, (a(0,1)+a(1,0))/s, (a(0,2)+a(2,0))/s), w: (a(2,1)-a(1,2))/s}
+        } else if a(1,1) > a(2,2) {
+            let s = (1.0 + a(1,1) - a(0,0) - a(2,2)).sqrt()*2.0;
+            Quaternion{v: Vec3::xyz((a(0,1)+a(1,0))/s, 0.25*s, (a(1,2)+a(2,1))/s), w: (a(0,2)-a(2,0))/s}
+        } else {
+            let s = (1.0 + a(2,2) - a(0,0) - a(1,1)).sqrt()*2.0;
+            Quaternion{v: Vec3::xyz((a(0,2)+a(2,0))/s, (a(1,2)+a(2,1))/s, 0.25*s), w: (a(1,0)-a(0,1))/s}
+        };
+        q.normalized()
+    }
+
+    pub fn dot(&self, rhs: &Quaternion) -> f32{
+        self.v.dot(&rhs.v) + self.w*rhs.w
+    }
+
+    pub fn normalized(&self) -> Quaternion{
+        let l = self.dot(self).sqrt();
+        Quaternion{v: &self.v/l, w: self.w/l}
+    }
+
+    pub fn to_mat3(&self) -> Mat3{
+        let (x, y, z, w) = (self.v.x, self.v.y, self.v.z, self.w);
+        Mat3{
+            values: [
+                1.0-2.0*(y*y+z*z), 2.0*(x*y-w*z),     2.0*(x*z+w*y),
+                2.0*(x*y+w*z),     1.0-2.0*(x*x+z*z), 2.0*(y*z-w*x),
+                2.0*(x*z-w*y),     2.0*(y*z+w*x),     1.0-2.0*(x*x+y*y)
+            ]
+        }
+    }
+
+    pub fn to_transform(&self) -> Transform{
+        let r = self.to_mat3();
+        Transform{
+            m: Mat4::from_mat3(&r, &Vec3::new()),
+            m_inv: Mat4::from_mat3(&r.transpose(), &Vec3::new())
+        }
+    }
+
+    //spherical interpolation along the shortest arc
+    pub fn slerp(&self, rhs: &Quaternion, t: f32) -> Quaternion{
+        let mut cos_theta = self.dot(rhs);
+        let mut q2 = *rhs;
+        if cos_theta < 0.0 {
+            q2 = Quaternion{v: rhs.v*(-1.0), w: -rhs.w};
+            cos_theta = -cos_theta;
+        }
+        if cos_theta > 0.9995 {
+            return Quaternion{v: lerp(&self.v, &q2.v, t), w: self.w*(1.0-t) + q2.w*t}.normalized();
+        }
+        let theta = clamp(cos_theta, -1.0, 1.0).acos()*t;
+        let perp = Quaternion{v: q2.v - (self.v*cos_theta), w: q2.w - self.w*cos_theta}.normalized();
+        let (sin, cos) = theta.sin_cos();
+        Quaternion{v: (self.v*cos) + (perp.v*sin), w: self.w*cos + perp.w*sin}
+    }
+}
+
+impl std::ops::Mul for &Quaternion {
+
+    type Output = Quaternion;
+    //Hamilton product: rotating by rhs, then by self
+    fn mul(self, rhs: &Quaternion) -> Quaternion{
+        Quaternion{
+            v: ((rhs.v*self.w) + (self.v*rhs.w)) + self.v.cross(&rhs.v),
+            w: self.w*rhs.w - self.v.dot(&rhs.v)
+        }
+    }
+}
+
+
+//Transform interpolated between two keyframes. The keyframes are decomposed
+//into translation, rotation and scale, which are interpolated separately
+//(rotation with slerp) so that rotating objects do not shear.
+#[derive(Copy,Clone)]
+pub struct AnimatedTransform{
+    start: Transform,
+    end: Transform,
+    t0: f32,
+    t1: f32,
+    parts: Option<[(Vec3, Quaternion, Mat3);2]>
+}
+
+impl AnimatedTransform{
+    pub fn fixed(t: Transform) -> AnimatedTransform{
+        AnimatedTransform{start: t, end: t, t0: 0.0, t1: 0.0, parts: None}
+    }
+
+    pub fn new(start: Transform, t0: f32, end: Transform, t1: f32) -> AnimatedTransform{
+        if t1 <= t0 {
+            return AnimatedTransform::fixed(start);
+        }
+        AnimatedTransform{start, end, t0, t1,
+                          parts: Some([start.decompose(), end.decompose()])}
+    }
+
+    pub fn is_animated(&self) -> bool{
+        self.parts.is_some()
+    }
+
+    pub fn time_range(&self) -> (f32, f32){
+        (self.t0, self.t1)
+    }
+
+    pub fn interpolate(&self, time: f32) -> Transform{
+        let parts = match &self.parts {
+            None => {return self.start;}
+            Some(p) => p
+        };
+        if time <= self.t0 {
+            return self.start;
+        }
+        if time >= self.t1 {
+            return self.end;
+        }
+        let a = (time - self.t0)/(self.t1 - self.t0);
+        let (t0, r0, s0) = &parts[0];
+        let (t1, r1, s1) = &parts[1];
+        let s = &(s0*(1.0-a)) + &(s1*a);
+        let scale = match Transform::from_linear(&s) {
+            Some(x) => x,
+            None => {return if a < 0.5 {self.start} else {self.end};}
+        };
+        &(&Transform::translate(lerp(t0, t1, a))*&r0.slerp(r1, a).to_transform())*&scale
+    }
+}
+
+pub fn lerp(a: &Vec3, b: &Vec3, t: f32) -> Vec3 {
+    (a*(1.0-t)) + (b*t)
+}
+
+#[allow(clippy::needless_return, reason = "baseline code, kept as written")]
 pub fn solve_quadratic(a : f32,b: f32,c: f32) -> Option<[f32;2]>{
     let mut det = b*b - 4.0*a*c;
     if det<0.0{
@@ -332,6 +844,211 @@ pub fn solve_quadratic(a : f32,b: f32,c: f32) -> Option<[f32;2]>{
 
 }
 
+//Bound on the relative error of n rounded floating point operations
+//in a row, (1+e)^n-1 <= gamma(n) with e half the machine epsilon
+#[inline]
+pub fn gamma(n: i32) -> f32 {
+    let e = f32::EPSILON*0.5;
+    (n as f32*e)/(1.0 - n as f32*e)
+}
+
+//smallest float greater than v
+#[inline]
+pub fn next_float_up(v: f32) -> f32 {
+    if v.is_infinite() && v > 0.0 {
+        return v;
+    }
+    let v = if v == -0.0 {0.0} else {v};
+    let bits = v.to_bits();
+    f32::from_bits(if v >= 0.0 {bits + 1} else {bits - 1})
+}
+
+//largest float less than v
+#[inline]
+pub fn next_float_down(v: f32) -> f32 {
+    if v.is_infinite() && v < 0.0 {
+        return v;
+    }
+    let v = if v == 0.0 {-0.0} else {v};
+    let bits = v.to_bits();
+    f32::from_bits(if v > 0.0 {bits - 1} else {bits + 1})
+}
+
+//A float together with an interval that certainly contains the exact
+//value it approximates. Arithmetic widens the interval by the rounding of
+//each operation, so it can be used to bound the error of a computation
+//such as a ray's parametric hit distance (PBRT 3.9.3).
+#[derive(Copy,Clone,Debug)]
+pub struct EFloat {
+    pub v: f32,
+    pub low: f32,
+    pub high: f32
+}
+
+impl EFloat {
+    //exact value
+    #[inline]
+    pub fn new(v: f32) -> EFloat {
+        EFloat{v, low: v, high: v}
+    }
+
+    //value off by at most err
+    #[inline]
+    pub fn with_error(v: f32, err: f32) -> EFloat {
+        if err == 0.0 {
+            return EFloat::new(v);
+        }
+        EFloat{v, low: next_float_down(v - err), high: next_float_up(v + err)}
+    }
+
+    #[inline]
+    pub fn sqrt(&self) -> EFloat {
+        EFloat{v: self.v.sqrt(), low: next_float_down(self.low.max(0.0).sqrt()), high: next_float_up(self.high.sqrt())}
+    }
+
+    //from the four products of the bounds of both intervals
+    #[inline]
+    fn span(v: f32, p: [f32; 4]) -> EFloat {
+        EFloat{
+            v,
+            low: next_float_down(p[0].min(p[1]).min(p[2].min(p[3]))),
+            high: next_float_up(p[0].max(p[1]).max(p[2].max(p[3])))
+        }
+    }
+}
+
+impl std::ops::Add for EFloat {
+    type Output = EFloat;
+    #[inline]
+    fn add(self, rhs: EFloat) -> EFloat {
+        EFloat{v: self.v + rhs.v, low: next_float_down(self.low + rhs.low), high: next_float_up(self.high + rhs.high)}
+    }
+}
+
+impl std::ops::Sub for EFloat {
+    type Output = EFloat;
+    #[inline]
+    fn sub(self, rhs: EFloat) -> EFloat {
+        EFloat{v: self.v - rhs.v, low: next_float_down(self.low - rhs.high), high: next_float_up(self.high - rhs.low)}
+    }
+}
+
+impl std::ops::Mul for EFloat {
+    type Output = EFloat;
+    #[inline]
+    fn mul(self, rhs: EFloat) -> EFloat {
+        EFloat::span(self.v*rhs.v, [self.low*rhs.low, self.high*rhs.low, self.low*rhs.high, self.high*rhs.high])
+    }
+}
+
+impl std::ops::Mul<f32> for EFloat {
+    type Output = EFloat;
+    #[inline]
+    fn mul(self, rhs: f32) -> EFloat {
+        self*EFloat::new(rhs)
+    }
+}
+
+impl std::ops::Div for EFloat {
+    type Output = EFloat;
+    //unbounded if rhs may be zero
+    #[inline]
+    fn div(self, rhs: EFloat) -> EFloat {
+        if rhs.low < 0.0 && rhs.high > 0.0 {
+            return EFloat{v: self.v/rhs.v, low: f32::NEG_INFINITY, high: f32::INFINITY};
+        }
+        EFloat::span(self.v/rhs.v, [self.low/rhs.low, self.high/rhs.low, self.low/rhs.high, self.high/rhs.high])
+    }
+}
+
+//Real roots of at^2+bt+c in increasing order, or of bt+c if a is zero.
+//In f64 for shapes whose hit points should be far more accurate than f32.
+pub fn solve_quadratic_f64(a: f64, b: f64, c: f64) -> Vec<f64> {
+    let mut roots = Vec::new();
+    if a == 0.0 {
+        if b != 0.0 {
+            roots.push(-c/b);
+        }
+        return roots;
+    }
+    solve_monic_quadratic(b/a, c/a, &mut roots);
+    roots.sort_by(|x, y| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal));
+    roots
+}
+
+//real roots of t^2+bt+c
+fn solve_monic_quadratic(b: f64, c: f64, roots: &mut Vec<f64>) {
+    let discrim = b*b - 4.0*c;
+    if discrim < 0.0 {
+        return;
+    }
+    let q = if b < 0.0 {-0.5*(b - discrim.sqrt())} else {-0.5*(b + discrim.sqrt())};
+    if q == 0.0 {
+        roots.push(0.0);
+        return;
+    }
+    roots.push(q);
+    roots.push(c/q);
+}
+
+//largest real root of t^3+at^2+bt+c, by Cardano's formula
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    //t = y - a/3 gives y^3+py+q
+    let p = b/3.0 - a*a/9.0;
+    let q = a*a*a/27.0 - a*b/6.0 + c*0.5;
+    let d = q*q + p*p*p;
+    let y = if d < 0.0 {
+        //three real roots, the largest with the smallest angle
+        let phi = (-q/(-p*p*p).sqrt()).clamp(-1.0, 1.0).acos()/3.0;
+        2.0*(-p).sqrt()*phi.cos()
+    } else {
+        let s = d.sqrt();
+        (s - q).cbrt() - (s + q).cbrt()
+    };
+    y - a/3.0
+}
+
+//Real roots of c[4]t^4+c[3]t^3+c[2]t^2+c[1]t+c[0] in increasing order, by
+//Ferrari's method, each polished with a few Newton steps and then checked
+pub fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    let mut roots = Vec::new();
+    if c[4] == 0.0 {
+        return roots;
+    }
+    let (a, b, cc, d) = (c[3]/c[4], c[2]/c[4], c[1]/c[4], c[0]/c[4]);
+    //t = y - a/4 gives y^4+py^2+qy+r
+    let p = b - 3.0/8.0*a*a;
+    let q = a*a*a/8.0 - a*b/2.0 + cc;
+    let r = -3.0/256.0*a*a*a*a + a*a*b/16.0 - a*cc/4.0 + d;
+    //(y^2+z)^2 = (2z-p)y^2 - qy + z^2-r, where the largest root of the
+    //resolvent cubic makes the right side a square
+    let z = largest_cubic_root(-0.5*p, -r, 0.5*r*p - q*q/8.0);
+    let u = (z*z - r).max(0.0).sqrt();
+    let v = (2.0*z - p).max(0.0).sqrt();
+    let v = if q < 0.0 {-v} else {v};
+    solve_monic_quadratic(v, z - u, &mut roots);
+    solve_monic_quadratic(-v, z + u, &mut roots);
+
+    let f = |t: f64| (((c[4]*t + c[3])*t + c[2])*t + c[1])*t + c[0];
+    let df = |t: f64| ((4.0*c[4]*t + 3.0*c[3])*t + 2.0*c[2])*t + c[1];
+    for t in roots.iter_mut() {
+        *t -= a/4.0;
+        for _ in 0..3 {
+            let slope = df(*t);
+            if slope == 0.0 {
+                break;
+            }
+            *t -= f(*t)/slope;
+        }
+    }
+    //clamping u and v above can turn complex roots into false real ones
+    let size = |t: f64| (((c[4].abs()*t.abs() + c[3].abs())*t.abs() + c[2].abs())*t.abs() + c[1].abs())*t.abs() + c[0].abs();
+    roots.retain(|&t| f(t).abs() <= 1e-9*size(t));
+    roots.sort_by(|x, y| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal));
+    roots
+}
+
+#[allow(clippy::needless_return, reason = "baseline code, kept as written")]
 pub fn sample_hemisphere_cos(r1: f32, r2: f32) -> Vec3 {
     let theta = r1*2.0*PI;
     let sin_phi = r2.sqrt();
@@ -341,7 +1058,23 @@ pub fn sample_hemisphere_cos(r1: f32, r2: f32) -> Vec3 {
 
 }
 
+//maps the unit square to the unit disk, preserving relative areas (Shirley-Chiu)
+pub fn sample_disk_concentric(r1: f32, r2: f32) -> (f32, f32) {
+    let a = 2.0*r1 - 1.0;
+    let b = 2.0*r2 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI/4.0*(b/a))
+    } else {
+        (b, PI/2.0 - PI/4.0*(a/b))
+    };
+    (r*theta.cos(), r*theta.sin())
+}
+
 //gets an orthogonal system -- function inpired PBRT
+#[allow(clippy::needless_return, reason = "baseline code, kept as written")]
 pub fn orthogonal(v1: Vec3) -> (Vec3,Vec3,Vec3) {
     let abs_x = v1.x.abs();
     let abs_y = v1.y.abs();
@@ -355,7 +1088,7 @@ pub fn orthogonal(v1: Vec3) -> (Vec3,Vec3,Vec3) {
             aux = Vec3::xyz(0.0,0.0,1.0);
         }
     } else {
-        if(abs_y<abs_z) {
+        if abs_y<abs_z {
             aux = Vec3::xyz(0.0,1.0,0.0);
         } else {
             aux = Vec3::xyz(0.0,0.0,1.0);
@@ -366,10 +1099,205 @@ pub fn orthogonal(v1: Vec3) -> (Vec3,Vec3,Vec3) {
     return (v1.normalized(), aux2.cross(&v1).normalized(), aux2.normalized());
 }
 
+//mirror direction of w around n
+pub fn reflect(w: &Vec3, n: &Vec3) -> Vec3 {
+    &(n*(2.0*w.dot(n))) - w
+}
+
+//wi points away from the surface, n is on the side of wi; eta = eta_i/eta_t.
+//None on total internal reflection
+pub fn refract(wi: &Vec3, n: &Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = n.dot(wi);
+    let sin2_i = (1.0 - cos_i*cos_i).max(0.0);
+    let sin2_t = eta*eta*sin2_i;
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some((wi*(-eta)) + (n*(eta*cos_i - cos_t)))
+}
+
+//unpolarized Fresnel reflectance of a dielectric interface
+pub fn fresnel_dielectric(cos_i: f32, eta_i: f32, eta_t: f32) -> f32 {
+    let cos_i = clamp(cos_i.abs(), 0.0, 1.0);
+    let sin_t = eta_i/eta_t*(1.0 - cos_i*cos_i).max(0.0).sqrt();
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t*sin_t).max(0.0).sqrt();
+    let par = (eta_t*cos_i - eta_i*cos_t)/(eta_t*cos_i + eta_i*cos_t);
+    let perp = (eta_i*cos_i - eta_t*cos_t)/(eta_i*cos_i + eta_t*cos_t);
+    (par*par + perp*perp)*0.5
+}
+
 pub fn linear2srgb(a:f32) -> f32 {
-    if(a>0.0031308){
+    if a>0.0031308 {
         (1.0+0.055)*a.powf(1.0/2.4)-0.055
     } else {
         12.92*a
     }
-}
\ No newline at end of file
+}
+
+//relative luminance of a linear sRGB colour
+pub fn luminance(c: &Vec3) -> f32 {
+    0.2126*c.x + 0.7152*c.y + 0.0722*c.z
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Vec3, b: &Vec3) -> bool {
+        (a - b).len() < 1e-4
+    }
+
+    #[test]
+    fn mat3_inverse_of_singular_is_none() {
+        let m = Mat3::from_axis(&Vec3::xyz(1.0,2.0,3.0), &Vec3::xyz(2.0,4.0,6.0), &Vec3::xyz(0.0,1.0,0.0));
+        assert!(m.inverse().is_none());
+        let r = Mat3::from_axis(&Vec3::xyz(2.0,0.0,0.0), &Vec3::xyz(0.0,1.0,1.0), &Vec3::xyz(0.0,0.0,3.0));
+        let v = Vec3::xyz(0.3,-1.2,2.0);
+        assert!(close(&(&r.inverse().unwrap()*&(&r*&v)), &v));
+        //only the shape of the matrix matters, not its scale
+        let rounded = Mat3::from_axis(&Vec3::xyz(0.1,0.2,0.3), &Vec3::xyz(0.3,0.6,0.9), &Vec3::xyz(0.7,0.1,0.0));
+        assert!((&rounded*1e6).inverse().is_none());
+        let small = &r*1e-6;
+        assert!(close(&(&small.inverse().unwrap()*&(&small*&v)), &v));
+    }
+
+    #[test]
+    fn mat4_inverse_is_relative_to_the_columns() {
+        let t = Transform::scale(Vec3::xyz(1e-13, 1e-14, 1e-12));
+        let p = Vec3::xyz(0.5, -0.3, 4.0);
+        let m = Transform::from_matrix(*t.matrix()).unwrap();
+        assert!(close(&m.inverse().point(&t.point(&p)), &p));
+        let rounded = Mat4::from_rows([0.1e6,0.3e6,0.7e6,0.0, 0.2e6,0.6e6,0.1e6,0.0, 0.3e6,0.9e6,0.0,0.0, 0.0,0.0,0.0,1.0]);
+        assert!(rounded.inverse().is_none());
+    }
+
+    #[test]
+    fn transform_inverse_round_trip() {
+        let t = &(&Transform::translate(Vec3::xyz(1.0,2.0,3.0))
+            *&Transform::rotate(Vec3::xyz(1.0,1.0,0.0), 0.7))
+            *&Transform::scale(Vec3::xyz(2.0,0.5,3.0));
+        let p = Vec3::xyz(0.5,-0.3,4.0);
+        assert!(close(&t.inverse().point(&t.point(&p)), &p));
+        let m = Transform::from_matrix(*t.matrix()).unwrap();
+        assert!(close(&m.inverse().point(&p), &t.inverse().point(&p)));
+    }
+
+    #[test]
+    fn points_vectors_and_normals() {
+        let t = &Transform::translate(Vec3::xyz(0.0,0.0,5.0))*&Transform::scale(Vec3::xyz(1.0,1.0,2.0));
+        assert!(close(&t.point(&Vec3::xyz(1.0,1.0,1.0)), &Vec3::xyz(1.0,1.0,7.0)));
+        assert!(close(&t.vector(&Vec3::xyz(1.0,1.0,1.0)), &Vec3::xyz(1.0,1.0,2.0)));
+        //normals stay perpendicular to transformed tangents
+        let n = Vec3::xyz(1.0,0.0,1.0);
+        let tangent = Vec3::xyz(1.0,0.0,-1.0);
+        assert!(t.normal(&n).dot(&t.vector(&tangent)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rotate_and_compose() {
+        let r = Transform::rotate(Vec3::xyz(0.0,0.0,1.0), PI*0.5);
+        assert!(close(&r.vector(&Vec3::xyz(1.0,0.0,0.0)), &Vec3::xyz(0.0,1.0,0.0)));
+        //translate after rotating
+        let t = &Transform::translate(Vec3::xyz(1.0,0.0,0.0))*&r;
+        assert!(close(&t.point(&Vec3::xyz(1.0,0.0,0.0)), &Vec3::xyz(1.0,1.0,0.0)));
+    }
+
+    #[test]
+    fn look_at_maps_target_to_minus_z() {
+        let t = Transform::look_at(Vec3::xyz(1.0,2.0,3.0), Vec3::xyz(1.0,2.0,-2.0), Vec3::xyz(0.0,1.0,0.0));
+        assert!(close(&t.point(&Vec3::xyz(1.0,2.0,3.0)), &Vec3::new()));
+        assert!(close(&t.point(&Vec3::xyz(1.0,2.0,-2.0)), &Vec3::xyz(0.0,0.0,-5.0)));
+    }
+
+    #[test]
+    fn perspective_near_and_far() {
+        let p = Transform::perspective(PI*0.5, 1.0, 10.0);
+        assert!(close(&p.point(&Vec3::xyz(0.0,0.0,-1.0)), &Vec3::xyz(0.0,0.0,0.0)));
+        assert!(close(&p.point(&Vec3::xyz(0.0,0.0,-10.0)), &Vec3::xyz(0.0,0.0,1.0)));
+        assert!(close(&p.point(&Vec3::xyz(2.0,0.0,-2.0)), &Vec3::xyz(1.0,0.0,p.point(&Vec3::xyz(0.0,0.0,-2.0)).z)));
+    }
+
+    #[test]
+    fn decompose_recovers_parts() {
+        let q = Quaternion::from_axis_angle(Vec3::xyz(0.0,1.0,1.0), 1.1);
+        let t = &(&Transform::translate(Vec3::xyz(1.0,-2.0,0.5))*&q.to_transform())
+            *&Transform::scale(Vec3::xyz(2.0,3.0,0.5));
+        let (tr, r, s) = t.decompose();
+        assert!(close(&tr, &Vec3::xyz(1.0,-2.0,0.5)));
+        assert!((r.dot(&q).abs() - 1.0).abs() < 1e-4);
+        assert!((s.at_ro(0,0) - 2.0).abs() < 1e-4);
+        assert!((s.at_ro(1,1) - 3.0).abs() < 1e-4);
+        assert!((s.at_ro(2,2) - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn quaternion_matches_rotate() {
+        let axis = Vec3::xyz(1.0,2.0,-1.0);
+        let q = Quaternion::from_axis_angle(axis, 0.8);
+        let v = Vec3::xyz(0.3,0.2,1.0);
+        assert!(close(&q.to_transform().vector(&v), &Transform::rotate(axis, 0.8).vector(&v)));
+        let back = Quaternion::from_mat3(&q.to_mat3());
+        assert!((back.dot(&q).abs() - 1.0).abs() < 1e-5);
+        //composition of two rotations around the same axis adds the angles
+        let q2 = &q*&q;
+        assert!(close(&q2.to_transform().vector(&v), &Transform::rotate(axis, 1.6).vector(&v)));
+    }
+
+    #[test]
+    fn slerp_halfway() {
+        let a = Quaternion::identity();
+        let b = Quaternion::from_axis_angle(Vec3::xyz(0.0,0.0,1.0), PI*0.5);
+        let h = a.slerp(&b, 0.5);
+        let v = h.to_transform().vector(&Vec3::xyz(1.0,0.0,0.0));
+        let s = 0.5f32.sqrt();
+        assert!(close(&v, &Vec3::xyz(s,s,0.0)));
+        assert!(close(&a.slerp(&b, 1.0).to_transform().vector(&Vec3::xyz(1.0,0.0,0.0)), &Vec3::xyz(0.0,1.0,0.0)));
+    }
+
+    #[test]
+    fn animated_transform_interpolates() {
+        let start = Transform::translate(Vec3::xyz(0.0,0.0,0.0));
+        let end = &Transform::translate(Vec3::xyz(2.0,0.0,0.0))*&Transform::rotate(Vec3::xyz(0.0,0.0,1.0), PI*0.5);
+        let a = AnimatedTransform::new(start, 0.0, end, 1.0);
+        let mid = a.interpolate(0.5);
+        let s = 0.5f32.sqrt();
+        assert!(close(&mid.point(&Vec3::xyz(1.0,0.0,0.0)), &Vec3::xyz(1.0+s,s,0.0)));
+        assert!(close(&a.interpolate(2.0).point(&Vec3::new()), &Vec3::xyz(2.0,0.0,0.0)));
+    }
+
+    #[test]
+    fn next_float_steps_one_ulp() {
+        assert!(next_float_up(1.0) > 1.0 && next_float_up(1.0) - 1.0 == f32::EPSILON);
+        assert!(next_float_down(0.0) < 0.0 && next_float_up(-0.0) > 0.0);
+        assert_eq!(next_float_down(next_float_up(-3.5)), -3.5);
+    }
+
+    #[test]
+    fn efloat_bounds_contain_exact_value() {
+        //(a + b)*c/d - sqrt(e), with the exact result from f64
+        let (a, b, c, d, e) = (0.1f32, 1e4f32, 3.3f32, 7.0f32, 2.0f32);
+        let x = (EFloat::new(a) + EFloat::new(b))*EFloat::new(c)/EFloat::new(d) - EFloat::new(e).sqrt();
+        let exact = (a as f64 + b as f64)*c as f64/d as f64 - (e as f64).sqrt();
+        assert!(x.low as f64 <= exact && exact <= x.high as f64);
+        assert!(x.high - x.low < 1e-2);
+        let (p, err) = Transform::scale(Vec3::from(1e3)).point_with_error(&Vec3::xyz(0.1, 0.2, 0.3), &Vec3::new());
+        assert!((p.x as f64 - 0.1f32 as f64*1e3).abs() <= err.x as f64 && err.x > 0.0);
+    }
+
+    #[test]
+    fn quartic_roots() {
+        //(t-1)(t+2)(t-3)(t-0.5) = t^4-2.5t^3-4t^2+8.5t-3
+        let r = solve_quartic([-3.0, 8.5, -4.0, -2.5, 1.0]);
+        assert_eq!(r.len(), 4);
+        for (x, e) in r.iter().zip([-2.0, 0.5, 1.0, 3.0].iter()) {
+            assert!((x - e).abs() < 1e-9, "{:?}", r);
+        }
+        //t^4+1 has no real roots
+        assert!(solve_quartic([1.0, 0.0, 0.0, 0.0, 1.0]).is_empty());
+    }
+}
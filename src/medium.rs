@@ -0,0 +1,329 @@
+use crate::math::Vec3;
+use crate::math::orthogonal;
+use crate::primitive::Ray;
+use crate::voxel::VoxelGrid;
+use crate::voxel::MajorantGrid;
+use crate::spectrum::blackbody_rgb;
+use crate::spectrum::blackbody;
+use crate::spectrum::reflectance_at;
+
+use std::f32::consts::PI;
+
+pub struct HenyeyGreenstein {
+    //mean cosine: >0 forward scattering, <0 backward, 0 isotropic
+    pub g: f32
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein{g}
+    }
+
+    //wo points back along the incoming ray, like Intersection::wo
+    pub fn eval(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        let g = self.g;
+        let cos = wo.dot(wi);
+        let denom = 1.0 + g*g + 2.0*g*cos;
+        (1.0 - g*g)/(4.0*PI*denom*denom.max(0.0).sqrt())
+    }
+
+    //the phase function is normalized, so the pdf equals eval
+    pub fn sample(&self, wo: &Vec3, r1: f32, r2: f32) -> (Vec3, f32) {
+        let g = self.g;
+        //cosine with respect to the propagation direction -wo
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0*r1
+        } else {
+            let sqr = (1.0 - g*g)/(1.0 - g + 2.0*g*r1);
+            (1.0 + g*g - sqr*sqr)/(2.0*g)
+        };
+        let sin = (1.0 - cos*cos).max(0.0).sqrt();
+        let phi = 2.0*PI*r2;
+        let (z, x, y) = orthogonal(*wo*(-1.0));
+        let wi = ((x*(sin*phi.cos())) + (y*(sin*phi.sin()))) + (z*cos);
+        let p = self.eval(wo, &wi);
+        (wi, p)
+    }
+}
+
+//Result of sampling a distance along a ray inside a medium
+pub struct MediumSample {
+    //ray parameter of the scattering event, None if the ray reached t_max
+    pub t: Option<f32>,
+    //throughput weight of the sample (transmittance, albedo and pdf combined)
+    pub weight: Vec3,
+    //radiance emitted by the medium along the sampled segment, already
+    //attenuated; it is added before the weight is applied
+    pub emission: Vec3
+}
+
+//Coloured properties are RGB; with wavelengths (spectral mode) they are
+//upsampled and the returned weights hold one value per wavelength.
+pub trait Medium: Send + Sync {
+    //samples a scattering event along r between 0 and t_max
+    fn sample(&self, r: &Ray, t_max: f32, lambdas: Option<&Vec3>, rand: &mut dyn FnMut() -> f32) -> MediumSample;
+    //fraction of light that crosses the medium along r between 0 and t_max
+    fn transmittance(&self, r: &Ray, t_max: f32, lambdas: Option<&Vec3>, rand: &mut dyn FnMut() -> f32) -> Vec3;
+    fn phase(&self) -> &HenyeyGreenstein;
+}
+
+
+//exp(-sigma_t*dist) per channel; a channel without extinction keeps all of
+//the light even over an infinite distance, where 0*inf would give NaN
+fn attenuation(sigma_t: &Vec3, dist: f32) -> Vec3 {
+    let channel = |s: f32| if s == 0.0 {1.0} else {(-s*dist).exp()};
+    Vec3::xyz(channel(sigma_t.x), channel(sigma_t.y), channel(sigma_t.z))
+}
+
+//Constant absorption and scattering coefficients (per unit length)
+pub struct HomogeneousMedium {
+    pub(crate) sigma_a: Vec3,
+    pub(crate) sigma_s: Vec3,
+    pub(crate) phase: HenyeyGreenstein
+}
+
+impl HomogeneousMedium {
+    pub fn new(sigma_a: Vec3, sigma_s: Vec3, g: f32) -> HomogeneousMedium {
+        HomogeneousMedium{sigma_a, sigma_s, phase: HenyeyGreenstein::new(g)}
+    }
+
+    fn sigma_s(&self, lambdas: Option<&Vec3>) -> Vec3 {
+        reflectance_at(&self.sigma_s, lambdas)
+    }
+
+    fn sigma_t(&self, lambdas: Option<&Vec3>) -> Vec3 {
+        reflectance_at(&self.sigma_a, lambdas) + self.sigma_s(lambdas)
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    //picks a channel uniformly and samples its exponential distance; the pdf
+    //is the average over channels so that coloured media stay unbiased
+    fn sample(&self, r: &Ray, t_max: f32, lambdas: Option<&Vec3>, rand: &mut dyn FnMut() -> f32) -> MediumSample {
+        let sigma_t = self.sigma_t(lambdas);
+        let len = r.d.len();
+        let channel = ((rand()*3.0) as usize).min(2);
+        let st = sigma_t.get(channel);
+        let dist = if st > 0.0 {-(1.0 - rand()).ln()/st} else {f32::INFINITY};
+        let t = (dist/len).min(t_max);
+        let scattered = t < t_max;
+
+        let tr = attenuation(&sigma_t, t*len);
+        let density = if scattered {&sigma_t*&tr} else {tr};
+        let pdf = (density.x + density.y + density.z)/3.0;
+        if pdf <= 0.0 {
+            return MediumSample{t: None, weight: Vec3::new(), emission: Vec3::new()};
+        }
+        if scattered {
+            MediumSample{t: Some(t), weight: &(&tr*&self.sigma_s(lambdas))/pdf, emission: Vec3::new()}
+        } else {
+            MediumSample{t: None, weight: &tr/pdf, emission: Vec3::new()}
+        }
+    }
+
+    fn transmittance(&self, r: &Ray, t_max: f32, lambdas: Option<&Vec3>, _rand: &mut dyn FnMut() -> f32) -> Vec3 {
+        attenuation(&self.sigma_t(lambdas), t_max*r.d.len())
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+
+//Heterogeneous medium from a density grid stretched over an axis aligned box,
+//with an optional temperature grid (Kelvin) that makes it glow like fire.
+//Extinction is grey (density*density_scale per unit length), so delta
+//tracking is used for sampling and ratio tracking for transmittance.
+pub struct GridMedium {
+    density: VoxelGrid,
+    majorants: MajorantGrid,
+    min: Vec3,
+    max: Vec3,
+    density_scale: f32,
+    albedo: Vec3,
+    phase: HenyeyGreenstein,
+    temperature: Option<VoxelGrid>,
+    //emitted radiance per temperature step, see blackbody_rgb
+    emission_table: Vec<Vec3>,
+    emission_scale: f32,
+    max_temperature: f32
+}
+
+const EMISSION_TABLE_SIZE: usize = 256;
+
+impl GridMedium {
+    pub fn new(density: VoxelGrid, min: Vec3, max: Vec3,
+        density_scale: f32, albedo: Vec3, g: f32) -> GridMedium {
+        GridMedium{
+            majorants: MajorantGrid::new(&density, 16),
+            density,
+            min,
+            max,
+            density_scale,
+            albedo,
+            phase: HenyeyGreenstein::new(g),
+            temperature: None,
+            emission_table: Vec::new(),
+            emission_scale: 0.0,
+            max_temperature: 0.0
+        }
+    }
+
+    //Adds black body emission; only the absorbing part of the medium emits.
+    //emission_scale multiplies blackbody_rgb
+    pub fn set_temperature(&mut self, temperature: VoxelGrid, emission_scale: f32) {
+        self.max_temperature = temperature.max_value();
+        self.emission_table = (0..EMISSION_TABLE_SIZE).map(|i| {
+            let t = self.max_temperature*i as f32/(EMISSION_TABLE_SIZE-1) as f32;
+            blackbody_rgb(t)*emission_scale
+        }).collect();
+        self.emission_scale = emission_scale;
+        self.temperature = Some(temperature);
+    }
+
+    fn emitted(&self, p: &Vec3, lambdas: Option<&Vec3>) -> Vec3 {
+        match &self.temperature {
+            None => Vec3::new(),
+            Some(t) => {
+                if self.max_temperature <= 0.0 {
+                    return Vec3::new();
+                }
+                if let Some(l) = lambdas {
+                    let k = t.lookup(p);
+                    return Vec3::xyz(blackbody(l.x, k), blackbody(l.y, k), blackbody(l.z, k))*self.emission_scale;
+                }
+                let x = t.lookup(p)/self.max_temperature*(EMISSION_TABLE_SIZE-1) as f32;
+                self.emission_table[(x.max(0.0) as usize).min(EMISSION_TABLE_SIZE-1)]
+            }
+        }
+    }
+
+    //ray in grid space and its parameter range inside the grid
+    fn to_grid(&self, r: &Ray, t_max: f32) -> Option<(Vec3, Vec3, f32, f32)> {
+        let extent = self.max - self.min;
+        let o = Vec3::xyz((r.o.x - self.min.x)/extent.x, (r.o.y - self.min.y)/extent.y, (r.o.z - self.min.z)/extent.z);
+        let d = Vec3::xyz(r.d.x/extent.x, r.d.y/extent.y, r.d.z/extent.z);
+        let mut t0: f32 = 0.0;
+        let mut t1 = t_max;
+        for a in 0..3 {
+            let inv = 1.0/d.get(a);
+            let mut near = (0.0 - o.get(a))*inv;
+            let mut far = (1.0 - o.get(a))*inv;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t0 > t1 || t1.is_nan() || t0.is_nan() {
+                return None;
+            }
+        }
+        Some((o, d, t0, t1))
+    }
+}
+
+impl Medium for GridMedium {
+    //delta tracking; emission is gathered at every tentative collision
+    fn sample(&self, r: &Ray, t_max: f32, lambdas: Option<&Vec3>, rand: &mut dyn FnMut() -> f32) -> MediumSample {
+        let (o, d, t0, t1) = match self.to_grid(r, t_max) {
+            Some(x) => x,
+            None => {return MediumSample{t: None, weight: Vec3::from(1.0), emission: Vec3::new()};}
+        };
+        let len = r.d.len();
+        let albedo = reflectance_at(&self.albedo, lambdas);
+        let absorption = Vec3::from(1.0) - albedo;
+        let mut emission = Vec3::new();
+        let mut scatter = None;
+        self.majorants.traverse(&o, &d, t0, t1, &mut |s0, s1, majorant| {
+            let sigma_maj = majorant*self.density_scale;
+            if sigma_maj <= 0.0 {
+                return true;
+            }
+            let mut t = s0;
+            loop {
+                t += -(1.0 - rand()).ln()/(sigma_maj*len);
+                if t >= s1 {
+                    return true;
+                }
+                let p = o + (d*t);
+                let sigma_t = self.density.lookup(&p)*self.density_scale;
+                if self.temperature.is_some() {
+                    emission = emission + (&absorption*&self.emitted(&p, lambdas))*(sigma_t/sigma_maj);
+                }
+                if rand() < sigma_t/sigma_maj {
+                    scatter = Some(t);
+                    return false;
+                }
+            }
+        });
+        match scatter {
+            Some(t) => MediumSample{t: Some(t), weight: albedo, emission},
+            None => MediumSample{t: None, weight: Vec3::from(1.0), emission}
+        }
+    }
+
+    //ratio tracking
+    fn transmittance(&self, r: &Ray, t_max: f32, _lambdas: Option<&Vec3>, rand: &mut dyn FnMut() -> f32) -> Vec3 {
+        let (o, d, t0, t1) = match self.to_grid(r, t_max) {
+            Some(x) => x,
+            None => {return Vec3::from(1.0);}
+        };
+        let len = r.d.len();
+        let mut tr = 1.0;
+        self.majorants.traverse(&o, &d, t0, t1, &mut |s0, s1, majorant| {
+            let sigma_maj = majorant*self.density_scale;
+            if sigma_maj <= 0.0 {
+                return true;
+            }
+            let mut t = s0;
+            loop {
+                t += -(1.0 - rand()).ln()/(sigma_maj*len);
+                if t >= s1 {
+                    return true;
+                }
+                let sigma_t = self.density.lookup(&(o + (d*t)))*self.density_scale;
+                tr *= 1.0 - sigma_t/sigma_maj;
+                if tr <= 0.0 {
+                    return false;
+                }
+            }
+        });
+        Vec3::from(tr.max(0.0))
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    //Rays escaping to infinity through a medium that lets one channel
+    //through must keep that channel, on average with weight 1, and no NaN
+    #[test]
+    fn unbounded_rays_escape_through_clear_channels() {
+        let mut rng = StdRng::seed_from_u64(31);
+        let medium = HomogeneousMedium::new(Vec3::xyz(0.0, 0.2, 1.0), Vec3::xyz(0.0, 0.5, 0.0), 0.0);
+        let r = Ray::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0));
+        let n = 30000;
+        let mut escaped = Vec3::new();
+        for _ in 0..n {
+            let s = medium.sample(&r, f32::INFINITY, None, &mut || rng.gen());
+            assert!(s.weight.x.is_finite() && s.weight.y.is_finite() && s.weight.z.is_finite());
+            if s.t.is_none() {
+                escaped = escaped + s.weight;
+            }
+        }
+        let escaped = &escaped/(n as f32);
+        assert!((escaped.x - 1.0).abs() < 0.05 && escaped.y == 0.0 && escaped.z == 0.0);
+        let tr = medium.transmittance(&r, f32::INFINITY, None, &mut || rng.gen());
+        assert!(tr.x == 1.0 && tr.y == 0.0 && tr.z == 0.0);
+    }
+}
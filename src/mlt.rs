@@ -0,0 +1,162 @@
+use crate::scene::Scene;
+use crate::math::Vec3;
+use crate::math::luminance;
+use crate::renderer::Renderer;
+use crate::control::RenderControl;
+use crate::renderer::PtRenderer;
+use crate::sampler::Sampler;
+use crate::sampler::Pcg32;
+use crate::sampler::MltSampler;
+use crate::film::Film;
+
+//Primary sample space Metropolis light transport (Kelemen et al. 2002) on
+//top of the path tracer. A path is a vector of random numbers: the first
+//two pick the image position, the rest are consumed by PtRenderer::sample.
+//Markov chains mutate these vectors and spend their samples where the
+//image is bright; every state is splatted to the film with the expected
+//value of the acceptance. A bootstrap phase estimates the overall image
+//brightness b and picks the chain start states.
+pub struct MltRenderer {
+    pt: PtRenderer,
+    //average number of mutations per pixel
+    mutations: u32,
+    bootstrap: u32,
+    chains: u32,
+    //standard deviation of small steps, and probability of a large step
+    sigma: f32,
+    large_step_probability: f32
+}
+
+impl MltRenderer {
+    pub fn new(mutations: u32) -> MltRenderer {
+        MltRenderer{
+            pt: PtRenderer::new(1),
+            mutations,
+            bootstrap: 100000,
+            chains: 100,
+            sigma: 0.01,
+            large_step_probability: 0.3
+        }
+    }
+
+    //radiance of the path encoded by the sampler, and its image coordinate
+    fn path(&self, scene: &Scene, sampler: &mut MltSampler) -> (Vec3, Vec3) {
+        let u = Vec3::xyz(sampler.next()*2.0 - 1.0, 1.0 - sampler.next()*2.0, 0.0);
+        let l = self.pt.sample(scene, u, sampler);
+        if l.x.is_finite() && l.y.is_finite() && l.z.is_finite() {
+            (l, u)
+        } else {
+            (Vec3::new(), u)
+        }
+    }
+
+    //Independent paths, each reproducible from its seed. Returns the running
+    //sum of their luminance, to pick chain starts from, and its mean b, the
+    //brightness of the image.
+    fn bootstrap(&self, scene: &Scene) -> (Vec<f64>, f64) {
+        let mut cdf = Vec::with_capacity(self.bootstrap as usize);
+        let mut sum = 0.0f64;
+        for seed in 0..self.bootstrap {
+            let mut sampler = MltSampler::new(seed as u64, self.sigma, self.large_step_probability);
+            let (l, _) = self.path(scene, &mut sampler);
+            sum += luminance(&l).max(0.0) as f64;
+            cdf.push(sum);
+        }
+        (cdf, sum/self.bootstrap.max(1) as f64)
+    }
+
+    fn splat(film: &mut Film, u: &Vec3, c: &Vec3) {
+        if let Some((x, y)) = film.raster(u) {
+            film.add(x, y, c);
+        }
+    }
+}
+
+impl Renderer for MltRenderer {
+    fn render(&self, scene: &mut Scene, fname: &str, control: &RenderControl) -> Result<(), String> {
+        let (w, h) = scene.camera.image().dimensions();
+        let mut film = Film::new(w, h);
+        let scene_ref: &Scene = scene;
+
+        let (cdf, b) = self.bootstrap(scene_ref);
+        let sum = cdf.last().copied().unwrap_or(0.0);
+
+        let total = self.mutations as u64*w as u64*h as u64;
+        let chains = if sum > 0.0 {self.chains as u64} else {0};
+        let mut rng = Pcg32::new(0, 2);
+        let mut done = 0;
+        for chain in 0..chains {
+            if control.should_stop() {
+                break;
+            }
+            control.report(&format!("Chain {}", chain));
+            let mutations = total/chains + if chain < total%chains {1} else {0};
+
+            //start state proportional to the bootstrap contributions
+            let target = rng.next_f32() as f64*sum;
+            let seed = cdf.partition_point(|&c| c <= target).min(cdf.len() - 1);
+            let mut sampler = MltSampler::new(seed as u64, self.sigma, self.large_step_probability);
+            let (mut l, mut u) = self.path(scene_ref, &mut sampler);
+            let mut f = luminance(&l);
+
+            for _ in 0..mutations {
+                sampler.start_iteration();
+                let (l_new, u_new) = self.path(scene_ref, &mut sampler);
+                let f_new = luminance(&l_new);
+                let accept = if f > 0.0 {(f_new/f).clamp(0.0, 1.0)} else {1.0};
+                if accept > 0.0 && f_new > 0.0 {
+                    MltRenderer::splat(&mut film, &u_new, &(l_new*(accept/f_new)));
+                }
+                if f > 0.0 {
+                    MltRenderer::splat(&mut film, &u, &(l*((1.0 - accept)/f)));
+                }
+                if rng.next_f32() < accept {
+                    l = l_new;
+                    u = u_new;
+                    f = f_new;
+                    sampler.accept();
+                } else {
+                    sampler.reject();
+                }
+            }
+            done += mutations;
+        }
+
+        let exposure = scene.camera.exposure();
+        //b/mutations per pixel for each mutation, scaled up when stopped early
+        film.develop(&mut *scene.camera, (b*total as f64/(done.max(1) as f64*self.mutations as f64)) as f32*exposure);
+        scene.camera.save_image(fname);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::RandomSampler;
+    use crate::scenes::furnace;
+
+    //The chains only get the relative brightness of the image right, b
+    //scales it. It is the mean luminance over the image, which the path
+    //tracer estimates here on a stratified grid.
+    #[test]
+    fn bootstrap_finds_the_mean_brightness_of_the_furnace() {
+        let (scene, _) = furnace(16, 0.5).build().unwrap();
+        let (cdf, b) = MltRenderer::new(1).bootstrap(&scene);
+        assert_eq!(cdf.len(), 100000);
+
+        let n = 256;
+        let pt = PtRenderer::new(1);
+        let mut sampler = RandomSampler::new(0);
+        let mut sum = 0.0f64;
+        for j in 0..n {
+            for i in 0..n {
+                let u = Vec3::xyz((i as f32 + 0.5)/n as f32*2.0 - 1.0, 1.0 - (j as f32 + 0.5)/n as f32*2.0, 0.0);
+                sum += luminance(&pt.sample(&scene, u, &mut sampler)) as f64;
+            }
+        }
+        let expected = sum/(n*n) as f64;
+        assert!(b > 0.5 && b < 1.0, "b {}", b);
+        assert!((b - expected).abs() <= 0.01*expected, "b {} for {}", b, expected);
+    }
+}
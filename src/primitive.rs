@@ -1,151 +1,2516 @@
 
 use crate::math::Vec3;
 use crate::material::Material;
-use crate::material::DummyMaterial;
-use crate::material::DiffuseMaterial;
 
 use std::option::Option;
-use crate::constants::EPS;
-use crate::math::solve_quadratic;
+use crate::math::EFloat;
+use crate::math::gamma;
+use crate::math::next_float_up;
+use crate::math::next_float_down;
+use crate::math::Transform;
+use crate::math::Mat3;
+use crate::math::orthogonal;
+use crate::math::sample_disk_concentric;
+use crate::math::solve_quadratic_f64;
+use crate::math::solve_quartic;
+use std::f32::consts::PI;
+use crate::math::AnimatedTransform;
+use crate::medium::Medium;
+use crate::sdf::SdfExpr;
+use std::sync::Arc;
 
 
 pub struct Ray{
     pub o: Vec3,
     pub d: Vec3,
-    pub max_t: f32
+    pub max_t: f32,
+    //time within the shutter interval, used by moving primitives
+    pub time: f32
 }
 
 impl Ray {
     pub fn new(o:Vec3, d:Vec3) -> Ray {
-        Ray {o:o, d:d, max_t: 10000.0}
+        Ray {o, d, max_t: 10000.0, time: 0.0}
+    }
+    pub fn with_time(o:Vec3, d:Vec3, time: f32) -> Ray {
+        Ray {o, d, max_t: 10000.0, time}
+    }
+}
+
+#[derive(Copy,Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb{min: Vec3::from(f32::INFINITY), max: Vec3::from(f32::NEG_INFINITY)}
+    }
+
+    pub fn around_sphere(o: &Vec3, r: f32) -> Aabb {
+        Aabb{min: o - &Vec3::from(r), max: o + &Vec3::from(r)}
+    }
+
+    pub fn union(&self, b: &Aabb) -> Aabb {
+        Aabb{
+            min: Vec3::xyz(self.min.x.min(b.min.x), self.min.y.min(b.min.y), self.min.z.min(b.min.z)),
+            max: Vec3::xyz(self.max.x.max(b.max.x), self.max.y.max(b.max.y), self.max.z.max(b.max.z))
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        &(self.min + self.max)*0.5
+    }
+
+    pub fn axis(v: &Vec3, axis: usize) -> f32 {
+        v.get(axis)
+    }
+
+    //slab test against [0, r.max_t]
+    pub fn hit(&self, r: &Ray) -> bool {
+        let mut t0 = 0.0;
+        let mut t1 = r.max_t;
+        for axis in 0..3 {
+            let inv_d = 1.0/Aabb::axis(&r.d, axis);
+            let o = Aabb::axis(&r.o, axis);
+            let mut near = (Aabb::axis(&self.min, axis) - o)*inv_d;
+            let mut far = (Aabb::axis(&self.max, axis) - o)*inv_d;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            //widened by the rounding error of far, so that boxes around
+            //what the ray hits are never missed
+            far *= 1.0 + 2.0*gamma(3);
+            t0 = if near > t0 {near} else {t0};
+            t1 = if far < t1 {far} else {t1};
+            if t0 > t1 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+//Relative amount by which rays between two points stop short of the
+//second, on top of offsetting both ends
+pub const SHADOW_EPSILON: f32 = 1e-4;
+
+//Moves p, which is off by at most p_error in each coordinate, along the
+//geometric normal n to the side of w, just far enough that the surface
+//can't be within its error bounds, and rounds it away from the surface.
+//Rays leaving from there don't hit the surface again, whatever the scale
+//of the scene (PBRT 3.9.5).
+pub fn offset_ray_origin(p: &Vec3, p_error: &Vec3, n: &Vec3, w: &Vec3) -> Vec3 {
+    let d = n.abs().dot(p_error);
+    let offset = if w.dot(n) < 0.0 {n*(-d)} else {n*d};
+    let po = p + &offset;
+    let round = |v: f32, o: f32| if o > 0.0 {next_float_up(v)} else if o < 0.0 {next_float_down(v)} else {v};
+    Vec3::xyz(round(po.x, offset.x), round(po.y, offset.y), round(po.z, offset.z))
+}
+
+//Where rays start from: a point with bounds on its floating point error
+//and the geometric normal of the surface it lies on
+#[derive(Copy,Clone)]
+pub struct SpawnPoint {
+    pub p: Vec3,
+    pub p_error: Vec3,
+    pub n: Vec3
+}
+
+impl SpawnPoint {
+    //point on no surface, e.g. a scattering event in a medium, or one whose
+    //error is negligible, like a sampled point on a light
+    pub fn at(p: Vec3) -> SpawnPoint {
+        SpawnPoint{p, p_error: Vec3::new(), n: Vec3::new()}
+    }
+
+    pub fn origin(&self, w: &Vec3) -> Vec3 {
+        offset_ray_origin(&self.p, &self.p_error, &self.n, w)
+    }
+
+    pub fn ray(&self, d: Vec3, time: f32) -> Ray {
+        Ray::with_time(self.origin(&d), d, time)
+    }
+
+    //Ray with a unit direction that ends just short of the other point. Both
+    //ends are offset, so that it hits neither surface.
+    pub fn ray_to(&self, to: &SpawnPoint, time: f32) -> Ray {
+        let o = self.origin(&(to.p - self.p));
+        let q = to.origin(&(o - to.p));
+        let d = q - o;
+        let dist = d.len();
+        let mut r = Ray::with_time(o, if dist > 0.0 {&d/dist} else {self.n}, time);
+        r.max_t = dist*(1.0 - SHADOW_EPSILON);
+        r
     }
 }
 
+#[derive(Copy,Clone)]
 pub struct Intersection<'a>{
     pub p: Vec3,
+    //bounds on the floating point error of p
+    pub p_error: Vec3,
     pub wo: Vec3,
     pub n: Vec3,
     pub uv: Vec3,
-    pub mat: &'a dyn Material
+    pub mat: &'a dyn Material,
+    //identifies the primitive that was hit, e.g. for false colour renders
+    pub object: usize,
+    //velocity of the hit point, for motion vectors
+    pub dpdt: Vec3,
+    //medium on the side the normal points away from, for closed primitives
+    pub interior: Option<&'a dyn Medium>
+}
+
+impl<'a> Intersection<'a> {
+    pub fn spawn_point(&self) -> SpawnPoint {
+        SpawnPoint{p: self.p, p_error: self.p_error, n: self.n}
+    }
+
+    //ray leaving the surface along d
+    pub fn spawn_ray(&self, d: Vec3, time: f32) -> Ray {
+        self.spawn_point().ray(d, time)
+    }
+}
+
+pub trait Primitive: Send + Sync {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>>;
+    fn intersect_any(&self, r: &mut Ray) -> bool;
+    //like intersect, but adds the number of bounding box and primitive
+    //tests to `cost`, for traversal cost heatmaps
+    fn intersect_counted(&self, r: &mut Ray, cost: &mut u32) -> Option<Intersection<'_>> {
+        *cost += 1;
+        self.intersect(r)
+    }
+    //bounds over the whole shutter interval, for moving primitives
+    fn bounds(&self) -> Aabb;
+    //For closed primitives, the stretches of the ray's whole line that are
+    //inside, also behind its origin and beyond max_t, in order. None for
+    //primitives that don't enclose anything, see Csg.
+    fn spans(&self, _r: &Ray) -> Option<Vec<Span<'_>>> {
+        None
+    }
+}
+
+//Stretch of a ray's line inside a closed primitive, from where it enters
+//at t0 to where it leaves at t1, with the surface at both ends. The normals
+//point out of the primitive at both.
+#[derive(Copy,Clone)]
+pub struct Span<'a> {
+    pub t0: f32,
+    pub enter: Intersection<'a>,
+    pub t1: f32,
+    pub exit: Intersection<'a>
+}
+
+//Id made from the bits of some floats (FNV-1a), e.g. of a primitive's
+//parameters. Unlike addresses, these ids are the same in every run.
+pub fn hash_floats(values: &[f32]) -> usize {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for v in values {
+        for b in v.to_bits().to_le_bytes().iter() {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+    }
+    h as usize
 }
 
-pub trait Primitive {
-    fn intersect(&self, r: &mut Ray) -> Option<Intersection>;
-    fn intersectAny(&self, r: &mut Ray) -> bool;
+//spherical coordinates of a unit normal, both in [0,1]
+fn sphere_uv(n: &Vec3) -> Vec3 {
+    let u = n.y.atan2(n.x)/(2.0*std::f32::consts::PI) + 0.5;
+    let v = n.z.clamp(-1.0, 1.0).acos()/std::f32::consts::PI;
+    Vec3::xyz(u, v, 0.0)
 }
 
-pub trait Group<'a>: Primitive {
-    fn add(&mut self, p: Box<dyn Primitive + 'a>);
-    fn buildIndex(&mut self){}
+pub trait Group: Primitive {
+    fn add(&mut self, p: Box<dyn Primitive>);
+    fn build_index(&mut self){}
 }
 
 
-pub struct SimpleGroup<'a>{
-    primitives: Vec<Box<dyn Primitive +'a>>
+pub struct SimpleGroup{
+    primitives: Vec<Box<dyn Primitive>>
 } 
 
-impl<'a> SimpleGroup<'a>{
-    pub fn new() -> SimpleGroup<'a> {
+impl Default for SimpleGroup {
+    fn default() -> SimpleGroup {
+        SimpleGroup::new()
+    }
+}
+
+impl SimpleGroup{
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
+    pub fn new() -> SimpleGroup {
         return SimpleGroup{primitives: Vec::new()}
     }
 }
 
-impl<'a> Group<'a> for SimpleGroup<'a> {
-    fn add(&mut self, p: Box<dyn Primitive + 'a>  ){
+impl Group for SimpleGroup {
+    fn add(&mut self, p: Box<dyn Primitive>){
         self.primitives.push(p);
     }
 }
 
-pub struct Sphere<'a> {
-    pub o: Vec3,
-    pub r: f32,
-    pub mat: &'a dyn Material
+pub struct Sphere {
+    pub(crate) o: Vec3,
+    pub(crate) r: f32,
+    pub(crate) mat: Arc<dyn Material>
+}
+
+impl Sphere {
+    pub fn new(o: Vec3, r: f32, mat: Arc<dyn Material>) -> Sphere {
+        Sphere{o, r, mat}
+    }
+
+    fn id(&self) -> usize {
+        hash_floats(&[self.o.x, self.o.y, self.o.z, self.r])
+    }
 }
 
 
-impl<'a> Primitive for Sphere<'a> {
-    fn intersect(&self, r:&mut Ray) -> Option<Intersection<'a>> {
-        if !self.intersectAny(r) {
+impl Primitive for Sphere {
+    fn intersect(&self, r:&mut Ray) -> Option<Intersection<'_>> {
+        if !self.intersect_any(r) {
             return None;
         }
 
-        let x = (r.d*r.max_t)+ r.o;
-        return Some(Intersection{
-            n: (x-self.o).normalized(),
-            p: x,
-            wo: r.d*(-1.0),
-            uv: Vec3::new(),
-            mat: self.mat
-        })
+        Some(sphere_hit(&self.o, self.r, r.max_t, r, &*self.mat, self.id(), Vec3::new()))
     }
     
-    fn intersectAny(&self, r:&mut Ray) -> bool {
-        //  r*r =  (x-o)^2
-        //  r*r = ((r.o + d*t- s.o))^2
-        //  r*r = ((r.o + d*t)^2  - 2*r.o*s.o - 2*s.o*d*t + s.o^2)      
-        //  0 = (r.o^2 + 2*r.o*d*t +d^2*t^2 - 2*r.o*s.o - 2*s.o*d*t + s.o^2 ) 
-        // 0 = (r.o^2 + s.o^2 -2*r.o*s.o +  (2*r.o*d - 2*s.o*d)*t + d^2 * t^2
+    fn intersect_any(&self, r:&mut Ray) -> bool {
+        intersect_sphere(&self.o, self.r, r)
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb::around_sphere(&self.o, self.r)
+    }
 
-        //  r*r =  (x)^2
-        //  r*r = ((r.o + d*t))^2    
-        //  0 = (r.o^2 + 2*r.o*d*t +d^2*t^2) -r*r
-        let aux = r.o-self.o;
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        Some(sphere_spans(&self.o, self.r, r, &*self.mat, self.id(), Vec3::new()))
+    }
+}
 
-        let a = r.d.lensqr();
-        let b = 2.0*(aux.dot(&r.d));
-        let c = aux.lensqr() - self.r*self.r;
 
-        let sol = solve_quadratic(a,b,c);
+//sphere whose centre moves linearly from o0 at time t0 to o1 at time t1
+pub struct MovingSphere {
+    pub(crate) o0: Vec3,
+    pub(crate) o1: Vec3,
+    pub(crate) t0: f32,
+    pub(crate) t1: f32,
+    pub(crate) r: f32,
+    pub(crate) mat: Arc<dyn Material>
+}
 
-        let d;
-        let e;
+impl MovingSphere {
+    pub fn new(o0: Vec3, t0: f32, o1: Vec3, t1: f32, r: f32, mat: Arc<dyn Material>) -> MovingSphere {
+        MovingSphere{o0, o1, t0, t1, r, mat}
+    }
 
-        
+    pub fn center(&self, time: f32) -> Vec3 {
+        if self.t1 <= self.t0 {
+            return self.o0;
+        }
+        //the sphere rests at its ends outside the motion, as bounds() assumes
+        let a = ((time - self.t0)/(self.t1 - self.t0)).clamp(0.0, 1.0);
+        (self.o0*(1.0-a)) + (self.o1*a)
+    }
 
-        match sol{
-            None => {return false;}
-            Some(x) => {d =x[0]; e = x[1];}
+    pub fn velocity(&self, time: f32) -> Vec3 {
+        if self.t1 <= self.t0 || time < self.t0 || time > self.t1 {
+            return Vec3::new();
         }
+        &(self.o1 - self.o0)/(self.t1 - self.t0)
+    }
 
-        if d<EPS || d>r.max_t {
-            if e<EPS || e> r.max_t {
-                return false;
-            } else {
-                r.max_t = e;
-                return true;
-            }
-        } else {
-            r.max_t = d;
-            return true;
+    fn id(&self) -> usize {
+        hash_floats(&[self.o0.x, self.o0.y, self.o0.z, self.o1.x, self.o1.y, self.o1.z, self.t0, self.t1, self.r])
+    }
+}
+
+impl Primitive for MovingSphere {
+    fn intersect(&self, r:&mut Ray) -> Option<Intersection<'_>> {
+        if !self.intersect_any(r) {
+            return None;
         }
 
+        Some(sphere_hit(&self.center(r.time), self.r, r.max_t, r, &*self.mat, self.id(), self.velocity(r.time)))
+    }
+
+    fn intersect_any(&self, r:&mut Ray) -> bool {
+        intersect_sphere(&self.center(r.time), self.r, r)
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb::around_sphere(&self.o0, self.r).union(&Aabb::around_sphere(&self.o1, self.r))
+    }
 
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        Some(sphere_spans(&self.center(r.time), self.r, r, &*self.mat, self.id(), self.velocity(r.time)))
     }
 }
 
+//The sphere's surface where the ray is at t
+fn sphere_hit<'a>(o: &Vec3, radius: f32, t: f32, r: &Ray, mat: &'a dyn Material, object: usize, dpdt: Vec3) -> Intersection<'a> {
+    let (x, p_error) = reproject_on_sphere(o, radius, &((r.d*t)+ r.o));
+    let n = (x-*o).normalized();
+    Intersection{
+        n,
+        p: x,
+        p_error,
+        wo: r.d*(-1.0),
+        uv: sphere_uv(&n),
+        mat,
+        object,
+        dpdt,
+        interior: None
+    }
+}
 
-impl<'a> Primitive for SimpleGroup<'a>{
-    fn intersect(&self, r:&mut Ray) -> Option<Intersection> {
-        let mut it = None;
-        for p in &self.primitives{
-            let tmp = p.intersect(r);
-            if !tmp.is_none(){
-                it = tmp;
-            }
-           // println!("Testing intersection: {}",!it.is_none());
-            
+//The roots are found in f64 from the origin relative to the centre, which
+//is exact there, so they are accurate enough to tell which side of the
+//surface an offset origin is on
+fn sphere_spans<'a>(o: &Vec3, radius: f32, r: &Ray, mat: &'a dyn Material, object: usize, dpdt: Vec3) -> Vec<Span<'a>> {
+    let aux = [r.o.x as f64 - o.x as f64, r.o.y as f64 - o.y as f64, r.o.z as f64 - o.z as f64];
+    let d = [r.d.x as f64, r.d.y as f64, r.d.z as f64];
+    let a = d[0]*d[0] + d[1]*d[1] + d[2]*d[2];
+    let b = 2.0*(d[0]*aux[0] + d[1]*aux[1] + d[2]*aux[2]);
+    let c = aux[0]*aux[0] + aux[1]*aux[1] + aux[2]*aux[2] - (radius as f64)*(radius as f64);
+    let roots = solve_quadratic_f64(a, b, c);
+    if roots.len() < 2 || roots[0] >= roots[1] {
+        return Vec::new();
+    }
+    let (t0, t1) = (roots[0] as f32, roots[1] as f32);
+    vec![Span{t0, enter: sphere_hit(o, radius, t0, r, mat, object, dpdt), t1, exit: sphere_hit(o, radius, t1, r, mat, object, dpdt)}]
+}
+
+
+//Ray-sphere test with bounds on the error of the hit distance (PBRT 3.2.2,
+//with the discriminant of PBRT-v4). Only hits whose whole interval lies in
+//(0, max_t] count, so that a ray leaving the surface from an offset origin
+//doesn't find it again.
+fn intersect_sphere(o: &Vec3, radius: f32, r: &mut Ray) -> bool {
+    //relative to the centre; the subtraction rounds once
+    let aux = &r.o-o;
+    if sphere_misses(&aux, &r.d, radius, r.max_t) {
+        return false;
+    }
+    let err = (r.o.abs() + o.abs())*gamma(1);
+    let ox = EFloat::with_error(aux.x, err.x);
+    let oy = EFloat::with_error(aux.y, err.y);
+    let oz = EFloat::with_error(aux.z, err.z);
+    let dx = EFloat::new(r.d.x);
+    let dy = EFloat::new(r.d.y);
+    let dz = EFloat::new(r.d.z);
+    let rad = EFloat::new(radius);
+
+    let a = dx*dx + dy*dy + dz*dz;
+    let b = (dx*ox + dy*oy + dz*oz)*2.0;
+    let c = ox*ox + oy*oy + oz*oz - rad*rad;
+
+    //b*b-4ac cancels badly for small or far away spheres; 4a(r^2-l^2), with
+    //l the distance from the centre to the line, doesn't
+    let f = b/(a*2.0);
+    let vx = ox - f*dx;
+    let vy = oy - f*dy;
+    let vz = oz - f*dz;
+    let l = (vx*vx + vy*vy + vz*vz).sqrt();
+    let discrim = a*4.0*(rad - l)*(rad + l);
+    if discrim.low < 0.0 {
+        return false;
+    }
+
+    let root = discrim.sqrt();
+    let q = if b.v < 0.0 {(b - root)*(-0.5)} else {(b + root)*(-0.5)};
+    let mut t0 = q/a;
+    let mut t1 = c/q;
+    if t0.low > t1.low {
+        std::mem::swap(&mut t0, &mut t1);
+    }
+    if t0.high > r.max_t || t1.low <= 0.0 {
+        return false;
+    }
+    let t = if t0.low > 0.0 {t0} else {t1};
+    if t.high > r.max_t {
+        return false;
+    }
+    r.max_t = t.v;
+    true
+}
+
+//Same steps as intersect_sphere in plain floats, which are what the middle
+//values of its intervals are. Wherever these miss, so do the bounds, so
+//most misses are found without the cost of tracking the error.
+fn sphere_misses(aux: &Vec3, d: &Vec3, radius: f32, max_t: f32) -> bool {
+    let a = d.x*d.x + d.y*d.y + d.z*d.z;
+    let b = (d.x*aux.x + d.y*aux.y + d.z*aux.z)*2.0;
+    let c = aux.x*aux.x + aux.y*aux.y + aux.z*aux.z - radius*radius;
+    let f = b/(a*2.0);
+    let v = Vec3::xyz(aux.x - f*d.x, aux.y - f*d.y, aux.z - f*d.z);
+    let l = (v.x*v.x + v.y*v.y + v.z*v.z).sqrt();
+    let discrim = a*4.0*(radius - l)*(radius + l);
+    if discrim < 0.0 {
+        return true;
+    }
+    let root = discrim.sqrt();
+    let q = if b < 0.0 {(b - root)*(-0.5)} else {(b + root)*(-0.5)};
+    let (t0, t1) = (q/a, c/q);
+    t0.max(t1) <= 0.0 || t0.min(t1) > max_t
+}
+
+//Moves a computed hit point onto the sphere. What error remains is that of
+//the projection and of adding the centre back (PBRT 3.9.4).
+pub(crate) fn reproject_on_sphere(o: &Vec3, radius: f32, x: &Vec3) -> (Vec3, Vec3) {
+    let rel = x - o;
+    let rel = rel*(radius/rel.len());
+    let p = o + &rel;
+    let p_error = (rel.abs()*gamma(5)) + (p.abs()*gamma(1));
+    (p, p_error)
+}
+
+
+//Rigid placement of a shape defined in its own space: the origin goes to
+//`origin`, z to the direction of `z` and x to the part of `x` orthogonal to it.
+//Fails if z is zero or x is parallel to it.
+fn frame(origin: &Vec3, x: &Vec3, z: &Vec3) -> Result<Transform, String> {
+    if !z.len().is_normal() || !origin.len().is_finite() {
+        return Err(String::from("the axis is zero or not finite"));
+    }
+    let z = z.normalized();
+    let x_len = x.len();
+    let x = x - &(z*x.dot(&z));
+    if !x_len.is_finite() || x.len() <= x_len*1e-6 {
+        return Err(String::from("the edges are parallel or zero"));
+    }
+    let x = x.normalized();
+    let y = z.cross(&x);
+    let r = Transform::from_linear(&Mat3::from_axis(&x, &y, &z)).ok_or("the frame is singular")?;
+    Ok(&Transform::translate(*origin)*&r)
+}
+
+//frame with z along axis and any x
+fn frame_around(origin: &Vec3, axis: &Vec3) -> Result<Transform, String> {
+    frame(origin, &orthogonal(*axis).1, axis)
+}
+
+//checks a length of a shape
+fn positive(name: &str, value: f32) -> Result<f32, String> {
+    if value > 0.0 && value.is_finite() {
+        Ok(value)
+    } else {
+        Err(format!("the {} must be positive, not {}", name, value))
+    }
+}
+
+//id of a shape from its placement and dimensions, see hash_floats
+fn frame_id(frame: &Transform, dims: &[f32]) -> usize {
+    let m = frame.matrix();
+    let mut values: Vec<f32> = (0..12).map(|i| *m.at_ro(i/4, i%4)).collect();
+    values.extend_from_slice(dims);
+    hash_floats(&values)
+}
+
+//Ray in the space of an object placed by `t`. The transformed origin is
+//moved forward along the ray by its rounding error, so that it doesn't end
+//up behind a surface the ray leaves (PBRT 3.9.5); dt is what to add to hit
+//distances in object space to get them in world space. The direction is
+//not normalized, so distances are otherwise the same.
+fn ray_to_object(t: &Transform, r: &Ray) -> (Ray, f32) {
+    let inv = t.inverse();
+    let (o, o_error) = inv.point_with_error(&r.o, &Vec3::new());
+    let d = inv.vector(&r.d);
+    let l2 = d.lensqr();
+    let dt = if l2 > 0.0 {d.abs().dot(&o_error)/l2} else {0.0};
+    (Ray{o: o + (d*dt), d, max_t: r.max_t - dt, time: r.time}, dt)
+}
+
+//hit of a shape in its own space, see world_hit
+struct LocalHit {
+    t: f32,
+    p: Vec3,
+    p_error: Vec3,
+    n: Vec3,
+    uv: Vec3
+}
+
+//Intersects a shape placed by `frame`. `hit` finds the nearest hit of a
+//ray in the shape's space within its max_t. Sets r.max_t and returns the
+//hit in world space.
+fn world_hit(frame: &Transform, r: &mut Ray, hit: impl Fn(&Ray) -> Option<LocalHit>) -> Option<LocalHit> {
+    let (local, dt) = ray_to_object(frame, r);
+    let h = to_world(frame, hit(&local)?, dt);
+    r.max_t = h.t;
+    Some(h)
+}
+
+fn to_world(frame: &Transform, h: LocalHit, dt: f32) -> LocalHit {
+    let (p, p_error) = placed_with_error(frame, &h.p, &h.p_error);
+    LocalHit{t: h.t + dt, p, p_error, n: frame.normal(&h.n).normalized(), uv: h.uv}
+}
+
+//Spans of a closed shape placed by `frame`, from the entry and exit hits
+//that `spans` finds along the whole line of a ray in the shape's space
+fn world_spans<'a>(frame: &Transform, r: &Ray, spans: impl Fn(&Ray) -> Vec<(LocalHit, LocalHit)>,
+                   mat: &'a dyn Material, object: usize) -> Vec<Span<'a>> {
+    let (local, dt) = ray_to_object(frame, r);
+    spans(&local).into_iter().map(|(a, b)| {
+        let (a, b) = (to_world(frame, a, dt), to_world(frame, b, dt));
+        Span{t0: a.t, t1: b.t, enter: shape_intersection(a, r, mat, object), exit: shape_intersection(b, r, mat, object)}
+    }).collect()
+}
+
+//Places a point of a shape with a rigid frame in the world. Rays leaving
+//it have their origins moved back into the frame, so its error bounds also
+//cover that rounding, which along the surface is more than moving them
+//forward in ray_to_object makes up for at grazing angles.
+fn placed_with_error(frame: &Transform, p: &Vec3, p_error: &Vec3) -> (Vec3, Vec3) {
+    let (p, p_error) = frame.point_with_error(p, p_error);
+    let back = frame.inverse().point_with_error(&p, &Vec3::new()).1;
+    (p, p_error + Vec3::from(back.len()))
+}
+
+fn shape_intersection<'a>(h: LocalHit, r: &Ray, mat: &'a dyn Material, object: usize) -> Intersection<'a> {
+    Intersection{
+        p: h.p,
+        p_error: h.p_error,
+        wo: r.d*(-1.0),
+        n: h.n,
+        uv: h.uv,
+        mat,
+        object,
+        dpdt: Vec3::new(),
+        interior: None
+    }
+}
+
+//point of a shape's surface in its own space, placed in the world
+fn world_point(frame: &Transform, p: &Vec3, n: &Vec3) -> SpawnPoint {
+    let (p, p_error) = placed_with_error(frame, p, &Vec3::new());
+    SpawnPoint{p, p_error, n: frame.normal(n).normalized()}
+}
+
+//Distance along the ray to the plane z = h in the shape's space, if within
+//(0, max_t). The plane is exact there, so the hit point's z can be set to h.
+fn hit_z_plane(r: &Ray, h: f32) -> Option<f32> {
+    if r.d.z == 0.0 {
+        return None;
+    }
+    let t = (h - r.o.z)/r.d.z;
+    if t > 0.0 && t < r.max_t {Some(t)} else {None}
+}
+
+//polar coordinates of a point in the xy plane as uv: angle and distance
+//over the radius, both in [0,1]
+fn disk_uv(p: &Vec3, radius: f32) -> Vec3 {
+    let u = p.y.atan2(p.x)/(2.0*PI) + 0.5;
+    Vec3::xyz(u, (p.x*p.x + p.y*p.y).sqrt()/radius, 0.0)
+}
+
+type SpanEnd = (f64, Option<f32>);
+
+//Stretch of a ray's line within 0 <= z <= height in a shape's space where
+//at^2 + bt + c <= 0, which for cylinders and cones is the inside of the
+//capped shape. Its ends are given as (t, z of the cap they are on, if not
+//on the side).
+fn quadric_span(a: f64, b: f64, c: f64, r: &Ray, height: f32) -> Option<(SpanEnd, SpanEnd)> {
+    let (oz, dz, h) = (r.o.z as f64, r.d.z as f64, height as f64);
+    let (z0, z1) = if dz == 0.0 {
+        if oz < 0.0 || oz > h {
+            return None;
         }
-        return it;
+        (f64::NEG_INFINITY, f64::INFINITY)
+    } else {
+        let (t0, t1) = (-oz/dz, (h - oz)/dz);
+        (t0.min(t1), t0.max(t1))
+    };
+    let mut ends: Vec<SpanEnd> = solve_quadratic_f64(a, b, c).into_iter()
+        .filter(|&t| t >= z0 && t <= z1).map(|t| (t, None)).collect();
+    for &t in [z0, z1].iter() {
+        if t.is_finite() && a*t*t + b*t + c <= 0.0 {
+            let cap = if (oz + t*dz) < h*0.5 {0.0} else {height};
+            ends.push((t, Some(cap)));
+        }
+    }
+    ends.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(std::cmp::Ordering::Equal));
+    let (first, last) = (*ends.first()?, *ends.last()?);
+    if first.0 < last.0 {Some((first, last))} else {None}
+}
+
+//Picks one of several parts by their areas with r, and rescales r to [0,1)
+//for sampling within the part
+fn pick_part(areas: &[f32], r: f32) -> (usize, f32) {
+    let total: f32 = areas.iter().sum();
+    let mut x = r*total;
+    for (i, &a) in areas.iter().enumerate() {
+        if x < a || i == areas.len() - 1 {
+            return (i, (x/a).clamp(0.0, 1.0 - f32::EPSILON));
+        }
+        x -= a;
+    }
+    (0, r)
+}
+
+//Primitives whose surface can be sampled uniformly by area, so that they
+//can be lights, see AreaLight
+pub trait Surface: Primitive {
+    fn area(&self) -> f32;
+    //uniform point on the surface, with its error bounds and normal
+    fn sample_area(&self, r1: f32, r2: f32) -> SpawnPoint;
+    //normal at p, if p lies on the surface
+    fn normal_at(&self, p: &Vec3) -> Option<Vec3>;
+    fn material(&self) -> &dyn Material;
+}
 
+//A shared primitive, e.g. a surface that is also a light
+impl<P: Primitive + ?Sized> Primitive for Arc<P> {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        (**self).intersect(r)
     }
 
-    
-    fn intersectAny(&self, r: &mut Ray) -> bool{
-        for p in &self.primitives{
-            if p.intersectAny(r){
-                return true;
-            }
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        (**self).intersect_any(r)
+    }
+
+    fn intersect_counted(&self, r: &mut Ray, cost: &mut u32) -> Option<Intersection<'_>> {
+        (**self).intersect_counted(r, cost)
+    }
+
+    fn bounds(&self) -> Aabb {
+        (**self).bounds()
+    }
+
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        (**self).spans(r)
+    }
+}
+
+
+//Infinite plane through o with normal n. Its uv are coordinates in the
+//plane, e.g. for tiled textures.
+#[derive(Clone)]
+pub struct Plane {
+    pub(crate) o: Vec3,
+    pub(crate) n: Vec3,
+    frame: Transform,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+impl Plane {
+    pub fn new(o: Vec3, n: Vec3, mat: Arc<dyn Material>) -> Result<Plane, String> {
+        let frame = frame_around(&o, &n)?;
+        Ok(Plane{o, n: n.normalized(), frame, mat, id: frame_id(&frame, &[])})
+    }
+
+    fn hit(r: &Ray) -> Option<LocalHit> {
+        let t = hit_z_plane(r, 0.0)?;
+        let p = r.o + (r.d*t);
+        Some(LocalHit{t, p: Vec3::xyz(p.x, p.y, 0.0), p_error: Vec3::new(), n: Vec3::xyz(0.0, 0.0, 1.0), uv: Vec3::xyz(p.x, p.y, 0.0)})
+    }
+}
+
+impl Primitive for Plane {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let h = world_hit(&self.frame, r, Plane::hit)?;
+        Some(shape_intersection(h, r, &*self.mat, self.id))
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        world_hit(&self.frame, r, Plane::hit).is_some()
+    }
+
+    //flat along an axis the plane is perpendicular to, infinite otherwise
+    fn bounds(&self) -> Aabb {
+        let mut b = Aabb{min: Vec3::from(f32::NEG_INFINITY), max: Vec3::from(f32::INFINITY)};
+        if self.n.x.abs() == 1.0 {
+            b.min.x = self.o.x;
+            b.max.x = self.o.x;
+        } else if self.n.y.abs() == 1.0 {
+            b.min.y = self.o.y;
+            b.max.y = self.o.y;
+        } else if self.n.z.abs() == 1.0 {
+            b.min.z = self.o.z;
+            b.max.z = self.o.z;
         }
-        return false;
+        b
+    }
+}
+
+
+//Disk of the given radius around o, facing along n
+#[derive(Clone)]
+pub struct Disk {
+    frame: Transform,
+    pub(crate) radius: f32,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+impl Disk {
+    pub fn new(o: Vec3, n: Vec3, radius: f32, mat: Arc<dyn Material>) -> Result<Disk, String> {
+        let frame = frame_around(&o, &n)?;
+        let radius = positive("radius", radius)?;
+        Ok(Disk{frame, radius, mat, id: frame_id(&frame, &[radius])})
+    }
+
+    fn hit(&self, r: &Ray) -> Option<LocalHit> {
+        let t = hit_z_plane(r, 0.0)?;
+        let p = r.o + (r.d*t);
+        if p.x*p.x + p.y*p.y > self.radius*self.radius {
+            return None;
+        }
+        let p = Vec3::xyz(p.x, p.y, 0.0);
+        Some(LocalHit{t, p, p_error: Vec3::new(), n: Vec3::xyz(0.0, 0.0, 1.0), uv: disk_uv(&p, self.radius)})
+    }
+}
+
+impl Primitive for Disk {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let h = world_hit(&self.frame, r, |l| self.hit(l))?;
+        Some(shape_intersection(h, r, &*self.mat, self.id))
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        world_hit(&self.frame, r, |l| self.hit(l)).is_some()
+    }
+
+    fn bounds(&self) -> Aabb {
+        transform_bounds(&self.frame, &Aabb{min: Vec3::xyz(-self.radius, -self.radius, 0.0), max: Vec3::xyz(self.radius, self.radius, 0.0)})
+    }
+}
+
+impl Surface for Disk {
+    fn area(&self) -> f32 {
+        PI*self.radius*self.radius
+    }
+
+    fn sample_area(&self, r1: f32, r2: f32) -> SpawnPoint {
+        let (x, y) = sample_disk_concentric(r1, r2);
+        world_point(&self.frame, &Vec3::xyz(x*self.radius, y*self.radius, 0.0), &Vec3::xyz(0.0, 0.0, 1.0))
+    }
+
+    fn normal_at(&self, p: &Vec3) -> Option<Vec3> {
+        let l = self.frame.inverse().point(p);
+        let tolerance = 1e-3*self.radius;
+        if l.z.abs() > tolerance || (l.x*l.x + l.y*l.y).sqrt() > self.radius + tolerance {
+            return None;
+        }
+        Some(self.frame.normal(&Vec3::xyz(0.0, 0.0, 1.0)).normalized())
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.mat
+    }
+}
+
+
+//Rectangle with a corner at o and the edges a and b, which should be
+//perpendicular; faces along a x b. uv go from 0 to 1 along the edges.
+#[derive(Clone)]
+pub struct Rect {
+    frame: Transform,
+    pub(crate) width: f32,
+    pub(crate) height: f32,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+impl Rect {
+    pub fn new(o: Vec3, a: Vec3, b: Vec3, mat: Arc<dyn Material>) -> Result<Rect, String> {
+        let (width, height) = (positive("width", a.len())?, positive("height", b.len())?);
+        let frame = frame(&o, &a, &a.cross(&b))?;
+        Ok(Rect{frame, width, height, mat, id: frame_id(&frame, &[width, height])})
+    }
+
+    //Rectangle between two corners that agree in one coordinate, facing the
+    //positive direction of that axis
+    pub fn axis_aligned(min: Vec3, max: Vec3, mat: Arc<dyn Material>) -> Result<Rect, String> {
+        let o = Vec3::xyz(min.x.min(max.x), min.y.min(max.y), min.z.min(max.z));
+        let e = (max - min).abs();
+        let (a, b) = if e.x <= e.y && e.x <= e.z {
+            (Vec3::xyz(0.0, e.y, 0.0), Vec3::xyz(0.0, 0.0, e.z))
+        } else if e.y <= e.z {
+            (Vec3::xyz(0.0, 0.0, e.z), Vec3::xyz(e.x, 0.0, 0.0))
+        } else {
+            (Vec3::xyz(e.x, 0.0, 0.0), Vec3::xyz(0.0, e.y, 0.0))
+        };
+        Rect::new(o, a, b, mat)
+    }
+
+    fn hit(&self, r: &Ray) -> Option<LocalHit> {
+        let t = hit_z_plane(r, 0.0)?;
+        let p = r.o + (r.d*t);
+        if p.x < 0.0 || p.x > self.width || p.y < 0.0 || p.y > self.height {
+            return None;
+        }
+        Some(LocalHit{t, p: Vec3::xyz(p.x, p.y, 0.0), p_error: Vec3::new(), n: Vec3::xyz(0.0, 0.0, 1.0),
+            uv: Vec3::xyz(p.x/self.width, p.y/self.height, 0.0)})
+    }
+}
+
+impl Primitive for Rect {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let h = world_hit(&self.frame, r, |l| self.hit(l))?;
+        Some(shape_intersection(h, r, &*self.mat, self.id))
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        world_hit(&self.frame, r, |l| self.hit(l)).is_some()
+    }
+
+    fn bounds(&self) -> Aabb {
+        transform_bounds(&self.frame, &Aabb{min: Vec3::new(), max: Vec3::xyz(self.width, self.height, 0.0)})
+    }
+}
+
+impl Surface for Rect {
+    fn area(&self) -> f32 {
+        self.width*self.height
+    }
+
+    fn sample_area(&self, r1: f32, r2: f32) -> SpawnPoint {
+        world_point(&self.frame, &Vec3::xyz(r1*self.width, r2*self.height, 0.0), &Vec3::xyz(0.0, 0.0, 1.0))
+    }
+
+    fn normal_at(&self, p: &Vec3) -> Option<Vec3> {
+        let l = self.frame.inverse().point(p);
+        let tolerance = 1e-3*self.width.max(self.height);
+        if l.z.abs() > tolerance || l.x < -tolerance || l.x > self.width + tolerance
+            || l.y < -tolerance || l.y > self.height + tolerance {
+            return None;
+        }
+        Some(self.frame.normal(&Vec3::xyz(0.0, 0.0, 1.0)).normalized())
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.mat
+    }
+}
+
+
+//Box with faces perpendicular to the axes of its frame. uv go from 0 to 1
+//across each face.
+#[derive(Clone)]
+pub struct Cuboid {
+    frame: Transform,
+    //half the size along each axis
+    pub(crate) half: Vec3,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+impl Cuboid {
+    //axis aligned, between two corners
+    pub fn new(min: Vec3, max: Vec3, mat: Arc<dyn Material>) -> Result<Cuboid, String> {
+        let centre = &(min + max)*0.5;
+        Cuboid::oriented(centre, Vec3::xyz(1.0, 0.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0), (max - min).abs(), mat)
     }
-}
\ No newline at end of file
+
+    //centred at o with the given size along the frame with x along `x`
+    //and z along `z`, see Rect for how they are made orthogonal
+    pub fn oriented(o: Vec3, x: Vec3, z: Vec3, size: Vec3, mat: Arc<dyn Material>) -> Result<Cuboid, String> {
+        let frame = frame(&o, &x, &z)?;
+        for &edge in [size.x, size.y, size.z].iter() {
+            positive("size", edge)?;
+        }
+        let half = size*0.5;
+        Ok(Cuboid{frame, half, mat, id: frame_id(&frame, &[half.x, half.y, half.z])})
+    }
+
+    fn face_hit(&self, r: &Ray, t: f32, axis: usize) -> LocalHit {
+        let p = r.o + (r.d*t);
+        let side = if r.o.get(axis) + r.d.get(axis)*t < 0.0 {-1.0} else {1.0};
+        let mut c = [p.x, p.y, p.z];
+        let mut n = [0.0; 3];
+        //exactly on the face
+        c[axis] = side*self.half.get(axis);
+        n[axis] = side;
+        let (a, b) = ((axis + 1)%3, (axis + 2)%3);
+        let uv = Vec3::xyz((c[a]/self.half.get(a) + 1.0)*0.5, (c[b]/self.half.get(b) + 1.0)*0.5, 0.0);
+        LocalHit{t, p: Vec3::xyz(c[0], c[1], c[2]), p_error: Vec3::new(), n: Vec3::xyz(n[0], n[1], n[2]), uv}
+    }
+
+    fn hit(&self, r: &Ray) -> Option<LocalHit> {
+        let (t0, axis0, t1, axis1) = self.slab(r)?;
+        if t0 > 0.0 && t0 < r.max_t {
+            return Some(self.face_hit(r, t0, axis0));
+        }
+        if t1 > 0.0 && t1 < r.max_t {
+            return Some(self.face_hit(r, t1, axis1));
+        }
+        None
+    }
+
+    fn local_spans(&self, r: &Ray) -> Vec<(LocalHit, LocalHit)> {
+        match self.slab(r) {
+            Some((t0, axis0, t1, axis1)) if t0 < t1 => vec![(self.face_hit(r, t0, axis0), self.face_hit(r, t1, axis1))],
+            _ => Vec::new()
+        }
+    }
+
+    //slab test over the whole line, keeping the axes of the entry and exit faces
+    fn slab(&self, r: &Ray) -> Option<(f32, usize, f32, usize)> {
+        let (mut t0, mut t1) = (f32::NEG_INFINITY, f32::INFINITY);
+        let (mut axis0, mut axis1) = (0, 0);
+        for axis in 0..3 {
+            let inv_d = 1.0/r.d.get(axis);
+            let o = r.o.get(axis);
+            let h = self.half.get(axis);
+            let mut near = (-h - o)*inv_d;
+            let mut far = (h - o)*inv_d;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            if near > t0 {
+                t0 = near;
+                axis0 = axis;
+            }
+            if far < t1 {
+                t1 = far;
+                axis1 = axis;
+            }
+        }
+        if t0 > t1 {
+            return None;
+        }
+        Some((t0, axis0, t1, axis1))
+    }
+
+    fn face_areas(&self) -> [f32; 3] {
+        let h = &self.half;
+        [8.0*h.y*h.z, 8.0*h.z*h.x, 8.0*h.x*h.y]
+    }
+}
+
+impl Primitive for Cuboid {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let h = world_hit(&self.frame, r, |l| self.hit(l))?;
+        Some(shape_intersection(h, r, &*self.mat, self.id))
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        world_hit(&self.frame, r, |l| self.hit(l)).is_some()
+    }
+
+    fn bounds(&self) -> Aabb {
+        transform_bounds(&self.frame, &Aabb{min: self.half*(-1.0), max: self.half})
+    }
+
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        Some(world_spans(&self.frame, r, |l| self.local_spans(l), &*self.mat, self.id))
+    }
+}
+
+impl Surface for Cuboid {
+    fn area(&self) -> f32 {
+        self.face_areas().iter().sum()
+    }
+
+    //a pair of opposite faces by area, then either of them
+    fn sample_area(&self, r1: f32, r2: f32) -> SpawnPoint {
+        let (axis, r1) = pick_part(&self.face_areas(), r1);
+        let (side, r1) = if r1 < 0.5 {(-1.0, r1*2.0)} else {(1.0, r1*2.0 - 1.0)};
+        let (a, b) = ((axis + 1)%3, (axis + 2)%3);
+        let mut c = [0.0; 3];
+        let mut n = [0.0; 3];
+        c[axis] = side*self.half.get(axis);
+        c[a] = (2.0*r1 - 1.0)*self.half.get(a);
+        c[b] = (2.0*r2 - 1.0)*self.half.get(b);
+        n[axis] = side;
+        world_point(&self.frame, &Vec3::xyz(c[0], c[1], c[2]), &Vec3::xyz(n[0], n[1], n[2]))
+    }
+
+    fn normal_at(&self, p: &Vec3) -> Option<Vec3> {
+        let l = self.frame.inverse().point(p);
+        let tolerance = 1e-3*self.half.x.max(self.half.y).max(self.half.z);
+        //the face the point is closest to, if it is within the box
+        let mut face = None;
+        let mut closest = tolerance;
+        for axis in 0..3 {
+            let d = l.get(axis).abs() - self.half.get(axis);
+            if d > tolerance {
+                return None;
+            }
+            if d.abs() <= closest {
+                closest = d.abs();
+                face = Some(axis);
+            }
+        }
+        let axis = face?;
+        let mut n = [0.0; 3];
+        n[axis] = if l.get(axis) < 0.0 {-1.0} else {1.0};
+        Some(self.frame.normal(&Vec3::xyz(n[0], n[1], n[2])).normalized())
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.mat
+    }
+}
+
+
+//Cylinder of the given radius from o along axis, which is as long as the
+//cylinder, optionally closed with disks at both ends. u goes around the
+//axis and v along it, or out from the centre on the caps.
+#[derive(Clone)]
+pub struct Cylinder {
+    frame: Transform,
+    pub(crate) radius: f32,
+    pub(crate) height: f32,
+    pub(crate) capped: bool,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+impl Cylinder {
+    pub fn new(o: Vec3, axis: Vec3, radius: f32, capped: bool, mat: Arc<dyn Material>) -> Result<Cylinder, String> {
+        let frame = frame_around(&o, &axis)?;
+        let (radius, height) = (positive("radius", radius)?, axis.len());
+        Ok(Cylinder{frame, radius, height, capped, mat,
+            id: frame_id(&frame, &[radius, height, if capped {1.0} else {0.0}])})
+    }
+
+    //the side's quadratic in f64 as for cones, the hit moved onto the side
+    fn hit(&self, r: &Ray) -> Option<LocalHit> {
+        let mut best = None;
+        let mut r = Ray{o: r.o, d: r.d, max_t: r.max_t, time: r.time};
+        let (ox, oy, oz) = (r.o.x as f64, r.o.y as f64, r.o.z as f64);
+        let (dx, dy, dz) = (r.d.x as f64, r.d.y as f64, r.d.z as f64);
+        let radius = self.radius as f64;
+        let roots = solve_quadratic_f64(dx*dx + dy*dy, 2.0*(dx*ox + dy*oy), ox*ox + oy*oy - radius*radius);
+        for &t in roots.iter() {
+            if t <= 0.0 || t >= r.max_t as f64 {
+                continue;
+            }
+            let z = oz + t*dz;
+            if z < 0.0 || z > self.height as f64 {
+                continue;
+            }
+            best = Some(self.side_hit(&r, t));
+            r.max_t = t as f32;
+            break;
+        }
+        if self.capped {
+            for &z in [0.0, self.height].iter() {
+                if let Some(t) = hit_z_plane(&r, z) {
+                    let p = r.o + (r.d*t);
+                    if p.x*p.x + p.y*p.y <= self.radius*self.radius {
+                        best = Some(self.cap_hit(&r, t, z));
+                        r.max_t = t;
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    fn side_hit(&self, r: &Ray, t: f64) -> LocalHit {
+        let x = r.o.x as f64 + t*r.d.x as f64;
+        let y = r.o.y as f64 + t*r.d.y as f64;
+        let z = r.o.z as f64 + t*r.d.z as f64;
+        let s = self.radius as f64/(x*x + y*y).sqrt();
+        let p = Vec3::xyz((x*s) as f32, (y*s) as f32, z as f32);
+        let n = Vec3::xyz(p.x/self.radius, p.y/self.radius, 0.0);
+        let uv = Vec3::xyz(p.y.atan2(p.x)/(2.0*PI) + 0.5, p.z/self.height, 0.0);
+        LocalHit{t: t as f32, p, p_error: p.abs()*gamma(2), n, uv}
+    }
+
+    fn cap_hit(&self, r: &Ray, t: f32, z: f32) -> LocalHit {
+        let p = r.o + (r.d*t);
+        let p = Vec3::xyz(p.x, p.y, z);
+        let nz = if z > 0.0 {1.0} else {-1.0};
+        LocalHit{t, p, p_error: Vec3::new(), n: Vec3::xyz(0.0, 0.0, nz), uv: disk_uv(&p, self.radius)}
+    }
+
+    fn local_spans(&self, r: &Ray) -> Vec<(LocalHit, LocalHit)> {
+        let (ox, oy) = (r.o.x as f64, r.o.y as f64);
+        let (dx, dy) = (r.d.x as f64, r.d.y as f64);
+        let radius = self.radius as f64;
+        let span = quadric_span(dx*dx + dy*dy, 2.0*(dx*ox + dy*oy), ox*ox + oy*oy - radius*radius, r, self.height);
+        let end = |(t, z): SpanEnd| match z {
+            Some(z) => self.cap_hit(r, t as f32, z),
+            None => self.side_hit(r, t)
+        };
+        span.map(|(a, b)| vec![(end(a), end(b))]).unwrap_or_default()
+    }
+
+    fn part_areas(&self) -> [f32; 3] {
+        let cap = if self.capped {PI*self.radius*self.radius} else {0.0};
+        [2.0*PI*self.radius*self.height, cap, cap]
+    }
+}
+
+impl Primitive for Cylinder {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let h = world_hit(&self.frame, r, |l| self.hit(l))?;
+        Some(shape_intersection(h, r, &*self.mat, self.id))
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        world_hit(&self.frame, r, |l| self.hit(l)).is_some()
+    }
+
+    fn bounds(&self) -> Aabb {
+        transform_bounds(&self.frame, &Aabb{min: Vec3::xyz(-self.radius, -self.radius, 0.0), max: Vec3::xyz(self.radius, self.radius, self.height)})
+    }
+
+    //only closed when capped
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        if !self.capped {
+            return None;
+        }
+        Some(world_spans(&self.frame, r, |l| self.local_spans(l), &*self.mat, self.id))
+    }
+}
+
+impl Surface for Cylinder {
+    fn area(&self) -> f32 {
+        self.part_areas().iter().sum()
+    }
+
+    fn sample_area(&self, r1: f32, r2: f32) -> SpawnPoint {
+        let (part, r1) = pick_part(&self.part_areas(), r1);
+        if part == 0 {
+            let phi = 2.0*PI*r2;
+            let n = Vec3::xyz(phi.cos(), phi.sin(), 0.0);
+            return world_point(&self.frame, &Vec3::xyz(n.x*self.radius, n.y*self.radius, r1*self.height), &n);
+        }
+        let (x, y) = sample_disk_concentric(r1, r2);
+        let (z, nz) = if part == 1 {(0.0, -1.0)} else {(self.height, 1.0)};
+        world_point(&self.frame, &Vec3::xyz(x*self.radius, y*self.radius, z), &Vec3::xyz(0.0, 0.0, nz))
+    }
+
+    fn normal_at(&self, p: &Vec3) -> Option<Vec3> {
+        let l = self.frame.inverse().point(p);
+        let tolerance = 1e-3*self.radius.max(self.height);
+        let rho = (l.x*l.x + l.y*l.y).sqrt();
+        if l.z < -tolerance || l.z > self.height + tolerance || rho > self.radius + tolerance {
+            return None;
+        }
+        //the nearest part
+        let side = (rho - self.radius).abs();
+        let cap = if self.capped {l.z.abs().min((l.z - self.height).abs())} else {f32::INFINITY};
+        let n = if side <= cap && side <= tolerance {
+            Vec3::xyz(l.x/rho, l.y/rho, 0.0)
+        } else if cap <= tolerance {
+            Vec3::xyz(0.0, 0.0, if l.z < self.height*0.5 {-1.0} else {1.0})
+        } else {
+            return None;
+        };
+        Some(self.frame.normal(&n).normalized())
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.mat
+    }
+}
+
+
+//Cone with its base of the given radius around o and its apex at o + axis,
+//optionally closed at the base. uv as for Cylinder.
+#[derive(Clone)]
+pub struct Cone {
+    frame: Transform,
+    pub(crate) radius: f32,
+    pub(crate) height: f32,
+    pub(crate) capped: bool,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+impl Cone {
+    pub fn new(o: Vec3, axis: Vec3, radius: f32, capped: bool, mat: Arc<dyn Material>) -> Result<Cone, String> {
+        let frame = frame_around(&o, &axis)?;
+        let (radius, height) = (positive("radius", radius)?, axis.len());
+        Ok(Cone{frame, radius, height, capped, mat,
+            id: frame_id(&frame, &[radius, height, if capped {1.0} else {0.0}])})
+    }
+
+    //outward normal of the side at a point of it
+    fn side_normal(&self, p: &Vec3) -> Vec3 {
+        let k = self.radius*self.radius/(self.height*self.height);
+        Vec3::xyz(p.x, p.y, k*(self.height - p.z)).normalized()
+    }
+
+    //The side's quadratic is solved in f64: its roots, and so the hit point,
+    //are then much more accurate than f32, and the point's error is that of
+    //rounding it. Interval bounds would be very loose by the apex, where
+    //both roots come close.
+    fn hit(&self, r: &Ray) -> Option<LocalHit> {
+        let mut best = None;
+        let mut r = Ray{o: r.o, d: r.d, max_t: r.max_t, time: r.time};
+        let (oz, dz, h) = (r.o.z as f64, r.d.z as f64, self.height as f64);
+        let (a, b, c) = self.quadratic(&r);
+        let roots = solve_quadratic_f64(a, b, c);
+        for &t in roots.iter() {
+            if t <= 0.0 || t >= r.max_t as f64 {
+                continue;
+            }
+            let pz = oz + t*dz;
+            //not on the mirrored cone beyond the apex
+            if pz < 0.0 || pz > h {
+                continue;
+            }
+            best = Some(self.side_hit(&r, t));
+            r.max_t = t as f32;
+            break;
+        }
+        if self.capped {
+            if let Some(t) = hit_z_plane(&r, 0.0) {
+                let p = r.o + (r.d*t);
+                if p.x*p.x + p.y*p.y <= self.radius*self.radius {
+                    best = Some(self.base_hit(&r, t));
+                }
+            }
+        }
+        best
+    }
+
+    //coefficients of the side's quadratic, see hit
+    fn quadratic(&self, r: &Ray) -> (f64, f64, f64) {
+        let (ox, oy, oz) = (r.o.x as f64, r.o.y as f64, r.o.z as f64);
+        let (dx, dy, dz) = (r.d.x as f64, r.d.y as f64, r.d.z as f64);
+        let h = self.height as f64;
+        let k = (self.radius as f64/h)*(self.radius as f64/h);
+        let zh = oz - h;
+        (dx*dx + dy*dy - k*dz*dz, 2.0*(dx*ox + dy*oy - k*dz*zh), ox*ox + oy*oy - k*zh*zh)
+    }
+
+    fn side_hit(&self, r: &Ray, t: f64) -> LocalHit {
+        let p = Vec3::xyz((r.o.x as f64 + t*r.d.x as f64) as f32, (r.o.y as f64 + t*r.d.y as f64) as f32,
+                          (r.o.z as f64 + t*r.d.z as f64) as f32);
+        let uv = Vec3::xyz(p.y.atan2(p.x)/(2.0*PI) + 0.5, p.z/self.height, 0.0);
+        LocalHit{t: t as f32, p, p_error: p.abs()*gamma(2), n: self.side_normal(&p), uv}
+    }
+
+    fn base_hit(&self, r: &Ray, t: f32) -> LocalHit {
+        let p = r.o + (r.d*t);
+        let p = Vec3::xyz(p.x, p.y, 0.0);
+        LocalHit{t, p, p_error: Vec3::new(), n: Vec3::xyz(0.0, 0.0, -1.0), uv: disk_uv(&p, self.radius)}
+    }
+
+    fn local_spans(&self, r: &Ray) -> Vec<(LocalHit, LocalHit)> {
+        let (a, b, c) = self.quadratic(r);
+        //an end on the top "cap" is through the apex
+        let end = |(t, z): SpanEnd| match z {
+            Some(0.0) => self.base_hit(r, t as f32),
+            _ => self.side_hit(r, t)
+        };
+        quadric_span(a, b, c, r, self.height).map(|(a, b)| vec![(end(a), end(b))]).unwrap_or_default()
+    }
+
+    fn part_areas(&self) -> [f32; 2] {
+        let slant = (self.radius*self.radius + self.height*self.height).sqrt();
+        [PI*self.radius*slant, if self.capped {PI*self.radius*self.radius} else {0.0}]
+    }
+}
+
+impl Primitive for Cone {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let h = world_hit(&self.frame, r, |l| self.hit(l))?;
+        Some(shape_intersection(h, r, &*self.mat, self.id))
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        world_hit(&self.frame, r, |l| self.hit(l)).is_some()
+    }
+
+    fn bounds(&self) -> Aabb {
+        transform_bounds(&self.frame, &Aabb{min: Vec3::xyz(-self.radius, -self.radius, 0.0), max: Vec3::xyz(self.radius, self.radius, self.height)})
+    }
+
+    //only closed when capped
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        if !self.capped {
+            return None;
+        }
+        Some(world_spans(&self.frame, r, |l| self.local_spans(l), &*self.mat, self.id))
+    }
+}
+
+impl Surface for Cone {
+    fn area(&self) -> f32 {
+        self.part_areas().iter().sum()
+    }
+
+    //on the side, the distance from the apex has a linear density
+    fn sample_area(&self, r1: f32, r2: f32) -> SpawnPoint {
+        let (part, r1) = pick_part(&self.part_areas(), r1);
+        if part == 0 {
+            let rho = self.radius*r1.sqrt();
+            let phi = 2.0*PI*r2;
+            let p = Vec3::xyz(rho*phi.cos(), rho*phi.sin(), self.height*(1.0 - rho/self.radius));
+            return world_point(&self.frame, &p, &self.side_normal(&p));
+        }
+        let (x, y) = sample_disk_concentric(r1, r2);
+        world_point(&self.frame, &Vec3::xyz(x*self.radius, y*self.radius, 0.0), &Vec3::xyz(0.0, 0.0, -1.0))
+    }
+
+    fn normal_at(&self, p: &Vec3) -> Option<Vec3> {
+        let l = self.frame.inverse().point(p);
+        let tolerance = 1e-3*self.radius.max(self.height);
+        if l.z < -tolerance || l.z > self.height + tolerance {
+            return None;
+        }
+        let rho = (l.x*l.x + l.y*l.y).sqrt();
+        //distances to the side, along its normal, and to the base
+        let slant = (self.radius*self.radius + self.height*self.height).sqrt();
+        let side = (rho - self.radius*(self.height - l.z)/self.height).abs()*self.height/slant;
+        let base = if self.capped && rho <= self.radius + tolerance {l.z.abs()} else {f32::INFINITY};
+        if side <= base && side <= tolerance {
+            return Some(self.frame.normal(&self.side_normal(&l)).normalized());
+        }
+        if base <= tolerance {
+            return Some(self.frame.normal(&Vec3::xyz(0.0, 0.0, -1.0)).normalized());
+        }
+        None
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.mat
+    }
+}
+
+
+//Torus around o in the plane perpendicular to axis: a tube of the minor
+//radius around a circle of the major radius, which must be larger. u goes
+//around the axis and v around the tube.
+#[derive(Clone)]
+pub struct Torus {
+    frame: Transform,
+    pub(crate) major: f32,
+    pub(crate) minor: f32,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+impl Torus {
+    pub fn new(o: Vec3, axis: Vec3, major: f32, minor: f32, mat: Arc<dyn Material>) -> Result<Torus, String> {
+        let frame = frame_around(&o, &axis)?;
+        let (major, minor) = (positive("major radius", major)?, positive("minor radius", minor)?);
+        if minor >= major {
+            return Err(format!("the minor radius {} must be smaller than the major radius {}", minor, major));
+        }
+        Ok(Torus{frame, major, minor, mat, id: frame_id(&frame, &[major, minor])})
+    }
+
+    fn normal(&self, p: &Vec3) -> Vec3 {
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+        let s = self.major/rho;
+        Vec3::xyz(p.x - p.x*s, p.y - p.y*s, p.z).normalized()
+    }
+
+    //Roots of the torus' quartic in f64. The origin is first moved to where
+    //the ray enters the bounding sphere, which keeps the coefficients small.
+    //The hit point's error is its distance to the surface, estimated from
+    //the implicit function, and the rounding to f32.
+    fn hit(&self, r: &Ray) -> Option<LocalHit> {
+        let t = self.roots(r, 0.0).into_iter().find(|&t| t > 0.0 && t < r.max_t as f64)?;
+        Some(self.hit_at(r, t))
+    }
+
+    //Roots from `from` on, sorted. Between consecutive ones, the ray is
+    //either inside or outside.
+    fn roots(&self, r: &Ray, from: f64) -> Vec<f64> {
+        let (big, small) = (self.major as f64, self.minor as f64);
+        let o = [r.o.x as f64, r.o.y as f64, r.o.z as f64];
+        let d = [r.d.x as f64, r.d.y as f64, r.d.z as f64];
+        let dot = |a: &[f64; 3], b: &[f64; 3]| a[0]*b[0] + a[1]*b[1] + a[2]*b[2];
+        let dd = dot(&d, &d);
+        let od = dot(&o, &d);
+        let bound = big + small;
+        let discrim = od*od - dd*(dot(&o, &o) - bound*bound);
+        if discrim < 0.0 || dd == 0.0 {
+            return Vec::new();
+        }
+        let exit = (-od + discrim.sqrt())/dd;
+        if exit <= from {
+            return Vec::new();
+        }
+        let shift = ((-od - discrim.sqrt())/dd).max(from);
+        let p = [o[0] + d[0]*shift, o[1] + d[1]*shift, o[2] + d[2]*shift];
+
+        //(|p|^2 - R^2 - r^2)^2 = 4R^2(r^2 - z^2)
+        let e = dot(&p, &p) - big*big - small*small;
+        let f = dot(&p, &d);
+        let four_r2 = 4.0*big*big;
+        let roots = solve_quartic([
+            e*e - four_r2*(small*small - p[2]*p[2]),
+            4.0*f*e + 2.0*four_r2*p[2]*d[2],
+            2.0*dd*e + 4.0*f*f + four_r2*d[2]*d[2],
+            4.0*dd*f,
+            dd*dd
+        ]);
+        roots.iter().map(|t| t + shift).collect()
+    }
+
+    fn hit_at(&self, r: &Ray, t: f64) -> LocalHit {
+        let (big, small) = (self.major as f64, self.minor as f64);
+        let o = [r.o.x as f64, r.o.y as f64, r.o.z as f64];
+        let d = [r.d.x as f64, r.d.y as f64, r.d.z as f64];
+        let dot = |a: &[f64; 3], b: &[f64; 3]| a[0]*b[0] + a[1]*b[1] + a[2]*b[2];
+        let four_r2 = 4.0*big*big;
+        let h = [o[0] + d[0]*t, o[1] + d[1]*t, o[2] + d[2]*t];
+        let s = dot(&h, &h) + big*big - small*small;
+        let value = s*s - four_r2*(h[0]*h[0] + h[1]*h[1]);
+        let grad = [4.0*s*h[0] - 2.0*four_r2*h[0], 4.0*s*h[1] - 2.0*four_r2*h[1], 4.0*s*h[2]];
+        let dist = (value.abs()/dot(&grad, &grad).sqrt()) as f32;
+        let p = Vec3::xyz(h[0] as f32, h[1] as f32, h[2] as f32);
+        let p_error = Vec3::from(dist + (self.major + self.minor)*gamma(2)) + (p.abs()*gamma(1));
+
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+        let uv = Vec3::xyz(p.y.atan2(p.x)/(2.0*PI) + 0.5, p.z.atan2(rho - self.major)/(2.0*PI) + 0.5, 0.0);
+        LocalHit{t: t as f32, p, p_error, n: self.normal(&p), uv}
+    }
+
+    //pairs of consecutive roots with the inside of the tube between them
+    fn local_spans(&self, r: &Ray) -> Vec<(LocalHit, LocalHit)> {
+        let roots = self.roots(r, f64::NEG_INFINITY);
+        let inside = |t: f64| {
+            let p = r.o + (r.d*(t as f32));
+            let rho = (p.x*p.x + p.y*p.y).sqrt();
+            (rho - self.major)*(rho - self.major) + p.z*p.z < self.minor*self.minor
+        };
+        roots.windows(2).filter(|w| w[0] < w[1] && inside(0.5*(w[0] + w[1])))
+            .map(|w| (self.hit_at(r, w[0]), self.hit_at(r, w[1]))).collect()
+    }
+}
+
+impl Primitive for Torus {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let h = world_hit(&self.frame, r, |l| self.hit(l))?;
+        Some(shape_intersection(h, r, &*self.mat, self.id))
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        world_hit(&self.frame, r, |l| self.hit(l)).is_some()
+    }
+
+    fn bounds(&self) -> Aabb {
+        let outer = self.major + self.minor;
+        transform_bounds(&self.frame, &Aabb{min: Vec3::xyz(-outer, -outer, -self.minor), max: Vec3::xyz(outer, outer, self.minor)})
+    }
+
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        Some(world_spans(&self.frame, r, |l| self.local_spans(l), &*self.mat, self.id))
+    }
+}
+
+impl Surface for Torus {
+    fn area(&self) -> f32 {
+        4.0*PI*PI*self.major*self.minor
+    }
+
+    //The area around the tube grows with the distance from the axis,
+    //R + r cos(phi); its distribution is inverted with Newton's method.
+    fn sample_area(&self, r1: f32, r2: f32) -> SpawnPoint {
+        let theta = 2.0*PI*r1;
+        let target = 2.0*PI*self.major*r2;
+        let mut phi = 2.0*PI*r2;
+        for _ in 0..8 {
+            let g = self.major*phi + self.minor*phi.sin() - target;
+            phi = (phi - g/(self.major + self.minor*phi.cos())).clamp(0.0, 2.0*PI);
+        }
+        let n = Vec3::xyz(phi.cos()*theta.cos(), phi.cos()*theta.sin(), phi.sin());
+        let rho = self.major + self.minor*phi.cos();
+        let p = Vec3::xyz(rho*theta.cos(), rho*theta.sin(), self.minor*phi.sin());
+        world_point(&self.frame, &p, &n)
+    }
+
+    fn normal_at(&self, p: &Vec3) -> Option<Vec3> {
+        let l = self.frame.inverse().point(p);
+        let rho = (l.x*l.x + l.y*l.y).sqrt();
+        let tube = ((rho - self.major)*(rho - self.major) + l.z*l.z).sqrt();
+        if (tube - self.minor).abs() > 1e-3*self.minor {
+            return None;
+        }
+        Some(self.frame.normal(&self.normal(&l)).normalized())
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.mat
+    }
+}
+
+
+impl Primitive for SimpleGroup{
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
+    fn intersect(&self, r:&mut Ray) -> Option<Intersection<'_>> {
+        let mut it = None;
+        for p in &self.primitives{
+            let tmp = p.intersect(r);
+            if tmp.is_some(){
+                it = tmp;
+            }
+           // println!("Testing intersection: {}",!it.is_none());
+            
+        }
+        return it;
+
+    }
+
+    fn intersect_counted(&self, r:&mut Ray, cost: &mut u32) -> Option<Intersection<'_>> {
+        let mut it = None;
+        for p in &self.primitives{
+            let tmp = p.intersect_counted(r, cost);
+            if tmp.is_some(){
+                it = tmp;
+            }
+        }
+        it
+    }
+
+    
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
+    fn intersect_any(&self, r: &mut Ray) -> bool{
+        for p in &self.primitives{
+            if p.intersect_any(r){
+                return true;
+            }
+        }
+        return false;
+    }
+
+    fn bounds(&self) -> Aabb {
+        let mut b = Aabb::empty();
+        for p in &self.primitives {
+            b = b.union(&p.bounds());
+        }
+        b
+    }
+}
+
+
+struct BvhNode {
+    bounds: Aabb,
+    //leaves: range of primitives; interior nodes have count 0,
+    //the left child right after them and the right child at `start`
+    start: usize,
+    count: usize
+}
+
+//bounding volume hierarchy; build_index must be called after the last add
+pub struct Bvh {
+    primitives: Vec<Box<dyn Primitive>>,
+    nodes: Vec<BvhNode>
+}
+
+impl Default for Bvh {
+    fn default() -> Bvh {
+        Bvh::new()
+    }
+}
+
+impl Bvh {
+    pub fn new() -> Bvh {
+        Bvh{primitives: Vec::new(), nodes: Vec::new()}
+    }
+
+    //recursively splits at the median centroid of the widest axis
+    fn build(&mut self, order: &mut [usize], bounds: &[Aabb], offset: usize) -> usize {
+        let node = self.nodes.len();
+        let mut b = Aabb::empty();
+        for &i in order.iter() {
+            b = b.union(&bounds[i]);
+        }
+        self.nodes.push(BvhNode{bounds: b, start: offset, count: order.len()});
+        if order.len() <= 2 {
+            return node;
+        }
+
+        let mut c = Aabb::empty();
+        for &i in order.iter() {
+            let p = bounds[i].centroid();
+            c = c.union(&Aabb{min: p, max: p});
+        }
+        let extent = c.max - c.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {0} else if extent.y > extent.z {1} else {2};
+        order.sort_by(|&i, &j| {
+            Aabb::axis(&bounds[i].centroid(), axis)
+                .partial_cmp(&Aabb::axis(&bounds[j].centroid(), axis))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let mid = order.len()/2;
+        let (left, right) = order.split_at_mut(mid);
+        self.build(left, bounds, offset);
+        let right_node = self.build(right, bounds, offset + mid);
+        self.nodes[node].start = right_node;
+        self.nodes[node].count = 0;
+        node
+    }
+}
+
+impl Group for Bvh {
+    fn add(&mut self, p: Box<dyn Primitive>){
+        self.primitives.push(p);
+        self.nodes.clear();
+    }
+
+    fn build_index(&mut self){
+        self.nodes.clear();
+        if self.primitives.is_empty() {
+            return;
+        }
+        let bounds: Vec<Aabb> = self.primitives.iter().map(|p| p.bounds()).collect();
+        let mut order: Vec<usize> = (0..self.primitives.len()).collect();
+        self.build(&mut order, &bounds, 0);
+
+        //store the primitives in leaf order
+        let mut items: Vec<Option<Box<dyn Primitive>>> = self.primitives.drain(..).map(Some).collect();
+        self.primitives = order.iter().map(|&i| items[i].take().unwrap()).collect();
+    }
+}
+
+impl Primitive for Bvh{
+    fn intersect(&self, r:&mut Ray) -> Option<Intersection<'_>> {
+        let mut cost = 0;
+        self.intersect_counted(r, &mut cost)
+    }
+
+    fn intersect_counted(&self, r:&mut Ray, cost: &mut u32) -> Option<Intersection<'_>> {
+        let mut it = None;
+        if self.nodes.is_empty() {
+            return it;
+        }
+        let mut stack = vec![0];
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            *cost += 1;
+            if !node.bounds.hit(r) {
+                continue;
+            }
+            if node.count > 0 {
+                for p in &self.primitives[node.start..node.start+node.count] {
+                    let tmp = p.intersect_counted(r, cost);
+                    if tmp.is_some(){
+                        it = tmp;
+                    }
+                }
+            } else {
+                stack.push(node.start);
+                stack.push(n+1);
+            }
+        }
+        it
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool{
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let mut stack = vec![0];
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            if !node.bounds.hit(r) {
+                continue;
+            }
+            if node.count > 0 {
+                for p in &self.primitives[node.start..node.start+node.count] {
+                    if p.intersect_any(r){
+                        return true;
+                    }
+                }
+            } else {
+                stack.push(node.start);
+                stack.push(n+1);
+            }
+        }
+        false
+    }
+
+    fn bounds(&self) -> Aabb {
+        match self.nodes.first() {
+            Some(n) => n.bounds,
+            None => Aabb::empty()
+        }
+    }
+}
+
+
+
+//A shared primitive placed in the scene with an affine transform, so that
+//many copies of e.g. a BVH can be drawn with a single copy in memory.
+//The transform may be keyframed, in which case it is evaluated at the ray's time.
+pub struct Instance {
+    pub(crate) prim: Arc<dyn Primitive>,
+    //object to world
+    pub(crate) transform: AnimatedTransform,
+    //distinguishes the copies of prim
+    id: usize
+}
+
+impl Instance {
+    pub fn new(prim: Arc<dyn Primitive>, transform: Transform) -> Instance {
+        Instance::animated(prim, AnimatedTransform::fixed(transform))
+    }
+
+    pub fn animated(prim: Arc<dyn Primitive>, transform: AnimatedTransform) -> Instance {
+        //placement of the frame at the start of the motion
+        let t = transform.interpolate(transform.time_range().0);
+        let o = t.point(&Vec3::new());
+        let x = t.vector(&Vec3::xyz(1.0, 0.0, 0.0));
+        let y = t.vector(&Vec3::xyz(0.0, 1.0, 0.0));
+        let z = t.vector(&Vec3::xyz(0.0, 0.0, 1.0));
+        let id = hash_floats(&[o.x, o.y, o.z, x.x, x.y, x.z, y.x, y.y, y.z, z.x, z.y, z.z]);
+        Instance{prim, transform, id}
+    }
+
+    //hit of the primitive in object space, placed by t as at the ray's time
+    fn to_world<'a>(&self, t: &Transform, it: Intersection<'a>, r: &Ray) -> Intersection<'a> {
+        let mut dpdt = t.vector(&it.dpdt);
+        if self.transform.is_animated() {
+            //central difference of the transformed point
+            let h = 1e-3*(self.transform.time_range().1 - self.transform.time_range().0).max(1e-3);
+            let a = self.transform.interpolate(r.time - h).point(&it.p);
+            let b = self.transform.interpolate(r.time + h).point(&it.p);
+            dpdt = dpdt + (&(b - a)/(2.0*h));
+        }
+        let (p, p_error) = t.point_with_error(&it.p, &it.p_error);
+        Intersection{
+            p,
+            p_error,
+            wo: r.d*(-1.0),
+            n: t.normal(&it.n).normalized(),
+            uv: it.uv,
+            mat: it.mat,
+            //copies of the same primitive get different ids
+            object: it.object.wrapping_mul(31) ^ self.id,
+            dpdt,
+            interior: it.interior
+        }
+    }
+}
+
+fn transform_bounds(t: &Transform, b: &Aabb) -> Aabb {
+    let mut out = Aabb::empty();
+    for i in 0..8 {
+        let corner = Vec3::xyz(if i&1 == 0 {b.min.x} else {b.max.x},
+                               if i&2 == 0 {b.min.y} else {b.max.y},
+                               if i&4 == 0 {b.min.z} else {b.max.z});
+        let p = t.point(&corner);
+        out = out.union(&Aabb{min: p, max: p});
+    }
+    out
+}
+
+impl Primitive for Instance {
+    fn intersect(&self, r:&mut Ray) -> Option<Intersection<'_>> {
+        let mut cost = 0;
+        self.intersect_counted(r, &mut cost)
+    }
+
+    fn intersect_counted(&self, r:&mut Ray, cost: &mut u32) -> Option<Intersection<'_>> {
+        let t = self.transform.interpolate(r.time);
+        let (mut local, dt) = ray_to_object(&t, r);
+        let it = self.prim.intersect_counted(&mut local, cost)?;
+        r.max_t = local.max_t + dt;
+        Some(self.to_world(&t, it, r))
+    }
+
+    fn intersect_any(&self, r:&mut Ray) -> bool {
+        let t = self.transform.interpolate(r.time);
+        let (mut local, dt) = ray_to_object(&t, r);
+        if self.prim.intersect_any(&mut local) {
+            r.max_t = local.max_t + dt;
+            return true;
+        }
+        false
+    }
+
+    //for keyframed transforms, the union of the bounds at several times
+    //over the motion, padded to cover rotation between the samples
+    fn bounds(&self) -> Aabb {
+        let b = self.prim.bounds();
+        if !self.transform.is_animated() {
+            return transform_bounds(&self.transform.interpolate(0.0), &b);
+        }
+        let (t0, t1) = self.transform.time_range();
+        let steps = 32;
+        let mut out = Aabb::empty();
+        for i in 0..steps+1 {
+            let time = t0 + (t1-t0)*(i as f32/steps as f32);
+            out = out.union(&transform_bounds(&self.transform.interpolate(time), &b));
+        }
+        let pad = (out.max - out.min)*(1.0/steps as f32);
+        Aabb{min: out.min - pad, max: out.max + pad}
+    }
+
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        let t = self.transform.interpolate(r.time);
+        let (local, dt) = ray_to_object(&t, r);
+        let spans = self.prim.spans(&local)?;
+        Some(spans.into_iter().map(|s| Span{
+            t0: s.t0 + dt,
+            enter: self.to_world(&t, s.enter, r),
+            t1: s.t1 + dt,
+            exit: self.to_world(&t, s.exit, r)
+        }).collect())
+    }
+}
+
+
+
+//Fills a closed primitive with a participating medium. Give the primitive an
+//InterfaceMaterial for a boundary that only separates media (e.g. fog), or
+//any other material for a surface with a medium behind it.
+pub struct MediumBoundary {
+    pub(crate) prim: Box<dyn Primitive>,
+    pub(crate) interior: Arc<dyn Medium>
+}
+
+impl MediumBoundary {
+    pub fn new(prim: Box<dyn Primitive>, interior: Arc<dyn Medium>) -> MediumBoundary {
+        MediumBoundary{prim, interior}
+    }
+}
+
+impl Primitive for MediumBoundary {
+    fn intersect(&self, r:&mut Ray) -> Option<Intersection<'_>> {
+        let mut it = self.prim.intersect(r)?;
+        it.interior = Some(&*self.interior);
+        Some(it)
+    }
+
+    fn intersect_counted(&self, r:&mut Ray, cost: &mut u32) -> Option<Intersection<'_>> {
+        let mut it = self.prim.intersect_counted(r, cost)?;
+        it.interior = Some(&*self.interior);
+        Some(it)
+    }
+
+    fn intersect_any(&self, r:&mut Ray) -> bool {
+        self.prim.intersect_any(r)
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.prim.bounds()
+    }
+
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        let mut spans = self.prim.spans(r)?;
+        for s in spans.iter_mut() {
+            s.enter.interior = Some(&*self.interior);
+            s.exit.interior = Some(&*self.interior);
+        }
+        Some(spans)
+    }
+}
+
+//how a Csg node combines its two children
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    //the first child without the second
+    Difference
+}
+
+//Constructive solid geometry: the union, intersection or difference of two
+//closed primitives, found by merging the spans of the ray inside each. The
+//node is closed itself, so nodes can be nested. Where the second child's
+//surface bounds a difference, its normal is flipped to point out of the
+//result. Surfaces keep the materials of their children.
+pub struct Csg {
+    pub(crate) op: CsgOp,
+    pub(crate) a: Box<dyn Primitive>,
+    pub(crate) b: Box<dyn Primitive>
+}
+
+impl Csg {
+    pub fn new(op: CsgOp, a: Box<dyn Primitive>, b: Box<dyn Primitive>) -> Result<Csg, String> {
+        //whether a primitive has spans doesn't depend on the ray
+        let probe = Ray::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0));
+        if a.spans(&probe).is_none() || b.spans(&probe).is_none() {
+            return Err(String::from("CSG needs closed primitives, e.g. spheres, boxes, capped cylinders or tori"));
+        }
+        Ok(Csg{op, a, b})
+    }
+
+    fn inside(&self, in_a: bool, in_b: bool) -> bool {
+        match self.op {
+            CsgOp::Union => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference => in_a && !in_b
+        }
+    }
+
+    //Goes through the ends of the children's spans in order, starting a span
+    //of the result where it becomes inside and ending it where it stops
+    fn combine(&self, r: &Ray) -> Vec<Span<'_>> {
+        //(t, of b, entering, surface)
+        let mut ends = Vec::new();
+        for (of_b, spans) in [(false, self.a.spans(r)), (true, self.b.spans(r))].iter() {
+            for s in spans.iter().flatten() {
+                ends.push((s.t0, *of_b, true, s.enter));
+                ends.push((s.t1, *of_b, false, s.exit));
+            }
+        }
+        ends.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut out = Vec::new();
+        let (mut in_a, mut in_b) = (false, false);
+        let mut start = None;
+        for (t, of_b, entering, mut it) in ends {
+            let was = self.inside(in_a, in_b);
+            if of_b {in_b = entering} else {in_a = entering}
+            let now = self.inside(in_a, in_b);
+            if was == now {
+                continue;
+            }
+            if of_b && self.op == CsgOp::Difference {
+                it.n = it.n*(-1.0);
+            }
+            if now {
+                start = Some((t, it));
+            } else if let Some((t0, enter)) = start.take() {
+                if t0 < t {
+                    out.push(Span{t0, enter, t1: t, exit: it});
+                }
+            }
+        }
+        out
+    }
+
+    //first end of a span within (0, max_t)
+    fn nearest<'a>(&self, spans: &[Span<'a>], r: &Ray) -> Option<(f32, Intersection<'a>)> {
+        for s in spans {
+            for &(t, it) in [(s.t0, s.enter), (s.t1, s.exit)].iter() {
+                if t > 0.0 {
+                    return if t < r.max_t {Some((t, it))} else {None};
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Primitive for Csg {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let (t, it) = self.nearest(&self.combine(r), r)?;
+        r.max_t = t;
+        Some(it)
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        self.intersect(r).is_some()
+    }
+
+    //the children's bounds for unions, their overlap for intersections and
+    //the first child's for differences
+    fn bounds(&self) -> Aabb {
+        let (a, b) = (self.a.bounds(), self.b.bounds());
+        match self.op {
+            CsgOp::Union => a.union(&b),
+            CsgOp::Intersection => {
+                let c = Aabb{
+                    min: Vec3::xyz(a.min.x.max(b.min.x), a.min.y.max(b.min.y), a.min.z.max(b.min.z)),
+                    max: Vec3::xyz(a.max.x.min(b.max.x), a.max.y.min(b.max.y), a.max.z.min(b.max.z))
+                };
+                //children that don't overlap leave nothing
+                if c.min.x > c.max.x || c.min.y > c.max.y || c.min.z > c.max.z {
+                    Aabb::empty()
+                } else {
+                    c
+                }
+            }
+            CsgOp::Difference => a
+        }
+    }
+
+    fn spans(&self, r: &Ray) -> Option<Vec<Span<'_>>> {
+        Some(self.combine(r))
+    }
+}
+
+//Implicit surface where an SdfExpr is zero, found by sphere tracing: rays
+//advance by the distance to the surface, as bounded by the function, until
+//they are within a small fraction of the shape's size of it. Normals are
+//central differences of the function. Copies can be placed with Instance.
+pub struct SdfPrimitive {
+    pub(crate) expr: SdfExpr,
+    bounds: Aabb,
+    lipschitz: f32,
+    //how close to the surface counts as on it
+    epsilon: f32,
+    pub(crate) mat: Arc<dyn Material>,
+    id: usize
+}
+
+//steps after which a ray is taken to miss, e.g. one grazing the surface
+const SDF_MAX_STEPS: u32 = 512;
+
+impl SdfPrimitive {
+    pub fn new(expr: SdfExpr, mat: Arc<dyn Material>) -> SdfPrimitive {
+        let b = expr.bounds();
+        let size = (b.max - b.min).len();
+        //also above the rounding of positions within the bounds
+        let far = (0..3).map(|i| b.min.get(i).abs().max(b.max.get(i).abs())).fold(0.0f32, f32::max);
+        let epsilon = 1e-4*size + 8.0*f32::EPSILON*far;
+        let pad = Vec3::from(epsilon);
+        let bounds = Aabb{min: b.min - pad, max: b.max + pad};
+        let lipschitz = expr.lipschitz();
+        let id = hash_floats(&[b.min.x, b.min.y, b.min.z, b.max.x, b.max.y, b.max.z, lipschitz]);
+        SdfPrimitive{expr, bounds, lipschitz, epsilon, mat, id}
+    }
+
+    //part of (0, max_t) where the ray is within the bounds
+    fn clip(&self, r: &Ray) -> Option<(f32, f32)> {
+        let (mut t0, mut t1) = (0.0f32, r.max_t);
+        for axis in 0..3 {
+            let inv_d = 1.0/r.d.get(axis);
+            let o = r.o.get(axis);
+            let mut near = (self.bounds.min.get(axis) - o)*inv_d;
+            let mut far = (self.bounds.max.get(axis) - o)*inv_d;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+
+    //Distance along the ray to the surface, adding the steps taken to cost.
+    //Steps use the absolute distance, so rays inside find their way out.
+    fn march(&self, r: &Ray, cost: &mut u32) -> Option<f32> {
+        let (mut t, t1) = self.clip(r)?;
+        let speed = self.lipschitz*r.d.len();
+        for _ in 0..SDF_MAX_STEPS {
+            *cost += 1;
+            let d = self.expr.distance(&(r.o + (r.d*t))).abs();
+            if d < self.epsilon {
+                return Some(t);
+            }
+            t += d/speed;
+            if t > t1 {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn normal(&self, p: &Vec3, d: &Vec3) -> Vec3 {
+        let h = self.epsilon;
+        let diff = |e: Vec3| self.expr.distance(&(p + &e)) - self.expr.distance(&(p - &e));
+        let g = Vec3::xyz(diff(Vec3::xyz(h, 0.0, 0.0)), diff(Vec3::xyz(0.0, h, 0.0)), diff(Vec3::xyz(0.0, 0.0, h)));
+        //flat, e.g. at a crease: facing the ray
+        if g.is_zero() {
+            return d.normalized()*(-1.0);
+        }
+        g.normalized()
+    }
+}
+
+impl Primitive for SdfPrimitive {
+    fn intersect(&self, r: &mut Ray) -> Option<Intersection<'_>> {
+        let mut cost = 0;
+        self.intersect_counted(r, &mut cost)
+    }
+
+    fn intersect_counted(&self, r: &mut Ray, cost: &mut u32) -> Option<Intersection<'_>> {
+        let t = self.march(r, cost)?;
+        r.max_t = t;
+        let p = r.o + (r.d*t);
+        let n = self.normal(&p, &r.d);
+        Some(Intersection{
+            p,
+            //within epsilon of the surface; three times that keeps rays
+            //leaving it from stopping at once where the function is shallow
+            p_error: Vec3::from(3.0*self.epsilon) + (p.abs()*gamma(2)),
+            wo: r.d*(-1.0),
+            n,
+            uv: sphere_uv(&n),
+            mat: &*self.mat,
+            object: self.id,
+            dpdt: Vec3::new(),
+            interior: None
+        })
+    }
+
+    fn intersect_any(&self, r: &mut Ray) -> bool {
+        let mut cost = 0;
+        match self.march(r, &mut cost) {
+            Some(t) => {
+                r.max_t = t;
+                true
+            }
+            None => false
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::DiffuseMaterial;
+    use rand::Rng;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn random_unit(rng: &mut impl Rng) -> Vec3 {
+        Vec3::xyz(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)).normalized()
+    }
+
+    //Shoots rays between the points given by sample (from, towards) and from
+    //every hit spawns a ray out of the surface in a random direction and a
+    //shadow ray back to where the first one came from. Neither may find the
+    //part of the surface it left closer than closest(sin), for the sine of
+    //the angle it leaves at: a shape that holds no other part of its
+    //surface in a tangent ball of radius R can only be met again 2R*sin away.
+    fn assert_spawned_rays_leave(shape: &dyn Primitive, rng: &mut StdRng, sample: &dyn Fn(&mut StdRng) -> (Vec3, Vec3),
+        closest: &dyn Fn(f32) -> f32, label: &str) {
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let (from, towards) = sample(rng);
+            let mut r = Ray::new(from, (towards - from).normalized());
+            r.max_t = f32::INFINITY;
+            let x = match shape.intersect(&mut r) {
+                Some(x) => x,
+                None => continue
+            };
+            hits += 1;
+            let d = random_unit(rng);
+            let d = if d.dot(&x.n) < 0.0 {d*(-1.0)} else {d};
+            let mut out = x.spawn_ray(d, 0.0);
+            out.max_t = f32::INFINITY;
+            if let Some(y) = shape.intersect(&mut out) {
+                assert!(y.object != x.object || out.max_t >= closest(d.dot(&x.n)),
+                    "{}: out again after {}", label, out.max_t);
+            }
+            let mut back = x.spawn_point().ray_to(&SpawnPoint::at(from), 0.0);
+            if let Some(y) = shape.intersect(&mut back) {
+                assert!(y.object != x.object || back.max_t >= closest(back.d.dot(&x.n).abs()),
+                    "{}: back again after {}", label, back.max_t);
+            }
+        }
+        assert!(hits > 200, "{}: only {} hits", label, hits);
+    }
+
+    //Rays leaving a sphere outwards must never find it again, and shadow
+    //rays must get back to where it was seen from, for spheres of any size
+    //and far from the origin
+    #[test]
+    fn spawned_rays_miss_their_own_sphere_at_any_scale() {
+        let mut rng = StdRng::seed_from_u64(47);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        for &scale in [1e-3f32, 1.0, 1e3, 1e5].iter() {
+            let o = Vec3::xyz(0.3, -2.0, 1001.0)*scale;
+            let s = Sphere::new(o, 1000.0*scale, mat.clone());
+            let sample = |rng: &mut StdRng| (o + (random_unit(rng)*(1500.0*scale)), o + (random_unit(rng)*(500.0*scale)));
+            assert_spawned_rays_leave(&s, &mut rng, &sample, &|_| f32::INFINITY, &format!("scale {}", scale));
+        }
+    }
+
+    //before and after the shutter interval the sphere stays at its ends,
+    //inside its bounds and without motion
+    #[test]
+    fn moving_sphere_rests_outside_its_motion() {
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let s = MovingSphere::new(Vec3::xyz(0.0, 0.0, 0.0), 0.0, Vec3::xyz(2.0, 0.0, 0.0), 1.0, 0.5, mat);
+        let b = s.bounds();
+        for &(time, x) in [(-1.0f32, 0.0f32), (3.0, 2.0)].iter() {
+            let mut r = Ray::new(Vec3::xyz(x, -5.0, 0.0), Vec3::xyz(0.0, 1.0, 0.0));
+            r.time = time;
+            r.max_t = f32::INFINITY;
+            let hit = s.intersect(&mut r).unwrap();
+            assert!((hit.p.y + 0.5).abs() < 1e-5 && (hit.p.x - x).abs() < 1e-5);
+            assert!(hit.p.x >= b.min.x && hit.p.x <= b.max.x);
+            assert!(hit.dpdt.is_zero());
+        }
+    }
+
+    //one of each shape that can be a light, placed away from the origin
+    //with a tilted axis and scaled
+    fn surfaces(scale: f32, mat: &Arc<dyn Material>) -> Vec<Box<dyn Surface>> {
+        let o = Vec3::xyz(0.3, -2.0, 11.0)*scale;
+        let axis = Vec3::xyz(0.2, 0.5, 1.0).normalized();
+        vec![
+            Box::new(Disk::new(o, axis, scale, mat.clone()).unwrap()),
+            Box::new(Rect::new(o, Vec3::xyz(1.0, 0.0, -0.2)*scale, Vec3::xyz(0.0, 2.0, 0.0)*scale, mat.clone()).unwrap()),
+            Box::new(Cuboid::oriented(o, Vec3::xyz(1.0, 1.0, 0.0), axis, Vec3::xyz(1.0, 2.0, 0.5)*scale, mat.clone()).unwrap()),
+            Box::new(Cylinder::new(o, axis*(2.0*scale), scale, true, mat.clone()).unwrap()),
+            Box::new(Cone::new(o, axis*(2.0*scale), scale, true, mat.clone()).unwrap()),
+            Box::new(Torus::new(o, axis, scale, 0.3*scale, mat.clone()).unwrap())
+        ]
+    }
+
+    //Rays towards a sampled point along its normal find it, with the same
+    //normal, which normal_at also gives
+    #[test]
+    fn sampled_points_are_hit_with_their_normal() {
+        let mut rng = StdRng::seed_from_u64(481);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        for (i, s) in surfaces(1.0, &mat).iter().enumerate() {
+            for _ in 0..1000 {
+                let x = s.sample_area(rng.gen(), rng.gen());
+                let mut r = Ray::new(x.p + (x.n*0.1), x.n*(-1.0));
+                r.max_t = f32::INFINITY;
+                let it = s.intersect(&mut r).unwrap_or_else(|| panic!("shape {} missed", i));
+                assert!((it.p - x.p).len() < 1e-4, "shape {}: hit {} away", i, (it.p - x.p).len());
+                assert!(it.n.dot(&x.n) > 0.999, "shape {}: hit normal", i);
+                let n = s.normal_at(&x.p).unwrap_or_else(|| panic!("shape {}: no normal", i));
+                assert!(n.dot(&x.n) > 0.999, "shape {}: normal_at", i);
+            }
+        }
+    }
+
+    //as for spheres, for all shapes; all but the torus are convex, and the
+    //torus holds no other part of itself within tangent balls of its minor
+    //radius, which is less than the hole's
+    #[test]
+    fn spawned_rays_miss_their_own_shape_at_any_scale() {
+        let mut rng = StdRng::seed_from_u64(48);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        for &scale in [1e-3f32, 1.0, 1e3, 1e5].iter() {
+            let centre = Vec3::xyz(0.3, -2.0, 11.0)*scale;
+            for (i, s) in surfaces(scale, &mat).iter().enumerate() {
+                let sample = |rng: &mut StdRng| (centre + (random_unit(rng)*(20.0*scale)), s.sample_area(rng.gen(), rng.gen()).p);
+                let reach = if i == 5 {0.3*scale} else {f32::INFINITY};
+                assert_spawned_rays_leave(&**s, &mut rng, &sample, &|sin| 2.0*reach*sin, &format!("shape {}, scale {}", i, scale));
+            }
+        }
+    }
+
+    //The first end of a closed shape's spans ahead of the ray is its hit
+    #[test]
+    fn spans_start_at_the_nearest_hit() {
+        let mut rng = StdRng::seed_from_u64(49);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let mut shapes: Vec<Box<dyn Primitive>> = vec![Box::new(Sphere::new(Vec3::xyz(0.3, -2.0, 11.0), 1.0, mat.clone()))];
+        for s in surfaces(1.0, &mat).into_iter().skip(2) {
+            shapes.push(s);
+        }
+        let centre = Vec3::xyz(0.3, -2.0, 11.0);
+        for (i, s) in shapes.iter().enumerate() {
+            for _ in 0..1000 {
+                let from = centre + (random_unit(&mut rng)*(1.5 + 3.5*rng.gen::<f32>()));
+                let mut r = Ray::new(from, random_unit(&mut rng));
+                r.max_t = f32::INFINITY;
+                let spans = s.spans(&r).unwrap();
+                let first = spans.iter().flat_map(|s| vec![(s.t0, s.enter), (s.t1, s.exit)]).find(|e| e.0 > 0.0);
+                match s.intersect(&mut r) {
+                    Some(x) => {
+                        let (t, it) = first.unwrap();
+                        assert!((t - r.max_t).abs() < 1e-4 && it.n.dot(&x.n) > 0.999, "shape {}", i);
+                    }
+                    //behind the ray, or a graze
+                    None => assert!(spans.iter().all(|s| s.t1 <= 0.0 || s.t1 - s.t0 < 1e-2), "shape {}", i)
+                }
+            }
+        }
+    }
+
+    fn ray_x(y: f32, z: f32) -> Ray {
+        Ray::new(Vec3::xyz(-5.0, y, z), Vec3::xyz(1.0, 0.0, 0.0))
+    }
+
+    //a cube of side 2 and a slightly larger sphere, both around the origin
+    #[test]
+    fn csg_combines_the_inside_of_its_children() {
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let node = |op| {
+            let cube = Cuboid::new(Vec3::from(-1.0), Vec3::from(1.0), mat.clone()).unwrap();
+            Csg::new(op, Box::new(cube), Box::new(Sphere::new(Vec3::new(), 1.2, mat.clone()))).unwrap()
+        };
+
+        let union = node(CsgOp::Union);
+        let mut r = ray_x(0.0, 0.0);
+        assert!((union.intersect(&mut r).unwrap().p.x + 1.2).abs() < 1e-5);
+        //no faces inside
+        assert_eq!(union.spans(&ray_x(0.9, 0.0)).unwrap().len(), 1);
+
+        let intersection = node(CsgOp::Intersection);
+        let mut r = ray_x(0.0, 0.0);
+        assert!((intersection.intersect(&mut r).unwrap().p.x + 1.0).abs() < 1e-5);
+        assert!(intersection.intersect(&mut ray_x(0.95, 0.95)).is_none());
+
+        //the sphere takes the middle out of the cube, leaving its corners
+        let difference = node(CsgOp::Difference);
+        assert!(difference.intersect(&mut ray_x(0.0, 0.0)).is_none());
+        let mut r = ray_x(0.9, 0.9);
+        let x = difference.intersect(&mut r).unwrap();
+        assert!((x.p.x + 1.0).abs() < 1e-5 && x.n.x < -0.999);
+        //leaving the cube's face into the hole, through the sphere's
+        //surface facing into it
+        let mut r = ray_x(0.8, 0.0);
+        let x = difference.intersect(&mut r).unwrap();
+        assert!((x.p.x + 1.0).abs() < 1e-5);
+        let mut inside = x.spawn_ray(Vec3::xyz(1.0, 0.0, 0.0), 0.0);
+        let y = difference.intersect(&mut inside).unwrap();
+        assert!((y.p.x + 0.8f32.mul_add(-0.8, 1.44).sqrt()).abs() < 1e-5);
+        assert!(y.n.x > 0.0 && y.n.y < 0.0);
+
+        //nested: the corners left within a smaller sphere
+        let nested = Csg::new(CsgOp::Intersection, Box::new(difference), Box::new(Sphere::new(Vec3::new(), 1.5, mat.clone()))).unwrap();
+        assert!(nested.intersect(&mut ray_x(0.0, 0.0)).is_none());
+        assert!(nested.intersect(&mut ray_x(0.9, 0.9)).is_some());
+        let x = nested.intersect(&mut ray_x(0.99, 0.99)).unwrap();
+        assert!((x.p.x + (2.25f32 - 2.0*0.99*0.99).sqrt()).abs() < 1e-5 && x.n.x < 0.0);
+    }
+
+    #[test]
+    fn degenerate_shapes_are_rejected() {
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let (o, z) = (Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0));
+        assert!(Plane::new(o, Vec3::new(), mat.clone()).is_err());
+        assert!(Disk::new(o, z, f32::NAN, mat.clone()).is_err());
+        assert!(Rect::new(o, z, z*2.0, mat.clone()).is_err());
+        assert!(Rect::axis_aligned(o, Vec3::xyz(1.0, 0.0, 0.0), mat.clone()).is_err());
+        assert!(Cuboid::oriented(o, z, z, Vec3::from(1.0), mat.clone()).is_err());
+        assert!(Cone::new(o, Vec3::xyz(f32::INFINITY, 0.0, 0.0), 1.0, true, mat.clone()).is_err());
+        assert!(Torus::new(o, z, 1.0, 2.0, mat.clone()).is_err());
+        assert!(Torus::new(o, z, 2.0, 1.0, mat.clone()).is_ok());
+    }
+
+    #[test]
+    fn csg_needs_closed_primitives() {
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let disk = Disk::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0), 1.0, mat.clone()).unwrap();
+        let open = Cylinder::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0), 1.0, false, mat.clone()).unwrap();
+        let sphere = || Box::new(Sphere::new(Vec3::new(), 1.0, mat.clone()));
+        assert!(Csg::new(CsgOp::Union, Box::new(disk), sphere()).is_err());
+        assert!(Csg::new(CsgOp::Difference, sphere(), Box::new(open)).is_err());
+        let capped = Cylinder::new(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0), 1.0, true, mat.clone()).unwrap();
+        assert!(Csg::new(CsgOp::Difference, sphere(), Box::new(capped)).is_ok());
+    }
+
+    //Rays leaving the surface of a CSG difference don't find it again where
+    //they left, including on the flipped surface of the hole
+    #[test]
+    fn spawned_rays_miss_csg_surfaces_at_any_scale() {
+        let mut rng = StdRng::seed_from_u64(49);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        for &scale in [1e-3f32, 1.0, 1e3, 1e5].iter() {
+            let o = Vec3::xyz(0.3, -2.0, 11.0)*scale;
+            let cube = Cuboid::oriented(o, Vec3::xyz(1.0, 1.0, 0.0), Vec3::xyz(0.2, 0.5, 1.0), Vec3::from(2.0*scale), mat.clone()).unwrap();
+            let cylinder = Cylinder::new(o - (Vec3::xyz(0.0, 0.0, 2.0)*scale), Vec3::xyz(0.0, 0.0, 4.0)*scale, 0.6*scale, true, mat.clone()).unwrap();
+            let hole = Csg::new(CsgOp::Union, Box::new(Sphere::new(o, 1.2*scale, mat.clone())), Box::new(cylinder)).unwrap();
+            let part = Csg::new(CsgOp::Difference, Box::new(cube), Box::new(hole)).unwrap();
+            let sample = |rng: &mut StdRng| (o + (random_unit(rng)*(5.0*scale)), o + (random_unit(rng)*(1.5*scale)));
+            //the cube is convex and the hole is made of surfaces that curve
+            //no tighter than the cylinder; hits on another child are fine
+            let closest = |sin: f32| 2.0*0.6*scale*sin;
+            assert_spawned_rays_leave(&part, &mut rng, &sample, &closest, &format!("scale {}", scale));
+        }
+    }
+
+    #[test]
+    fn disjoint_csg_intersection_is_empty() {
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let a = Sphere::new(Vec3::new(), 1.0, mat.clone());
+        let b = Sphere::new(Vec3::xyz(3.0, 0.0, 0.0), 1.0, mat.clone());
+        let nothing = Csg::new(CsgOp::Intersection, Box::new(a), Box::new(b)).unwrap();
+        let bounds = nothing.bounds();
+        assert!(bounds.min.x == f32::INFINITY && bounds.max.x == f32::NEG_INFINITY);
+        let mut bvh = Bvh::new();
+        bvh.add(Box::new(nothing));
+        bvh.add(Box::new(Sphere::new(Vec3::xyz(0.0, 5.0, 0.0), 1.0, mat)));
+        bvh.build_index();
+        let mut r = Ray::new(Vec3::xyz(0.0, -5.0, 0.0), Vec3::xyz(0.0, 1.0, 0.0));
+        assert!(bvh.intersect(&mut r).is_some_and(|x| (x.p.y - 4.0).abs() < 1e-4));
+    }
+
+    //Sphere tracing an exact distance finds what the analytic shape does
+    #[test]
+    fn sdf_sphere_matches_the_sphere() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let o = Vec3::xyz(0.3, -2.0, 1.0);
+        let sphere = Sphere::new(o, 0.7, mat.clone());
+        let sdf = SdfPrimitive::new(SdfExpr::Translate{offset: o, e: Box::new(SdfExpr::Sphere{r: 0.7})}, mat.clone());
+        for _ in 0..1000 {
+            let from = o + (random_unit(&mut rng)*3.0);
+            let d = ((o + (random_unit(&mut rng)*0.8)) - from).normalized();
+            let (mut a, mut b) = (Ray::new(from, d), Ray::new(from, d));
+            match (sphere.intersect(&mut a), sdf.intersect(&mut b)) {
+                //within the march's epsilon of the sphere, if not quite where
+                //the ray meets it at grazing angles
+                (Some(x), Some(y)) => {
+                    assert!(((y.p - o).len() - 0.7).abs() < sdf.epsilon && x.n.dot(&y.n) > 0.999);
+                }
+                (None, None) => {}
+                //only rays passing within epsilon of it may tell them apart
+                (x, _) => {
+                    let rho = ((from - o) - (d*(from - o).dot(&d))).len();
+                    assert!(x.is_none() && (rho - 0.7).abs() < sdf.epsilon, "hit {}, {} from the centre", x.is_some(), rho);
+                }
+            }
+        }
+    }
+
+    //Rays leaving implicit surfaces don't stop where they left, also where
+    //the function isn't an exact distance and far from the origin
+    #[test]
+    fn spawned_rays_leave_implicit_surfaces() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let shape = "(smooth-union 0.3 (twist 2 (round-box 0.3 0.3 0.8 0.05)) (translate 0.4 0 0 (sphere 0.4)))";
+        for &scale in [1.0f32, 1e3].iter() {
+            let o = Vec3::xyz(0.3, -2.0, 11.0)*scale;
+            let expr = SdfExpr::Translate{offset: o, e: Box::new(SdfExpr::parse(shape).unwrap())};
+            let sdf = SdfPrimitive::new(expr, mat.clone());
+            let sample = |rng: &mut StdRng| (o + (random_unit(rng)*3.0), o + (random_unit(rng)*0.5));
+            //The surface is only known to within the march's epsilon, so
+            //the shape's own curvature can't be relied on. A ray that stops
+            //where it left does so within about one epsilon; any other hit
+            //must be at least as far as a tangent ball of four epsilon allows.
+            let closest = |sin: f32| 8.0*sdf.epsilon*sin;
+            assert_spawned_rays_leave(&sdf, &mut rng, &sample, &closest, &format!("scale {}", scale));
+        }
+    }
+
+    //An instanced unit sphere under scale and translation is hit where a
+    //sphere built in place is, with the same distance, point and normal
+    #[test]
+    fn instanced_sphere_hits_like_a_placed_one() {
+        let mut rng = StdRng::seed_from_u64(29);
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let (o, r) = (Vec3::xyz(3.0, -1.0, 4.0), 2.5);
+        let unit: Arc<dyn Primitive> = Arc::new(Sphere::new(Vec3::new(), 1.0, mat.clone()));
+        let instance = Instance::new(unit, &Transform::translate(o)*&Transform::scale(Vec3::from(r)));
+        let placed = Sphere::new(o, r, mat);
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let from = o + (random_unit(&mut rng)*6.0);
+            let towards = o + (random_unit(&mut rng)*3.0);
+            let d = (towards - from).normalized();
+            let (mut a, mut b) = (Ray::new(from, d), Ray::new(from, d));
+            match (instance.intersect(&mut a), placed.intersect(&mut b)) {
+                (Some(x), Some(y)) => {
+                    hits += 1;
+                    assert!((a.max_t - b.max_t).abs() < 1e-4*b.max_t, "t {} for {}", a.max_t, b.max_t);
+                    assert!((x.p - y.p).len() < 1e-4*r && (x.n - y.n).len() < 1e-4);
+                }
+                (None, None) => {}
+                (x, y) => panic!("instance hit {}, sphere hit {}", x.is_some(), y.is_some())
+            }
+        }
+        assert!(hits > 300, "only {} hits", hits);
+    }
+
+    #[test]
+    fn instance_bounds_follow_the_transform() {
+        let mat: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(Vec3::from(0.5)));
+        let unit: Arc<dyn Primitive> = Arc::new(Sphere::new(Vec3::new(), 1.0, mat));
+        let close = |a: &Vec3, b: &Vec3| (a - b).len() < 1e-4;
+
+        let t = &Transform::translate(Vec3::xyz(3.0, -1.0, 4.0))*&Transform::scale(Vec3::xyz(1.0, 2.0, 0.5));
+        let b = Instance::new(unit.clone(), t).bounds();
+        assert!(close(&b.min, &Vec3::xyz(2.0, -3.0, 3.5)) && close(&b.max, &Vec3::xyz(4.0, 1.0, 4.5)));
+
+        //turned a quarter around z the box is turned with it, and half way
+        //its corners reach out by sqrt(2)
+        let quarter = Instance::new(unit.clone(), Transform::rotate(Vec3::xyz(0.0, 0.0, 1.0), std::f32::consts::FRAC_PI_2)).bounds();
+        assert!(close(&quarter.min, &Vec3::from(-1.0)) && close(&quarter.max, &Vec3::from(1.0)));
+        let eighth = Instance::new(unit, Transform::rotate(Vec3::xyz(0.0, 0.0, 1.0), std::f32::consts::FRAC_PI_4)).bounds();
+        let s = 2.0f32.sqrt();
+        assert!(close(&eighth.min, &Vec3::xyz(-s, -s, -1.0)) && close(&eighth.max, &Vec3::xyz(s, s, 1.0)));
+    }
+}
@@ -1,21 +1,45 @@
 use image::RgbImage;
-use rand::Rng;
 use crate::scene::Scene;
 use crate::math::Vec3;
 use crate::primitive::Ray;
-use crate::primitive::Primitive;
 use crate::math::linear2srgb;
-use rand::thread_rng;
+use crate::primitive::Intersection;
+use crate::primitive::SpawnPoint;
+use crate::medium::Medium;
+use crate::spectrum::reflectance_at;
+use crate::spectrum::illuminant_at;
+use crate::spectrum::sample_wavelengths;
+use crate::spectrum::spectrum_to_rgb;
+use crate::sampler::Sampler;
+use crate::sampler::RandomSampler;
+use crate::film::Aov;
+use crate::film::AovFilm;
+use crate::film::Film;
+use crate::denoise::Denoiser;
+use crate::film::false_colour;
+use crate::material::material_id;
+use crate::film::heatmap;
+use crate::film::PixelStats;
+use crate::checkpoint::Checkpoint;
+use crate::control::RenderControl;
+
+//longest path the path tracer follows through surfaces and ordinary media
+const MAX_BOUNCES: usize = 32;
+//random walks in dense subsurface materials need many more vertices
+const MAX_WALK_STEPS: usize = 256;
 
 pub trait Renderer{
-    fn render(&self, scene: &mut Scene, fname: &String);
+    //Renders the scene to fname. The renderer checks control as it goes and
+    //when asked to stop early still writes the image it has so far. Errors
+    //are about the files written besides the image.
+    fn render(&self, scene: &mut Scene, fname: &str, control: &RenderControl) -> Result<(), String>;
 }
 
 pub struct DummyRenderer {
 }
 
 impl Renderer for DummyRenderer{
-    fn render(&self, scene: &mut Scene, fname: &String){
+    fn render(&self, scene: &mut Scene, fname: &str, _control: &RenderControl) -> Result<(), String> {
         let image : &RgbImage= scene.camera.image();
         let (x,y) = image.dimensions();
         for i in 0..x{
@@ -29,71 +53,400 @@ impl Renderer for DummyRenderer{
             }
         }
         scene.camera.save_image(fname);
+        Ok(())
     }
 }
 
+//In spectral mode each camera sample carries three wavelengths (hero
+//wavelength sampling) in place of the RGB channels, and is converted to
+//sRGB through CIE XYZ when it reaches the film.
 pub struct PtRenderer {
-    samples: u32
+    samples: u32,
+    spectral: bool,
+    aovs: bool,
+    denoise: bool,
+    //relative error threshold of adaptive sampling
+    adaptive: Option<f32>,
+    //seconds between writes of the image during progressive rendering
+    progressive: Option<f32>,
+    checkpoint: Option<String>
+}
+
+//Radiance of a path, split by the lobe of its first scattering event and
+//the number of bounces. Media scattering counts as diffuse.
+#[derive(Copy,Clone)]
+pub struct Lobes {
+    pub emission: Vec3,
+    pub direct_diffuse: Vec3,
+    pub indirect_diffuse: Vec3,
+    pub specular: Vec3
+}
+
+impl Default for Lobes {
+    fn default() -> Lobes {
+        Lobes::new()
+    }
+}
+
+impl Lobes {
+    pub fn new() -> Lobes {
+        Lobes{emission: Vec3::new(), direct_diffuse: Vec3::new(), indirect_diffuse: Vec3::new(), specular: Vec3::new()}
+    }
+
+    //light that reached the camera after `bounces` scattering events
+    fn add(&mut self, bounces: u32, first_specular: bool, c: &Vec3) {
+        let pass = if bounces == 0 {
+            &mut self.emission
+        } else if first_specular {
+            &mut self.specular
+        } else if bounces == 1 {
+            &mut self.direct_diffuse
+        } else {
+            &mut self.indirect_diffuse
+        };
+        *pass = &*pass + c;
+    }
+
+    pub fn map(&self, f: &dyn Fn(&Vec3) -> Vec3) -> Lobes {
+        Lobes{
+            emission: f(&self.emission),
+            direct_diffuse: f(&self.direct_diffuse),
+            indirect_diffuse: f(&self.indirect_diffuse),
+            specular: f(&self.specular)
+        }
+    }
+
+    pub fn total(&self) -> Vec3 {
+        (self.emission + self.direct_diffuse) + (self.indirect_diffuse + self.specular)
+    }
 }
 
 impl PtRenderer {
     pub fn new(s: u32) -> PtRenderer{
-        if s<0 {
-            panic!("Samples must be positive!");
-        }
         PtRenderer{
-            samples: s
+            samples: s,
+            spectral: false,
+            aovs: false,
+            denoise: false,
+            adaptive: None,
+            progressive: None,
+            checkpoint: None
         }
     }
-    fn sky(&self, r: &Ray) -> Vec3{
-        let a = r.d.normalized();
-        let v = Vec3{x:1.0,y:0.0,z:1.0}*(a.z*0.5 + 0.5);
-        return v;
+
+    //also write the AOVs next to the image, see AovFilm
+    pub fn set_aovs(&mut self, enabled: bool) {
+        self.aovs = enabled;
+    }
+
+    //also write a denoised copy of the image, <name>_denoised.<ext>
+    pub fn set_denoise(&mut self, enabled: bool) {
+        self.denoise = enabled;
+    }
+
+    //Adaptive sampling: pixels stop once the standard error of their mean,
+    //relative to its luminance, falls below threshold, and the rest of the
+    //budget goes to noisier pixels. A <name>_samples.<ext> heatmap of the
+    //sample counts is written next to the image.
+    pub fn set_adaptive(&mut self, threshold: Option<f32>) {
+        self.adaptive = threshold;
+    }
+
+    //Progressive rendering: one jittered sample per pixel and pass, and the
+    //image is written every `interval` seconds. It can't be combined with
+    //adaptive sampling, and neither can checkpoints or render limits.
+    pub fn set_progressive(&mut self, interval: Option<f32>) {
+        self.progressive = interval;
     }
 
-    fn russian_roulette(col: &Vec3)-> f32{
+    //Progressive rendering that resumes from this file if it exists and
+    //saves to it whenever the image is written. Resuming, also with a higher
+    //sample count, gives the same result as an uninterrupted render.
+    pub fn set_checkpoint(&mut self, fname: Option<String>) {
+        self.checkpoint = fname;
+    }
+
+    pub fn spectral(s: u32) -> PtRenderer{
+        PtRenderer{
+            spectral: true,
+            ..PtRenderer::new(s)
+        }
+    }
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
+    pub fn russian_roulette(col: &Vec3)-> f32{
         //LOL this is not LAB ahahahah
         return (col.x*0.2 + col.y*0.5 +col.z*0.3)*0.5 + 0.4;
     }
 
-    fn get_random(&self) -> f32 {
-        return thread_rng().gen::<f32>();
+    //whether a ray leaving x along d goes into the volume of a subsurface material
+    fn enters_walk(x: &Intersection, d: &Vec3) -> bool {
+        x.interior.is_none() && x.mat.interior().is_some() && d.dot(&x.n) < 0.0
+    }
+
+    //medium on the other side of x, for a ray leaving x along d
+    fn medium_after<'a>(scene: &'a Scene, x: &Intersection<'a>, d: &Vec3, current: Option<&'a dyn Medium>)
+        -> Option<&'a dyn Medium> {
+        let interior = match x.interior {
+            Some(m) => Some(m),
+            None => x.mat.interior()
+        };
+        match interior {
+            None => current,
+            Some(m) => if d.dot(&x.n) < 0.0 {Some(m)} else {scene.medium.as_deref()}
+        }
+    }
+
+    //Transmittance between two points. Interface surfaces are crossed, any
+    //other surface blocks the ray.
+    #[allow(clippy::too_many_arguments, reason = "the path's time, medium and wavelengths go along")]
+    fn transmittance<'a>(&self, scene: &'a Scene, from: SpawnPoint, to: &SpawnPoint, time: f32,
+        medium: Option<&'a dyn Medium>, lambdas: Option<&Vec3>, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut tr = Vec3::from(1.0);
+        let mut from = from;
+        let mut medium = medium;
+        let mut rand = || sampler.next();
+        for _ in 0..32 {
+            let mut r = from.ray_to(to, time);
+            let left = r.max_t;
+            let it = scene.primitive.intersect(&mut r);
+            let seg = if it.is_some() {r.max_t} else {left};
+            if let Some(m) = medium {
+                tr = &tr*&m.transmittance(&r, seg, lambdas, &mut rand);
+            }
+            match it {
+                None => {return tr;}
+                Some(x) => {
+                    if !x.mat.is_interface() {
+                        return Vec3::new();
+                    }
+                    medium = PtRenderer::medium_after(scene, &x, &r.d, medium);
+                    from = x.spawn_point();
+                }
+            }
+        }
+        tr
+    }
+
+    //solid angle density of hitting the emitter at `hit` by light sampling from p
+    fn light_pdf(&self, scene: &Scene, p: &Vec3, wi: &Vec3, hit: &Vec3) -> f32 {
+        if scene.lights.is_empty() {
+            return 0.0;
+        }
+        let mut pdf = 0.0;
+        for l in &scene.lights {
+            pdf += l.pdf(p, wi, hit);
+        }
+        pdf/scene.lights.len() as f32
     }
 
-    fn integrate(&self, scene: &Scene, r: &mut Ray) -> Vec3{
+    //Next event estimation: samples one light and returns its MIS weighted
+    //contribution. f gives the scattering function value and pdf for wi.
+    #[allow(clippy::too_many_arguments, reason = "the path's state and the scattering function at its end")]
+    fn sample_direct<'a>(&self, scene: &'a Scene, from: SpawnPoint, time: f32, medium: Option<&'a dyn Medium>,
+        lambdas: Option<&Vec3>, sampler: &mut dyn Sampler, f: &dyn Fn(&Vec3) -> (Vec3, f32)) -> Vec3 {
+        if scene.lights.is_empty() {
+            return Vec3::new();
+        }
+        let n = scene.lights.len();
+        let l = ((sampler.next()*n as f32) as usize).min(n-1);
+        let r1 = sampler.next();
+        let r2 = sampler.next();
+        let ls = match scene.lights[l].sample(&from.p, lambdas, r1, r2) {
+            Some(x) => x,
+            None => {return Vec3::new();}
+        };
+        if ls.pdf <= 0.0 || ls.radiance.is_zero() {
+            return Vec3::new();
+        }
+        let (value, scatter_pdf) = f(&ls.wi);
+        if value.is_zero() {
+            return Vec3::new();
+        }
+        let tr = self.transmittance(scene, from, &SpawnPoint::at(from.p + (ls.wi*ls.dist)), time, medium, lambdas, sampler);
+        if tr.is_zero() {
+            return Vec3::new();
+        }
+        let light_pdf = ls.pdf/n as f32;
+        let w = power_heuristic(light_pdf, scatter_pdf);
+        (&(&value*&ls.radiance)*&tr)*(w/light_pdf)
+    }
+
+    //Weighted RGB radiance of a camera ray through the image coordinate u
+    pub fn sample(&self, scene: &Scene, u: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        match self.trace_camera(scene, u, sampler) {
+            Some((_, lobes)) => lobes.total(),
+            None => Vec3::new()
+        }
+    }
+
+    //Like sample, but keeps the split by lobe and also returns the camera
+    //ray. None if the lens blocked the ray.
+    pub fn trace_camera(&self, scene: &Scene, u: Vec3, sampler: &mut dyn Sampler) -> Option<(Ray, Lobes)> {
+        let r1 = sampler.next();
+        let r2 = sampler.next();
+        let r3 = sampler.next();
+        let (ray, weight) = scene.camera.sample_ray(u, r1, r2, r3);
+        if weight <= 0.0 {
+            return None;
+        }
+        let mut r = Ray::with_time(ray.o, ray.d, ray.time);
+        let lobes = if self.spectral {
+            let lambdas = sample_wavelengths(sampler.next());
+            self.integrate_lobes(scene, &mut r, Some(&lambdas), sampler)
+                .map(&|c| spectrum_to_rgb(c, &lambdas)*weight)
+        } else {
+            self.integrate_lobes(scene, &mut r, None, sampler).map(&|c| c*weight)
+        };
+        Some((ray, lobes))
+    }
+
+    //Radiance along r: RGB, or one value per wavelength when lambdas is set.
+    //All random decisions are taken from the sampler, in a fixed order.
+    pub fn integrate(&self, scene: &Scene, r: &mut Ray, lambdas: Option<&Vec3>, sampler: &mut dyn Sampler) -> Vec3{
+        self.integrate_lobes(scene, r, lambdas, sampler).total()
+    }
+
+    #[allow(clippy::needless_return, reason = "baseline code, kept as written")]
+    pub fn integrate_lobes(&self, scene: &Scene, r: &mut Ray, lambdas: Option<&Vec3>, sampler: &mut dyn Sampler) -> Lobes{
         //println!("Ray: {} {} {}",r.d.x/r.d.y,-r.d.y/r.d.y,r.d.z/r.d.y);
         
-        let mut color = Vec3::new();
+        let mut color = Lobes::new();
         let mut mul = Vec3::from(1.0);
+        let mut medium = scene.medium.as_deref();
+
+        //state of the last scattering vertex, for MIS of emitter hits
+        let mut prev_p = r.o;
+        let mut prev_pdf = 0.0;
+        let mut specular = true;
+        //scattering events so far, and whether the first one was specular
+        let mut bounces = 0;
+        let mut first_specular = false;
+        //only the hero wavelength is left after a dispersive surface
+        let mut hero_only = false;
         
-        for steps in 0..32 {
+        //surface and ordinary medium vertices so far; random walks through
+        //the inside of a subsurface material have their own, larger budget
+        let mut steps = 0;
+        let mut walk_steps = 0;
+        let mut walking = false;
+
+        while steps < MAX_BOUNCES && walk_steps < MAX_WALK_STEPS {
             let it = scene.primitive.intersect(r);
+
+            if let Some(m) = medium {
+                let t_max = if it.is_some() {r.max_t} else {f32::INFINITY};
+                let ms = m.sample(r, t_max, lambdas, &mut || sampler.next());
+                color.add(bounces, first_specular, &(&mul*&ms.emission));
+                mul = &mul*&ms.weight;
+                if mul.is_zero() {
+                    break;
+                }
+                if let Some(t) = ms.t {
+                    bounces += 1;
+                    if walking {
+                        walk_steps += 1;
+                    } else {
+                        steps += 1;
+                    }
+                    let p = r.o + (r.d*t);
+                    let wo = r.d.normalized()*(-1.0);
+                    let phase = m.phase();
+                    let direct = self.sample_direct(scene, SpawnPoint::at(p), r.time, medium, lambdas, sampler, &|wi: &Vec3| {
+                        let f = phase.eval(&wo, wi);
+                        (Vec3::from(f), f)
+                    });
+                    color.add(bounces, first_specular, &(&mul*&direct));
+
+                    let r1 = sampler.next();
+                    let r2 = sampler.next();
+                    let (wi, pdf) = phase.sample(&wo, r1, r2);
+                    if steps + walk_steps > 3 {
+                        //keep walks in high albedo media alive
+                        let rr = ms.weight.x.max(ms.weight.y).max(ms.weight.z).min(1.0);
+                        if sampler.next() > rr {
+                            break;
+                        }
+                        mul = &mul/rr;
+                    }
+                    prev_p = p;
+                    prev_pdf = pdf;
+                    specular = false;
+                    *r = Ray::with_time(p, wi, r.time);
+                    continue;
+                }
+            }
+
             match it {
-                None => {color =  color + &mul*&self.sky(r); break;}
+                None => {color.add(bounces, first_specular, &(&mul*&illuminant_at(&sky(r), lambdas))); break;}
                 Some(x) => {
+                    steps += 1;
                     //return Vec3::from(((r.d.normalized()).dot(&x.n.normalized())).abs());
-                    color =  color + &mul* &x.mat.emit(&x);
-                    let r1 = self.get_random();
-                    let r2 = self.get_random();
-                    let (sample,mut p) = x.mat.sample(&x,r1,r2);
+                    if x.mat.is_interface() {
+                        walking = PtRenderer::enters_walk(&x, &r.d);
+                        medium = PtRenderer::medium_after(scene, &x, &r.d, medium);
+                        *r = x.spawn_ray(r.d, r.time);
+                        continue;
+                    }
+
+                    let emit = x.mat.emit_at(&x, lambdas);
+                    if !emit.is_zero() {
+                        let w = if specular {
+                            1.0
+                        } else {
+                            power_heuristic(prev_pdf, self.light_pdf(scene, &prev_p, &r.d, &x.p))
+                        };
+                        color.add(bounces, first_specular, &(&mul*&(emit*w)));
+                    }
+                    let r1 = sampler.next();
+                    let r2 = sampler.next();
+                    let dispersive = lambdas.is_some() && x.mat.is_dispersive();
+                    if dispersive && !hero_only {
+                        //the other wavelengths would refract elsewhere; the
+                        //hero estimate now stands for all three
+                        mul = Vec3::xyz(mul.x*3.0, 0.0, 0.0);
+                        hero_only = true;
+                    }
+                    let (sample,mut p) = match lambdas {
+                        Some(l) if dispersive => x.mat.sample_at(&x, l.x, r1, r2),
+                        _ => x.mat.sample(&x,r1,r2)
+                    };
 
                     //negative probability encodes zero reflectance
                     if p<=0.0 {
                         break;
                     }
+
+                    specular = x.mat.is_delta();
+                    if bounces == 0 {
+                        first_specular = specular;
+                    }
+                    bounces += 1;
+                    if !specular {
+                        let direct = self.sample_direct(scene, x.spawn_point(), r.time, medium, lambdas, sampler, &|wi: &Vec3| {
+                            (reflectance_at(&x.mat.eval(&x, wi), lambdas), x.mat.pdf(&x, wi))
+                        });
+                        color.add(bounces, first_specular, &(&mul*&direct));
+                    }
+                    prev_p = x.p;
+                    prev_pdf = p;
                     
-                    let eval = x.mat.eval(&x,&sample);
-                    if steps > 2{
+                    let eval = match lambdas {
+                        Some(l) if dispersive => x.mat.eval_at(&x, &sample, l.x),
+                        _ => reflectance_at(&x.mat.eval(&x,&sample), lambdas)
+                    };
+                    if steps + walk_steps > 3 {
                         let rr = PtRenderer::russian_roulette(&eval);
-                        if self.get_random() > rr {
+                        if sampler.next() > rr {
                             break;
                         }
 
-                        p = p*rr;
+                        p *= rr;
                     }
                     mul = &(&mul*&eval)/p;
                     
-                    *r = Ray::new(x.p, sample);
+                    walking = PtRenderer::enters_walk(&x, &sample);
+                    medium = PtRenderer::medium_after(scene, &x, &sample, medium);
+                    *r = x.spawn_ray(sample, r.time);
                 }
             }
 
@@ -103,47 +456,361 @@ impl PtRenderer {
     }
 }
 
+//radiance of rays leaving the scene
+pub fn sky(r: &Ray) -> Vec3{
+    let a = r.d.normalized();
+    Vec3{x:1.0,y:0.0,z:1.0}*(a.z*0.5 + 0.5)
+}
 
-impl Renderer for PtRenderer{
-    fn render(&self, scene: &mut Scene, fname: &String){
-        let image = scene.camera.image();
-        let (x,y) = image.dimensions();
-        let mut rng = rand::thread_rng();
-        for i in 0..x{
-            println!("Rendering line {}", i);
-            for j in 0..y{
+//First surface along the ray that is not a media interface. Adds the
+//intersection tests to cost, see Primitive::intersect_counted.
+pub fn first_surface<'a>(scene: &'a Scene, r: &mut Ray, cost: &mut u32) -> Option<Intersection<'a>> {
+    for _ in 0..32 {
+        let x = scene.primitive.intersect_counted(r, cost)?;
+        if !x.mat.is_interface() {
+            return Some(x);
+        }
+        *r = x.spawn_ray(r.d, r.time);
+    }
+    None
+}
 
-                let mut col = Vec3::new();
-                for k in 0..self.samples {
-                    for l in 0..self.samples{
-                        //let r1 = rng.gen::<f32>();
-                        //let r2 = rng.gen::<f32>();
-
-                        
-                        let r1 = 0.5;
-                        let r2 = 0.5;
-                        let mut u = (i as f32 + (k as f32 +r1)
-                                              /self.samples as f32       )    
-                                 /x as f32;
-                        
-                        let mut v = (j as f32 + (l as f32 +r2)
-                                 /self.samples as f32       )    
-                            /y as f32;
-
-                        u = u*2.0-1.0;
-                        v = -(v*2.0-1.0);
-                        let sample = self.integrate(scene, &mut scene.camera.get_ray(Vec3{x:u,y:v,z:0.0}));
-                        col = col + sample;
+//visibility between two points, seeing through interface surfaces
+pub fn unoccluded(scene: &Scene, a: &SpawnPoint, b: &SpawnPoint, time: f32) -> bool {
+    let mut from = *a;
+    for _ in 0..32 {
+        let mut r = from.ray_to(b, time);
+        match scene.primitive.intersect(&mut r) {
+            None => {return true;}
+            Some(x) => {
+                if !x.mat.is_interface() {
+                    return false;
+                }
+                from = x.spawn_point();
+            }
+        }
+    }
+    false
+}
+
+fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32 {
+    let a = pdf_a*pdf_a;
+    let b = pdf_b*pdf_b;
+    if a + b == 0.0 {
+        return 0.0;
+    }
+    a/(a+b)
+}
+
+
+impl PtRenderer {
+    //traces a camera sample through u and adds it to pixel (i,j)
+    #[allow(clippy::too_many_arguments, reason = "the pixel and every output a sample goes to")]
+    fn add_sample(&self, scene: &Scene, u: Vec3, i: u32, j: u32, sampler: &mut dyn Sampler,
+        stats: &mut PixelStats, aovs: &mut Option<AovFilm>, exposure: f32) {
+        match self.trace_camera(scene, u, sampler) {
+            Some((ray, lobes)) => {
+                stats.add(&lobes.total());
+                if let Some(aovs) = aovs {
+                    PtRenderer::add_aovs(scene, aovs, i, j, &ray, &lobes, exposure);
+                }
+            }
+            None => stats.add(&Vec3::new())
+        }
+    }
+
+    //samples*samples stratified samples per pixel, one stratum per pass over
+    //the image. A cancelled render stops as soon as every pixel has a sample,
+    //and is then only noisier.
+    #[allow(clippy::too_many_arguments, reason = "render lends each of its buffers separately")]
+    fn render_fixed(&self, scene: &Scene, (x, y): (u32, u32), stats: &mut [PixelStats],
+        samplers: &mut [RandomSampler], aovs: &mut Option<AovFilm>, exposure: f32, control: &RenderControl) {
+        let passes = self.samples*self.samples;
+        for pass in 0..passes {
+            let (k, l) = (pass/self.samples, pass%self.samples);
+            control.report(&format!("Pass {} of {}", pass + 1, passes));
+            for j in 0..y {
+                if pass > 0 && control.should_stop() {
+                    control.report(&format!("Stopping in pass {}", pass + 1));
+                    return;
+                }
+                for i in 0..x {
+                    let p = (j*x + i) as usize;
+                    let u = ((i as f32 + (k as f32 + 0.5)/self.samples as f32)/x as f32)*2.0 - 1.0;
+                    let v = -(((j as f32 + (l as f32 + 0.5)/self.samples as f32)/y as f32)*2.0 - 1.0);
+                    self.add_sample(scene, Vec3::xyz(u, v, 0.0), i, j, &mut samplers[p], &mut stats[p], aovs, exposure);
+                }
+            }
+        }
+    }
+
+    //Spends the same total budget as the fixed sampling, samples*samples per
+    //pixel on average. After a first pass, the remaining samples go out in
+    //rounds, to every pixel whose relative error is still above threshold
+    //in proportion to that error, until no such pixel is left.
+    #[allow(clippy::too_many_arguments, reason = "as render_fixed, with the error threshold")]
+    fn render_adaptive(&self, scene: &Scene, (x, y): (u32, u32), threshold: f32, stats: &mut [PixelStats],
+        samplers: &mut [RandomSampler], aovs: &mut Option<AovFilm>, exposure: f32, control: &RenderControl) {
+        let spp = (self.samples*self.samples) as u64;
+        let budget = spp*stats.len() as u64;
+        let mut spent = 0;
+        let mut sample_pixel = |p: usize, count: u64, stats: &mut [PixelStats]| {
+            let (i, j) = (p as u32 % x, p as u32 / x);
+            for _ in 0..count {
+                let sampler = &mut samplers[p];
+                let u = ((i as f32 + sampler.next())/x as f32)*2.0 - 1.0;
+                let v = -(((j as f32 + sampler.next())/y as f32)*2.0 - 1.0);
+                self.add_sample(scene, Vec3::xyz(u, v, 0.0), i, j, sampler, &mut stats[p], aovs, exposure);
+            }
+        };
+
+        //the first samples go out in passes over the image, so that a
+        //cancelled render stops once every pixel has one
+        let first = (spp/4).max(16).min(spp);
+        for pass in 0..first {
+            for p in 0..stats.len() {
+                if pass > 0 && control.should_stop() {
+                    return;
+                }
+                sample_pixel(p, 1, stats);
+            }
+        }
+        spent += first*stats.len() as u64;
+
+        let round = (budget/8).max(1);
+        while spent < budget && !control.should_stop() {
+            control.report(&format!("Adaptive round, {} samples left", budget - spent));
+            //A pixel whose few samples happened to agree (e.g. were all
+            //black) looks converged, so the error is the largest in its 3x3
+            //neighbourhood
+            let own: Vec<f32> = stats.iter().map(|p| p.relative_error()).collect();
+            let mut errors = vec![0.0; stats.len()];
+            let mut total = 0.0;
+            for (p, error) in errors.iter_mut().enumerate() {
+                let (i, j) = ((p as u32 % x) as i64, (p as u32 / x) as i64);
+                for qj in (j - 1).max(0)..(j + 2).min(y as i64) {
+                    for qi in (i - 1).max(0)..(i + 2).min(x as i64) {
+                        *error = f32::max(*error, own[(qj*x as i64 + qi) as usize]);
                     }
                 }
+                if *error >= threshold {
+                    total += *error as f64;
+                }
+            }
+            if total <= 0.0 {
+                break;
+            }
+            let this_round = round.min(budget - spent) as f64;
+            for (p, &error) in errors.iter().enumerate() {
+                if control.should_stop() {
+                    return;
+                }
+                if error >= threshold && spent < budget {
+                    let count = ((this_round*error as f64/total).ceil() as u64).min(budget - spent);
+                    sample_pixel(p, count, stats);
+                    spent += count;
+                }
+            }
+        }
+    }
 
-                col = &col / (self.samples*self.samples) as f32;
+    //Renders passes of one sample per pixel, writing the image and the
+    //checkpoint every so often. Stops after samples*samples passes, or the
+    //target sample count of control if set; with only a target noise level
+    //it goes on until every pixel reaches it.
+    fn render_progressive(&self, scene: &mut Scene, state: &mut Checkpoint, exposure: f32, fname: &str,
+        control: &RenderControl) -> Result<(), String> {
+        let (x, y) = (state.width, state.height);
+        let passes = match (control.target_samples(), control.target_noise()) {
+            (Some(n), _) => n,
+            (None, Some(_)) => u32::MAX,
+            (None, None) => self.samples*self.samples
+        };
+        let interval = self.progressive.unwrap_or(60.0);
+        let mut last_write = std::time::Instant::now();
+        for pass in state.passes..passes {
+            if control.should_stop() {
+                control.report(&format!("Stopping after {} passes", pass));
+                break;
+            }
+            if let Some(target) = control.target_noise() {
+                //the first passes say little about the error
+                if pass >= 16 && state.stats.iter().all(|p| p.relative_error() <= target) {
+                    control.report(&format!("Noise target reached after {} passes", pass));
+                    break;
+                }
+            }
+            control.report(&format!("Pass {} of {}", pass + 1, passes));
+            for j in 0..y {
+                for i in 0..x {
+                    let p = (j*x + i) as usize;
+                    let mut sampler = RandomSampler::with_stream(p as u64, pass as u64);
+                    let u = ((i as f32 + sampler.next())/x as f32)*2.0 - 1.0;
+                    let v = -(((j as f32 + sampler.next())/y as f32)*2.0 - 1.0);
+                    self.add_sample(scene, Vec3::xyz(u, v, 0.0), i, j, &mut sampler, &mut state.stats[p], &mut state.aovs, exposure);
+                }
+            }
+            state.passes = pass + 1;
+            if state.passes < passes && last_write.elapsed().as_secs_f32() >= interval {
+                PtRenderer::develop(scene, &state.stats, exposure);
+                scene.camera.save_image(fname);
+                //a later write may still succeed, so the render goes on
+                if let Err(e) = self.save_checkpoint(state) {
+                    control.report(&e);
+                }
+                last_write = std::time::Instant::now();
+            }
+        }
+        self.save_checkpoint(state)
+    }
 
-                
+    //the checkpoint to continue from, if its file exists
+    fn resume(&self, (x, y): (u32, u32), with_aovs: bool, control: &RenderControl) -> Result<Option<Checkpoint>, String> {
+        let checkpoint = match &self.checkpoint {
+            Some(c) if std::path::Path::new(c).exists() => c,
+            _ => {return Ok(None);}
+        };
+        let loaded = Checkpoint::load(checkpoint).map_err(|e| format!("couldn't resume: {}", e))?;
+        if loaded.width != x || loaded.height != y || loaded.aovs.is_some() != with_aovs {
+            return Err(format!("couldn't resume: {} is for a different image size or AOV setting", checkpoint));
+        }
+        control.report(&format!("Resuming after {} passes", loaded.passes));
+        Ok(Some(loaded))
+    }
+
+    fn save_checkpoint(&self, state: &Checkpoint) -> Result<(), String> {
+        match &self.checkpoint {
+            Some(checkpoint) => state.save(checkpoint).map_err(|e| format!("couldn't save checkpoint: {}", e)),
+            None => Ok(())
+        }
+    }
+
+    //Writes the mean of every pixel to the camera image. Returns it as a
+    //film, and the variance of the means.
+    fn develop(scene: &mut Scene, stats: &[PixelStats], exposure: f32) -> (Film, Film) {
+        let (x, y) = scene.camera.image().dimensions();
+        let mut film = Film::new(x, y);
+        let mut variance = Film::new(x, y);
+        for j in 0..y {
+            for i in 0..x {
+                let p = &stats[(j*x + i) as usize];
+                let col = p.mean*exposure;
+                film.add(i, j, &col);
+                variance.add(i, j, &(p.variance()*(exposure*exposure)));
                 scene.camera.set_pixel(i,j,[linear2srgb(col.x),linear2srgb(col.y),linear2srgb(col.z)]);
             }
         }
+        (film, variance)
+    }
+
+    //adds one camera sample to the AOVs of pixel (i,j)
+    fn add_aovs(scene: &Scene, aovs: &mut AovFilm, i: u32, j: u32, ray: &Ray, lobes: &Lobes, exposure: f32) {
+        aovs.add(Aov::Emission, i, j, &(lobes.emission*exposure));
+        aovs.add(Aov::DirectDiffuse, i, j, &(lobes.direct_diffuse*exposure));
+        aovs.add(Aov::IndirectDiffuse, i, j, &(lobes.indirect_diffuse*exposure));
+        aovs.add(Aov::Specular, i, j, &(lobes.specular*exposure));
+
+        let mut r = Ray::with_time(ray.o, ray.d, ray.time);
+        let mut cost = 0;
+        let x = match first_surface(scene, &mut r, &mut cost) {
+            Some(x) => x,
+            None => {return;}
+        };
+        aovs.add(Aov::Albedo, i, j, &x.mat.albedo(&x));
+        aovs.add(Aov::Normal, i, j, &x.n);
+        aovs.add(Aov::Depth, i, j, &Vec3::from((x.p - ray.o).len()));
+        aovs.add(Aov::Position, i, j, &x.p);
+        aovs.add(Aov::ObjectId, i, j, &false_colour(x.object));
+        aovs.add(Aov::MaterialId, i, j, &false_colour(material_id(x.mat)));
+
+        //where the point projects to at shutter open and close
+        let (open, close) = scene.camera.shutter();
+        if close > open {
+            let p0 = x.p + (x.dpdt*(open - ray.time));
+            let p1 = x.p + (x.dpdt*(close - ray.time));
+            if let (Some(a), Some(b)) = (scene.camera.sample_importance(&p0, open), scene.camera.sample_importance(&p1, close)) {
+                let motion = Vec3::xyz((b.u.x - a.u.x)*0.5*aovs.get(Aov::Motion).width as f32,
+                                       (a.u.y - b.u.y)*0.5*aovs.get(Aov::Motion).height as f32, 0.0);
+                aovs.add(Aov::Motion, i, j, &motion);
+            }
+        }
+    }
+}
+
+impl Renderer for PtRenderer{
+    fn render(&self, scene: &mut Scene, fname: &str, control: &RenderControl) -> Result<(), String> {
+        let image = scene.camera.image();
+        let (x,y) = image.dimensions();
+        //the denoiser is guided by the albedo and normal AOVs
+        let mut aovs = if self.aovs || self.denoise {Some(AovFilm::new(x, y))} else {None};
+        let mut stats = vec![PixelStats::new(); (x*y) as usize];
+        let mut samplers: Vec<RandomSampler> = (0..x*y).map(|p| RandomSampler::new(p as u64)).collect();
+        let exposure = scene.camera.exposure();
+        let progressive = self.progressive.is_some() || self.checkpoint.is_some() || control.is_limited();
+        if progressive && self.adaptive.is_some() {
+            return Err(String::from("adaptive sampling can't be combined with progressive rendering, checkpoints or render limits"));
+        }
+        //a checkpoint that can't be resumed stops the render before it starts,
+        //failing to save one only after the image is written
+        let mut result = match self.adaptive {
+            _ if progressive => {
+                let mut state = match self.resume((x, y), aovs.is_some(), control)? {
+                    Some(loaded) => loaded,
+                    None => Checkpoint{width: x, height: y, passes: 0, stats: std::mem::take(&mut stats), aovs: aovs.take()}
+                };
+                let saved = self.render_progressive(scene, &mut state, exposure, fname, control);
+                stats = state.stats;
+                aovs = state.aovs;
+                saved
+            }
+            Some(threshold) => {
+                self.render_adaptive(scene, (x, y), threshold, &mut stats, &mut samplers, &mut aovs, exposure, control);
+                Ok(())
+            }
+            None => {
+                self.render_fixed(scene, (x, y), &mut stats, &mut samplers, &mut aovs, exposure, control);
+                Ok(())
+            }
+        };
+
+        let (film, variance) = PtRenderer::develop(scene, &stats, exposure);
         scene.camera.save_image(fname);
+
+        let path = std::path::Path::new(fname);
+        let stem = path.with_extension("").to_string_lossy().into_owned();
+        let ext = path.extension().map(|e| e.to_string_lossy().into_owned()).unwrap_or(String::from("png"));
+        if let Some(aovs) = &mut aovs {
+            for j in 0..y {
+                for i in 0..x {
+                    aovs.scale_pixel(i, j, 1.0/stats[(j*x + i) as usize].n.max(1) as f32);
+                }
+            }
+            //the other outputs are still written if the AOVs can't be
+            if self.aovs {
+                if let Err(e) = aovs.save(&stem, 1.0) {
+                    result = result.and(Err(format!("couldn't save AOVs: {}", e)));
+                }
+            }
+            if self.denoise {
+                //pixels need two samples for a variance of their own
+                let variance = if stats.iter().all(|p| p.n >= 2) {Some(&variance)} else {None};
+                let denoised = Denoiser::new().denoise(&film, variance, aovs.get(Aov::Albedo), aovs.get(Aov::Normal));
+                denoised.develop(&mut *scene.camera, 1.0);
+                scene.camera.save_image(&format!("{}_denoised.{}", stem, ext));
+            }
+        }
+        if self.adaptive.is_some() {
+            //sample count heatmap, scaled by the largest count
+            let max = stats.iter().fold(1, |a, p| a.max(p.n)) as f32;
+            for j in 0..y {
+                for i in 0..x {
+                    let c = heatmap(stats[(j*x + i) as usize].n as f32/max);
+                    scene.camera.set_pixel(i, j, [c.x, c.y, c.z]);
+                }
+            }
+            scene.camera.save_image(&format!("{}_samples.{}", stem, ext));
+        }
+        result
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,248 @@
+use std::f32::consts::PI;
+
+//Source of the random numbers an integrator consumes, in order. Keeping all
+//randomness behind it makes renders reproducible and lets Metropolis
+//renderers replay and mutate the numbers a path was built from.
+pub trait Sampler {
+    //next uniform number in [0,1)
+    fn next(&mut self) -> f32;
+}
+
+
+//PCG32 generator (O'Neill 2014): small state, independent streams
+pub struct Pcg32 {
+    state: u64,
+    inc: u64
+}
+
+impl Pcg32 {
+    pub fn new(seed: u64, stream: u64) -> Pcg32 {
+        let mut rng = Pcg32{state: 0, inc: (stream << 1) | 1};
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32*(1.0/16777216.0)
+    }
+}
+
+
+//Independent uniform numbers
+pub struct RandomSampler {
+    rng: Pcg32
+}
+
+impl RandomSampler {
+    pub fn new(seed: u64) -> RandomSampler {
+        RandomSampler{rng: Pcg32::new(seed, 0)}
+    }
+
+    //independent sequences for the same seed
+    pub fn with_stream(seed: u64, stream: u64) -> RandomSampler {
+        RandomSampler{rng: Pcg32::new(seed, stream)}
+    }
+}
+
+impl Sampler for RandomSampler {
+    fn next(&mut self) -> f32 {
+        self.rng.next_f32()
+    }
+}
+
+
+#[derive(Copy,Clone)]
+struct PrimarySample {
+    value: f32,
+    //iteration of the last change, and the state before it
+    modified: u64,
+    backup_value: f32,
+    backup_modified: u64
+}
+
+//Primary sample space sampler for Metropolis light transport (Kelemen et
+//al. 2002). The numbers handed out are a point in the unit hypercube that
+//is mutated every iteration, either with a small gaussian step or, with
+//probability large_step_probability, replaced entirely. Coordinates are
+//mutated lazily when they are first used in an iteration, so paths of any
+//length work. After an iteration the mutation is accepted or rejected.
+pub struct MltSampler {
+    rng: Pcg32,
+    sigma: f32,
+    large_step_probability: f32,
+    x: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    index: usize
+}
+
+impl MltSampler {
+    //the first iteration is a large step, so samplers with the same seed
+    //start on the same path
+    pub fn new(seed: u64, sigma: f32, large_step_probability: f32) -> MltSampler {
+        MltSampler{
+            rng: Pcg32::new(seed, 1),
+            sigma,
+            large_step_probability,
+            x: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0
+        }
+    }
+
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.next_f32() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for xi in self.x.iter_mut() {
+            if xi.modified == self.iteration {
+                xi.value = xi.backup_value;
+                xi.modified = xi.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn normal(&mut self) -> f32 {
+        let u1 = 1.0 - self.rng.next_f32();
+        let u2 = self.rng.next_f32();
+        (-2.0*u1.ln()).sqrt()*(2.0*PI*u2).cos()
+    }
+
+    //brings coordinate i up to date with the current iteration
+    fn ensure_ready(&mut self, i: usize) {
+        while self.x.len() <= i {
+            self.x.push(PrimarySample{value: 0.0, modified: 0, backup_value: 0.0, backup_modified: 0});
+        }
+        //a coordinate unused since the last accepted large step takes part
+        //in it retroactively
+        if self.x[i].modified < self.last_large_step {
+            self.x[i].value = self.rng.next_f32();
+            self.x[i].modified = self.last_large_step;
+        }
+        let mut xi = self.x[i];
+        xi.backup_value = xi.value;
+        xi.backup_modified = xi.modified;
+        if self.large_step {
+            xi.value = self.rng.next_f32();
+        } else {
+            //the small steps skipped while unused add up to a wider gaussian
+            let steps = (self.iteration - xi.modified) as f32;
+            xi.value += self.normal()*self.sigma*steps.sqrt();
+            xi.value = (xi.value - xi.value.floor()).min(1.0 - f32::EPSILON);
+        }
+        xi.modified = self.iteration;
+        self.x[i] = xi;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next(&mut self) -> f32 {
+        let i = self.index;
+        self.ensure_ready(i);
+        self.index += 1;
+        self.x[i].value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //the values of the first n coordinates in the current iteration
+    fn draw(sampler: &mut MltSampler, n: usize) -> Vec<f32> {
+        (0..n).map(|_| sampler.next()).collect()
+    }
+
+    fn current(sampler: &MltSampler) -> Vec<f32> {
+        sampler.x.iter().map(|x| x.value).collect()
+    }
+
+    #[test]
+    fn rejected_mutations_are_undone_and_accepted_ones_kept() {
+        let mut sampler = MltSampler::new(7, 0.05, 0.0);
+        let first = draw(&mut sampler, 8);
+        for iteration in 0..100 {
+            let before = current(&sampler);
+            sampler.start_iteration();
+            //paths of varying length touch varying coordinates
+            let used = 4 + iteration%5;
+            let mutated = draw(&mut sampler, used);
+            assert!(mutated.iter().zip(before.iter()).any(|(a, b)| a != b));
+            if iteration%2 == 0 {
+                sampler.reject();
+                assert_eq!(current(&sampler), before);
+            } else {
+                sampler.accept();
+                assert_eq!(current(&sampler)[..used], mutated[..]);
+            }
+        }
+        assert_ne!(current(&sampler), first);
+
+        //after a rejection the next iteration starts from the restored state,
+        //so the same mutation replays the same numbers
+        let mut a = MltSampler::new(3, 0.05, 0.0);
+        let mut b = MltSampler::new(3, 0.05, 0.0);
+        draw(&mut a, 6);
+        draw(&mut b, 6);
+        a.start_iteration();
+        draw(&mut a, 6);
+        a.reject();
+        assert_eq!(current(&a), current(&b));
+    }
+
+    #[test]
+    fn large_steps_draw_fresh_uniforms() {
+        let mut small = MltSampler::new(1, 0.001, 0.0);
+        let mut large = MltSampler::new(1, 0.001, 1.0);
+        let mut prev_small = draw(&mut small, 4);
+        let mut prev_large = draw(&mut large, 4);
+        let (mut far, mut sum, n) = (0, 0.0, 2000);
+        for _ in 0..n {
+            small.start_iteration();
+            large.start_iteration();
+            let s = draw(&mut small, 4);
+            let l = draw(&mut large, 4);
+            small.accept();
+            large.accept();
+            for k in 0..4 {
+                //small steps wrap around the unit interval
+                let d = (s[k] - prev_small[k]).abs();
+                assert!(d.min(1.0 - d) < 0.01, "small step of {}", d);
+                if (l[k] - prev_large[k]).abs() > 0.1 {
+                    far += 1;
+                }
+                assert!((0.0..1.0).contains(&l[k]));
+                sum += l[k] as f64;
+            }
+            prev_small = s;
+            prev_large = l;
+        }
+        //independent uniforms are more than 0.1 apart 81% of the time
+        assert!(far as f32 > 0.75*(4*n) as f32, "{} far apart", far);
+        let mean = sum/(4*n) as f64;
+        assert!((mean - 0.5).abs() < 0.02, "mean {}", mean);
+    }
+}
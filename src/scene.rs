@@ -1,10 +1,77 @@
 use crate::primitive::Primitive;
+use crate::primitive::Group;
+use crate::primitive::SimpleGroup;
 use crate::light::Light;
 use crate::camera::Camera;
-use crate::primitive::Group;
+use crate::medium::Medium;
+use std::sync::Arc;
+
+//Owns everything it renders: the primitives (which hold their materials),
+//the lights and the camera. Materials, media and lights are reference
+//counted, so they can be shared between primitives and with the loader that
+//made them, and the whole scene can be sent to or shared between threads.
+pub struct Scene {
+    pub(crate) primitive: Box<dyn Group>,
+    pub(crate) lights: Vec<Arc<dyn Light>>,
+    pub(crate) camera: Box<dyn Camera>,
+    //atmosphere filling the space outside of any closed primitive
+    pub(crate) medium: Option<Arc<dyn Medium>>
+}
+
+impl Scene {
+    //an empty scene, whose primitives are tested one after the other
+    pub fn new(camera: Box<dyn Camera>) -> Scene {
+        Scene::with_group(Box::new(SimpleGroup::new()), camera)
+    }
+
+    //an empty scene that adds its primitives to group, e.g. a Bvh
+    pub fn with_group(group: Box<dyn Group>, camera: Box<dyn Camera>) -> Scene {
+        Scene{primitive: group, lights: Vec::new(), camera, medium: None}
+    }
+
+    //build_index has to be called after the last add
+    pub fn add(&mut self, primitive: Box<dyn Primitive>) {
+        self.primitive.add(primitive);
+    }
+
+    pub fn build_index(&mut self) {
+        self.primitive.build_index();
+    }
+
+    //lights are sampled for next event estimation; their geometry has to
+    //be added as a primitive as well
+    pub fn add_light(&mut self, light: Arc<dyn Light>) {
+        self.lights.push(light);
+    }
+
+    pub fn set_medium(&mut self, medium: Option<Arc<dyn Medium>>) {
+        self.medium = medium;
+    }
+
+    pub fn set_camera(&mut self, camera: Box<dyn Camera>) {
+        self.camera = camera;
+    }
+
+    pub fn primitive(&self) -> &dyn Primitive {
+        &*self.primitive
+    }
+
+    pub fn lights(&self) -> &[Arc<dyn Light>] {
+        &self.lights
+    }
+
+    pub fn camera(&self) -> &dyn Camera {
+        &*self.camera
+    }
+
+    pub fn camera_mut(&mut self) -> &mut dyn Camera {
+        &mut *self.camera
+    }
+}
 
-pub struct Scene<'a> {
-    pub primitive: &'a dyn Primitive,
-    pub lights: Vec<&'a dyn Light>,
-    pub camera: &'a mut dyn Camera
-}
\ No newline at end of file
+//renderers may share the scene between threads
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<Scene>();
+}
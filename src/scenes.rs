@@ -0,0 +1,190 @@
+use crate::math::Vec3;
+use crate::builder::SceneBuilder;
+use crate::camera::CameraPerspective;
+use crate::material::Material;
+use crate::material::DiffuseMaterial;
+use crate::material::DielectricMaterial;
+use crate::material::EmissionMaterial;
+use crate::primitive::Disk;
+use crate::primitive::Rect;
+use crate::primitive::Cuboid;
+use crate::primitive::Cylinder;
+use crate::primitive::Cone;
+use crate::primitive::Torus;
+use crate::primitive::Sphere;
+use crate::primitive::Csg;
+use crate::primitive::CsgOp;
+use crate::sdf::SdfExpr;
+use std::sync::Arc;
+
+//Built-in scenes for the command line and for tests, square images of the
+//given size. They are returned as builders so that callers can add to
+//them or swap the camera before building.
+
+pub const NAMES: [&str; 6] = ["cornell", "cornell-area", "furnace", "preview", "shapes", "implicit"];
+
+//Cornell box of huge spheres, lit by the cap of another one through the
+//ceiling, with two small white balls
+pub fn cornell_box(size: u32) -> SceneBuilder {
+    SceneBuilder::new()
+        .material("white", DiffuseMaterial::new(Vec3::xyz(0.8, 0.8, 0.8)))
+        .material("green", DiffuseMaterial::new(Vec3::xyz(0.05, 0.8, 0.05)))
+        .material("red", DiffuseMaterial::new(Vec3::xyz(0.8, 0.05, 0.05)))
+        .material("light", EmissionMaterial::new(Vec3::xyz(1.0, 1.0, 1.0), 6.0))
+        .sphere(Vec3::xyz(0.3, 0.0, 0.1), 0.2).with_material("white")
+        .sphere(Vec3::xyz(-0.2, -0.5, -0.8), 0.2).with_material("white")
+        .sphere(Vec3::xyz(1001.0, 0.0, 0.0), 1000.0).with_material("green")
+        .sphere(Vec3::xyz(-1001.0, 0.0, 0.0), 1000.0).with_material("red")
+        .sphere(Vec3::xyz(0.0, 0.0, 1001.0), 1000.0).with_material("white")
+        .sphere(Vec3::xyz(0.0, 0.0, -1001.0), 1000.0).with_material("white")
+        .sphere(Vec3::xyz(0.0, -1001.0, 0.0), 1000.0).with_material("white")
+        .sphere(Vec3::xyz(0.6, 0.0, 7.05), 6.06).with_material("light")
+        .camera(CameraPerspective::new(size, size, 1.0, Vec3::xyz(0.0, 1.0, 0.0), Vec3::xyz(0.0, -1.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0)))
+}
+
+//The same box with rectangles for walls, reaching far behind the camera,
+//lit by a disk just below the ceiling
+pub fn cornell_box_area(size: u32) -> SceneBuilder {
+    SceneBuilder::new()
+        .material("white", DiffuseMaterial::new(Vec3::xyz(0.8, 0.8, 0.8)))
+        .material("green", DiffuseMaterial::new(Vec3::xyz(0.05, 0.8, 0.05)))
+        .material("red", DiffuseMaterial::new(Vec3::xyz(0.8, 0.05, 0.05)))
+        .material("light", EmissionMaterial::new(Vec3::xyz(1.0, 1.0, 1.0), 6.0))
+        .sphere(Vec3::xyz(0.3, 0.0, 0.1), 0.2).with_material("white")
+        .sphere(Vec3::xyz(-0.2, -0.5, -0.8), 0.2).with_material("white")
+        .surface(|m| Rect::axis_aligned(Vec3::xyz(1.0, -1.0, -1.0), Vec3::xyz(1.0, 5.0, 1.0), m)).with_material("green")
+        .surface(|m| Rect::axis_aligned(Vec3::xyz(-1.0, -1.0, -1.0), Vec3::xyz(-1.0, 5.0, 1.0), m)).with_material("red")
+        .surface(|m| Rect::axis_aligned(Vec3::xyz(-1.0, -1.0, 1.0), Vec3::xyz(1.0, 5.0, 1.0), m)).with_material("white")
+        .surface(|m| Rect::axis_aligned(Vec3::xyz(-1.0, -1.0, -1.0), Vec3::xyz(1.0, 5.0, -1.0), m)).with_material("white")
+        .surface(|m| Rect::axis_aligned(Vec3::xyz(-1.0, -1.0, -1.0), Vec3::xyz(1.0, -1.0, 1.0), m)).with_material("white")
+        .surface(|m| Disk::new(Vec3::xyz(0.6, 0.0, 0.99), Vec3::xyz(0.0, 0.0, -1.0), 0.35, m)).with_material("light")
+        .camera(CameraPerspective::new(size, size, 1.0, Vec3::xyz(0.0, 1.0, 0.0), Vec3::xyz(0.0, -1.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0)))
+}
+
+//White furnace: a ball of the given albedo inside a uniformly emitting dome
+//of radiance one. Being convex, the ball only sees the dome, so it renders
+//as exactly its albedo and the background as one; anything else is a bug
+//in the integrator or in energy conservation.
+pub fn furnace(size: u32, albedo: f32) -> SceneBuilder {
+    SceneBuilder::new()
+        .material("ball", DiffuseMaterial::new(Vec3::from(albedo)))
+        .material("dome", EmissionMaterial::new(Vec3::xyz(1.0, 1.0, 1.0), 1.0))
+        .sphere(Vec3::new(), 1.0).with_material("ball")
+        .sphere(Vec3::new(), 100.0).with_material("dome")
+        .camera(CameraPerspective::new(size, size, 0.5, Vec3::xyz(0.0, -4.0, 0.0), Vec3::xyz(0.0, 1.0, 0.0), Vec3::xyz(0.0, 0.0, 1.0)))
+}
+
+//A ball of the material on a grey floor, lit from the upper left
+pub fn material_preview<M: Material + 'static>(size: u32, material: M) -> SceneBuilder {
+    SceneBuilder::new()
+        .material("preview", material)
+        .material("floor", DiffuseMaterial::new(Vec3::xyz(0.5, 0.5, 0.5)))
+        .material("light", EmissionMaterial::new(Vec3::xyz(1.0, 1.0, 1.0), 8.0))
+        .sphere(Vec3::xyz(0.0, 0.0, 0.0), 0.5).with_material("preview")
+        .sphere(Vec3::xyz(0.0, 0.0, -1000.5), 1000.0).with_material("floor")
+        .sphere(Vec3::xyz(-1.5, -1.0, 2.0), 0.5).with_material("light")
+        .camera(CameraPerspective::new(size, size, 0.5, Vec3::xyz(0.0, -3.0, 0.8), Vec3::xyz(0.0, 1.0, -0.25), Vec3::xyz(0.0, 0.0, 1.0)))
+}
+
+//One of each analytic shape on a plane, lit by a disk
+pub fn shapes(size: u32) -> SceneBuilder {
+    let builder = SceneBuilder::new()
+        .material("floor", DiffuseMaterial::new(Vec3::xyz(0.5, 0.5, 0.5)))
+        .material("red", DiffuseMaterial::new(Vec3::xyz(0.8, 0.1, 0.1)))
+        .material("green", DiffuseMaterial::new(Vec3::xyz(0.1, 0.7, 0.1)))
+        .material("blue", DiffuseMaterial::new(Vec3::xyz(0.1, 0.2, 0.8)))
+        .material("yellow", DiffuseMaterial::new(Vec3::xyz(0.8, 0.7, 0.1)))
+        .material("white", DiffuseMaterial::new(Vec3::xyz(0.8, 0.8, 0.8)))
+        .material("light", EmissionMaterial::new(Vec3::xyz(1.0, 1.0, 1.0), 8.0));
+    let part = machined_part(Vec3::xyz(1.4, 1.2, 0.41), builder.lookup_material("white").unwrap()).unwrap();
+    builder
+        .primitive(Box::new(part))
+        .plane(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0)).with_material("floor")
+        .surface(|m| Cuboid::oriented(Vec3::xyz(-1.2, 0.4, 0.35), Vec3::xyz(1.0, 0.6, 0.0), Vec3::xyz(0.0, 0.0, 1.0), Vec3::from(0.7), m)).with_material("red")
+        .surface(|m| Cylinder::new(Vec3::xyz(-0.3, -0.4, 0.0), Vec3::xyz(0.0, 0.0, 0.9), 0.3, true, m)).with_material("green")
+        .surface(|m| Cone::new(Vec3::xyz(0.5, 0.5, 0.0), Vec3::xyz(0.0, 0.0, 1.0), 0.4, true, m)).with_material("blue")
+        .surface(|m| Torus::new(Vec3::xyz(1.3, -0.3, 0.2), Vec3::xyz(0.3, -0.2, 1.0), 0.4, 0.15, m)).with_material("yellow")
+        .surface(|m| Disk::new(Vec3::xyz(-1.0, -1.5, 3.0), Vec3::xyz(0.3, 0.4, -1.0), 0.6, m)).with_material("light")
+        .camera(CameraPerspective::new(size, size, 0.6, Vec3::xyz(0.0, -4.5, 2.0), Vec3::xyz(0.0, 1.0, -0.4), Vec3::xyz(0.0, 0.0, 1.0)))
+}
+
+//a block with rounded corners and a hole through it, made with CSG
+fn machined_part(o: Vec3, mat: Arc<dyn Material>) -> Result<Csg, String> {
+    let cube = Cuboid::new(o - Vec3::from(0.4), o + Vec3::from(0.4), mat.clone())?;
+    let block = Csg::new(CsgOp::Intersection, Box::new(cube), Box::new(Sphere::new(o, 0.55, mat.clone())))?;
+    let axis = Vec3::xyz(0.3, -0.6, 1.0).normalized();
+    let hole = Cylinder::new(o - (axis*0.6), axis*1.2, 0.2, true, mat)?;
+    Csg::new(CsgOp::Difference, Box::new(block), Box::new(hole))
+}
+
+//implicit surfaces in the format of SdfExpr::parse, with their materials
+const IMPLICIT: [(&str, &str); 4] = [
+    ("red", "(translate -1.2 0.2 0.6 (twist 2 (round-box 0.25 0.25 0.6 0.06)))"),
+    ("green", "(translate -0.1 -0.3 0.4 (smooth-union 0.25 (sphere 0.4) (capsule 0 0 0 0.5 0.3 0.5 0.12)))"),
+    ("blue", "(translate 1.1 0.1 0.4 (smooth-subtract 0.08 (box 0.4 0.4 0.4) (sphere 0.52)))"),
+    ("yellow", "(translate 0 1.4 0.1 (repeat 0.8 0 0 2 0 0 (torus 0.28 0.1)))")
+];
+
+//Implicit surfaces on a floor: a twisted bar, a blob, a box hollowed out
+//by a sphere and a row of rings
+pub fn implicit(size: u32) -> SceneBuilder {
+    let mut builder = SceneBuilder::new()
+        .material("floor", DiffuseMaterial::new(Vec3::xyz(0.5, 0.5, 0.5)))
+        .material("red", DiffuseMaterial::new(Vec3::xyz(0.8, 0.1, 0.1)))
+        .material("green", DiffuseMaterial::new(Vec3::xyz(0.1, 0.7, 0.1)))
+        .material("blue", DiffuseMaterial::new(Vec3::xyz(0.1, 0.2, 0.8)))
+        .material("yellow", DiffuseMaterial::new(Vec3::xyz(0.8, 0.7, 0.1)))
+        .material("light", EmissionMaterial::new(Vec3::xyz(1.0, 1.0, 1.0), 8.0))
+        .plane(Vec3::new(), Vec3::xyz(0.0, 0.0, 1.0)).with_material("floor")
+        .surface(|m| Disk::new(Vec3::xyz(-1.0, -1.5, 3.0), Vec3::xyz(0.3, 0.4, -1.0), 0.6, m)).with_material("light")
+        .camera(CameraPerspective::new(size, size, 0.6, Vec3::xyz(0.0, -4.5, 2.0), Vec3::xyz(0.0, 1.0, -0.4), Vec3::xyz(0.0, 0.0, 1.0)));
+    for (material, text) in IMPLICIT.iter() {
+        builder = builder.sdf(SdfExpr::parse(text).unwrap()).with_material(material);
+    }
+    builder
+}
+
+//one of NAMES; the preview ball is glass
+pub fn by_name(name: &str, size: u32) -> Result<SceneBuilder, String> {
+    match name {
+        "cornell" => Ok(cornell_box(size)),
+        "cornell-area" => Ok(cornell_box_area(size)),
+        "furnace" => Ok(furnace(size, 0.8)),
+        "preview" => Ok(material_preview(size, DielectricMaterial::new(1.5))),
+        "shapes" => Ok(shapes(size)),
+        "implicit" => Ok(implicit(size)),
+        _ => Err(format!("unknown scene {}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::linear2srgb;
+    use crate::renderer::PtRenderer;
+    use crate::renderer::Renderer;
+    use crate::control::RenderControl;
+
+    #[test]
+    fn canonical_scenes_are_valid() {
+        for name in NAMES.iter() {
+            assert!(by_name(name, 8).unwrap().validate().is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn furnace_ball_renders_as_its_albedo() {
+        let (mut scene, _) = furnace(16, 0.5).build().unwrap();
+        let fname = std::env::temp_dir().join("rusty_pete_furnace_test.png");
+        PtRenderer::new(4).render(&mut scene, &fname.to_string_lossy(), &RenderControl::new()).unwrap();
+        let image = scene.camera_mut().image();
+        //the dome is also sampled as a light, so pixels are noisy; their
+        //mean over the middle of the ball is not
+        let ball = (6..10).flat_map(|i| (6..10).map(move |j| (i, j)))
+            .map(|(i, j)| image.get_pixel(i, j)[0] as f32).sum::<f32>()/16.0;
+        let background = image.get_pixel(0, 0)[0] as f32;
+        assert!((ball - linear2srgb(0.5)*255.0).abs() <= 2.0, "ball {}", ball);
+        assert!(background >= 254.0, "background {}", background);
+        std::fs::remove_file(fname).ok();
+    }
+}
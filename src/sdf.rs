@@ -0,0 +1,339 @@
+use crate::math::Vec3;
+use crate::primitive::Aabb;
+
+use std::fs;
+
+//Signed distance functions built up as expression trees: negative inside,
+//zero on the surface and positive outside. Up to the Lipschitz bound they
+//never exceed the distance to the surface, so that SdfPrimitive can march
+//rays by them. Shapes are centred at the origin; tori and twists go around
+//z, as for Torus.
+#[derive(Clone)]
+pub enum SdfExpr {
+    Sphere{r: f32},
+    //half the size along each axis
+    Cuboid{half: Vec3},
+    //box of the given half size with its edges rounded off by r
+    RoundBox{half: Vec3, r: f32},
+    Torus{major: f32, minor: f32},
+    //segment from a to b, thickened by r
+    Capsule{a: Vec3, b: Vec3, r: f32},
+    Union(Box<SdfExpr>, Box<SdfExpr>),
+    //union blended over about k
+    SmoothUnion{k: f32, a: Box<SdfExpr>, b: Box<SdfExpr>},
+    //a without b, blended over about k
+    SmoothSubtraction{k: f32, a: Box<SdfExpr>, b: Box<SdfExpr>},
+    Translate{offset: Vec3, e: Box<SdfExpr>},
+    //copies of e every period along each axis, count of them on either side
+    //of the original; e should fit within a period
+    Repeat{period: Vec3, count: [u32; 3], e: Box<SdfExpr>},
+    //e turned around z by k radians per unit of height
+    Twist{k: f32, e: Box<SdfExpr>}
+}
+
+fn box_distance(p: &Vec3, half: &Vec3) -> f32 {
+    let q = &p.abs() - half;
+    let outside = Vec3::xyz(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).len();
+    outside + q.x.max(q.y).max(q.z).min(0.0)
+}
+
+//polynomial smooth minimum, at most k/4 below min(a, b)
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    let h = (k - (a - b).abs()).max(0.0)/k;
+    a.min(b) - h*h*k*0.25
+}
+
+//distance from the z axis of the farthest corner of b
+fn radius_around_z(b: &Aabb) -> f32 {
+    let x = b.min.x.abs().max(b.max.x.abs());
+    let y = b.min.y.abs().max(b.max.y.abs());
+    (x*x + y*y).sqrt()
+}
+
+impl SdfExpr {
+    pub fn distance(&self, p: &Vec3) -> f32 {
+        match self {
+            SdfExpr::Sphere{r} => p.len() - r,
+            SdfExpr::Cuboid{half} => box_distance(p, half),
+            SdfExpr::RoundBox{half, r} => box_distance(p, &(half - &Vec3::from(*r))) - r,
+            SdfExpr::Torus{major, minor} => {
+                let rho = (p.x*p.x + p.y*p.y).sqrt() - major;
+                (rho*rho + p.z*p.z).sqrt() - minor
+            }
+            SdfExpr::Capsule{a, b, r} => {
+                let pa = p - a;
+                let ba = b - a;
+                let l2 = ba.lensqr();
+                let h = if l2 > 0.0 {(pa.dot(&ba)/l2).clamp(0.0, 1.0)} else {0.0};
+                (pa - (ba*h)).len() - r
+            }
+            SdfExpr::Union(a, b) => a.distance(p).min(b.distance(p)),
+            SdfExpr::SmoothUnion{k, a, b} => smooth_min(a.distance(p), b.distance(p), *k),
+            SdfExpr::SmoothSubtraction{k, a, b} => -smooth_min(-a.distance(p), b.distance(p), *k),
+            SdfExpr::Translate{offset, e} => e.distance(&(p - offset)),
+            SdfExpr::Repeat{period, count, e} => {
+                //into the nearest copy's cell
+                let fold = |c: f32, period: f32, n: u32| {
+                    if n == 0 {
+                        return c;
+                    }
+                    c - period*(c/period).round().clamp(-(n as f32), n as f32)
+                };
+                e.distance(&Vec3::xyz(fold(p.x, period.x, count[0]), fold(p.y, period.y, count[1]), fold(p.z, period.z, count[2])))
+            }
+            SdfExpr::Twist{k, e} => {
+                let (s, c) = (k*p.z).sin_cos();
+                e.distance(&Vec3::xyz(c*p.x - s*p.y, s*p.x + c*p.y, p.z))
+            }
+        }
+    }
+
+    //conservative bounds of the inside
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            SdfExpr::Sphere{r} => Aabb::around_sphere(&Vec3::new(), *r),
+            SdfExpr::Cuboid{half} | SdfExpr::RoundBox{half, ..} => Aabb{min: *half*(-1.0), max: *half},
+            SdfExpr::Torus{major, minor} => {
+                let outer = major + minor;
+                Aabb{min: Vec3::xyz(-outer, -outer, -minor), max: Vec3::xyz(outer, outer, *minor)}
+            }
+            SdfExpr::Capsule{a, b, r} => {
+                let ends = Aabb::around_sphere(a, *r);
+                ends.union(&Aabb::around_sphere(b, *r))
+            }
+            SdfExpr::Union(a, b) => a.bounds().union(&b.bounds()),
+            //the blend adds at most k/4 around the union
+            SdfExpr::SmoothUnion{k, a, b} => {
+                let u = a.bounds().union(&b.bounds());
+                let pad = Vec3::from(k*0.25);
+                Aabb{min: u.min - pad, max: u.max + pad}
+            }
+            //only ever takes away from a
+            SdfExpr::SmoothSubtraction{a, ..} => a.bounds(),
+            SdfExpr::Translate{offset, e} => {
+                let b = e.bounds();
+                Aabb{min: &b.min + offset, max: &b.max + offset}
+            }
+            SdfExpr::Repeat{period, count, e} => {
+                let b = e.bounds();
+                let spread = Vec3::xyz(period.x*count[0] as f32, period.y*count[1] as f32, period.z*count[2] as f32);
+                Aabb{min: b.min - spread, max: b.max + spread}
+            }
+            SdfExpr::Twist{e, ..} => {
+                let b = e.bounds();
+                let rho = radius_around_z(&b);
+                Aabb{min: Vec3::xyz(-rho, -rho, b.min.z), max: Vec3::xyz(rho, rho, b.max.z)}
+            }
+        }
+    }
+
+    //Bound on how much faster than the distance to the surface the function
+    //can change. It is 1 for exact distances; twisting shears space by up to
+    //s = k rho at a distance rho from the axis, which stretches it by up to
+    //(s + sqrt(s^2 + 4))/2.
+    pub fn lipschitz(&self) -> f32 {
+        match self {
+            SdfExpr::Union(a, b) | SdfExpr::SmoothUnion{a, b, ..} | SdfExpr::SmoothSubtraction{a, b, ..} =>
+                a.lipschitz().max(b.lipschitz()),
+            SdfExpr::Translate{e, ..} | SdfExpr::Repeat{e, ..} => e.lipschitz(),
+            SdfExpr::Twist{k, e} => {
+                let shear = k.abs()*radius_around_z(&e.bounds());
+                e.lipschitz()*(shear + (shear*shear + 4.0).sqrt())*0.5
+            }
+            _ => 1.0
+        }
+    }
+
+    //Parses an expression in prefix form, the numbers of each operation
+    //before its operands, e.g.
+    //    (smooth-union 0.1 (sphere 0.5) (translate 0 0 0.6 (capsule 0 0 0 0 0 0.5 0.2)))
+    //The operations are
+    //    (sphere r) (box hx hy hz) (round-box hx hy hz r) (torus major minor)
+    //    (capsule ax ay az bx by bz r) (union a b ...) (smooth-union k a b)
+    //    (smooth-subtract k a b) (translate x y z e) (repeat px py pz nx ny nz e)
+    //    (twist k e)
+    //with box sizes as half sizes. '#' starts a comment up to the end of the line.
+    pub fn parse(text: &str) -> Result<SdfExpr, String> {
+        let mut tokens = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let spaced = line.replace('(', " ( ").replace(')', " ) ");
+            tokens.extend(spaced.split_whitespace().map(String::from));
+        }
+        let mut next = 0;
+        let e = parse_expr(&tokens, &mut next)?;
+        if next < tokens.len() {
+            return Err(format!("unexpected '{}' after the expression", tokens[next]));
+        }
+        Ok(e)
+    }
+
+    pub fn load(filename: &str) -> Result<SdfExpr, String> {
+        match fs::read_to_string(filename) {
+            Ok(s) => SdfExpr::parse(&s),
+            Err(e) => Err(format!("couldn't read {}: {}", filename, e))
+        }
+    }
+}
+
+//numbers an operation takes, and how many operands at least and at most
+fn arity(name: &str) -> Option<(usize, usize, usize)> {
+    match name {
+        "sphere" => Some((1, 0, 0)),
+        "box" => Some((3, 0, 0)),
+        "round-box" => Some((4, 0, 0)),
+        "torus" => Some((2, 0, 0)),
+        "capsule" => Some((7, 0, 0)),
+        "union" => Some((0, 2, usize::MAX)),
+        "smooth-union" | "smooth-subtract" => Some((1, 2, 2)),
+        "translate" => Some((3, 1, 1)),
+        "repeat" => Some((6, 1, 1)),
+        "twist" => Some((1, 1, 1)),
+        _ => None
+    }
+}
+
+fn parse_expr(tokens: &[String], next: &mut usize) -> Result<SdfExpr, String> {
+    let token = |i: usize| tokens.get(i).map(|t| t.as_str());
+    match token(*next) {
+        Some("(") => {}
+        Some(t) => {return Err(format!("expected '(', got '{}'", t));}
+        None => {return Err(String::from("expected an expression, got the end"));}
+    }
+    let name = token(*next + 1).ok_or_else(|| String::from("expected an operation, got the end"))?;
+    let (numbers, min_operands, max_operands) = arity(name).ok_or_else(|| format!("unknown operation '{}'", name))?;
+    *next += 2;
+
+    let mut v = Vec::new();
+    for _ in 0..numbers {
+        match token(*next).and_then(|t| t.parse::<f32>().ok()) {
+            Some(x) if x.is_finite() => v.push(x),
+            _ => {return Err(format!("{}: expected {} numbers", name, numbers));}
+        }
+        *next += 1;
+    }
+    let mut operands = Vec::new();
+    while token(*next) == Some("(") {
+        operands.push(Box::new(parse_expr(tokens, next)?));
+    }
+    match token(*next) {
+        Some(")") => {*next += 1;}
+        Some(t) => {return Err(format!("{}: unexpected '{}'", name, t));}
+        None => {return Err(format!("{}: missing ')'", name));}
+    }
+    let n_operands = operands.len();
+    if n_operands < min_operands || n_operands > max_operands {
+        return Err(format!("{}: wrong number of operands, {}", name, n_operands));
+    }
+
+    let positive = |x: f32, what: &str| if x > 0.0 {Ok(x)} else {Err(format!("{}: {} must be positive", name, what))};
+    let mut operands = operands.into_iter();
+    let mut operand = || operands.next().unwrap();
+    let e = match name {
+        "sphere" => SdfExpr::Sphere{r: positive(v[0], "radius")?},
+        "box" => SdfExpr::Cuboid{half: Vec3::xyz(positive(v[0], "size")?, positive(v[1], "size")?, positive(v[2], "size")?)},
+        "round-box" => {
+            let half = Vec3::xyz(positive(v[0], "size")?, positive(v[1], "size")?, positive(v[2], "size")?);
+            let r = positive(v[3], "rounding")?;
+            if r > half.x.min(half.y).min(half.z) {
+                return Err(format!("{}: rounding is larger than the box", name));
+            }
+            SdfExpr::RoundBox{half, r}
+        }
+        "torus" => SdfExpr::Torus{major: positive(v[0], "major radius")?, minor: positive(v[1], "minor radius")?},
+        "capsule" => SdfExpr::Capsule{a: Vec3::xyz(v[0], v[1], v[2]), b: Vec3::xyz(v[3], v[4], v[5]), r: positive(v[6], "radius")?},
+        "union" => {
+            let mut e = *operand();
+            for _ in 1..n_operands {
+                e = SdfExpr::Union(Box::new(e), operand());
+            }
+            e
+        }
+        "smooth-union" => SdfExpr::SmoothUnion{k: positive(v[0], "blend distance")?, a: operand(), b: operand()},
+        "smooth-subtract" => SdfExpr::SmoothSubtraction{k: positive(v[0], "blend distance")?, a: operand(), b: operand()},
+        "translate" => SdfExpr::Translate{offset: Vec3::xyz(v[0], v[1], v[2]), e: operand()},
+        "repeat" => {
+            let mut count = [0; 3];
+            for i in 0..3 {
+                let n = v[3 + i];
+                if n < 0.0 || n.fract() != 0.0 {
+                    return Err(format!("{}: counts must be whole numbers", name));
+                }
+                if n > 0.0 {
+                    positive(v[i], "period")?;
+                }
+                count[i] = n as u32;
+            }
+            SdfExpr::Repeat{period: Vec3::xyz(v[0], v[1], v[2]), count, e: operand()}
+        }
+        "twist" => SdfExpr::Twist{k: v[0], e: operand()},
+        _ => unreachable!()
+    };
+    Ok(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_expressions() {
+        let e = SdfExpr::parse("
+            # a ball with a handle, and a ring beside it
+            (union
+                (smooth-union 0.1 (sphere 0.5) (capsule 0 0 0 0 0 1 0.1))
+                (translate 2 0 0 (torus 0.5 0.1))
+                (repeat 3 0 0 1 0 0 (box 0.2 0.2 0.2)))").unwrap();
+        assert!((e.distance(&Vec3::xyz(0.0, -1.0, 0.0)) - 0.5).abs() < 1e-6);
+        assert!(e.distance(&Vec3::xyz(0.0, 0.0, 0.9)) < 0.0);
+        assert!((e.distance(&Vec3::xyz(2.5, 0.0, 0.0)) + 0.1).abs() < 1e-6);
+        //the copy of the box at x = -3
+        assert!(e.distance(&Vec3::xyz(-3.0, 0.0, 0.0)) < 0.0);
+        let b = e.bounds();
+        assert!(b.min.x <= -3.2 && b.max.x >= 3.2 && b.max.z >= 1.1);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        let errors = [
+            ("(sphere)", "expected 1 numbers"),
+            ("(sphere -1)", "radius must be positive"),
+            ("(cylinder 1 2)", "unknown operation"),
+            ("(union (sphere 1))", "wrong number of operands"),
+            ("(translate 1 2 3 (sphere 1)", "missing ')'"),
+            ("(sphere 1) (sphere 2)", "after the expression"),
+            ("(round-box 1 1 0.1 0.2)", "rounding is larger"),
+            ("(repeat 1 1 1 1.5 0 0 (sphere 0.2))", "whole numbers")
+        ];
+        for (text, error) in errors.iter() {
+            match SdfExpr::parse(text) {
+                Ok(_) => panic!("{} parsed", text),
+                Err(e) => assert!(e.contains(error), "{}: {}", text, e)
+            }
+        }
+    }
+
+    //points where a twisted box is inside are within its bounds, and its
+    //distance doesn't change faster than the Lipschitz bound allows
+    #[test]
+    fn twists_stay_within_bounds_and_lipschitz() {
+        let e = SdfExpr::parse("(twist 3 (round-box 0.5 0.2 1 0.05))").unwrap();
+        let b = e.bounds();
+        let l = e.lipschitz();
+        let p = |i: u32, j: u32, k: u32| Vec3::xyz(-1.0 + 0.1*i as f32, -1.0 + 0.1*j as f32, -1.2 + 0.1*k as f32);
+        for i in 0..20 {
+            for j in 0..20 {
+                for k in 0..24 {
+                    let a = p(i, j, k);
+                    let da = e.distance(&a);
+                    if da < 0.0 {
+                        assert!(a.x >= b.min.x && a.x <= b.max.x && a.y >= b.min.y && a.y <= b.max.y && a.z >= b.min.z && a.z <= b.max.z);
+                    }
+                    for c in [p(i + 1, j, k), p(i, j + 1, k), p(i, j, k + 1)].iter() {
+                        assert!((da - e.distance(c)).abs() <= l*0.1 + 1e-5);
+                    }
+                }
+            }
+        }
+    }
+}
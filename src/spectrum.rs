@@ -0,0 +1,231 @@
+use crate::math::Vec3;
+use crate::math::Mat3;
+
+use std::sync::OnceLock;
+
+//Gaussian with different widths left and right of the mean, for the CIE fit
+fn lobe(x: f32, mu: f32, s1: f32, s2: f32) -> f32 {
+    let s = if x < mu {s1} else {s2};
+    let t = (x - mu)/s;
+    (-0.5*t*t).exp()
+}
+
+//CIE 1931 colour matching functions, multi-lobe fit by Wyman et al. 2013.
+//lambda in nanometers
+pub fn cie_xyz(lambda: f32) -> Vec3 {
+    Vec3::xyz(
+        1.056*lobe(lambda, 599.8, 37.9, 31.0) + 0.362*lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065*lobe(lambda, 501.1, 20.4, 26.2),
+        0.821*lobe(lambda, 568.8, 46.9, 40.5) + 0.286*lobe(lambda, 530.9, 16.3, 31.1),
+        1.217*lobe(lambda, 437.0, 11.8, 36.0) + 0.681*lobe(lambda, 459.0, 26.0, 13.8)
+    )
+}
+
+//linear sRGB (D65 white)
+#[allow(clippy::excessive_precision, reason = "the matrix as published, aligned")]
+pub fn xyz_to_rgb(c: &Vec3) -> Vec3 {
+    Vec3::xyz(
+         3.2404542*c.x - 1.5371385*c.y - 0.4985314*c.z,
+        -0.9692660*c.x + 1.8760108*c.y + 0.0415560*c.z,
+         0.0556434*c.x - 0.2040259*c.y + 1.0572252*c.z
+    )
+}
+
+//spectral radiance of a black body (Planck's law), lambda in nanometers,
+//in W/(sr m^2 nm)
+pub fn planck(lambda: f32, temperature: f32) -> f32 {
+    if temperature <= 0.0 {
+        return 0.0;
+    }
+    let c = 2.99792458e8f64;
+    let h = 6.62606957e-34f64;
+    let kb = 1.3806488e-23f64;
+    let l = lambda as f64*1e-9;
+    let le = (2.0*h*c*c)/(l.powi(5)*(((h*c)/(l*kb*temperature as f64)).exp() - 1.0));
+    (le*1e-9) as f32
+}
+
+//Range of visible wavelengths sampled in spectral mode, in nanometers
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+//integral of f(lambda) times the colour matching functions
+fn integrate_xyz(f: &dyn Fn(f32) -> f32) -> Vec3 {
+    let mut xyz = Vec3::new();
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        xyz = xyz + cie_xyz(lambda)*f(lambda);
+        lambda += 1.0;
+    }
+    xyz
+}
+
+//Normalisation shared by RGB and spectral mode, computed once
+struct Tables {
+    //luminance of the unnormalised 6500K black body
+    reference: f32,
+    //rgb of the normalised 6500K black body, which is mapped to white
+    white: Vec3,
+    //rgb to weights of the upsampling basis
+    basis_inverse: Mat3
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let reference = integrate_xyz(&|l| planck(l, 6500.0)).y;
+        let white = xyz_to_rgb(&integrate_xyz(&|l| planck(l, 6500.0)/reference));
+        let column = |k: usize| {
+            let xyz = integrate_xyz(&|l| basis(k, l)*planck(l, 6500.0)/reference);
+            let rgb = xyz_to_rgb(&xyz);
+            Vec3::xyz(rgb.x/white.x, rgb.y/white.y, rgb.z/white.z)
+        };
+        let m = Mat3::from_axis(&column(0), &column(1), &column(2));
+        Tables{
+            reference,
+            white,
+            basis_inverse: m.inverse().expect("degenerate spectral basis")
+        }
+    })
+}
+
+//Linear sRGB of a CIE XYZ value, white balanced so that the 6500K black
+//body (the illuminant of RGB emitters in spectral mode) is exactly white
+pub fn xyz_to_film(c: &Vec3) -> Vec3 {
+    let w = tables().white;
+    let rgb = xyz_to_rgb(c);
+    Vec3::xyz(rgb.x/w.x, rgb.y/w.y, rgb.z/w.z)
+}
+
+//Black body spectral radiance scaled so that a 6500K black body has
+//luminance 1
+pub fn blackbody(lambda: f32, temperature: f32) -> f32 {
+    planck(lambda, temperature)/tables().reference
+}
+
+//Linear sRGB colour of a black body, scaled so that a 6500K black body has
+//luminance 1. Brightness grows roughly with the fourth power of temperature.
+pub fn blackbody_rgb(temperature: f32) -> Vec3 {
+    xyz_to_film(&integrate_xyz(&|l| blackbody(l, temperature)))
+}
+
+fn smoothstep(edge: f32, width: f32, x: f32) -> f32 {
+    let t = ((x - edge)/width + 0.5).clamp(0.0, 1.0);
+    t*t*(3.0 - 2.0*t)
+}
+
+//Smooth blue, green and red bands that sum to one at every wavelength, so
+//a grey colour becomes a constant spectrum
+fn basis(k: usize, lambda: f32) -> f32 {
+    let blue_green = smoothstep(490.0, 30.0, lambda);
+    let green_red = smoothstep(585.0, 30.0, lambda);
+    match k {
+        0 => green_red,
+        1 => blue_green - green_red,
+        _ => 1.0 - blue_green
+    }
+}
+
+//Reflectance spectrum of an RGB colour at one wavelength. The band weights
+//are chosen so the spectrum lit by the white illuminant gives back the
+//colour; very saturated colours are clamped to stay non-negative.
+pub fn rgb_to_spectrum(rgb: &Vec3, lambda: f32) -> f32 {
+    let c = &tables().basis_inverse*rgb;
+    (c.x*basis(0, lambda) + c.y*basis(1, lambda) + c.z*basis(2, lambda)).max(0.0)
+}
+
+//Values of an RGB reflectance (or any other RGB quantity that scales light,
+//like scattering coefficients) at the given wavelengths. Without wavelengths
+//the renderer runs in RGB mode and the colour is returned unchanged.
+pub fn reflectance_at(rgb: &Vec3, lambdas: Option<&Vec3>) -> Vec3 {
+    match lambdas {
+        None => *rgb,
+        Some(l) => Vec3::xyz(rgb_to_spectrum(rgb, l.x), rgb_to_spectrum(rgb, l.y), rgb_to_spectrum(rgb, l.z))
+    }
+}
+
+//Like reflectance_at for emitted radiance: RGB white is the 6500K black body
+pub fn illuminant_at(rgb: &Vec3, lambdas: Option<&Vec3>) -> Vec3 {
+    match lambdas {
+        None => *rgb,
+        Some(l) => {
+            let r = reflectance_at(rgb, lambdas);
+            Vec3::xyz(r.x*blackbody(l.x, 6500.0), r.y*blackbody(l.y, 6500.0), r.z*blackbody(l.z, 6500.0))
+        }
+    }
+}
+
+//Hero wavelength sampling: u picks the hero wavelength uniformly, the other
+//two are rotated by a third of the range, so each is uniform as well
+pub fn sample_wavelengths(u: f32) -> Vec3 {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let l = |i: f32| {
+        let t = u + i/3.0;
+        LAMBDA_MIN + (t - t.floor())*range
+    };
+    Vec3::xyz(l(0.0), l(1.0), l(2.0))
+}
+
+//Film contribution (linear sRGB) of radiance values at the sampled
+//wavelengths; averages the three single wavelength estimates of XYZ
+pub fn spectrum_to_rgb(values: &Vec3, lambdas: &Vec3) -> Vec3 {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let mut xyz = Vec3::new();
+    for i in 0..3 {
+        xyz = xyz + cie_xyz(lambdas.get(i))*(values.get(i)*range/3.0);
+    }
+    xyz_to_film(&xyz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //film colour of an RGB emitter rendered in spectral mode, averaged over
+    //stratified wavelength samples
+    fn round_trip(rgb: &Vec3) -> Vec3 {
+        let n = 4096;
+        let mut sum = Vec3::new();
+        for k in 0..n {
+            let lambdas = sample_wavelengths((k as f32 + 0.5)/n as f32);
+            sum = sum + spectrum_to_rgb(&illuminant_at(rgb, Some(&lambdas)), &lambdas);
+        }
+        &sum/n as f32
+    }
+
+    #[test]
+    fn grey_becomes_a_constant_spectrum() {
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            assert!((rgb_to_spectrum(&Vec3::from(1.0), lambda) - 1.0).abs() < 1e-4, "white at {}", lambda);
+            assert!((rgb_to_spectrum(&Vec3::from(0.3), lambda) - 0.3).abs() < 1e-4, "grey at {}", lambda);
+            lambda += 5.0;
+        }
+    }
+
+    #[test]
+    fn spectra_give_back_their_colour() {
+        //primaries need bands of negative weight, which are clamped
+        let colours = [(Vec3::from(1.0), 1e-3), (Vec3::from(0.18), 1e-3),
+            (Vec3::xyz(1.0, 0.0, 0.0), 0.02), (Vec3::xyz(0.0, 1.0, 0.0), 0.02), (Vec3::xyz(0.0, 0.0, 1.0), 0.02)];
+        for (rgb, tolerance) in colours.iter() {
+            let c = round_trip(rgb);
+            assert!((c - *rgb).len() <= *tolerance, "{} {} {} -> {} {} {}", rgb.x, rgb.y, rgb.z, c.x, c.y, c.z);
+        }
+    }
+
+    #[test]
+    fn wavelengths_stay_in_range_a_third_apart() {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        for k in 0..1001 {
+            let u = (k as f32/1000.0).min(1.0 - f32::EPSILON);
+            let l = sample_wavelengths(u);
+            for i in 0..3 {
+                assert!(l.get(i) >= LAMBDA_MIN && l.get(i) < LAMBDA_MAX, "{} for u {}", l.get(i), u);
+                let next = l.get((i + 1)%3);
+                let step = (next - l.get(i) + range)%range;
+                assert!((step - range/3.0).abs() < 1e-2, "{} apart for u {}", step, u);
+            }
+        }
+    }
+}
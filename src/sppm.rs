@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use crate::scene::Scene;
+use crate::math::Vec3;
+use crate::primitive::Ray;
+use crate::primitive::Intersection;
+use crate::renderer::Renderer;
+use crate::control::RenderControl;
+use crate::renderer::sky;
+use crate::renderer::unoccluded;
+use crate::primitive::SpawnPoint;
+use crate::film::Film;
+use crate::sampler::Sampler;
+use crate::sampler::RandomSampler;
+
+use std::f32::consts::PI;
+
+//Stochastic progressive photon mapping. Every iteration traces one camera
+//path per pixel through specular surfaces up to its first diffuse surface
+//(the visible point), where direct lighting is sampled, and then shoots
+//photons from the lights. Photons that land near a visible point add
+//indirect light to its pixel, and the gathering radii shrink so the
+//estimate converges, including caustics seen through glass.
+//Participating media and motion blur are not supported.
+pub struct SppmRenderer {
+    iterations: u32,
+    photons: u32,
+    initial_radius: f32,
+    max_depth: usize
+}
+
+//fraction of the new photons kept at each radius update
+const ALPHA: f32 = 2.0/3.0;
+
+struct Pixel {
+    radius: f32,
+    //direct light and emission seen by the camera paths, summed
+    ld: Vec3,
+    //photon count and flux of all previous iterations
+    n: f32,
+    tau: Vec3,
+    //photons gathered in the current iteration
+    phi: Vec3,
+    m: u32
+}
+
+struct VisiblePoint<'a> {
+    it: Intersection<'a>,
+    //throughput of the camera path
+    beta: Vec3
+}
+
+//uniform grid over the visible points, hashed by cell
+struct PointGrid {
+    cell: f32,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>
+}
+
+impl PointGrid {
+    fn new(points: &[Option<VisiblePoint>], pixels: &[Pixel]) -> PointGrid {
+        let cell = pixels.iter().fold(0.0f32, |a, p| a.max(p.radius)).max(1e-6);
+        let mut grid = PointGrid{cell, cells: HashMap::new()};
+        for (i, vp) in points.iter().enumerate() {
+            if let Some(vp) = vp {
+                let r = pixels[i].radius;
+                let lo = grid.key(&(vp.it.p - Vec3::from(r)));
+                let hi = grid.key(&(vp.it.p + Vec3::from(r)));
+                for x in lo.0..hi.0 + 1 {
+                    for y in lo.1..hi.1 + 1 {
+                        for z in lo.2..hi.2 + 1 {
+                            grid.cells.entry((x, y, z)).or_default().push(i);
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn key(&self, p: &Vec3) -> (i64, i64, i64) {
+        ((p.x/self.cell).floor() as i64, (p.y/self.cell).floor() as i64, (p.z/self.cell).floor() as i64)
+    }
+
+    fn lookup(&self, p: &Vec3) -> &[usize] {
+        match self.cells.get(&self.key(p)) {
+            Some(v) => v,
+            None => &[]
+        }
+    }
+}
+
+impl SppmRenderer {
+    pub fn new(iterations: u32, photons: u32, initial_radius: f32) -> SppmRenderer {
+        SppmRenderer{iterations, photons, initial_radius, max_depth: 16}
+    }
+
+    //one light sample, without MIS
+    fn sample_direct(&self, scene: &Scene, x: &Intersection, time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        if scene.lights.is_empty() {
+            return Vec3::new();
+        }
+        let n = scene.lights.len();
+        let l = ((sampler.next()*n as f32) as usize).min(n-1);
+        let ls = match scene.lights[l].sample(&x.p, None, sampler.next(), sampler.next()) {
+            Some(ls) => ls,
+            None => {return Vec3::new();}
+        };
+        if ls.pdf <= 0.0 || ls.radiance.is_zero() {
+            return Vec3::new();
+        }
+        let f = x.mat.eval(x, &ls.wi);
+        if f.is_zero() || !unoccluded(scene, &x.spawn_point(), &SpawnPoint::at(x.p + (ls.wi*ls.dist)), time) {
+            return Vec3::new();
+        }
+        (&f*&ls.radiance)*(n as f32/ls.pdf)
+    }
+
+    //Follows the camera ray through specular surfaces. Adds emission and
+    //direct light to ld and returns the first diffuse hit.
+    fn camera_pass<'a>(&self, scene: &'a Scene, ray: Ray, weight: f32, ld: &mut Vec3,
+        sampler: &mut dyn Sampler) -> Option<VisiblePoint<'a>> {
+        let primitive = &*scene.primitive;
+        let mut ray = ray;
+        let mut beta = Vec3::from(weight);
+        for _ in 0..256 {
+            let x = match primitive.intersect(&mut ray) {
+                None => {
+                    *ld = *ld + &beta*&sky(&ray);
+                    return None;
+                }
+                Some(x) => x
+            };
+            if x.mat.is_interface() {
+                ray = x.spawn_ray(ray.d, ray.time);
+                continue;
+            }
+            *ld = *ld + &beta*&x.mat.emit(&x);
+            let (wi, pdf) = x.mat.sample(&x, sampler.next(), sampler.next());
+            if pdf <= 0.0 {
+                return None;
+            }
+            if !x.mat.is_delta() {
+                *ld = *ld + &beta*&self.sample_direct(scene, &x, ray.time, sampler);
+                return Some(VisiblePoint{it: x, beta});
+            }
+            beta = &(&beta*&x.mat.eval(&x, &wi))/pdf;
+            if beta.is_zero() {
+                return None;
+            }
+            ray = x.spawn_ray(wi, ray.time);
+        }
+        None
+    }
+
+    //Traces one photon and adds its flux to the visible points it passes.
+    //The first hit is skipped, direct light comes from the camera pass.
+    fn photon_pass(&self, scene: &Scene, grid: &PointGrid, points: &[Option<VisiblePoint>], pixels: &mut [Pixel],
+        sampler: &mut dyn Sampler) {
+        if scene.lights.is_empty() {
+            return;
+        }
+        let n = scene.lights.len();
+        let l = ((sampler.next()*n as f32) as usize).min(n-1);
+        let es = match scene.lights[l].sample_emission(None, sampler.next(), sampler.next(),
+            sampler.next(), sampler.next()) {
+            Some(x) => x,
+            None => {return;}
+        };
+        let pdf = es.pdf_pos*es.pdf_dir/n as f32;
+        if pdf <= 0.0 || es.radiance.is_zero() {
+            return;
+        }
+        let mut beta = es.radiance*(es.n.dot(&es.ray.d).abs()/pdf);
+        let mut ray = es.ray;
+        let mut depth = 0;
+        for _ in 0..256 {
+            let x = match scene.primitive.intersect(&mut ray) {
+                None => {return;}
+                Some(x) => x
+            };
+            if x.mat.is_interface() {
+                ray = x.spawn_ray(ray.d, ray.time);
+                continue;
+            }
+            if depth > 0 && !x.mat.is_delta() {
+                for &i in grid.lookup(&x.p) {
+                    let vp = match &points[i] {
+                        Some(vp) => vp,
+                        None => {continue;}
+                    };
+                    if (vp.it.p - x.p).lensqr() > pixels[i].radius*pixels[i].radius {
+                        continue;
+                    }
+                    //eval includes the cosine at the visible point, which
+                    //the density estimate must not
+                    let cos = vp.it.n.dot(&x.wo).abs();
+                    if cos <= 0.0 {
+                        continue;
+                    }
+                    let f = &vp.it.mat.eval(&vp.it, &x.wo)/cos;
+                    pixels[i].phi = pixels[i].phi + (&f*&beta);
+                    pixels[i].m += 1;
+                }
+            }
+            depth += 1;
+            if depth >= self.max_depth {
+                return;
+            }
+            let (wi, pdf) = x.mat.sample(&x, sampler.next(), sampler.next());
+            if pdf <= 0.0 {
+                return;
+            }
+            let next = &(&beta*&x.mat.eval(&x, &wi))/pdf;
+            //russian roulette on the change of throughput
+            let old = beta.x.max(beta.y).max(beta.z);
+            let q = if old > 0.0 {(next.x.max(next.y).max(next.z)/old).min(1.0)} else {0.0};
+            if sampler.next() >= q {
+                return;
+            }
+            beta = &next/q;
+            ray = x.spawn_ray(wi, ray.time);
+        }
+    }
+}
+
+impl Renderer for SppmRenderer {
+    fn render(&self, scene: &mut Scene, fname: &str, control: &RenderControl) -> Result<(), String> {
+        let (w, h) = scene.camera.image().dimensions();
+        let mut pixels: Vec<Pixel> = (0..w*h).map(|_| Pixel{
+            radius: self.initial_radius, ld: Vec3::new(), n: 0.0, tau: Vec3::new(), phi: Vec3::new(), m: 0
+        }).collect();
+        let mut iterations = 0;
+        {
+            let scene: &Scene = scene;
+            for iteration in 0..self.iterations {
+                if control.should_stop() {
+                    break;
+                }
+                control.report(&format!("Iteration {}", iteration));
+                let mut points = Vec::with_capacity(pixels.len());
+                for j in 0..h {
+                    for i in 0..w {
+                        let mut sampler = RandomSampler::with_stream((j*w + i) as u64, iteration as u64);
+                        let u = ((i as f32 + sampler.next())/w as f32)*2.0 - 1.0;
+                        let v = -(((j as f32 + sampler.next())/h as f32)*2.0 - 1.0);
+                        let (ray, weight) = scene.camera.sample_ray(Vec3::xyz(u, v, 0.0),
+                            sampler.next(), sampler.next(), sampler.next());
+                        let pixel = &mut pixels[(j*w + i) as usize];
+                        if weight > 0.0 {
+                            points.push(self.camera_pass(scene, ray, weight, &mut pixel.ld, &mut sampler));
+                        } else {
+                            points.push(None);
+                        }
+                    }
+                }
+
+                let grid = PointGrid::new(&points, &pixels);
+                //the photons of an iteration take the seed after the last pixel's
+                let mut sampler = RandomSampler::with_stream((w*h) as u64, iteration as u64);
+                for _ in 0..self.photons {
+                    self.photon_pass(scene, &grid, &points, &mut pixels, &mut sampler);
+                }
+
+                //progressive radius reduction
+                for (p, vp) in pixels.iter_mut().zip(points.iter()) {
+                    if let Some(vp) = vp {
+                        let phi = &vp.beta*&p.phi;
+                        if p.m > 0 {
+                            let n = p.n + ALPHA*p.m as f32;
+                            let radius = p.radius*(n/(p.n + p.m as f32)).sqrt();
+                            p.tau = (p.tau + phi)*((radius*radius)/(p.radius*p.radius));
+                            p.n = n;
+                            p.radius = radius;
+                        }
+                    }
+                    p.phi = Vec3::new();
+                    p.m = 0;
+                }
+                iterations += 1;
+            }
+        }
+
+        let mut film = Film::new(w, h);
+        let iterations = iterations.max(1) as f32;
+        let photons = iterations*(self.photons as f32);
+        for j in 0..h {
+            for i in 0..w {
+                let p = &pixels[(j*w + i) as usize];
+                let indirect = &p.tau/(photons*PI*p.radius*p.radius);
+                film.add(i, j, &((&p.ld/iterations) + indirect));
+            }
+        }
+        let exposure = scene.camera.exposure();
+        film.develop(&mut *scene.camera, exposure);
+        scene.camera.save_image(fname);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::linear2srgb;
+    use crate::scenes::furnace;
+
+    #[test]
+    fn furnace_ball_renders_as_its_albedo() {
+        let (mut scene, _) = furnace(16, 0.5).build().unwrap();
+        let fname = std::env::temp_dir().join("rusty_pete_sppm_furnace_test.png");
+        SppmRenderer::new(8, 2000, 0.1).render(&mut scene, &fname.to_string_lossy(), &RenderControl::new()).unwrap();
+        let image = scene.camera_mut().image();
+        let ball = (6..10).flat_map(|i| (6..10).map(move |j| (i, j)))
+            .map(|(i, j)| image.get_pixel(i, j)[0] as f32).sum::<f32>()/16.0;
+        let background = image.get_pixel(0, 0)[0] as f32;
+        assert!((ball - linear2srgb(0.5)*255.0).abs() <= 2.0, "ball {}", ball);
+        assert!(background >= 254.0, "background {}", background);
+        std::fs::remove_file(fname).ok();
+    }
+}
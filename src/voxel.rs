@@ -0,0 +1,267 @@
+use crate::math::Vec3;
+
+use std::fs;
+
+//Dense grid of scalar values (density, temperature, ...) covering the unit cube
+pub struct VoxelGrid {
+    pub nx: usize,
+    pub ny: usize,
+    pub nz: usize,
+    values: Vec<f32>
+}
+
+fn read_f32(data: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes([data[offset], data[offset+1], data[offset+2], data[offset+3]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset+1], data[offset+2], data[offset+3]])
+}
+
+impl VoxelGrid {
+    pub fn new(nx: usize, ny: usize, nz: usize, values: Vec<f32>) -> VoxelGrid {
+        assert_eq!(values.len(), nx*ny*nz, "voxel count does not match the resolution");
+        VoxelGrid{nx, ny, nz, values}
+    }
+
+    //Parses a voxel file: one ASCII header line followed by little endian binary data.
+    //  "voxels dense <nx> <ny> <nz>"            then nx*ny*nz f32 values, x fastest
+    //  "voxels sparse <nx> <ny> <nz> <count>"   then count (u32 x, u32 y, u32 z, f32 value) records;
+    //                                           missing voxels are zero
+    pub fn parse(data: &[u8]) -> Result<VoxelGrid, String> {
+        let end = match data.iter().position(|&c| c == b'\n') {
+            Some(e) => e,
+            None => {return Err(String::from("missing voxel header"));}
+        };
+        let header = String::from_utf8_lossy(&data[..end]).to_string();
+        let fields: Vec<&str> = header.split_whitespace().collect();
+        if fields.len() < 5 || fields[0] != "voxels" {
+            return Err(format!("bad voxel header '{}'", header));
+        }
+        let dims: Result<Vec<usize>, _> = fields[2..].iter().map(|f| f.parse::<usize>()).collect();
+        let dims = match dims {
+            Ok(d) => d,
+            Err(_) => {return Err(format!("bad voxel header '{}'", header));}
+        };
+        let (nx, ny, nz) = (dims[0], dims[1], dims[2]);
+        let n = match nx.checked_mul(ny).and_then(|n| n.checked_mul(nz)) {
+            Some(n) if n > 0 => n,
+            _ => {return Err(format!("bad voxel resolution {}x{}x{}", nx, ny, nz));}
+        };
+        let body = &data[end+1..];
+
+        match fields[1] {
+            "dense" => {
+                if n.checked_mul(4).is_none_or(|bytes| body.len() < bytes) {
+                    return Err(format!("expected {} voxels, file is too short", n));
+                }
+                let values = (0..n).map(|i| read_f32(body, i*4)).collect();
+                Ok(VoxelGrid::new(nx, ny, nz, values))
+            }
+            "sparse" => {
+                if dims.len() < 4 {
+                    return Err(String::from("sparse voxel header needs a count"));
+                }
+                let count = dims[3];
+                if count.checked_mul(16).is_none_or(|bytes| body.len() < bytes) {
+                    return Err(format!("expected {} sparse voxels, file is too short", count));
+                }
+                //the grid isn't bounded by the file size here
+                let mut values = Vec::new();
+                if values.try_reserve_exact(n).is_err() {
+                    return Err(format!("voxel grid {}x{}x{} is too large", nx, ny, nz));
+                }
+                values.resize(n, 0.0);
+                for i in 0..count {
+                    let x = read_u32(body, i*16) as usize;
+                    let y = read_u32(body, i*16+4) as usize;
+                    let z = read_u32(body, i*16+8) as usize;
+                    if x >= nx || y >= ny || z >= nz {
+                        return Err(format!("sparse voxel {} is out of range", i));
+                    }
+                    values[(z*ny + y)*nx + x] = read_f32(body, i*16+12);
+                }
+                Ok(VoxelGrid::new(nx, ny, nz, values))
+            }
+            other => Err(format!("unknown voxel layout '{}'", other))
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<VoxelGrid, String> {
+        match fs::read(filename) {
+            Ok(data) => VoxelGrid::parse(&data),
+            Err(e) => Err(format!("couldn't read {}: {}", filename, e))
+        }
+    }
+
+    //zero outside of the grid
+    pub fn voxel(&self, x: i64, y: i64, z: i64) -> f32 {
+        if x < 0 || y < 0 || z < 0 || x >= self.nx as i64 || y >= self.ny as i64 || z >= self.nz as i64 {
+            return 0.0;
+        }
+        self.values[((z as usize)*self.ny + y as usize)*self.nx + x as usize]
+    }
+
+    //trilinear interpolation, p in [0,1]^3
+    pub fn lookup(&self, p: &Vec3) -> f32 {
+        let x = p.x*self.nx as f32 - 0.5;
+        let y = p.y*self.ny as f32 - 0.5;
+        let z = p.z*self.nz as f32 - 0.5;
+        let (ix, iy, iz) = (x.floor(), y.floor(), z.floor());
+        let (fx, fy, fz) = (x - ix, y - iy, z - iz);
+        let (ix, iy, iz) = (ix as i64, iy as i64, iz as i64);
+
+        let mut v = 0.0;
+        for k in 0..2 {
+            for j in 0..2 {
+                for i in 0..2 {
+                    let w = (if i == 0 {1.0-fx} else {fx})
+                          * (if j == 0 {1.0-fy} else {fy})
+                          * (if k == 0 {1.0-fz} else {fz});
+                    v += w*self.voxel(ix+i, iy+j, iz+k);
+                }
+            }
+        }
+        v
+    }
+
+    pub fn max_value(&self) -> f32 {
+        self.values.iter().cloned().fold(0.0, f32::max)
+    }
+}
+
+
+//Coarse grid of upper bounds of a VoxelGrid, used to take long steps through
+//empty or thin regions during delta and ratio tracking
+pub struct MajorantGrid {
+    res: [usize;3],
+    values: Vec<f32>
+}
+
+impl MajorantGrid {
+    pub fn new(grid: &VoxelGrid, max_res: usize) -> MajorantGrid {
+        let res = [grid.nx.min(max_res).max(1), grid.ny.min(max_res).max(1), grid.nz.min(max_res).max(1)];
+        let n = [grid.nx, grid.ny, grid.nz];
+        let mut values = vec![0.0; res[0]*res[1]*res[2]];
+
+        //voxel index range whose trilinear footprint overlaps cell c along an axis
+        let range = |axis: usize, c: usize| {
+            let lo = (c as f32*n[axis] as f32/res[axis] as f32 - 0.5).floor() as i64;
+            let hi = ((c+1) as f32*n[axis] as f32/res[axis] as f32 - 0.5).ceil() as i64;
+            (lo, hi)
+        };
+
+        for cz in 0..res[2] {
+            for cy in 0..res[1] {
+                for cx in 0..res[0] {
+                    let (x0, x1) = range(0, cx);
+                    let (y0, y1) = range(1, cy);
+                    let (z0, z1) = range(2, cz);
+                    let mut m: f32 = 0.0;
+                    for z in z0..z1+1 {
+                        for y in y0..y1+1 {
+                            for x in x0..x1+1 {
+                                m = m.max(grid.voxel(x, y, z));
+                            }
+                        }
+                    }
+                    values[(cz*res[1] + cy)*res[0] + cx] = m;
+                }
+            }
+        }
+        MajorantGrid{res, values}
+    }
+
+    //Walks the cells pierced by o + t*d (grid space) between t0 and t1, calling
+    //f(segment start, segment end, majorant) until it returns false
+    pub fn traverse(&self, o: &Vec3, d: &Vec3, t0: f32, t1: f32, f: &mut dyn FnMut(f32, f32, f32) -> bool) {
+        let p = o + &(d*t0);
+        let mut cell = [0i64;3];
+        let mut step = [0i64;3];
+        let mut next = [f32::INFINITY;3];
+        let mut delta = [f32::INFINITY;3];
+        for a in 0..3 {
+            let res = self.res[a] as f32;
+            let pa = p.get(a);
+            let da = d.get(a);
+            cell[a] = ((pa*res) as i64).max(0).min(self.res[a] as i64 - 1);
+            if da > 0.0 {
+                step[a] = 1;
+                next[a] = t0 + ((cell[a]+1) as f32/res - pa)/da;
+                delta[a] = 1.0/(res*da);
+            } else if da < 0.0 {
+                step[a] = -1;
+                next[a] = t0 + (cell[a] as f32/res - pa)/da;
+                delta[a] = -1.0/(res*da);
+            }
+        }
+
+        let mut t = t0;
+        loop {
+            let a = if next[0] < next[1] && next[0] < next[2] {0} else if next[1] < next[2] {1} else {2};
+            let t_end = next[a].min(t1);
+            let idx = (cell[2] as usize*self.res[1] + cell[1] as usize)*self.res[0] + cell[0] as usize;
+            if !f(t, t_end, self.values[idx]) || t_end >= t1 {
+                return;
+            }
+            t = t_end;
+            cell[a] += step[a];
+            if cell[a] < 0 || cell[a] >= self.res[a] as i64 {
+                return;
+            }
+            next[a] += delta[a];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(header: &str, records: &[[u8; 4]]) -> Vec<u8> {
+        let mut data = format!("{}\n", header).into_bytes();
+        for r in records {
+            data.extend_from_slice(r);
+        }
+        data
+    }
+
+    #[test]
+    fn parses_dense_and_sparse_grids() {
+        let dense: Vec<[u8; 4]> = (0..8).map(|i| (i as f32).to_le_bytes()).collect();
+        let g = VoxelGrid::parse(&file("voxels dense 2 2 2", &dense)).unwrap();
+        assert_eq!((g.nx, g.ny, g.nz), (2, 2, 2));
+        assert_eq!(g.voxel(1, 0, 1), 5.0);
+
+        let record = [1u32.to_le_bytes(), 2u32.to_le_bytes(), 0u32.to_le_bytes(), 0.5f32.to_le_bytes()];
+        let g = VoxelGrid::parse(&file("voxels sparse 2 3 1 1", &record)).unwrap();
+        assert_eq!(g.voxel(1, 2, 0), 0.5);
+        assert_eq!(g.voxel(0, 2, 0), 0.0);
+        assert_eq!(g.max_value(), 0.5);
+    }
+
+    #[test]
+    fn rejects_truncated_and_malformed_files() {
+        let dense: Vec<[u8; 4]> = (0..7).map(|i| (i as f32).to_le_bytes()).collect();
+        let record = [1u32.to_le_bytes(), 2u32.to_le_bytes(), 0u32.to_le_bytes(), 0.5f32.to_le_bytes()];
+        let cases = [
+            (file("voxels dense 2 2 2", &dense), "file is too short"),
+            (file("voxels sparse 2 3 1 2", &record), "file is too short"),
+            (file("voxels sparse 1 1 1 1", &record), "out of range"),
+            (file("voxels dense 0 4 4", &[]), "bad voxel resolution"),
+            (file("voxels dense 4294967296 4294967296 4294967296", &[]), "bad voxel resolution"),
+            (file("voxels dense 2305843009213693952 2 1", &[]), "file is too short"),
+            (file("voxels sparse 1 1 1 1152921504606846976", &record), "file is too short"),
+            (file("voxels sparse 1 1 1", &[]), "needs a count"),
+            (file("voxels dense 2 -2 2", &[]), "bad voxel header"),
+            (file("voxels packed 2 2 2", &dense), "unknown voxel layout"),
+            (b"voxels dense 1 1 1".to_vec(), "missing voxel header")
+        ];
+        for (data, message) in cases.iter() {
+            match VoxelGrid::parse(data) {
+                Ok(_) => panic!("parsed a bad file, expected '{}'", message),
+                Err(e) => assert!(e.contains(message), "'{}' should contain '{}'", e, message)
+            }
+        }
+    }
+}